@@ -0,0 +1,54 @@
+//! # Allocator module
+//!
+//! The hardware independent parts of our heap allocators. They work on plain
+//! addresses, so the host tests can run them on a simulated heap region.
+
+pub mod fixed_size_block;
+pub mod linked_list;
+
+/// Align the given address `addr` upwards to alignment `align`.
+///
+/// Requires that `align` is a power of two.
+pub fn align_up(addr: usize, align: usize) -> usize {
+    // let remainder = addr % align;
+    // if remainder == 0 {
+    //     addr // addr already aligned with the given alignment
+    // } else {
+    //     // Align the address by subtracting the remainder (so that the new
+    //     // remainder is 0) and then adding the alignment (so that the address
+    //     // does not become smaller than the original address).
+    //     addr - remainder + align
+    // }
+
+    // Note that this isn't the most efficient way to implement this function. A
+    // much faster implementation below:
+
+    // Create a bitmask to align the address in a very efficient way.
+    (addr + align - 1) & !(align - 1)
+    // the `!` operator is bitwise NOT.
+    // the `&` operator is bitwise AND.
+}
+
+#[cfg(test)]
+mod tests {
+    use super::align_up;
+
+    #[test]
+    fn align_up_keeps_aligned_addresses() {
+        assert_eq!(align_up(0, 8), 0);
+        assert_eq!(align_up(4096, 4096), 4096);
+    }
+
+    #[test]
+    fn align_up_rounds_to_next_multiple() {
+        assert_eq!(align_up(1, 8), 8);
+        assert_eq!(align_up(4097, 4096), 8192);
+        for addr in 0..1000 {
+            for &align in &[1, 2, 8, 64, 512] {
+                let aligned = align_up(addr, align);
+                assert_eq!(aligned % align, 0);
+                assert!(aligned >= addr && aligned - addr < align);
+            }
+        }
+    }
+}
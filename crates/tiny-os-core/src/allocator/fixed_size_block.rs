@@ -0,0 +1,76 @@
+//! # Fixed-size block allocator module
+//!
+//! The size classes of the fixed-size block allocator of the kernel.
+
+use alloc::alloc::Layout;
+
+/// The block sizes to use.
+///
+/// The sizes must each be power of 2 because they are also used as the block
+/// alignment (alignments must be always powers of 2).
+/// 
+/// We don’t define any block sizes smaller than 8 because each block must be
+/// capable of storing a 64-bit pointer to the next block when freed. For
+/// allocations greater than 2048 bytes we will fall back to a linked list
+/// allocator.
+/// 
+/// To simplify the implementation, we define that the size of a block is also
+/// its required alignment in memory. So a 16 byte block is always aligned on a
+/// 16-byte boundary and a 512 byte block is aligned on a 512-byte boundary.
+pub const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// A helper function that choose an appropriate (lowest possible) block size
+/// for the given layout.
+///
+/// Returns an index into the `BLOCK_SIZES` array.
+pub fn list_index(layout: &Layout) -> Option<usize> {
+    // The block must have at least the size and alignment required by the given
+    // layout.
+    let required_block_size = layout.size().max(layout.align());
+    // To find the next-larger block in the `BLOCK_SIZES` slice, we first use
+    // the `iter()` method to get an iterator and then the `position()` method
+    // to find the index of the first block that is as least as large as the
+    // `required_block_size`.
+    BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout(size: usize, align: usize) -> Layout {
+        Layout::from_size_align(size, align).unwrap()
+    }
+
+    #[test]
+    fn smallest_fitting_block() {
+        assert_eq!(list_index(&layout(1, 1)), Some(0));
+        assert_eq!(list_index(&layout(8, 8)), Some(0));
+        assert_eq!(list_index(&layout(9, 1)), Some(1));
+        assert_eq!(list_index(&layout(2048, 8)), Some(BLOCK_SIZES.len() - 1));
+    }
+
+    #[test]
+    fn alignment_selects_larger_block() {
+        assert_eq!(list_index(&layout(8, 64)), Some(3));
+        assert_eq!(BLOCK_SIZES[list_index(&layout(1, 512)).unwrap()], 512);
+    }
+
+    #[test]
+    fn large_allocations_use_fallback() {
+        assert_eq!(list_index(&layout(2049, 8)), None);
+        assert_eq!(list_index(&layout(8, 4096)), None);
+    }
+
+    #[test]
+    fn blocks_fit_size_and_alignment() {
+        for size in 1..=2048 {
+            for &align in &[1, 8, 32, 256] {
+                if let Some(index) = list_index(&layout(size, align)) {
+                    let block_size = BLOCK_SIZES[index];
+                    assert!(block_size >= size && block_size.is_multiple_of(align));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,424 @@
+//! # Linked list allocator module
+//!
+//! A heap backed by a linked list of free memory blocks.
+//! 
+//! This approach construct a single linked list in the freed memory, with each
+//! node being a freed memory region.
+//!
+//! The list is sorted by address, so that a freed region can be merged with
+//! free neighbours. Allocations use either the first or the smallest suitable
+//! region, see `FitStrategy`.
+
+use super::align_up;
+use core::{ mem, ptr };
+use alloc::alloc::Layout;
+
+/// A free region of the heap.
+///
+/// The type is public so that other allocators can size caches for it, its
+/// fields are private.
+pub struct ListNode {
+    size: usize,
+    // An optional pointer to the next node. The `&'static mut` type
+    // semantically describes an owned object behind a pointer. Basically, it’s
+    // a `Box` without a destructor that frees the object at the end of the
+    // scope.
+    next: Option<&'static mut ListNode>
+}
+
+// The type has a simple constructor function named `new` and methods to
+// calculate the start and end addresses of the represented region.
+impl ListNode {
+    const fn new(size: usize) -> Self {
+        ListNode { size, next: None }
+    }
+
+    fn start_addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn end_addr(&self) -> usize {
+        self.start_addr() + self.size
+    }
+}
+
+/// The strategy for choosing a free region for an allocation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FitStrategy {
+    /// Use the first region that is large enough. This is fast, but tends to
+    /// split large regions at the start of the heap.
+    FirstFit,
+    /// Use the smallest region that is large enough. This requires walking the
+    /// whole list, but keeps large regions intact for large allocations.
+    BestFit,
+}
+
+pub struct LinkedListAllocator {
+    // A head node that points to the first heap region. It has a size of 0 and
+    // is never merged with a real region.
+    head: ListNode,
+    /// How a free region is chosen for an allocation.
+    strategy: FitStrategy,
+    /// The size of the heap that was passed to `init`.
+    heap_size: usize,
+    /// The number of allocations that were not freed yet.
+    allocations: usize,
+}
+
+impl Default for LinkedListAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LinkedListAllocator {
+    /// Creates an empty LinkedListAllocator that uses first fit.
+    pub const fn new() -> Self {
+        Self::with_strategy(FitStrategy::FirstFit)
+    }
+
+    /// Creates an empty LinkedListAllocator that uses the given strategy.
+    pub const fn with_strategy(strategy: FitStrategy) -> Self {
+        Self {
+            head: ListNode::new(0),
+            strategy,
+            heap_size: 0,
+            allocations: 0,
+        }
+    }
+
+    /// Initialize the allocator with the given heap bounds.
+    ///
+    /// This function is unsafe because the caller must guarantee that the given
+    /// heap bounds are valid and that the heap is unused. This method must be
+    /// called only once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.add_free_region(heap_start, heap_size);
+        self.heap_size = heap_size;
+    }
+
+    /// Changes the strategy for future allocations.
+    pub fn set_strategy(&mut self, strategy: FitStrategy) {
+        self.strategy = strategy;
+    }
+
+    /// Adds the given memory region to the list.
+    /// 
+    /// This method provides the fundamental insert operation on the linked
+    /// list. It is called from `init` and from our `dealloc` implementation.
+    /// Remember, the `dealloc` method is called when an allocated memory region
+    /// is freed again. To keep track of this freed memory region, we insert it
+    /// into the list at the position given by its address and merge it with
+    /// the regions directly before and after it if they are free too.
+    unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+        // ensure that the freed region is capable of holding ListNode
+        assert_eq!(align_up(addr, mem::align_of::<ListNode>()), addr);
+        assert!(size >= mem::size_of::<ListNode>());
+
+        // Find the last node that starts before the freed region. This is the
+        // dummy `head` node if the region comes before all other regions.
+        let mut current = &mut self.head;
+        while current.next.as_ref().is_some_and(|next| next.start_addr() < addr) {
+            current = current.next.as_mut().unwrap();
+        }
+
+        // merge with the following region if it starts right at our end
+        let mut size = size;
+        let mut next = current.next.take();
+        if let Some(following) = next.as_ref() {
+            assert!(addr + size <= following.start_addr(), "freed region overlaps a free region");
+        }
+        if next.as_ref().is_some_and(|following| following.start_addr() == addr + size) {
+            let following = next.unwrap();
+            size += following.size;
+            next = following.next.take();
+        }
+
+        // merge with the preceding region if it ends right at our start
+        if current.size > 0 {
+            assert!(current.end_addr() <= addr, "freed region overlaps a free region");
+        }
+        if current.size > 0 && current.end_addr() == addr {
+            current.size += size;
+            current.next = next;
+        } else {
+            // create a new list node and insert it behind `current`
+            let mut node = ListNode::new(size);
+            node.next = next;
+            let node_ptr = addr as *mut ListNode;
+            node_ptr.write(node);
+            current.next = Some(&mut *node_ptr)
+        }
+    }
+
+    /// Looks for a free region with the given size and alignment and removes it
+    /// from the list.
+    ///
+    /// Returns a tuple of the list node and the start address of the
+    /// allocation.
+    fn find_region(&mut self, size: usize, align: usize) -> Option<(&'static mut ListNode, usize)> {
+        // The start address of the chosen region, its size, and the start
+        // address of the allocation inside it.
+        let mut chosen: Option<(usize, usize, usize)> = None;
+        // Look for a large enough memory region in linked list.
+        // Iterate over the list elements.
+        for region in self.regions() {
+            let alloc_start = match Self::alloc_from_region(region, size, align) {
+                Ok(alloc_start) => alloc_start,
+                // Region not suitable -> continue with next region
+                Err(()) => continue,
+            };
+            match self.strategy {
+                FitStrategy::FirstFit => {
+                    chosen = Some((region.start_addr(), region.size, alloc_start));
+                    break;
+                }
+                FitStrategy::BestFit => {
+                    if chosen.is_none_or(|(_, best_size, _)| region.size < best_size) {
+                        chosen = Some((region.start_addr(), region.size, alloc_start));
+                    }
+                    // a region that fits exactly can't be beaten
+                    if region.size == size {
+                        break;
+                    }
+                }
+            }
+        }
+
+        // If we iterated over the whole list but found no region that is
+        // suitable for an allocation, we return None. Otherwise we remove the
+        // chosen region from the list.
+        let (region_start, _, alloc_start) = chosen?;
+        Some((self.remove_region(region_start), alloc_start))
+    }
+
+    /// Removes the region that starts at the given address from the list.
+    ///
+    /// Panics if there is no such region.
+    fn remove_region(&mut self, start_addr: usize) -> &'static mut ListNode {
+        // Reference to current list node, updated for each iteration.
+        let mut current = &mut self.head; // at the beginning, current is set to the (dummy) `head` node.
+        while let Some(ref mut region) = current.next {
+            if region.start_addr() == start_addr {
+                let next = region.next.take();
+                let region = current.next.take().unwrap();
+                current.next = next;
+                return region;
+            }
+            current = current.next.as_mut().unwrap();
+        }
+        panic!("region {:#x} is not in the free list", start_addr);
+    }
+
+    /// The function checks whether a region is suitable for an allocation with
+    /// given size and alignment.
+    /// 
+    /// Try to use the given region for an allocation with given size and
+    /// alignment.
+    ///
+    /// Returns the allocation start address on success.
+    fn alloc_from_region(region: &ListNode, size: usize, align: usize)
+        -> Result<usize, ()>
+    {
+        // First, calculates the start and end address of a potential
+        // allocation.
+        let mut alloc_start = align_up(region.start_addr(), align);
+        // The part of the region before the allocation is given back to the
+        // list, so like the excess part after the allocation (see below), it
+        // must be able to hold a ListNode. If it can't, we skip to the next
+        // aligned address that leaves enough room.
+        let front_size = alloc_start - region.start_addr();
+        if front_size > 0 && front_size < mem::size_of::<ListNode>() {
+            alloc_start = align_up(region.start_addr() + mem::size_of::<ListNode>(), align);
+        }
+        let alloc_end = alloc_start.checked_add(size).ok_or(())?;
+
+        // This check is necessary because most of the time an allocation does
+        // not fit a suitable region perfectly, so that a part of the region
+        // remains usable after the allocation. This part of the region must
+        // store its own ListNode after the allocation, so it must be large
+        // enough to do so. The check verifies exactly that: either the
+        // allocation fits perfectly (`excess_size == 0`) or the excess size is
+        // large enough to store a ListNode.
+        if alloc_end > region.end_addr() {
+            // region too small
+            return Err(());
+        }
+        let excess_size = region.end_addr() - alloc_end;
+        if excess_size > 0 && excess_size < mem::size_of::<ListNode>() {
+            // rest of region too small to hold a ListNode (required because the
+            // allocation splits the region in a used and a free part)
+            return Err(());
+        }
+
+        // region suitable for allocation
+        Ok(alloc_start)
+    }
+    /// Adjust the given layout so that the resulting allocated memory region is
+    /// also capable of storing a `ListNode`.
+    ///
+    /// Returns the adjusted size and alignment as a (size, align) tuple.
+    fn size_align(layout: Layout) -> (usize, usize) {
+        let layout = layout
+            // increase the alignment to the alignment of a ListNode if
+            // necessary.
+            .align_to(mem::align_of::<ListNode>())
+            .expect("adjusting alignment failed")
+            // round up the size to a multiple of the alignment to ensure that
+            // the start address of the next memory block will have the correct
+            // alignment for storing a ListNode too.
+            .pad_to_align();
+        // the `max` method enforce a minimum allocation size of
+        // `mem::size_of::<ListNode>`.
+        let size = layout.size().max(mem::size_of::<ListNode>());
+        (size, layout.align())
+    }
+
+    /// Returns an iterator over the free regions in list order.
+    fn regions(&self) -> impl Iterator<Item = &ListNode> + '_ {
+        let mut current = self.head.next.as_deref();
+        core::iter::from_fn(move || {
+            let region = current?;
+            current = region.next.as_deref();
+            Some(region)
+        })
+    }
+
+    /// Returns the size of the heap that was passed to `init`.
+    pub fn heap_size(&self) -> usize {
+        self.heap_size
+    }
+
+    /// Returns the number of allocations that were not freed yet.
+    pub fn allocation_count(&self) -> usize {
+        self.allocations
+    }
+
+    /// Returns the sum of the sizes of all free regions.
+    pub fn bytes_free(&self) -> usize {
+        self.regions().map(|region| region.size).sum()
+    }
+
+    /// Returns the size of the largest free region.
+    ///
+    /// Adjacent free regions are merged, so once all allocations are freed,
+    /// the largest free region is the complete heap again.
+    pub fn largest_free_region(&self) -> usize {
+        self.regions().map(|region| region.size).max().unwrap_or(0)
+    }
+
+    /// Allocates memory for the given layout.
+    ///
+    /// Returns a null pointer if no free region is large enough. This is the
+    /// implementation of `GlobalAlloc::alloc` for the kernel, which only adds
+    /// locking around it.
+    ///
+    /// This method is unsafe because the allocator must have been initialized
+    /// with valid heap bounds.
+    pub unsafe fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        // Perform layout adjustments
+        //
+        // `size_align` function ensure that each allocated block is capable of
+        // storing a `ListNode`. This is important because the memory block is
+        // going to be deallocated at some point, where we want to write a
+        // `ListNode` to it. If the block is smaller than a `ListNode` or does
+        // not have the correct alignment, undefined behavior can occur.
+        let (size, align) = LinkedListAllocator::size_align(layout);
+
+        // Uses the `find_region` method to find a suitable memory region for
+        // the allocation and remove it from the list. If this doesn’t succeed
+        // and `None` is returned, it returns `null_mut` to signal an error as
+        // there is no suitable memory region.
+        if let Some((region, alloc_start)) = self.find_region(size, align) {
+            // Read the bounds before we give parts of the region back, since
+            // adding the front part overwrites the node of the region.
+            let (region_start, region_end) = (region.start_addr(), region.end_addr());
+            let alloc_end = alloc_start.checked_add(size).expect("overflow");
+            let front_size = alloc_start - region_start;
+            if front_size > 0 {
+                self.add_free_region(region_start, front_size);
+            }
+            let excess_size = region_end - alloc_end;
+            if excess_size > 0 {
+                self.add_free_region(alloc_end, excess_size);
+            }
+            self.allocations += 1;
+            alloc_start as *mut u8
+        } else {
+            ptr::null_mut()
+        }
+    }
+
+    /// Frees memory that was returned by `allocate` with the same layout.
+    ///
+    /// This method is unsafe because the caller must guarantee that `ptr` was
+    /// allocated by this allocator with the given layout.
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        // Perform layout adjustments
+        let (size, _) = LinkedListAllocator::size_align(layout);
+
+        // add the deallocated region to the free list.
+        self.add_free_region(ptr as usize, size);
+        self.allocations -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::boxed::Box;
+
+    /// A free region of the given size at a simulated heap.
+    fn region(size: usize) -> Box<[u64]> {
+        std::vec![0u64; size / 8].into_boxed_slice()
+    }
+
+    fn node(memory: &mut [u64]) -> &ListNode {
+        let node = memory.as_mut_ptr() as *mut ListNode;
+        unsafe {
+            node.write(ListNode::new(memory.len() * 8));
+            &*node
+        }
+    }
+
+    #[test]
+    fn region_fits_exactly() {
+        let mut memory = region(64);
+        let node = node(&mut memory);
+        assert_eq!(LinkedListAllocator::alloc_from_region(node, 64, 8), Ok(node.start_addr()));
+    }
+
+    #[test]
+    fn region_too_small() {
+        let mut memory = region(64);
+        let node = node(&mut memory);
+        assert_eq!(LinkedListAllocator::alloc_from_region(node, 72, 8), Err(()));
+    }
+
+    #[test]
+    fn excess_too_small_for_node() {
+        let mut memory = region(64);
+        let node = node(&mut memory);
+        let excess = mem::size_of::<ListNode>() - 8;
+        assert_eq!(LinkedListAllocator::alloc_from_region(node, 64 - excess, 8), Err(()));
+    }
+
+    #[test]
+    fn front_padding_holds_a_node() {
+        let mut memory = region(4096);
+        let node = node(&mut memory);
+        let start = LinkedListAllocator::alloc_from_region(node, 64, 16).unwrap();
+        assert_eq!(start % 16, 0);
+        let front = start - node.start_addr();
+        assert!(front == 0 || front >= mem::size_of::<ListNode>());
+    }
+
+    #[test]
+    fn size_align_fits_a_node() {
+        let layout = Layout::from_size_align(1, 1).unwrap();
+        let (size, align) = LinkedListAllocator::size_align(layout);
+        assert!(size >= mem::size_of::<ListNode>());
+        assert_eq!(align, mem::align_of::<ListNode>());
+    }
+}
+
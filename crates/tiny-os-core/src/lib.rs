@@ -0,0 +1,33 @@
+//! # Tiny OS core
+//!
+//! The parts of our kernel that don't depend on the hardware.
+//!
+//! Everything in this crate only needs `core` and `alloc`, so besides being
+//! used by the kernel, it also builds for the host target. This allows us to
+//! test the allocator and executor logic with a normal `cargo test` instead of
+//! booting a VM:
+//!
+//! ```sh
+//! cd crates/tiny-os-core
+//! cargo +stable test --target x86_64-unknown-linux-gnu
+//! ```
+//!
+//! The `--target` flag is required because the `.cargo/config.toml` of the
+//! kernel sets our custom target as default. We use a stable toolchain since it
+//! ignores the `build-std` option of that config, which would otherwise try to
+//! rebuild `core` for the host.
+
+#![no_std]
+// Our unsafe functions explain their requirements in the prose of their doc
+// comment, like the rest of the kernel, instead of a `# Safety` section.
+#![allow(clippy::missing_safety_doc)]
+
+extern crate alloc;
+
+// The unit tests run on the host, where the test harness needs the standard
+// library.
+#[cfg(test)]
+extern crate std;
+
+pub mod allocator;
+pub mod task;
@@ -0,0 +1,355 @@
+//! # Broadcast module
+//!
+//! A channel that delivers every value to all receivers.
+
+use alloc::{ boxed::Box, sync::Arc, vec::Vec };
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{ Context, Poll },
+};
+use futures_util::stream::Stream;
+use crate::task::sync::wait_queue::WaitQueue;
+use super::{ SendError, TryRecvError, TrySendError };
+
+/// Creates a broadcast channel that buffers up to `capacity` values.
+///
+/// Each receiver gets a clone of every value that is sent after it
+/// subscribed. A value stays in the buffer until all receivers read it, so
+/// the slowest receiver limits the senders: `Sender::send` waits while the
+/// buffer is full. Use `Sender::subscribe` to add receivers.
+///
+/// Panics if `capacity` is 0.
+pub fn channel<T: Clone>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "the capacity of a channel must not be 0");
+    let slots: Vec<_> = (0..capacity).map(|_| Slot { value: None, remaining: 0 }).collect();
+    let shared = Arc::new(spin::Mutex::new(State {
+        slots: slots.into_boxed_slice(),
+        tail: 0,
+        senders: 1,
+        receivers: 1,
+        recv_waiters: WaitQueue::new(),
+        send_waiters: WaitQueue::new(),
+    }));
+    let receiver = Receiver { shared: shared.clone(), next: 0, waiter: None };
+    (Sender { shared }, receiver)
+}
+
+struct Slot<T> {
+    value: Option<T>,
+    /// The number of receivers that didn't read the value yet. The slot is
+    /// free when this is 0.
+    remaining: usize,
+}
+
+/// The state of a channel.
+///
+/// Unlike the other primitives, we wake tasks while we hold the lock, so that
+/// `try_send` doesn't need to collect the wakers in an allocated list. This is
+/// fine since wakers only schedule their task and never touch the channel.
+struct State<T> {
+    /// The buffer, allocated when the channel is created. The values are
+    /// numbered in the order they are sent, and value `n` is stored in slot
+    /// `n % capacity`.
+    slots: Box<[Slot<T>]>,
+    /// The number of the next value.
+    tail: u64,
+    senders: usize,
+    receivers: usize,
+    recv_waiters: WaitQueue,
+    send_waiters: WaitQueue,
+}
+
+impl<T> State<T> {
+    fn slot(&mut self, n: u64) -> &mut Slot<T> {
+        let len = self.slots.len() as u64;
+        &mut self.slots[(n % len) as usize]
+    }
+
+    /// Stores a value for all receivers and wakes them.
+    ///
+    /// This doesn't allocate or free memory, so `try_send` can call it in
+    /// interrupt handlers.
+    fn push(&mut self, value: T) -> Result<(), TrySendError<T>> {
+        if self.receivers == 0 {
+            return Err(TrySendError::Closed(value));
+        }
+        let (tail, receivers) = (self.tail, self.receivers);
+        let slot = self.slot(tail);
+        // The slot of the next value holds the oldest value, until all
+        // receivers read it.
+        if slot.remaining > 0 {
+            return Err(TrySendError::Full(value));
+        }
+        slot.value = Some(value);
+        slot.remaining = receivers;
+        self.tail += 1;
+        self.recv_waiters.wake_all();
+        Ok(())
+    }
+
+    /// Marks value `n` as read by one receiver. Returns the value if it was
+    /// the last receiver, which frees the slot.
+    fn release(&mut self, n: u64) -> Option<T> {
+        let slot = self.slot(n);
+        slot.remaining -= 1;
+        if slot.remaining > 0 {
+            return None;
+        }
+        let value = slot.value.take();
+        if let Some(waiter) = self.send_waiters.pop() {
+            waiter.wake();
+        }
+        value
+    }
+
+    /// Reads the value with number `*next` for a receiver.
+    fn recv(&mut self, next: &mut u64) -> Result<T, TryRecvError>
+    where
+        T: Clone,
+    {
+        if *next == self.tail {
+            return Err(if self.senders == 0 { TryRecvError::Closed } else { TryRecvError::Empty });
+        }
+        let n = *next;
+        *next += 1;
+        let slot = self.slot(n);
+        // The last receiver gets the value itself instead of a clone.
+        let value = if slot.remaining == 1 { None } else { slot.value.clone() };
+        let last = self.release(n);
+        Ok(value.or(last).unwrap())
+    }
+}
+
+/// The sending half of a broadcast channel.
+///
+/// Clone it to get more senders. The channel is closed for the receivers
+/// when all senders were dropped.
+pub struct Sender<T> {
+    shared: Arc<spin::Mutex<State<T>>>,
+}
+
+impl<T> Sender<T> {
+    /// Sends a value to all receivers, waiting while the buffer is full.
+    ///
+    /// Fails if there are no receivers. Dropping the future before it
+    /// completes drops the value.
+    pub fn send(&self, value: T) -> Sending<'_, T> {
+        Sending {
+            sender: self,
+            value: Some(value),
+            waiter: None,
+        }
+    }
+
+    /// Sends a value if the buffer has room, without waiting.
+    ///
+    /// This can be used in interrupt handlers: it doesn't allocate, and it
+    /// only tries to take the lock of the channel. If the interrupted code
+    /// holds the lock, it fails with `TrySendError::Full`.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        match self.shared.try_lock() {
+            Some(mut state) => state.push(value),
+            None => Err(TrySendError::Full(value)),
+        }
+    }
+
+    /// Creates a receiver, which gets all values that are sent from now on.
+    pub fn subscribe(&self) -> Receiver<T> {
+        let mut state = self.shared.lock();
+        state.receivers += 1;
+        Receiver { shared: self.shared.clone(), next: state.tail, waiter: None }
+    }
+
+    /// Returns the number of receivers.
+    pub fn receiver_count(&self) -> usize {
+        self.shared.lock().receivers
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock().senders += 1;
+        Sender { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            state.recv_waiters.wake_all();
+        }
+    }
+}
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Sender").field("receivers", &self.receiver_count()).finish()
+    }
+}
+
+/// The future that `Sender::send` returns.
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Sending<'a, T> {
+    sender: &'a Sender<T>,
+    /// The value, until it is sent.
+    value: Option<T>,
+    /// Our ID in the queue of waiting senders.
+    waiter: Option<u64>,
+}
+
+// We never pin the value, we only move it into the channel.
+impl<T> Unpin for Sending<'_, T> {}
+
+impl<T> Future for Sending<'_, T> {
+    type Output = Result<(), SendError<T>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let sender = self.sender;
+        let value = self.value.take().expect("`Sending` polled after completion");
+        let mut state = sender.shared.lock();
+        let result = match state.push(value) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Closed(value)) => Err(SendError(value)),
+            Err(TrySendError::Full(value)) => {
+                self.value = Some(value);
+                // A receiver that frees a slot takes us out of the queue when
+                // it wakes us, so we might have to queue up again.
+                let queued = match self.waiter {
+                    Some(id) => state.send_waiters.update(id, cx.waker()),
+                    None => false,
+                };
+                if !queued {
+                    self.waiter = Some(state.send_waiters.push(0, cx.waker()));
+                }
+                return Poll::Pending;
+            }
+        };
+        if let Some(id) = self.waiter.take() {
+            state.send_waiters.remove(id);
+        }
+        Poll::Ready(result)
+    }
+}
+
+impl<T> Drop for Sending<'_, T> {
+    fn drop(&mut self) {
+        if let Some(id) = self.waiter {
+            let mut state = self.sender.shared.lock();
+            if !state.send_waiters.remove(id) {
+                // We were woken for a free slot that we no longer need.
+                if let Some(next) = state.send_waiters.pop() {
+                    next.wake();
+                }
+            }
+        }
+    }
+}
+
+/// The receiving half of a broadcast channel.
+pub struct Receiver<T> {
+    shared: Arc<spin::Mutex<State<T>>>,
+    /// The number of the next value that we read.
+    next: u64,
+    /// Our ID in the queue of waiting receivers.
+    waiter: Option<u64>,
+}
+
+impl<T: Clone> Receiver<T> {
+    /// Waits for the next value.
+    ///
+    /// Returns `None` when all senders were dropped and we read all values.
+    pub fn recv(&mut self) -> Recv<'_, T> {
+        Recv { receiver: self }
+    }
+
+    /// Takes the next value if one is available, without waiting.
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        self.shared.lock().recv(&mut self.next)
+    }
+
+    /// Polls for the next value, like `recv`.
+    pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<Option<T>> {
+        let mut state = self.shared.lock();
+        let value = match state.recv(&mut self.next) {
+            Ok(value) => Some(value),
+            Err(TryRecvError::Closed) => None,
+            Err(TryRecvError::Empty) => {
+                // Senders take us out of the queue when they wake us.
+                let queued = match self.waiter {
+                    Some(id) => state.recv_waiters.update(id, cx.waker()),
+                    None => false,
+                };
+                if !queued {
+                    self.waiter = Some(state.recv_waiters.push(0, cx.waker()));
+                }
+                return Poll::Pending;
+            }
+        };
+        if let Some(id) = self.waiter.take() {
+            state.recv_waiters.remove(id);
+        }
+        Poll::Ready(value)
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Returns the number of values that we didn't read yet.
+    pub fn len(&self) -> usize {
+        (self.shared.lock().tail - self.next) as usize
+    }
+
+    /// Returns `true` if we read all values.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        if let Some(id) = self.waiter {
+            state.recv_waiters.remove(id);
+        }
+        // The values that we didn't read must not block the senders.
+        for n in self.next..state.tail {
+            state.release(n);
+        }
+        state.receivers -= 1;
+        if state.receivers == 0 {
+            // Waiting senders fail now.
+            state.send_waiters.wake_all();
+        }
+    }
+}
+
+impl<T: Clone> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.get_mut().poll_recv(cx)
+    }
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Receiver").field("len", &self.len()).finish()
+    }
+}
+
+/// The future that `Receiver::recv` returns.
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Recv<'a, T> {
+    receiver: &'a mut Receiver<T>,
+}
+
+impl<T: Clone> Future for Recv<'_, T> {
+    type Output = Option<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.receiver.poll_recv(cx)
+    }
+}
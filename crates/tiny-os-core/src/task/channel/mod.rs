@@ -0,0 +1,147 @@
+//! # Channel module
+//!
+//! Channels that pass values between tasks, and from interrupt handlers to
+//! tasks:
+//!
+//! - `mpsc`: many senders and one receiver, either with a bounded buffer,
+//!   where senders wait while it is full, or with an unbounded one.
+//! - `oneshot`: a single value from one sender to one receiver, e.g. the
+//!   result of a request.
+//! - `broadcast`: every receiver gets a clone of every value. The buffer is
+//!   bounded, and senders wait for the slowest receiver.
+//!
+//! A receiver that waits stores its `Waker` in the channel and returns
+//! `Pending`, like the primitives of the `sync` module. The receivers of
+//! `mpsc` and `broadcast` also implement `Stream`, so the `StreamExt` methods
+//! like `next` work with them.
+
+use core::fmt;
+
+pub mod broadcast;
+pub mod mpsc;
+pub mod oneshot;
+
+/// The error of a send when all receivers were dropped. It contains the value
+/// that couldn't be sent.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> SendError<T> {
+    /// Returns the value that couldn't be sent.
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+// We don't require `T: Debug`, so that `unwrap` works for all values.
+impl<T> fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SendError").finish_non_exhaustive()
+    }
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the channel is closed")
+    }
+}
+
+/// The error of a `try_send`. It contains the value that couldn't be sent.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The buffer of the channel is full.
+    Full(T),
+    /// All receivers were dropped.
+    Closed(T),
+}
+
+impl<T> TrySendError<T> {
+    /// Returns the value that couldn't be sent.
+    pub fn into_inner(self) -> T {
+        match self {
+            TrySendError::Full(value) | TrySendError::Closed(value) => value,
+        }
+    }
+}
+
+impl<T> fmt::Debug for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => write!(f, "Full(..)"),
+            TrySendError::Closed(_) => write!(f, "Closed(..)"),
+        }
+    }
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => write!(f, "the channel is full"),
+            TrySendError::Closed(_) => write!(f, "the channel is closed"),
+        }
+    }
+}
+
+/// The error of a `try_recv`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// No value is available right now.
+    Empty,
+    /// No value is available, and all senders were dropped, so no value will
+    /// come anymore.
+    Closed,
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TryRecvError::Empty => write!(f, "the channel is empty"),
+            TryRecvError::Closed => write!(f, "the channel is closed"),
+        }
+    }
+}
+
+/// The error of a `oneshot::Receiver` whose sender was dropped without
+/// sending a value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the sender was dropped")
+    }
+}
+
+// ********** Sidenote **********
+//
+// # Sending from interrupt handlers
+//
+// Channels are the way to hand data from an interrupt handler to a task, like
+// the scancodes of the keyboard interrupt. An interrupt handler can interrupt
+// a task at any instruction, also while that task uses the same channel. So it
+// must not wait for a lock that the task holds, since the task can't continue
+// before the handler returns. And it must not allocate, since the task might
+// hold the lock of the heap.
+//
+// These sends are safe in interrupt handlers:
+//
+// - `mpsc::Sender::try_send`: the buffer is an `ArrayQueue`, which is
+//   allocated when the channel is created and doesn't use locks. The receiver
+//   is woken through an `AtomicWaker`.
+// - `oneshot::Sender::send`: the value is stored in a slot of the channel, and
+//   an atomic state tells the receiver when it is written.
+// - `broadcast::Sender::try_send`: the buffer is allocated up front, but it is
+//   protected by a spin lock. `try_send` only tries to take the lock, and
+//   fails with `TrySendError::Full` if the interrupted code holds it.
+//
+// `mpsc::UnboundedSender::send` is not safe there: the `SegQueue` of the
+// unbounded channel allocates a new block now and then. The async `send`
+// methods are not either, but they only make sense in tasks anyway.
+//
+// Waking the receiver calls its `Waker`. The wakers of our executor only push
+// the task to the lock-free run list, so this is fine.
+//
+// Finally, the handler shouldn't drop the last handle of a channel, since that
+// frees the memory of the channel. In practice, the sender lives in a static
+// or in the state of the handler, like the `SCANCODE_QUEUE` of the keyboard
+// module.
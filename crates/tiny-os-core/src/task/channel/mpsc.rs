@@ -0,0 +1,384 @@
+//! # Mpsc module
+//!
+//! Channels with many senders and one receiver.
+
+use alloc::sync::Arc;
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{ AtomicBool, AtomicUsize, Ordering },
+    task::{ Context, Poll },
+};
+use crossbeam_queue::{ ArrayQueue, PushError, SegQueue };
+use futures_util::{ stream::Stream, task::AtomicWaker };
+use crate::task::sync::wait_queue::{ WaitQueue, Waiter };
+use super::{ SendError, TryRecvError, TrySendError };
+
+/// Creates a channel that buffers up to `capacity` values.
+///
+/// `Sender::send` waits while the buffer is full, so a fast sender can't
+/// use up the heap. `Sender::try_send` fails instead, which makes it usable
+/// in interrupt handlers.
+///
+/// Panics if `capacity` is 0.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "the capacity of a channel must not be 0");
+    let shared = Arc::new(Shared::new(Queue::Bounded(ArrayQueue::new(capacity))));
+    (Sender { shared: shared.clone() }, Receiver { shared })
+}
+
+/// Creates a channel without a limit on the buffered values.
+///
+/// Sending never waits, but it allocates, so don't send from interrupt
+/// handlers.
+pub fn unbounded_channel<T>() -> (UnboundedSender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared::new(Queue::Unbounded(SegQueue::new())));
+    (UnboundedSender { shared: shared.clone() }, Receiver { shared })
+}
+
+enum Queue<T> {
+    Bounded(ArrayQueue<T>),
+    Unbounded(SegQueue<T>),
+}
+
+struct Shared<T> {
+    queue: Queue<T>,
+    /// The waker of the receiver.
+    ///
+    /// Senders wake the receiver without a lock, so they can run in interrupt
+    /// handlers.
+    recv_waker: AtomicWaker,
+    senders: AtomicUsize,
+    /// Set when the receiver is dropped.
+    closed: AtomicBool,
+    /// The senders of a bounded channel that wait for a free place.
+    ///
+    /// Only tasks lock this queue: `try_send` never waits, so interrupt
+    /// handlers don't need it.
+    send_waiters: spin::Mutex<WaitQueue>,
+}
+
+impl<T> Shared<T> {
+    fn new(queue: Queue<T>) -> Self {
+        Shared {
+            queue,
+            recv_waker: AtomicWaker::new(),
+            senders: AtomicUsize::new(1),
+            closed: AtomicBool::new(false),
+            send_waiters: spin::Mutex::new(WaitQueue::new()),
+        }
+    }
+
+    /// Adds a value to the queue and wakes the receiver. Returns the value if
+    /// the queue is full.
+    fn push(&self, value: T) -> Result<(), T> {
+        match &self.queue {
+            Queue::Bounded(queue) => queue.push(value).map_err(|PushError(value)| value)?,
+            Queue::Unbounded(queue) => queue.push(value),
+        }
+        self.recv_waker.wake();
+        Ok(())
+    }
+
+    /// Takes the oldest value out of the queue and wakes a sender that waits
+    /// for the free place.
+    fn pop(&self) -> Option<T> {
+        let value = match &self.queue {
+            Queue::Bounded(queue) => queue.pop().ok()?,
+            Queue::Unbounded(queue) => return queue.pop().ok(),
+        };
+        // Senders register under the lock before they give up, so they either
+        // see the free place or are in the queue by now.
+        let waiter = self.send_waiters.lock().pop();
+        if let Some(waiter) = waiter {
+            waiter.wake();
+        }
+        Some(value)
+    }
+
+    fn len(&self) -> usize {
+        match &self.queue {
+            Queue::Bounded(queue) => queue.len(),
+            Queue::Unbounded(queue) => queue.len(),
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        if self.is_closed() {
+            return Err(TrySendError::Closed(value));
+        }
+        self.push(value).map_err(TrySendError::Full)
+    }
+
+    fn add_sender(&self) {
+        self.senders.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn drop_sender(&self) {
+        // The release ordering makes our values visible to a receiver that
+        // sees that no senders are left.
+        if self.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.recv_waker.wake();
+        }
+    }
+}
+
+/// The sending half of a bounded channel, see `channel`.
+///
+/// Clone it to get more senders. The channel is closed for the receiver when
+/// all senders were dropped.
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Sends a value, waiting while the buffer is full.
+    ///
+    /// Fails if the receiver was dropped. Dropping the future before it
+    /// completes drops the value.
+    pub fn send(&self, value: T) -> Sending<'_, T> {
+        Sending {
+            sender: self,
+            value: Some(value),
+            waiter: None,
+        }
+    }
+
+    /// Sends a value if the buffer has room, without waiting.
+    ///
+    /// This neither locks nor allocates, so it can be used in interrupt
+    /// handlers.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        self.shared.try_send(value)
+    }
+
+    /// Returns `true` if the receiver was dropped.
+    pub fn is_closed(&self) -> bool {
+        self.shared.is_closed()
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.add_sender();
+        Sender { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.shared.drop_sender();
+    }
+}
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Sender").field("len", &self.shared.len()).finish()
+    }
+}
+
+/// The future that `Sender::send` returns.
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Sending<'a, T> {
+    sender: &'a Sender<T>,
+    /// The value, until it is sent.
+    value: Option<T>,
+    /// Our ID in the queue of waiting senders.
+    waiter: Option<u64>,
+}
+
+// We never pin the value, we only move it into the channel.
+impl<T> Unpin for Sending<'_, T> {}
+
+impl<T> Future for Sending<'_, T> {
+    type Output = Result<(), SendError<T>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let sender = self.sender;
+        let shared = &*sender.shared;
+        let value = self.value.take().expect("`Sending` polled after completion");
+        // We hold the lock while we try to send, so a receiver that frees a
+        // place in between wakes us, see `Shared::pop`.
+        let mut waiters = shared.send_waiters.lock();
+        let result = if shared.is_closed() {
+            Err(SendError(value))
+        } else {
+            match shared.push(value) {
+                Ok(()) => Ok(()),
+                Err(value) => {
+                    self.value = Some(value);
+                    // The receiver takes us out of the queue when it wakes us,
+                    // so we might have to queue up again.
+                    let queued = match self.waiter {
+                        Some(id) => waiters.update(id, cx.waker()),
+                        None => false,
+                    };
+                    if !queued {
+                        self.waiter = Some(waiters.push(0, cx.waker()));
+                    }
+                    return Poll::Pending;
+                }
+            }
+        };
+        if let Some(id) = self.waiter.take() {
+            waiters.remove(id);
+        }
+        Poll::Ready(result)
+    }
+}
+
+impl<T> Drop for Sending<'_, T> {
+    fn drop(&mut self) {
+        if let Some(id) = self.waiter {
+            let mut waiters = self.sender.shared.send_waiters.lock();
+            if !waiters.remove(id) {
+                // We were woken for a free place that we no longer need.
+                let next = waiters.pop();
+                drop(waiters);
+                if let Some(next) = next {
+                    next.wake();
+                }
+            }
+        }
+    }
+}
+
+/// The sending half of an unbounded channel, see `unbounded_channel`.
+pub struct UnboundedSender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> UnboundedSender<T> {
+    /// Sends a value. Fails only if the receiver was dropped.
+    ///
+    /// Don't call this from interrupt handlers, since it might allocate.
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        self.shared.try_send(value).map_err(|err| SendError(err.into_inner()))
+    }
+
+    /// Returns `true` if the receiver was dropped.
+    pub fn is_closed(&self) -> bool {
+        self.shared.is_closed()
+    }
+}
+
+impl<T> Clone for UnboundedSender<T> {
+    fn clone(&self) -> Self {
+        self.shared.add_sender();
+        UnboundedSender { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for UnboundedSender<T> {
+    fn drop(&mut self) {
+        self.shared.drop_sender();
+    }
+}
+
+impl<T> fmt::Debug for UnboundedSender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("UnboundedSender").field("len", &self.shared.len()).finish()
+    }
+}
+
+/// The receiving half of a bounded or unbounded channel.
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Waits for the next value.
+    ///
+    /// Returns `None` when all senders were dropped and the buffer is empty.
+    pub fn recv(&mut self) -> Recv<'_, T> {
+        Recv { receiver: self }
+    }
+
+    /// Takes the next value if one is available, without waiting.
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        if let Some(value) = self.shared.pop() {
+            return Ok(value);
+        }
+        if self.shared.senders.load(Ordering::Acquire) == 0 {
+            // A sender might have sent a value right before it was dropped.
+            self.shared.pop().ok_or(TryRecvError::Closed)
+        } else {
+            Err(TryRecvError::Empty)
+        }
+    }
+
+    /// Polls for the next value, like `recv`.
+    pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<Option<T>> {
+        match self.try_recv() {
+            Ok(value) => return Poll::Ready(Some(value)),
+            Err(TryRecvError::Closed) => return Poll::Ready(None),
+            Err(TryRecvError::Empty) => {}
+        }
+        // Like the keyboard task, we check again after registering the waker,
+        // so we don't miss a value that was sent in between.
+        self.shared.recv_waker.register(cx.waker());
+        match self.try_recv() {
+            Ok(value) => {
+                self.shared.recv_waker.take();
+                Poll::Ready(Some(value))
+            }
+            Err(TryRecvError::Closed) => Poll::Ready(None),
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+
+    /// Returns the number of buffered values.
+    pub fn len(&self) -> usize {
+        self.shared.len()
+    }
+
+    /// Returns `true` if no values are buffered.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::Release);
+        // Waiting senders fail now.
+        let waiters = self.shared.send_waiters.lock().take_all();
+        waiters.into_iter().for_each(Waiter::wake);
+        // We drop the buffered values here, so that the last sender doesn't
+        // drop them, which might be an interrupt handler.
+        while self.shared.pop().is_some() {}
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.get_mut().poll_recv(cx)
+    }
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Receiver").field("len", &self.len()).finish()
+    }
+}
+
+/// The future that `Receiver::recv` returns.
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Recv<'a, T> {
+    receiver: &'a mut Receiver<T>,
+}
+
+impl<T> Future for Recv<'_, T> {
+    type Output = Option<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.receiver.poll_recv(cx)
+    }
+}
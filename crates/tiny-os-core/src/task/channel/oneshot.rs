@@ -0,0 +1,166 @@
+//! # Oneshot module
+//!
+//! A channel for a single value.
+
+use alloc::sync::Arc;
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{ AtomicU8, Ordering },
+    task::{ Context, Poll },
+};
+use futures_util::task::AtomicWaker;
+use super::{ RecvError, SendError, TryRecvError };
+
+/// Creates a channel that passes one value from the sender to the receiver.
+///
+/// The receiver is a future, so the result is available with
+/// `receiver.await`.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let inner = Arc::new(Inner {
+        state: AtomicU8::new(0),
+        value: UnsafeCell::new(None),
+        recv_waker: AtomicWaker::new(),
+    });
+    (Sender { inner: Some(inner.clone()) }, Receiver { inner })
+}
+
+/// Set by the sender after it wrote the value.
+const VALUE_SENT: u8 = 1 << 0;
+/// Set when the sender is dropped without sending.
+const SENDER_DROPPED: u8 = 1 << 1;
+/// Set when the receiver is dropped.
+const RECEIVER_DROPPED: u8 = 1 << 2;
+
+struct Inner<T> {
+    state: AtomicU8,
+    /// Only the sender writes the value, before it sets `VALUE_SENT`. Only the
+    /// receiver reads it, after it saw `VALUE_SENT`. So they never access it at
+    /// the same time, and we don't need a lock.
+    value: UnsafeCell<Option<T>>,
+    recv_waker: AtomicWaker,
+}
+
+// The value moves from the sender to the receiver, which might run on another
+// thread of the host tests. The state makes sure that only one of them
+// accesses it.
+unsafe impl<T: Send> Send for Inner<T> {}
+unsafe impl<T: Send> Sync for Inner<T> {}
+
+/// The sending half of a oneshot channel.
+pub struct Sender<T> {
+    /// `None` after the value was sent.
+    inner: Option<Arc<Inner<T>>>,
+}
+
+impl<T> Sender<T> {
+    /// Sends the value. Fails if the receiver was dropped.
+    ///
+    /// This neither waits, locks, nor allocates, so it can be used in
+    /// interrupt handlers.
+    pub fn send(mut self, value: T) -> Result<(), SendError<T>> {
+        let inner = self.inner.take().expect("the value is only sent once");
+        if inner.state.load(Ordering::Acquire) & RECEIVER_DROPPED != 0 {
+            return Err(SendError(value));
+        }
+        // The receiver doesn't read the value before we set `VALUE_SENT`.
+        unsafe { *inner.value.get() = Some(value) };
+        let state = inner.state.fetch_or(VALUE_SENT, Ordering::AcqRel);
+        if state & RECEIVER_DROPPED != 0 {
+            // The receiver was dropped before it could see the value, so it
+            // never reads it, and we can take it back.
+            let value = unsafe { (*inner.value.get()).take() };
+            return Err(SendError(value.unwrap()));
+        }
+        inner.recv_waker.wake();
+        Ok(())
+    }
+
+    /// Returns `true` if the receiver was dropped, so `send` would fail.
+    pub fn is_closed(&self) -> bool {
+        match &self.inner {
+            Some(inner) => inner.state.load(Ordering::Acquire) & RECEIVER_DROPPED != 0,
+            None => false,
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if let Some(inner) = &self.inner {
+            inner.state.fetch_or(SENDER_DROPPED, Ordering::AcqRel);
+            inner.recv_waker.wake();
+        }
+    }
+}
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Sender").field("is_closed", &self.is_closed()).finish()
+    }
+}
+
+/// The receiving half of a oneshot channel.
+///
+/// Awaiting it returns the value, or `RecvError` if the sender was dropped
+/// without sending one.
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Receiver<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Takes the value if it was sent, without waiting.
+    ///
+    /// After the value was taken, this returns `TryRecvError::Closed`.
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let state = self.inner.state.load(Ordering::Acquire);
+        if state & VALUE_SENT != 0 {
+            // The sender no longer touches the value after setting the flag.
+            unsafe { (*self.inner.value.get()).take() }.ok_or(TryRecvError::Closed)
+        } else if state & SENDER_DROPPED != 0 {
+            Err(TryRecvError::Closed)
+        } else {
+            Err(TryRecvError::Empty)
+        }
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<T, RecvError>> {
+        match self.try_recv() {
+            Ok(value) => return Poll::Ready(Ok(value)),
+            Err(TryRecvError::Closed) => return Poll::Ready(Err(RecvError)),
+            Err(TryRecvError::Empty) => {}
+        }
+        // Check again after registering, see `mpsc::Receiver::poll_recv`.
+        self.inner.recv_waker.register(cx.waker());
+        match self.try_recv() {
+            Ok(value) => Poll::Ready(Ok(value)),
+            Err(TryRecvError::Closed) => Poll::Ready(Err(RecvError)),
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let state = self.inner.state.fetch_or(RECEIVER_DROPPED, Ordering::AcqRel);
+        if state & VALUE_SENT != 0 {
+            // Drop the value here rather than in the sender, which might be an
+            // interrupt handler.
+            unsafe { (*self.inner.value.get()).take() };
+        }
+    }
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.inner.state.load(Ordering::Acquire);
+        f.debug_struct("Receiver").field("value_sent", &(state & VALUE_SENT != 0)).finish()
+    }
+}
@@ -0,0 +1,645 @@
+//! # Executor module
+//! 
+//! An executor with waker support.
+//! 
+//! To fix the performance problem in simple executor, we need to create an
+//! executor that properly utilizes the `Waker` notifications. This way, the
+//! executor is notified when for example, the next keyboard interrupt occurs,
+//! so it does not need to keep polling the `print_keypresses` task over and
+//! over again.
+
+use super::{
+    join::{ self, JoinHandle },
+    monitor::{ Monitor, Registry, TaskInfo },
+    run_queue::{ RunQueue, TaskHeader, TaskRef },
+    Priority,
+    Task,
+    TaskId,
+    NUM_PRIORITIES,
+};
+use alloc::{
+    collections::{ BTreeMap, VecDeque },
+    rc::Rc,
+    sync::{ Arc, Weak },
+    vec::Vec,
+};
+use core::{
+    fmt,
+    future::Future,
+    mem::ManuallyDrop,
+    sync::atomic::Ordering,
+    task::{ Waker, Context, Poll, RawWaker, RawWakerVTable },
+};
+use crossbeam_queue::SegQueue;
+
+// Instead of storing tasks in a `VecDeque` like we did for our
+// `SimpleExecutor`, we use a `task_queue` of woken tasks and a `BTreeMap` named
+// `tasks` that contains the actual `Task` instances. The map is indexed by the
+// `TaskId` to allow efficient continuation of a specific task.
+pub struct Executor {
+    tasks: BTreeMap<TaskId, Task>,
+    // The `task_queue` field is a `RunQueue` of task headers, wrapped into the
+    // `Arc` type that implements _reference counting_. Reference counting makes
+    // it possible to share ownership of the value between multiple owners. It
+    // works by allocating the value on the heap and counting the number of
+    // active references to it. When the number of active references reaches
+    // zero, the value is no longer needed and can be deallocated.
+    //
+    // We use this `Arc<RunQueue>` type for the `task_queue` because it will be
+    // shared between the executor and wakers. The idea is that the wakers push
+    // the header of the woken task to the queue. The executor sits on the
+    // receiving end of the queue, retrieves the woken tasks by their ID from
+    // the `tasks` map, and then runs them. The queue is built from the headers
+    // themselves, so pushing never allocates and the queue can't overflow,
+    // which is important since interrupt handlers wake tasks (see the
+    // `run_queue` module).
+    task_queue: Arc<RunQueue>,
+    // The woken tasks that the executor took out of the `task_queue`, one list
+    // for every `Priority`. Only the executor accesses these lists, so they
+    // can be normal `VecDeque`s.
+    ready: [VecDeque<TaskRef>; NUM_PRIORITIES],
+    // This map caches the [`Waker`] of a task after its creation. This has two
+    // reasons: First, it improves performance by reusing the same waker for
+    // multiple wake-ups of the same task instead of creating a new waker each
+    // time. Second, it ensures that reference-counted wakers are not
+    // deallocated inside interrupt handlers because it could lead to deadlocks.
+    waker_cache: BTreeMap<TaskId, Waker>,
+    // Tasks that were created through a `Spawner`. The spawners can't access
+    // the `tasks` map, so they push new tasks to this queue, and
+    // `run_ready_tasks` moves them to the map.
+    spawn_queue: Arc<SegQueue<Task>>,
+    // The headers of all tasks, for the `Monitor`. The `tasks` map can't be
+    // shared, since the executor holds a mutable reference into it while it
+    // polls a task.
+    registry: Rc<Registry>,
+    // Measures the time that tasks spend in `poll`, see `set_clock`.
+    clock: Option<fn() -> u64>,
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Executor {
+    // Creates an `Executor`.
+    //
+    // Tasks are not `Send`, so clippy suggests an `Rc` for the `spawn_queue`.
+    // But spawners might run in interrupt handlers, which can interrupt an
+    // update of the reference count, so we need the atomic counts of `Arc`
+    // even on a single CPU.
+    #[allow(clippy::arc_with_non_send_sync)]
+    pub fn new() -> Self {
+        let task_queue = Arc::new(RunQueue::new());
+        Executor {
+            tasks: BTreeMap::new(),
+            registry: Rc::new(Registry::new(task_queue.clone())),
+            task_queue,
+            ready: [VecDeque::new(), VecDeque::new(), VecDeque::new(), VecDeque::new()],
+            waker_cache: BTreeMap::new(),
+            spawn_queue: Arc::new(SegQueue::new()),
+            clock: None,
+        }
+    }
+
+    /// Sets the clock that measures how long tasks spend in `poll`.
+    ///
+    /// The clock returns the current time in nanoseconds. The core crate has no
+    /// clock of its own, so without one, `TaskInfo::busy` stays zero.
+    pub fn set_clock(&mut self, clock: fn() -> u64) {
+        self.clock = Some(clock);
+    }
+
+    /// Returns a `Monitor` that reads the tasks of this executor.
+    ///
+    /// Unlike `snapshot`, the monitor doesn't borrow the executor, so it can
+    /// be used while the executor runs, e.g. in the panic handler.
+    pub fn monitor(&self) -> Monitor {
+        Monitor {
+            registry: Rc::downgrade(&self.registry),
+        }
+    }
+
+    /// Returns the snapshots of all tasks, in the order of their IDs.
+    ///
+    /// Tasks that a `Spawner` created are only included after the next
+    /// `run_ready_tasks`.
+    pub fn snapshot(&self) -> Vec<TaskInfo> {
+        // The registry is only borrowed inside of `&mut self` methods, so it
+        // can't be busy here.
+        self.monitor().snapshot().expect("task registry is borrowed")
+    }
+
+    /// Returns a `Spawner` that adds tasks to this executor.
+    ///
+    /// Unlike `spawn`, the spawner doesn't borrow the executor, so it can be
+    /// moved into tasks while the executor runs.
+    pub fn spawner(&self) -> Spawner {
+        Spawner {
+            spawn_queue: Arc::downgrade(&self.spawn_queue),
+        }
+    }
+
+    // Spawn task.
+    //
+    // Wraps the future into a task, adds it to the tasks map and immediately
+    // wakes it by pushing its ID to the task_queue. The returned `JoinHandle`
+    // completes with the output of the future. It can be dropped if the output
+    // is not needed.
+    //
+    // The task gets the `Normal` priority. To spawn an existing `Task` with
+    // its own ID, name, and priority, use `spawn_task` instead.
+    pub fn spawn<F>(&mut self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        self.spawn_with_priority(Priority::Normal, future)
+    }
+
+    /// Spawns a task with the given priority, see `spawn`.
+    pub fn spawn_with_priority<F>(&mut self, priority: Priority, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        self.spawn_with_options(None, priority, future)
+    }
+
+    /// Spawns a task with a name, which shows up in the `TaskInfo` of the
+    /// task, see `spawn`.
+    pub fn spawn_named<F>(
+        &mut self,
+        name: &'static str,
+        priority: Priority,
+        future: F,
+    ) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        self.spawn_with_options(Some(name), priority, future)
+    }
+
+    /// Spawns an existing `Task`, which keeps its ID, name, and priority.
+    ///
+    /// Passing a `Task` to `spawn` would work as well, since a task is a future,
+    /// but `spawn` would wrap it into a new task with a new ID and the
+    /// `Normal` priority.
+    pub fn spawn_task(&mut self, task: Task) -> JoinHandle<()> {
+        let (task, handle) = task.joinable();
+        insert_task(&mut self.tasks, &mut self.waker_cache, &self.task_queue, &self.registry, task);
+        handle
+    }
+
+    fn spawn_with_options<F>(
+        &mut self,
+        name: Option<&'static str>,
+        priority: Priority,
+        future: F,
+    ) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        let (future, handle) = join::joinable(future);
+        let task = Task::with_options(name, priority, future);
+        insert_task(&mut self.tasks, &mut self.waker_cache, &self.task_queue, &self.registry, task);
+        handle
+    }
+
+    // Execute the tasks in the `task_queue`.
+    //
+    // The basic idea of this function is similar to our `SimpleExecutor`: Loop
+    // over all tasks in the `task_queue`, create a waker for each task, and
+    // then poll it. However, instead of adding pending tasks back to the end of
+    // the `task_queue`, we let the wakers of the tasks take care of
+    // adding woken tasks back to the queue.
+    //
+    // The ready tasks are polled in rounds of weighted round-robin: in every
+    // round, each priority level gets to poll up to `Priority::weight` tasks,
+    // starting with the highest level. The function returns when no task is
+    // ready or after `POLL_BUDGET` polls, so that a task that keeps waking
+    // itself can't keep the caller in here forever.
+    pub fn run_ready_tasks(&mut self) {
+        // We use _destructuring_ to split `self` into its fields to avoid some
+        // borrow checker errors. Namely, our implementation needs to access
+        // the `self.task_queue` from within a closure, which currently tries to
+        // borrow `self` completely. This is a fundamental borrow checker issue
+        // that will be resolved when [RFC 2229] is [implemented][RFC 2229
+        // impl].
+        // 
+        // [RFC 2229]: https://github.com/rust-lang/rfcs/pull/2229
+        // [RFC 2229 impl]: https://github.com/rust-lang/rust/issues/53488
+        let Self {
+            tasks,
+            task_queue,
+            ready,
+            waker_cache,
+            spawn_queue,
+            registry,
+            clock,
+        } = self;
+
+        let mut budget = POLL_BUDGET;
+        loop {
+            let mut polled = false;
+            for &priority in Priority::ALL.iter() {
+                for _ in 0..priority.weight() {
+                    // Tasks might spawn other tasks, so we check for new tasks
+                    // before each poll.
+                    while let Ok(task) = spawn_queue.pop() {
+                        insert_task(tasks, waker_cache, task_queue, registry, task);
+                    }
+                    if budget == 0 {
+                        return;
+                    }
+                    let ready = &mut ready[priority.index()];
+                    if ready.is_empty() {
+                        task_queue.drain(priority, |header| ready.push_back(header));
+                    }
+                    let header = match ready.pop_front() {
+                        Some(header) => header,
+                        None => break,
+                    };
+                    budget -= 1;
+                    polled = true;
+                    poll_task(tasks, waker_cache, task_queue, registry, *clock, &header);
+                }
+            }
+            if !polled {
+                return;
+            }
+        }
+    }
+
+    /// Returns `true` if no task is ready to run.
+    ///
+    /// A `false` result can become stale immediately if wakers run in interrupt
+    /// handlers, see the `sleep_if_idle` method of the kernel executor.
+    pub fn is_idle(&self) -> bool {
+        self.ready.iter().all(VecDeque::is_empty)
+            && self.task_queue.is_empty()
+            && self.spawn_queue.is_empty()
+    }
+
+    /// Returns the number of tasks that are not finished yet.
+    ///
+    /// Tasks that a `Spawner` created are only counted after the next
+    /// `run_ready_tasks`.
+    pub fn task_count(&self) -> usize {
+        self.tasks.len()
+    }
+}
+
+/// The maximum number of polls in one call of `Executor::run_ready_tasks`.
+pub const POLL_BUDGET: usize = 128;
+
+// Polls the task of the given header once.
+fn poll_task(
+    tasks: &mut BTreeMap<TaskId, Task>,
+    waker_cache: &mut BTreeMap<TaskId, Waker>,
+    task_queue: &RunQueue,
+    registry: &Registry,
+    clock: Option<fn() -> u64>,
+    header: &TaskHeader,
+) {
+    // Wake-ups from now on must schedule the task again, even those during the
+    // poll.
+    header.unschedule();
+    let task_id = header.id;
+
+    // For each popped task, we retrieve a mutable reference to the
+    // corresponding task from the `tasks` map. Since our `ScancodeStream`
+    // implementation registers wakers before checking whether a task needs to
+    // be put to sleep, it might happen that a wake-up occurs for a task that no
+    // longer exists. In this case, we simply ignore the wake-up.
+    let task = match tasks.get_mut(&task_id) {
+        Some(task) => task,
+        None => return, // task no longer exists
+    };
+    // To avoid the performance overhead of creating a waker on each poll, the
+    // `waker_cache` map stores the waker for each task, which `insert_task`
+    // created together with the task.
+    let waker = &waker_cache[&task_id];
+    let mut context = Context::from_waker(waker);
+
+    // Record the statistics of the poll for the `Monitor`. The guard marks the
+    // task as no longer running, even if it panics.
+    let start = clock.map(|clock| clock());
+    let running = Running::new(task_queue, task_id);
+    let poll = task.poll(&mut context);
+    drop(running);
+    header.polls.fetch_add(1, Ordering::Relaxed);
+    if let (Some(clock), Some(start)) = (clock, start) {
+        header.busy_nanos.fetch_add(clock().saturating_sub(start), Ordering::Relaxed);
+    }
+
+    // A task is finished when it returns `Poll::Ready`. In that case, we remove
+    // it from the `tasks` map using the `BTreeMap::remove` method. We also
+    // remove its cached waker and its header from the registry.
+    match poll {
+        Poll::Ready(()) => {
+            // task done -> remove it and its cached waker
+            tasks.remove(&task_id);
+            waker_cache.remove(&task_id);
+            registry.remove(task_id);
+        }
+        Poll::Pending => {}
+    }
+}
+
+// Tells the wakers and the `Monitor` which task the executor polls.
+struct Running<'a> {
+    task_queue: &'a RunQueue,
+}
+
+impl<'a> Running<'a> {
+    fn new(task_queue: &'a RunQueue, task_id: TaskId) -> Self {
+        task_queue.set_running(Some(task_id));
+        Running { task_queue }
+    }
+}
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        self.task_queue.set_running(None);
+    }
+}
+
+// Adds a task to the tasks map and immediately wakes it by pushing its header
+// to the `task_queue`.
+fn insert_task(
+    tasks: &mut BTreeMap<TaskId, Task>,
+    waker_cache: &mut BTreeMap<TaskId, Waker>,
+    task_queue: &Arc<RunQueue>,
+    registry: &Registry,
+    task: Task,
+) {
+    let task_id = task.id;
+    let header = TaskHeader::new(task_id, task.priority, task.name, Arc::downgrade(task_queue));
+
+    // If there is already a task with the same ID in the map, the
+    // `BTreeMap::insert` method returns it. This should never happen since each
+    // task has an unique ID, so we panic in this case since it indicates a bug
+    // in our code.
+    if tasks.insert(task_id, task).is_some() {
+        panic!("task with same ID already in tasks");
+    }
+    // The header knows the `task_queue`, so the waker only needs a reference
+    // to the header. Cloning the `TaskRef` only increases its reference count,
+    // so creating the waker doesn't allocate.
+    waker_cache.insert(task_id, task_waker(header.clone()));
+    registry.insert(header.clone());
+    task_queue.push_new(header);
+}
+
+/// A handle that spawns tasks on an `Executor` without borrowing it.
+///
+/// Spawners are cheap to clone. They only hold a weak reference to the
+/// executor, so spawning fails with `SpawnError::NoExecutor` after the
+/// executor was dropped.
+///
+/// The spawn queue is lock-free and unbounded, so spawning never blocks. It
+/// allocates the task on the heap, though, so it is only safe in an interrupt
+/// handler if the interrupted code can't hold the lock of the heap allocator.
+#[derive(Clone)]
+pub struct Spawner {
+    spawn_queue: Weak<SegQueue<Task>>,
+}
+
+impl Spawner {
+    /// Spawns a task and returns its `JoinHandle`.
+    ///
+    /// The task runs the next time the executor runs its ready tasks. Panics
+    /// if the executor was dropped, use `try_spawn` where a panic is not an
+    /// option.
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        self.spawn_with_priority(Priority::Normal, future)
+    }
+
+    /// Spawns a task with the given priority, see `spawn`.
+    pub fn spawn_with_priority<F>(&self, priority: Priority, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        match self.try_spawn_with_priority(priority, future) {
+            Ok(handle) => handle,
+            Err(err) => panic!("failed to spawn task: {}", err),
+        }
+    }
+
+    /// Spawns a task like `spawn`, but returns an error instead of panicking.
+    /// On an error, the future is dropped.
+    pub fn try_spawn<F>(&self, future: F) -> Result<JoinHandle<F::Output>, SpawnError>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        self.try_spawn_with_priority(Priority::Normal, future)
+    }
+
+    /// Spawns a task with the given priority, see `try_spawn`.
+    pub fn try_spawn_with_priority<F>(
+        &self,
+        priority: Priority,
+        future: F,
+    ) -> Result<JoinHandle<F::Output>, SpawnError>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        self.try_spawn_task(None, priority, future)
+    }
+
+    /// Spawns a task with a name, see `Executor::spawn_named` and `spawn`.
+    pub fn spawn_named<F>(
+        &self,
+        name: &'static str,
+        priority: Priority,
+        future: F,
+    ) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        match self.try_spawn_named(name, priority, future) {
+            Ok(handle) => handle,
+            Err(err) => panic!("failed to spawn task {}: {}", name, err),
+        }
+    }
+
+    /// Spawns a task with a name, see `Executor::spawn_named` and `try_spawn`.
+    pub fn try_spawn_named<F>(
+        &self,
+        name: &'static str,
+        priority: Priority,
+        future: F,
+    ) -> Result<JoinHandle<F::Output>, SpawnError>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        self.try_spawn_task(Some(name), priority, future)
+    }
+
+    fn try_spawn_task<F>(
+        &self,
+        name: Option<&'static str>,
+        priority: Priority,
+        future: F,
+    ) -> Result<JoinHandle<F::Output>, SpawnError>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        let spawn_queue = self.spawn_queue.upgrade().ok_or(SpawnError::NoExecutor)?;
+        let (future, handle) = join::joinable(future);
+        spawn_queue.push(Task::with_options(name, priority, future));
+        Ok(handle)
+    }
+}
+
+/// The errors of `Spawner::try_spawn`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    /// The executor was dropped, or there is no executor to spawn on.
+    NoExecutor,
+}
+
+impl fmt::Display for SpawnError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SpawnError::NoExecutor => write!(f, "no executor to spawn the task on"),
+        }
+    }
+}
+
+// The job of the waker is to push the header of the woken task to the
+// `task_queue` of the executor. The header already stores a weak reference to
+// the `task_queue`, so the waker only needs a `TaskRef` to the header.
+//
+// In order to use the header for polling futures, we need to convert it to a
+// [`Waker`] instance first. This is required because the [`Future::poll`]
+// method takes a [`Context`] instance as argument, which can only be
+// constructed from the `Waker` type. The simplest way is to implement the
+// `Arc`-based [`Wake`][wake-trait] trait and then use the [`From`]
+// implementations provided by the standard library to construct the `Waker`.
+// But that requires an additional `Arc` allocation for every task, whose
+// layout depends on the internals of `Arc`. Instead, we provide a
+// [`RawWakerVTable`] ourselves, whose data pointer is a `TaskRef` turned into a
+// raw pointer. This means that the functions of the table have to count the
+// references of the header like an `Arc` would.
+//
+// [wake-trait]: https://doc.rust-lang.org/nightly/alloc/task/trait.Wake.html
+
+// Creates the waker of the task with the given header.
+fn task_waker(header: TaskRef) -> Waker {
+    let raw = RawWaker::new(header.into_raw() as *const (), &TASK_WAKER_VTABLE);
+    // The functions of the table uphold the contract of `RawWaker`.
+    unsafe { Waker::from_raw(raw) }
+}
+
+static TASK_WAKER_VTABLE: RawWakerVTable = RawWakerVTable::new(
+    clone_waker,
+    wake_waker,
+    wake_waker_by_ref,
+    drop_waker,
+);
+
+// Borrows the `TaskRef` behind the data pointer of a waker without taking
+// ownership of it.
+unsafe fn borrow_header(data: *const ()) -> ManuallyDrop<TaskRef> {
+    ManuallyDrop::new(TaskRef::from_raw(data as *const TaskHeader))
+}
+
+unsafe fn clone_waker(data: *const ()) -> RawWaker {
+    let header = borrow_header(data);
+    RawWaker::new(TaskRef::clone(&header).into_raw() as *const (), &TASK_WAKER_VTABLE)
+}
+
+// The difference between `wake` and `wake_by_ref` is that the former takes
+// ownership of the waker, so it has to drop its reference to the header
+// afterwards. Waking a task that is already scheduled does nothing.
+unsafe fn wake_waker(data: *const ()) {
+    let header = TaskRef::from_raw(data as *const TaskHeader);
+    TaskHeader::wake(&header);
+}
+
+unsafe fn wake_waker_by_ref(data: *const ()) {
+    TaskHeader::wake(&borrow_header(data));
+}
+
+unsafe fn drop_waker(data: *const ()) {
+    drop(TaskRef::from_raw(data as *const TaskHeader));
+}
+
+// ********** Sidenote **********
+//
+// ## Possible Extensions
+//
+// Our executor is now able to run tasks in an efficient way. It utilizes waker
+// notifications to avoid polling waiting tasks and puts the CPU to sleep when
+// there is currently no work to do. However, our executor is still quite basic
+// and there are many possible ways to extend its functionality:
+// 
+// - **Scheduling**: A single _first in first out_ (FIFO) `task_queue`, which
+//   is often also called _round robin_ scheduling, treats all tasks the same.
+//   We prioritize latency-critical tasks instead, with a FIFO queue per
+//   priority level that is served by _weighted round robin_ (see the sidenote
+//   below). Other workloads might need other strategies. See the [scheduling
+//   chapter] of the [_Operating Systems: Three Easy Pieces_] book or the
+//   [Wikipedia article on scheduling][scheduling-wiki] for more information.
+// - **Task Spawning**: Our `Executor::spawn` method requires a `&mut self`
+//   reference and is thus no longer available after starting the `run`
+//   method. The `Spawner` type fixes this: it shares a separate queue with the
+//   executor, which the executor checks in its run loop. This allows task
+//   creation from within tasks themselves.
+// - **Utilizing Threads**: We don't have support for threads yet, but we will
+//   add it later. This will make it possible to launch multiple instances of
+//   the executor in different threads. The advantage of this approach is that
+//   the delay imposed by long running tasks can be reduced because other tasks
+//   can run concurrently. This approach also allows it to utilize multiple CPU
+//   cores.
+// - **Load Balancing**: When adding threading support, it becomes important how
+//   to distribute the tasks between the executors to ensure that all CPU cores
+//   are utilized. A common technique for this is [_work stealing_].
+// 
+// [scheduling chapter]: http://pages.cs.wisc.edu/~remzi/OSTEP/cpu-sched.pdf
+// [_Operating Systems: Three Easy Pieces_]:
+//     http://pages.cs.wisc.edu/~remzi/OSTEP/
+// [scheduling-wiki]: https://en.wikipedia.org/wiki/Scheduling_(computing)
+// [_work stealing_]: https://en.wikipedia.org/wiki/Work_stealing
+
+// ********** Sidenote **********
+//
+// ## Weighted round robin
+//
+// With strict priorities, the executor would always poll the highest ready
+// level first. A bottom half that keeps waking itself would then starve all
+// other tasks forever. Instead, every level gets a number of polls per round,
+// its weight:
+//
+// | Priority    | Weight |
+// |-------------|--------|
+// | BottomHalf  | 8      |
+// | Interactive | 4      |
+// | Normal      | 2      |
+// | Background  | 1      |
+//
+// If all levels are busy, a bottom half gets 8 of 15 polls and a background
+// task 1 of 15, but no level waits for more than one round. If a level has
+// fewer ready tasks than its weight, the remaining polls go to the lower
+// levels, so an idle level costs nothing.
+//
+// Within a level, the queue is still FIFO: a task that wakes itself goes to the
+// back of its queue, behind the other tasks of its level.
+//
+// Note that a task that never returns from `poll` still blocks everything else.
+// Our executor is cooperative: it can only switch tasks at `.await` points.
@@ -0,0 +1,253 @@
+//! # Join module
+//!
+//! The `JoinHandle` that `Executor::spawn` returns for a task.
+//!
+//! The handle is a future that completes with the output of the task, so a
+//! task can await the result of another task. It can also abort the task.
+//!
+//! The executor doesn't know about any of this: `spawn` wraps the future of
+//! the task in a `JoinTask`, which stores the output in a shared slot and wakes
+//! the task that awaits the handle.
+
+use alloc::rc::Rc;
+use core::{
+    cell::RefCell,
+    fmt,
+    future::Future,
+    mem,
+    pin::Pin,
+    task::{ Context, Poll, Waker },
+};
+
+/// The reason why a task didn't complete.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    /// The task was aborted, or the executor was dropped before the task
+    /// completed.
+    Cancelled,
+    /// The task panicked while it was polled.
+    ///
+    /// The kernel aborts on a panic, so this can only happen where panics
+    /// unwind, for example in the unit tests on the host.
+    Panicked,
+}
+
+impl JoinError {
+    /// Returns `true` if the task was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        *self == JoinError::Cancelled
+    }
+
+    /// Returns `true` if the task panicked.
+    pub fn is_panic(&self) -> bool {
+        *self == JoinError::Panicked
+    }
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JoinError::Cancelled => write!(f, "task was cancelled"),
+            JoinError::Panicked => write!(f, "task panicked"),
+        }
+    }
+}
+
+enum JoinState<T> {
+    /// The task hasn't completed yet.
+    Running,
+    /// The task completed, but the handle didn't take the result yet.
+    Done(Result<T, JoinError>),
+    /// The handle took the result.
+    Joined,
+}
+
+/// The state that a task shares with its `JoinHandle`.
+///
+/// Our executor runs all tasks on one CPU and doesn't require them to be
+/// `Send`, so an `Rc<RefCell>` is enough.
+struct Shared<T> {
+    state: JoinState<T>,
+    /// Set by `JoinHandle::abort`.
+    aborted: bool,
+    /// The waker of the task that awaits the handle.
+    join_waker: Option<Waker>,
+    /// The waker of the task itself, which `abort` uses to get the task
+    /// polled.
+    task_waker: Option<Waker>,
+}
+
+impl<T> Shared<T> {
+    /// Stores the result of the task and returns the waker of the task that
+    /// awaits it. The waker must be called after the `RefCell` borrow ended,
+    /// since it might access the state again.
+    fn complete(&mut self, result: Result<T, JoinError>) -> Option<Waker> {
+        if let JoinState::Running = self.state {
+            self.state = JoinState::Done(result);
+            self.task_waker = None;
+            self.join_waker.take()
+        } else {
+            None
+        }
+    }
+}
+
+/// Wraps `future` so that its output is reported to the returned handle.
+pub(crate) fn joinable<F>(future: F) -> (JoinTask<F>, JoinHandle<F::Output>)
+where
+    F: Future,
+{
+    let shared = Rc::new(RefCell::new(Shared {
+        state: JoinState::Running,
+        aborted: false,
+        join_waker: None,
+        task_waker: None,
+    }));
+    let task = JoinTask { future, shared: shared.clone() };
+    (task, JoinHandle { shared })
+}
+
+/// The future that the executor runs for a spawned task.
+pub(crate) struct JoinTask<F: Future> {
+    future: F,
+    shared: Rc<RefCell<Shared<F::Output>>>,
+}
+
+impl<F: Future> Future for JoinTask<F> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        // A manual "pin projection", see `Timeout` in the timer module of the
+        // kernel: the inner future is never moved out of `self`.
+        let this = unsafe { self.get_unchecked_mut() };
+        {
+            let mut shared = this.shared.borrow_mut();
+            if shared.aborted {
+                let waker = shared.complete(Err(JoinError::Cancelled));
+                drop(shared);
+                wake(waker);
+                return Poll::Ready(());
+            }
+            if !matches!(shared.state, JoinState::Running) {
+                // The task panicked in an earlier poll, so its future must not
+                // be polled again.
+                return Poll::Ready(());
+            }
+            match &shared.task_waker {
+                Some(waker) if waker.will_wake(cx.waker()) => {}
+                _ => shared.task_waker = Some(cx.waker().clone()),
+            }
+        }
+
+        let guard = PanicGuard { shared: &this.shared };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        let poll = future.poll(cx);
+        mem::forget(guard);
+
+        match poll {
+            Poll::Ready(output) => {
+                let waker = this.shared.borrow_mut().complete(Ok(output));
+                wake(waker);
+                Poll::Ready(())
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<F: Future> Drop for JoinTask<F> {
+    fn drop(&mut self) {
+        // The task is dropped before it completed, for example together with
+        // the executor.
+        let waker = self.shared.borrow_mut().complete(Err(JoinError::Cancelled));
+        wake(waker);
+    }
+}
+
+/// Reports a panic of the task to its handle.
+///
+/// The guard is forgotten after a poll returned, so it is only dropped while a
+/// panic unwinds through `JoinTask::poll`.
+struct PanicGuard<'a, T> {
+    shared: &'a RefCell<Shared<T>>,
+}
+
+impl<T> Drop for PanicGuard<'_, T> {
+    fn drop(&mut self) {
+        // `try_borrow_mut` because we must not panic while unwinding.
+        let (task_waker, join_waker) = match self.shared.try_borrow_mut() {
+            Ok(mut shared) => {
+                let task_waker = shared.task_waker.take();
+                (task_waker, shared.complete(Err(JoinError::Panicked)))
+            }
+            Err(_) => (None, None),
+        };
+        // Waking the task lets the executor poll it once more, which drops it.
+        wake(task_waker);
+        wake(join_waker);
+    }
+}
+
+fn wake(waker: Option<Waker>) {
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
+
+/// An owned permission to await the output of a spawned task.
+///
+/// Awaiting the handle returns the output of the task, or a `JoinError` if
+/// the task didn't complete. Dropping the handle detaches the task: it keeps
+/// running, but its output is dropped.
+pub struct JoinHandle<T> {
+    shared: Rc<RefCell<Shared<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Aborts the task.
+    ///
+    /// The executor drops the task the next time it runs it, which is soon
+    /// because `abort` wakes the task. Awaiting the handle then returns
+    /// `JoinError::Cancelled`. Aborting a completed task does nothing.
+    pub fn abort(&self) {
+        let waker = {
+            let mut shared = self.shared.borrow_mut();
+            if !matches!(shared.state, JoinState::Running) {
+                return;
+            }
+            shared.aborted = true;
+            shared.task_waker.take()
+        };
+        // A task that was never polled has no waker yet, but it is in the
+        // task queue anyway.
+        wake(waker);
+    }
+
+    /// Returns `true` if the task completed, was cancelled, or panicked.
+    pub fn is_finished(&self) -> bool {
+        !matches!(self.shared.borrow().state, JoinState::Running)
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut shared = self.shared.borrow_mut();
+        match mem::replace(&mut shared.state, JoinState::Joined) {
+            JoinState::Done(result) => Poll::Ready(result),
+            JoinState::Running => {
+                shared.state = JoinState::Running;
+                shared.join_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            JoinState::Joined => panic!("`JoinHandle` polled after completion"),
+        }
+    }
+}
+
+impl<T> fmt::Debug for JoinHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("JoinHandle").field("finished", &self.is_finished()).finish()
+    }
+}
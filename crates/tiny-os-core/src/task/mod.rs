@@ -0,0 +1,245 @@
+//! # Task module
+
+use core::{ 
+    fmt,
+    future::Future, 
+    pin::Pin,
+    task::{ Context, Poll },
+    sync::atomic::{ AtomicU64, Ordering },
+};
+use alloc::boxed::Box;
+
+pub mod channel;
+pub mod executor;
+pub mod join;
+pub mod monitor;
+mod run_queue;
+pub mod simple_executor;
+pub mod sync;
+pub mod timer;
+
+pub use join::{ JoinError, JoinHandle };
+pub use monitor::{ Monitor, MonitorError, TaskInfo, TaskState, WakeSource };
+pub use run_queue::TaskHeader;
+
+/// The scheduling class of a task, see `Executor::spawn_with_priority`.
+///
+/// The executor prefers tasks with a higher priority, but every level gets a
+/// share of the polls (its weight), so a busy task can't starve the tasks of
+/// lower levels.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Work that an interrupt handler deferred to a task (the "bottom half" of
+    /// the handler), which should run as soon as possible.
+    BottomHalf,
+    /// Tasks that react to the user, like the keyboard task.
+    Interactive,
+    /// The default for tasks that don't ask for a priority.
+    #[default]
+    Normal,
+    /// Long-running work that may be delayed.
+    Background,
+}
+
+/// The number of priority levels.
+pub const NUM_PRIORITIES: usize = 4;
+
+impl Priority {
+    /// All levels, from the highest to the lowest.
+    pub const ALL: [Priority; NUM_PRIORITIES] = [
+        Priority::BottomHalf,
+        Priority::Interactive,
+        Priority::Normal,
+        Priority::Background,
+    ];
+
+    /// The number of tasks of this level that the executor polls in each
+    /// round, before it continues with the next level.
+    pub const fn weight(self) -> usize {
+        match self {
+            Priority::BottomHalf => 8,
+            Priority::Interactive => 4,
+            Priority::Normal => 2,
+            Priority::Background => 1,
+        }
+    }
+
+    /// The position of the level in `ALL`.
+    pub const fn index(self) -> usize {
+        self as usize
+    }
+}
+
+// A newtype wrapper around a pinned, heap allocated, and dynamically dispatched
+// future with the empty type `()` as output.
+pub struct Task {
+    // This field makes it possible to uniquely name a task, which is required
+    // for waking a specific task.
+    id: TaskId,
+    // The ready queue that the task is pushed to when it is woken.
+    priority: Priority,
+    // A name for debugging, which the `Monitor` of the executor shows.
+    name: Option<&'static str>,
+    future: Pin<Box<dyn Future<Output = ()>>>,
+}
+
+impl Task {
+    // Create a new Task structs from futures.
+    //
+    // The `'static` lifetime is required here because the returned `Task` can
+    // live for an arbitrary time, so the future needs to be valid for that time
+    // too.
+    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+        Task::with_options(None, Priority::default(), future)
+    }
+
+    // Creates a task with a name, see `Task::name`. Spawn it with
+    // `Executor::spawn_task`, which keeps the name.
+    pub fn named(name: &'static str, future: impl Future<Output = ()> + 'static) -> Task {
+        Task::with_options(Some(name), Priority::default(), future)
+    }
+
+    // Creates a task with the given name and priority. The executor uses this
+    // for `spawn_with_priority` and `spawn_named`.
+    pub(crate) fn with_options(
+        name: Option<&'static str>,
+        priority: Priority,
+        future: impl Future<Output = ()> + 'static,
+    ) -> Task {
+        Task {
+            id: TaskId::new(),
+            priority,
+            name,
+            // Pins `future` in memory.
+            future: Box::pin(future),
+        }
+    }
+
+    // Returns the unique ID of the task.
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    // Returns the priority of the task.
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    // Returns the name of the task, if it has one. Names don't have to be
+    // unique, they only help to find a task in a `TaskInfo` dump.
+    pub fn name(&self) -> Option<&'static str> {
+        self.name
+    }
+
+    // Wraps the future of the task in a `JoinTask` for `Executor::spawn_task`.
+    // The returned task keeps the ID, name, and priority of this task.
+    pub(crate) fn joinable(self) -> (Task, JoinHandle<()>) {
+        let (future, handle) = join::joinable(self.future);
+        let task = Task {
+            id: self.id,
+            priority: self.priority,
+            name: self.name,
+            future: Box::pin(future),
+        };
+        (task, handle)
+    }
+
+    // Allow the executor to poll the stored future.
+    pub(crate) fn poll(&mut self, context: &mut Context) -> Poll<()> {
+        // Since the `poll` method of the `Future` trait expects to be called on
+        // a `Pin<&mut T>` type, we use the `Pin::as_mut` method to convert the
+        // `self.future` field of type `Pin<Box<T>>` first. Then we `call` poll
+        // on the converted `self.future` field and return the result. Since the
+        // `Task::poll` method should be only called by the executor, we keep
+        // the function private to this crate.
+        self.future.as_mut().poll(context)
+    }
+}
+
+// A task is a future as well, so it can be awaited or passed to `spawn`. Use
+// `Executor::spawn_task` to spawn it as it is.
+impl Future for Task {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        self.future.as_mut().poll(context)
+    }
+}
+
+// Gives each task an unique ID. This is required because we need a way to
+// specify which task should be woken.
+//
+// The `TaskId` struct is a simple wrapper type around `u64`. The sortable trait
+// is important because we want to use `TaskId` as the key type of a `BTreeMap`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    // Returns the ID as a number. IDs count up from 0 in the order in which
+    // the tasks were created.
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    fn new() -> Self {
+        // Ensure that each ID is assigned only once.
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        // The fetch_add method atomically increases the value and returns the
+        // previous value in one atomic operation. This means that even when the
+        // TaskId::new method is called in parallel, every ID is returned
+        // exactly once.
+        //
+        // The Ordering parameter defines whether the compiler is allowed to
+        // reorder the fetch_add operation in the instructions stream. Since we
+        // only require that the ID is unique, the Relaxed ordering with the
+        // weakest requirements is enough in this case.
+        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+// ********** SIdenote **********
+//
+// # Implementation
+//
+// ## Task
+//
+// The `Task` struct is a newtype wrapper around a pinned, heap allocated, and
+// dynamically dispatched future with the empty type `()` as output. Let’s go
+// through it in detail:
+// - We require that the future associated with a task returns `()`. This means
+//   that tasks don’t return any result, they are just executed for its side
+//   effects. For example, the `example_task` function we defined in `main.rs`
+//   has no return value, but it prints something to the screen as a side
+//   effect.
+//   `Executor::spawn` lifts this restriction: it wraps a future with any
+//   output into a task that stores the output for a `JoinHandle` (see the
+//   `join` module).
+// - The `dyn` keyword indicates that we store a trait object in the Box. This
+//   means that the methods on the future are dynamically dispatched, which
+//   makes it possible to store different types of futures in the Task type.
+//   This is important because each `async fn` has its own type and we want to
+//   be able to create multiple different tasks.
+// - The `Pin<Box>` type ensures that a value cannot be moved in memory by
+//   placing it on the heap and preventing the creation of `&mut` references to
+//   it. This is important because futures generated by async/await might be
+//   self-referential, i.e. contain pointers to itself that would be invalidated
+//   when the future is moved.
+//
+// ## Executor with Waker Support
+//
+// To fix the performance problem, we need to create an executor that properly
+// utilizes the `Waker` notifications. This way, the executor is notified when
+// the next keyboard interrupt occurs, so it does not need to keep polling the
+// `print_keypresses` task over and over again.
+//
+// ### Task Id
+//
+// The first step in creating an executor with proper support for waker
+// notifications is to give each task an unique ID. This is required because we
+// need a way to specify which task should be woken.
@@ -0,0 +1,227 @@
+//! # Monitor module
+//!
+//! Shows what the tasks of an executor are doing, e.g. to find the task that
+//! hangs the system.
+//!
+//! The executor keeps the statistics of a task in its `TaskHeader`, which is
+//! shared with the wakers of the task. The headers of all tasks are in a
+//! `Registry`, which a `Monitor` can read without borrowing the executor. This
+//! works even while the executor is stuck in the `poll` of a task, for example
+//! from an interrupt handler or the panic handler.
+
+use alloc::{ collections::BTreeMap, rc::Weak, sync::Arc, vec::Vec };
+use core::{ cell::RefCell, fmt, sync::atomic::Ordering, time::Duration };
+use super::{ run_queue::{ RunQueue, TaskRef }, Priority, TaskId };
+
+/// What a task is doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    /// The task was woken and waits until the executor polls it.
+    Ready,
+    /// The task waits for a wake-up.
+    Pending,
+    /// The executor is polling the task right now.
+    ///
+    /// If this doesn't change, the task doesn't return from `poll`, which
+    /// blocks all other tasks.
+    Running,
+}
+
+impl fmt::Display for TaskState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TaskState::Ready => write!(f, "ready"),
+            TaskState::Pending => write!(f, "pending"),
+            TaskState::Running => write!(f, "running"),
+        }
+    }
+}
+
+/// Who woke a task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WakeSource {
+    /// The task wasn't woken yet, it runs because it was spawned.
+    Spawn,
+    /// The task was woken while the executor polled the given task, which can
+    /// be the task itself.
+    Task(TaskId),
+    /// The task was woken between two polls, usually by an interrupt handler.
+    External,
+}
+
+impl WakeSource {
+    /// Packs the source into a `u64`, so that wakers can store it atomically.
+    pub(crate) fn encode(self) -> u64 {
+        match self {
+            WakeSource::Spawn => 0,
+            WakeSource::External => 1,
+            WakeSource::Task(task_id) => task_id.as_u64() + 2,
+        }
+    }
+
+    pub(crate) fn decode(value: u64) -> WakeSource {
+        match value {
+            0 => WakeSource::Spawn,
+            1 => WakeSource::External,
+            id => WakeSource::Task(TaskId(id - 2)),
+        }
+    }
+}
+
+impl fmt::Display for WakeSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WakeSource::Spawn => write!(f, "spawn"),
+            WakeSource::Task(task_id) => write!(f, "task {}", task_id),
+            WakeSource::External => write!(f, "external"),
+        }
+    }
+}
+
+/// A snapshot of a task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskInfo {
+    pub id: TaskId,
+    pub name: Option<&'static str>,
+    pub priority: Priority,
+    pub state: TaskState,
+    /// The number of times the executor polled the task.
+    pub polls: u64,
+    /// The total time that the task spent in `poll`. This is zero if the
+    /// executor has no clock, see `Executor::set_clock`.
+    pub busy: Duration,
+    pub last_wake: WakeSource,
+}
+
+impl fmt::Display for TaskInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:>5} {:<16} {:<11} {:<8} {:>8} polls {:>10} us busy, woken by {}",
+            self.id,
+            self.name.unwrap_or("-"),
+            // `Priority` only implements `Debug`, which ignores the width.
+            PriorityName(self.priority),
+            self.state,
+            self.polls,
+            self.busy.as_micros(),
+            self.last_wake,
+        )
+    }
+}
+
+struct PriorityName(Priority);
+
+impl fmt::Display for PriorityName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self.0 {
+            Priority::BottomHalf => "bottom-half",
+            Priority::Interactive => "interactive",
+            Priority::Normal => "normal",
+            Priority::Background => "background",
+        };
+        f.pad(name)
+    }
+}
+
+/// The reason why a `Monitor` can't read the tasks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MonitorError {
+    /// The executor was dropped.
+    NoExecutor,
+    /// The executor is adding or removing a task right now. This can only
+    /// happen if the monitor interrupted the executor, e.g. in an interrupt
+    /// handler or the panic handler.
+    Busy,
+}
+
+impl fmt::Display for MonitorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MonitorError::NoExecutor => write!(f, "the executor was dropped"),
+            MonitorError::Busy => write!(f, "the task list is being updated"),
+        }
+    }
+}
+
+/// The headers of all tasks of an executor.
+///
+/// Our executor runs on one CPU, so a `RefCell` is enough: `try_borrow` fails
+/// instead of blocking if a monitor interrupts the executor while it updates
+/// the map, like `try_lock` of a spin lock.
+pub(crate) struct Registry {
+    headers: RefCell<BTreeMap<TaskId, TaskRef>>,
+    task_queue: Arc<RunQueue>,
+}
+
+impl Registry {
+    pub(crate) fn new(task_queue: Arc<RunQueue>) -> Registry {
+        Registry {
+            headers: RefCell::new(BTreeMap::new()),
+            task_queue,
+        }
+    }
+
+    pub(crate) fn insert(&self, header: TaskRef) {
+        self.headers.borrow_mut().insert(header.id, header);
+    }
+
+    pub(crate) fn remove(&self, task_id: TaskId) {
+        self.headers.borrow_mut().remove(&task_id);
+    }
+
+    /// Calls `f` with the snapshot of every task, in the order of their IDs.
+    ///
+    /// This doesn't allocate.
+    pub(crate) fn try_for_each(&self, mut f: impl FnMut(&TaskInfo)) -> Result<(), MonitorError> {
+        let headers = self.headers.try_borrow().map_err(|_| MonitorError::Busy)?;
+        let running = self.task_queue.running();
+        for header in headers.values() {
+            let state = if running == Some(header.id) {
+                TaskState::Running
+            } else if header.is_scheduled() {
+                TaskState::Ready
+            } else {
+                TaskState::Pending
+            };
+            f(&TaskInfo {
+                id: header.id,
+                name: header.name,
+                priority: header.priority,
+                state,
+                polls: header.polls.load(Ordering::Relaxed),
+                busy: Duration::from_nanos(header.busy_nanos.load(Ordering::Relaxed)),
+                last_wake: header.last_wake(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// A handle that reads the tasks of an `Executor` without borrowing it.
+///
+/// Like a `Spawner`, it only holds a weak reference to the executor.
+#[derive(Clone)]
+pub struct Monitor {
+    pub(crate) registry: Weak<Registry>,
+}
+
+impl Monitor {
+    /// Calls `f` with the snapshot of every task that the executor started, in
+    /// the order of their IDs.
+    ///
+    /// This doesn't allocate, so it can run while the heap is locked, e.g. in
+    /// the panic handler.
+    pub fn try_for_each(&self, f: impl FnMut(&TaskInfo)) -> Result<(), MonitorError> {
+        let registry = self.registry.upgrade().ok_or(MonitorError::NoExecutor)?;
+        registry.try_for_each(f)
+    }
+
+    /// Returns the snapshots of all tasks, see `try_for_each`.
+    pub fn snapshot(&self) -> Result<Vec<TaskInfo>, MonitorError> {
+        let registry = self.registry.upgrade().ok_or(MonitorError::NoExecutor)?;
+        let mut infos = Vec::new();
+        registry.try_for_each(|info| infos.push(*info))?;
+        Ok(infos)
+    }
+}
@@ -0,0 +1,286 @@
+//! # Run queue module
+//!
+//! The queue that wakers push woken tasks to.
+//!
+//! A fixed-size queue of task IDs overflows if more tasks are woken than it
+//! has slots, which would panic in the waker, possibly inside an interrupt
+//! handler. Instead, every task has a `TaskHeader` with a "scheduled" flag,
+//! and the headers themselves are the nodes of the queue. A task is only
+//! pushed if its flag was not set yet, so it is in the queue at most once, and
+//! the queue can never hold more entries than there are tasks.
+//!
+//! Pushing neither allocates nor blocks, so wakers can run in interrupt
+//! handlers.
+//!
+//! The header also holds the statistics of the task, which the `monitor`
+//! module reads.
+
+use alloc::{ boxed::Box, sync::Weak };
+use core::{
+    ops::Deref,
+    ptr::{ self, NonNull },
+    sync::atomic::{ self, AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering },
+};
+use super::{ monitor::WakeSource, Priority, TaskId, NUM_PRIORITIES };
+
+/// The part of a task that its wakers share with the executor.
+///
+/// Every task allocates exactly one `TaskHeader` on the heap, see `TaskRef`.
+/// The type is public so that allocators can size a cache for it, its fields
+/// are private.
+pub struct TaskHeader {
+    pub(crate) id: TaskId,
+    pub(crate) priority: Priority,
+    pub(crate) name: Option<&'static str>,
+    /// The number of times the executor polled the task.
+    pub(crate) polls: AtomicU64,
+    /// The total time that the task spent in `poll`, in nanoseconds.
+    pub(crate) busy_nanos: AtomicU64,
+    /// The `WakeSource` of the last wake-up, see `WakeSource::encode`.
+    last_wake: AtomicU64,
+    /// Set while the task is in the run queue, or in the ready list of the
+    /// executor. The executor clears it right before it polls the task, so a
+    /// wake-up during the poll schedules the task again.
+    scheduled: AtomicBool,
+    /// The next header in the run list. Only valid while `scheduled` is set.
+    next: AtomicPtr<TaskHeader>,
+    /// The number of `TaskRef`s to this header, including those behind the
+    /// wakers of the task.
+    refs: AtomicUsize,
+    /// The queue that the wakers of the task push it to. It is weak, so that
+    /// a waker that outlives the executor doesn't keep the queue alive.
+    task_queue: Weak<RunQueue>,
+}
+
+impl TaskHeader {
+    /// Creates the header of a new task. The task starts as scheduled, since
+    /// the executor pushes it to the run queue right away.
+    #[allow(clippy::new_ret_no_self)]
+    pub(crate) fn new(
+        id: TaskId,
+        priority: Priority,
+        name: Option<&'static str>,
+        task_queue: Weak<RunQueue>,
+    ) -> TaskRef {
+        let header = Box::new(TaskHeader {
+            id,
+            priority,
+            name,
+            polls: AtomicU64::new(0),
+            busy_nanos: AtomicU64::new(0),
+            last_wake: AtomicU64::new(WakeSource::Spawn.encode()),
+            scheduled: AtomicBool::new(true),
+            next: AtomicPtr::new(ptr::null_mut()),
+            refs: AtomicUsize::new(1),
+            task_queue,
+        });
+        TaskRef {
+            ptr: NonNull::from(Box::leak(header)),
+        }
+    }
+
+    /// Marks the task as not scheduled, right before it is polled.
+    pub(crate) fn unschedule(&self) {
+        self.scheduled.store(false, Ordering::SeqCst);
+    }
+
+    /// Returns `true` if the task waits in the run queue or a ready list.
+    pub(crate) fn is_scheduled(&self) -> bool {
+        self.scheduled.load(Ordering::SeqCst)
+    }
+
+    /// Returns who woke the task last.
+    pub(crate) fn last_wake(&self) -> WakeSource {
+        WakeSource::decode(self.last_wake.load(Ordering::Relaxed))
+    }
+
+    /// Pushes the task to the run queue of its executor, unless it is already
+    /// scheduled or the executor was dropped.
+    pub(crate) fn wake(this: &TaskRef) {
+        if let Some(task_queue) = this.task_queue.upgrade() {
+            task_queue.schedule(this);
+        }
+    }
+}
+
+/// A counted reference to a `TaskHeader`, like an `Arc`.
+///
+/// The header counts its references itself, so its allocation is a plain
+/// `Box<TaskHeader>`, whose layout allocators can know without relying on the
+/// internals of `Arc`. It also lets the header serve as the data of the wakers
+/// of the task, so creating a waker doesn't allocate (see `executor`).
+pub(crate) struct TaskRef {
+    ptr: NonNull<TaskHeader>,
+}
+
+// The header only consists of atomics and values that are never changed, so
+// it can be shared between CPUs and interrupt handlers like an `Arc`.
+unsafe impl Send for TaskRef {}
+unsafe impl Sync for TaskRef {}
+
+impl TaskRef {
+    /// Turns the reference into a raw pointer without dropping it.
+    pub(crate) fn into_raw(self) -> *const TaskHeader {
+        let ptr = self.ptr.as_ptr();
+        core::mem::forget(self);
+        ptr
+    }
+
+    /// Turns a pointer of `into_raw` back into a reference.
+    ///
+    /// This function is unsafe because `ptr` must come from `into_raw`, and
+    /// each pointer must only be turned back once.
+    pub(crate) unsafe fn from_raw(ptr: *const TaskHeader) -> TaskRef {
+        TaskRef {
+            ptr: NonNull::new_unchecked(ptr as *mut TaskHeader),
+        }
+    }
+}
+
+impl Clone for TaskRef {
+    fn clone(&self) -> TaskRef {
+        // Like `Arc::clone`, a new reference can only be created from an
+        // existing one, so `Relaxed` is enough.
+        self.refs.fetch_add(1, Ordering::Relaxed);
+        TaskRef { ptr: self.ptr }
+    }
+}
+
+impl Deref for TaskRef {
+    type Target = TaskHeader;
+
+    fn deref(&self) -> &TaskHeader {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl Drop for TaskRef {
+    fn drop(&mut self) {
+        // Like `Arc::drop`: the `Release` decrement and the `Acquire` fence
+        // make sure that all uses of the header happen before it is freed.
+        if self.refs.fetch_sub(1, Ordering::Release) == 1 {
+            atomic::fence(Ordering::Acquire);
+            drop(unsafe { Box::from_raw(self.ptr.as_ptr()) });
+        }
+    }
+}
+
+/// The run lists of all priority levels.
+pub(crate) struct RunQueue {
+    levels: [RunList; NUM_PRIORITIES],
+    /// The source of wake-ups that happen right now: the task that the
+    /// executor is polling, or `External` between polls.
+    current: AtomicU64,
+}
+
+impl RunQueue {
+    pub(crate) fn new() -> RunQueue {
+        RunQueue {
+            levels: [RunList::new(), RunList::new(), RunList::new(), RunList::new()],
+            current: AtomicU64::new(WakeSource::External.encode()),
+        }
+    }
+
+    /// Sets the task that the executor is polling, `None` between polls.
+    pub(crate) fn set_running(&self, task_id: Option<TaskId>) {
+        let source = task_id.map_or(WakeSource::External, WakeSource::Task);
+        self.current.store(source.encode(), Ordering::Relaxed);
+    }
+
+    /// Returns the task that the executor is polling.
+    pub(crate) fn running(&self) -> Option<TaskId> {
+        match WakeSource::decode(self.current.load(Ordering::Relaxed)) {
+            WakeSource::Task(task_id) => Some(task_id),
+            _ => None,
+        }
+    }
+
+    /// Pushes a new task, whose header is already marked as scheduled.
+    pub(crate) fn push_new(&self, header: TaskRef) {
+        self.levels[header.priority.index()].push(header);
+    }
+
+    /// Pushes the task unless it is already scheduled.
+    pub(crate) fn schedule(&self, header: &TaskRef) {
+        header.last_wake.store(self.current.load(Ordering::Relaxed), Ordering::Relaxed);
+        if !header.scheduled.swap(true, Ordering::SeqCst) {
+            // The clone only increments the reference count, it doesn't
+            // allocate.
+            self.levels[header.priority.index()].push(header.clone());
+        }
+    }
+
+    /// Takes all tasks of the given level out of the queue and passes them
+    /// to `f`, in the order in which they were pushed.
+    pub(crate) fn drain(&self, priority: Priority, f: impl FnMut(TaskRef)) {
+        self.levels[priority.index()].drain(f)
+    }
+
+    /// Returns `true` if no task is in the queue.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.levels.iter().all(RunList::is_empty)
+    }
+}
+
+/// A lock-free list of task headers.
+///
+/// Wakers push to the front with a compare-and-swap loop (a "Treiber stack").
+/// The executor, the only consumer, takes the whole list at once with a single
+/// `swap` and reverses it to get the push order. Taking the whole list avoids
+/// the ABA problem of popping single nodes from a lock-free stack.
+struct RunList {
+    head: AtomicPtr<TaskHeader>,
+}
+
+impl RunList {
+    const fn new() -> RunList {
+        RunList { head: AtomicPtr::new(ptr::null_mut()) }
+    }
+
+    fn push(&self, header: TaskRef) {
+        // The list owns a reference to the header, which `drain` turns back
+        // into a `TaskRef`.
+        let node = header.into_raw() as *mut TaskHeader;
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            unsafe { (*node).next.store(head, Ordering::Relaxed) };
+            // `Release` publishes the `next` pointer together with the node.
+            match self.head.compare_exchange_weak(head, node, Ordering::Release, Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    fn drain(&self, mut f: impl FnMut(TaskRef)) {
+        let mut node = self.head.swap(ptr::null_mut(), Ordering::Acquire);
+        if node.is_null() {
+            return;
+        }
+        // The list is in reverse push order, so we reverse it first.
+        let mut reversed = ptr::null_mut();
+        while !node.is_null() {
+            let next = unsafe { (*node).next.load(Ordering::Relaxed) };
+            unsafe { (*node).next.store(reversed, Ordering::Relaxed) };
+            reversed = node;
+            node = next;
+        }
+        let mut node = reversed;
+        while !node.is_null() {
+            let header = unsafe { TaskRef::from_raw(node) };
+            node = header.next.swap(ptr::null_mut(), Ordering::Relaxed);
+            f(header);
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Acquire).is_null()
+    }
+}
+
+impl Drop for RunList {
+    fn drop(&mut self) {
+        // Release the references that the list owns.
+        self.drain(drop);
+    }
+}
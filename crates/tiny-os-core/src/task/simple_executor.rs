@@ -15,6 +15,12 @@ pub struct SimpleExecutor {
 // The idea behind using this type is that we insert new tasks through the
 // `spawn` method at the end and pop the next task for execution from the front.
 // This way, we get a simple FIFO queue ("first in, first out").
+impl Default for SimpleExecutor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl SimpleExecutor {
     pub fn new() -> SimpleExecutor {
         SimpleExecutor {
@@ -77,7 +83,7 @@ fn dummy_raw_waker() -> RawWaker {
     // create the `RawWaker`. The passed `*const ()` does not matter since none
     // of the vtable functions uses it. For this reason, we simply pass a null
     // pointer.
-    RawWaker::new(0 as *const (), vtable)
+    RawWaker::new(core::ptr::null(), vtable)
 }
 
 // Turn `RawWaker` instance into a `Waker`.
@@ -0,0 +1,132 @@
+//! # Barrier module
+//!
+//! Lets a group of tasks wait for each other.
+
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{ Context, Poll },
+};
+use super::wait_queue::{ WaitQueue, Waiter };
+
+/// Makes `n` tasks wait until all of them arrived.
+///
+/// The barrier can be reused: after the `n`th task arrived, the next `n`
+/// calls of `wait` form a new group.
+pub struct Barrier {
+    n: usize,
+    state: spin::Mutex<State>,
+}
+
+struct State {
+    /// The number of tasks of the current group that arrived.
+    arrived: usize,
+    waiters: WaitQueue,
+}
+
+impl Barrier {
+    /// Creates a barrier for groups of `n` tasks. A barrier for 0 tasks
+    /// behaves like one for a single task: `wait` never waits.
+    pub const fn new(n: usize) -> Self {
+        Barrier {
+            n: if n == 0 { 1 } else { n },
+            state: spin::Mutex::new(State {
+                arrived: 0,
+                waiters: WaitQueue::new(),
+            }),
+        }
+    }
+
+    /// Waits until `n` tasks called `wait`.
+    ///
+    /// Dropping the future before it completes takes the task out of the
+    /// group again.
+    pub fn wait(&self) -> BarrierWait<'_> {
+        BarrierWait {
+            barrier: self,
+            state: WaitState::Start,
+        }
+    }
+}
+
+impl fmt::Debug for Barrier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Barrier").field("n", &self.n).finish()
+    }
+}
+
+/// The result of `Barrier::wait`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BarrierWaitResult {
+    is_leader: bool,
+}
+
+impl BarrierWaitResult {
+    /// Returns `true` for exactly one task of each group: the one that
+    /// arrived last.
+    pub fn is_leader(&self) -> bool {
+        self.is_leader
+    }
+}
+
+enum WaitState {
+    Start,
+    Waiting(u64),
+    Done,
+}
+
+/// The future that `Barrier::wait` returns.
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct BarrierWait<'a> {
+    barrier: &'a Barrier,
+    state: WaitState,
+}
+
+impl Future for BarrierWait<'_> {
+    type Output = BarrierWaitResult;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<BarrierWaitResult> {
+        let barrier = self.barrier;
+        let mut state = barrier.state.lock();
+        let is_leader = match self.state {
+            WaitState::Start => {
+                state.arrived += 1;
+                if state.arrived < barrier.n {
+                    let id = state.waiters.push(0, cx.waker());
+                    self.state = WaitState::Waiting(id);
+                    return Poll::Pending;
+                }
+                // We are the last task of the group, so we release the others
+                // and start a new group.
+                state.arrived = 0;
+                let waiters = state.waiters.take_all();
+                drop(state);
+                waiters.into_iter().for_each(Waiter::wake);
+                true
+            }
+            WaitState::Waiting(id) => {
+                if state.waiters.update(id, cx.waker()) {
+                    return Poll::Pending;
+                }
+                false
+            }
+            WaitState::Done => panic!("`BarrierWait` polled after completion"),
+        };
+        self.state = WaitState::Done;
+        Poll::Ready(BarrierWaitResult { is_leader })
+    }
+}
+
+impl Drop for BarrierWait<'_> {
+    fn drop(&mut self) {
+        if let WaitState::Waiting(id) = self.state {
+            let mut state = self.barrier.state.lock();
+            // If the group is already complete, we were released and there is
+            // nothing to undo.
+            if state.waiters.remove(id) {
+                state.arrived -= 1;
+            }
+        }
+    }
+}
@@ -0,0 +1,58 @@
+//! # Sync module
+//!
+//! Synchronization primitives for tasks: `Mutex`, `RwLock`, `Semaphore`,
+//! `Notify`, and `Barrier`.
+//!
+//! A task that has to wait stores its `Waker` in the wait queue of the
+//! primitive and returns `Pending`, so the executor runs other tasks in the
+//! meantime. The primitives work with any executor, since they only use the
+//! `Waker` of the task.
+
+mod barrier;
+mod mutex;
+mod notify;
+mod rwlock;
+mod semaphore;
+pub(crate) mod wait_queue;
+
+pub use barrier::{ Barrier, BarrierWait, BarrierWaitResult };
+pub use mutex::{ Mutex, MutexGuard };
+pub use notify::{ Notified, Notify };
+pub use rwlock::{ RwLock, RwLockReadGuard, RwLockWriteGuard };
+pub use semaphore::{ Acquire, Semaphore, SemaphorePermit };
+
+// ********** Sidenote **********
+//
+// # Why not `spin::Mutex`?
+//
+// A spin lock waits by spinning in a loop until the lock is free. This works
+// as long as the lock is only held for a short time and the holder keeps
+// running. With cooperative tasks, the holder doesn't keep running: if a task
+// holds a `spin::MutexGuard` across an `.await` and another task tries to
+// lock the same mutex, the second task spins forever. The executor never gets
+// the CPU back, so it can't poll the first task, which would release the lock.
+//
+// The primitives in this module never spin while they wait. The second task
+// puts its `Waker` into the wait queue of the mutex and returns `Pending`. When
+// the first task drops its guard, the mutex takes the waker out of the queue
+// and wakes the second task, which then owns the lock.
+//
+// Internally, each primitive still protects its wait queue with a
+// `spin::Mutex`. That lock is only held for a few instructions and never across
+// an `.await`, so it can't deadlock between tasks. It is not safe to use the
+// primitives from interrupt handlers, though: an interrupt handler that
+// interrupts a task while it holds the internal lock would spin forever.
+//
+// ## Fairness
+//
+// All primitives wake their waiters in FIFO order. The `Semaphore` goes one
+// step further: if the first waiter needs more permits than are available,
+// later waiters wait too, even if they need fewer permits. Otherwise a task
+// that waits for many permits, like a writer of a `RwLock`, could starve.
+//
+// ## Cancellation
+//
+// A future that is dropped while it waits, e.g. by `timeout` or
+// `JoinHandle::abort`, leaves the wait queue. If the primitive already
+// handed it what it waited for (permits of a semaphore, or a notification of
+// `notify_one`), it passes it on to the next waiter, so nothing is lost.
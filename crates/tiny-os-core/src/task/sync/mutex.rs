@@ -0,0 +1,121 @@
+//! # Mutex module
+//!
+//! An async mutex, which a task can hold across `.await` points.
+
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    marker::PhantomData,
+    ops::{ Deref, DerefMut },
+};
+use super::semaphore::Semaphore;
+
+/// A mutual exclusion lock whose `lock` method waits asynchronously.
+///
+/// Unlike `spin::Mutex`, a task that waits for the lock returns `Pending`, so
+/// the executor can run the task that holds the lock in the meantime. The
+/// lock is fair: tasks get it in the order in which they asked for it.
+///
+/// The mutex is built on a `Semaphore` with a single permit.
+pub struct Mutex<T: ?Sized> {
+    semaphore: Semaphore,
+    data: UnsafeCell<T>,
+}
+
+// Same bounds as `spin::Mutex`: the guard hands out `&mut T` to one task at a
+// time, which might be another task than the one that created the mutex.
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    /// Creates an unlocked mutex.
+    pub const fn new(data: T) -> Self {
+        Mutex {
+            semaphore: Semaphore::new(1),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Consumes the mutex and returns the protected data.
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Waits until the lock is free and locks it.
+    ///
+    /// Dropping the future before it completes gives up the place in the
+    /// queue.
+    pub async fn lock(&self) -> MutexGuard<'_, T> {
+        // The guard gives the permit back, not the `SemaphorePermit`.
+        self.semaphore.acquire().await.forget();
+        MutexGuard { mutex: self, _marker: PhantomData }
+    }
+
+    /// Locks the mutex if it is free and no other task waits for it.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let permit = self.semaphore.try_acquire()?;
+        permit.forget();
+        Some(MutexGuard { mutex: self, _marker: PhantomData })
+    }
+
+    /// Returns a mutable reference to the data.
+    ///
+    /// No locking is needed since the `&mut self` guarantees that nobody else
+    /// holds the lock.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Mutex::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.debug_struct("Mutex").field("data", &&*guard).finish(),
+            None => f.debug_struct("Mutex").field("data", &"<locked>").finish(),
+        }
+    }
+}
+
+/// Gives access to the data of a locked `Mutex` and unlocks it on drop.
+#[must_use = "the mutex is unlocked immediately if the guard is not used"]
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+    // The guard hands out `&mut T`, so it may only be shared between threads
+    // if `T` is `Sync`. The `&Mutex<T>` alone only requires `T: Send`.
+    _marker: PhantomData<&'a mut T>,
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // The permit of the semaphore guarantees exclusive access.
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.semaphore.add_permits(1);
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for MutexGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
@@ -0,0 +1,166 @@
+//! # Notify module
+//!
+//! Wakes waiting tasks without passing any data.
+
+use alloc::vec::Vec;
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{ Context, Poll },
+};
+use super::wait_queue::{ WaitQueue, Waiter };
+
+/// Notifies tasks that something happened, e.g. that new work is available.
+///
+/// A task waits with `notified().await`. `notify_one` wakes the task that
+/// waits the longest. If no task waits, it stores a permit instead, so the
+/// next `notified` completes immediately and the notification is not lost.
+/// `notify_waiters` wakes all tasks that wait right now.
+///
+/// Don't call `notify_one` from an interrupt handler: the task that it
+/// interrupts might hold the internal lock. Use a channel of the `channel`
+/// module or an `AtomicWaker` there.
+pub struct Notify {
+    state: spin::Mutex<State>,
+}
+
+struct State {
+    /// Set by `notify_one` if no task waits.
+    permit: bool,
+    waiters: WaitQueue,
+    /// The waiters that `notify_one` took out of the queue, but which didn't
+    /// see the notification yet. If such a waiter is dropped, it passes the
+    /// notification on.
+    notified: Vec<u64>,
+}
+
+impl Notify {
+    /// Creates a `Notify` without a stored permit.
+    pub const fn new() -> Self {
+        Notify {
+            state: spin::Mutex::new(State {
+                permit: false,
+                waiters: WaitQueue::new(),
+                notified: Vec::new(),
+            }),
+        }
+    }
+
+    /// Wakes the task that waits the longest, or stores a permit for the next
+    /// `notified` if no task waits.
+    ///
+    /// At most one permit is stored, so notifying twice without a waiter
+    /// completes only one `notified`.
+    pub fn notify_one(&self) {
+        let waiter = {
+            let mut state = self.state.lock();
+            match state.waiters.pop() {
+                Some(waiter) => {
+                    let id = waiter.id();
+                    state.notified.push(id);
+                    Some(waiter)
+                }
+                None => {
+                    state.permit = true;
+                    None
+                }
+            }
+        };
+        if let Some(waiter) = waiter {
+            waiter.wake();
+        }
+    }
+
+    /// Wakes all tasks that wait right now. Unlike `notify_one`, this doesn't
+    /// store a permit.
+    pub fn notify_waiters(&self) {
+        let waiters = self.state.lock().waiters.take_all();
+        waiters.into_iter().for_each(Waiter::wake);
+    }
+
+    /// Waits for a notification.
+    pub fn notified(&self) -> Notified<'_> {
+        Notified {
+            notify: self,
+            state: NotifiedState::Start,
+        }
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Notify::new()
+    }
+}
+
+impl fmt::Debug for Notify {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Notify").field("permit", &self.state.lock().permit).finish()
+    }
+}
+
+enum NotifiedState {
+    Start,
+    Waiting(u64),
+    Done,
+}
+
+/// The future that `Notify::notified` returns.
+///
+/// The future only waits after it was polled for the first time, so a
+/// `notify_waiters` between its creation and the first poll is missed.
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    state: NotifiedState,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let mut state = self.notify.state.lock();
+        match self.state {
+            NotifiedState::Start => {
+                if state.permit {
+                    state.permit = false;
+                } else {
+                    let id = state.waiters.push(0, cx.waker());
+                    drop(state);
+                    self.state = NotifiedState::Waiting(id);
+                    return Poll::Pending;
+                }
+            }
+            NotifiedState::Waiting(id) => {
+                if state.waiters.update(id, cx.waker()) {
+                    return Poll::Pending;
+                }
+                state.notified.retain(|&notified| notified != id);
+            }
+            NotifiedState::Done => panic!("`Notified` polled after completion"),
+        }
+        drop(state);
+        self.state = NotifiedState::Done;
+        Poll::Ready(())
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        if let NotifiedState::Waiting(id) = self.state {
+            let mut state = self.notify.state.lock();
+            if state.waiters.remove(id) {
+                return;
+            }
+            // If `notify_one` picked us, another task should get the
+            // notification instead.
+            let position = state.notified.iter().position(|&notified| notified == id);
+            if let Some(position) = position {
+                state.notified.swap_remove(position);
+                drop(state);
+                self.notify.notify_one();
+            }
+        }
+    }
+}
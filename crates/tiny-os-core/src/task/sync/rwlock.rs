@@ -0,0 +1,151 @@
+//! # RwLock module
+//!
+//! An async reader-writer lock.
+
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    ops::{ Deref, DerefMut },
+};
+use super::semaphore::Semaphore;
+
+/// The number of permits of the semaphore of a `RwLock`. A reader takes one
+/// permit and a writer all of them, so this is also the maximum number of
+/// readers.
+const MAX_READS: usize = Semaphore::MAX_PERMITS;
+
+/// A lock that allows many readers or a single writer at a time.
+///
+/// Like our `Mutex`, the lock is fair: a waiting writer is not overtaken by
+/// readers that come later, so a steady stream of readers can't starve it.
+pub struct RwLock<T: ?Sized> {
+    semaphore: Semaphore,
+    data: UnsafeCell<T>,
+}
+
+// Same bounds as `spin::RwLock`: readers on different threads share `&T`, and
+// a writer might be another thread than the one that created the lock.
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    /// Creates an unlocked lock.
+    pub const fn new(data: T) -> Self {
+        RwLock {
+            semaphore: Semaphore::new(MAX_READS),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Consumes the lock and returns the protected data.
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// Waits until no writer holds or waits for the lock, and locks it for
+    /// reading.
+    pub async fn read(&self) -> RwLockReadGuard<'_, T> {
+        self.semaphore.acquire().await.forget();
+        RwLockReadGuard { lock: self }
+    }
+
+    /// Waits until nobody holds the lock, and locks it for writing.
+    pub async fn write(&self) -> RwLockWriteGuard<'_, T> {
+        self.semaphore.acquire_many(MAX_READS).await.forget();
+        RwLockWriteGuard { lock: self }
+    }
+
+    /// Locks the lock for reading if this is possible without waiting.
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        self.semaphore.try_acquire()?.forget();
+        Some(RwLockReadGuard { lock: self })
+    }
+
+    /// Locks the lock for writing if this is possible without waiting.
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        self.semaphore.try_acquire_many(MAX_READS)?.forget();
+        Some(RwLockWriteGuard { lock: self })
+    }
+
+    /// Returns a mutable reference to the data, see `Mutex::get_mut`.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: Default> Default for RwLock<T> {
+    fn default() -> Self {
+        RwLock::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_read() {
+            Some(guard) => f.debug_struct("RwLock").field("data", &&*guard).finish(),
+            None => f.debug_struct("RwLock").field("data", &"<locked>").finish(),
+        }
+    }
+}
+
+/// Gives shared access to the data of a `RwLock` and unlocks it on drop.
+#[must_use = "the lock is unlocked immediately if the guard is not used"]
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // No writer can hold the lock while we hold a permit.
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.semaphore.add_permits(1);
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLockReadGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// Gives exclusive access to the data of a `RwLock` and unlocks it on drop.
+#[must_use = "the lock is unlocked immediately if the guard is not used"]
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // We hold all permits, so nobody else holds the lock.
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.semaphore.add_permits(MAX_READS);
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLockWriteGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
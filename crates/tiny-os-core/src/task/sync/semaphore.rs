@@ -0,0 +1,220 @@
+//! # Semaphore module
+//!
+//! An async counting semaphore, which is also the base of our `Mutex` and
+//! `RwLock`.
+
+use alloc::vec::Vec;
+use core::{
+    fmt,
+    future::Future,
+    mem,
+    pin::Pin,
+    task::{ Context, Poll },
+};
+use super::wait_queue::{ WaitQueue, Waiter };
+
+/// Limits how many tasks can access a resource at the same time.
+///
+/// Tasks acquire permits and give them back when they drop the returned
+/// `SemaphorePermit`. If not enough permits are available, `acquire` waits
+/// without blocking the executor.
+///
+/// Permits are handed out in FIFO order: a task that asks for many permits is
+/// not overtaken by tasks that ask for fewer, so it can't starve.
+pub struct Semaphore {
+    state: spin::Mutex<State>,
+}
+
+struct State {
+    permits: usize,
+    waiters: WaitQueue,
+}
+
+impl State {
+    /// Takes the waiters out of the queue whose requests can be served now, in
+    /// FIFO order. The caller wakes them after unlocking the state.
+    fn grant(&mut self) -> Vec<Waiter> {
+        let mut granted = Vec::new();
+        while let Some(waiter) = self.waiters.front() {
+            if waiter.wanted > self.permits {
+                break;
+            }
+            self.permits -= waiter.wanted;
+            granted.extend(self.waiters.pop());
+        }
+        granted
+    }
+}
+
+impl Semaphore {
+    /// The maximum number of permits of a semaphore.
+    ///
+    /// The limit leaves room for adding permits without an overflow.
+    pub const MAX_PERMITS: usize = usize::MAX >> 3;
+
+    /// Creates a semaphore with the given number of permits.
+    pub const fn new(permits: usize) -> Self {
+        assert!(permits <= Self::MAX_PERMITS, "too many permits");
+        Semaphore {
+            state: spin::Mutex::new(State {
+                permits,
+                waiters: WaitQueue::new(),
+            }),
+        }
+    }
+
+    /// Returns the number of permits that are not acquired.
+    pub fn available_permits(&self) -> usize {
+        self.state.lock().permits
+    }
+
+    /// Adds `n` permits, which might wake waiting tasks.
+    pub fn add_permits(&self, n: usize) {
+        let granted = {
+            let mut state = self.state.lock();
+            assert!(state.permits + n <= Self::MAX_PERMITS, "too many permits");
+            state.permits += n;
+            state.grant()
+        };
+        granted.into_iter().for_each(Waiter::wake);
+    }
+
+    /// Waits for a permit.
+    pub fn acquire(&self) -> Acquire<'_> {
+        self.acquire_many(1)
+    }
+
+    /// Waits until `n` permits are available and acquires them together.
+    pub fn acquire_many(&self, n: usize) -> Acquire<'_> {
+        Acquire {
+            semaphore: self,
+            wanted: n,
+            state: AcquireState::Start,
+        }
+    }
+
+    /// Acquires a permit if one is available and no other task waits.
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        self.try_acquire_many(1)
+    }
+
+    /// Acquires `n` permits if they are available and no other task waits.
+    pub fn try_acquire_many(&self, n: usize) -> Option<SemaphorePermit<'_>> {
+        let mut state = self.state.lock();
+        if state.waiters.is_empty() && state.permits >= n {
+            state.permits -= n;
+            Some(SemaphorePermit { semaphore: self, permits: n })
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Debug for Semaphore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Semaphore").field("permits", &self.available_permits()).finish()
+    }
+}
+
+/// Acquired permits of a `Semaphore`, which are given back on drop.
+#[must_use = "the permits are given back immediately if they are not used"]
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+}
+
+impl SemaphorePermit<'_> {
+    /// Returns the number of permits.
+    pub fn num_permits(&self) -> usize {
+        self.permits
+    }
+
+    /// Keeps the permits acquired. Use `Semaphore::add_permits` to give them
+    /// back.
+    pub fn forget(self) {
+        mem::forget(self);
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.add_permits(self.permits);
+    }
+}
+
+impl fmt::Debug for SemaphorePermit<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SemaphorePermit").field("permits", &self.permits).finish()
+    }
+}
+
+enum AcquireState {
+    /// Not polled yet.
+    Start,
+    /// In the wait queue of the semaphore with the given ID.
+    Waiting(u64),
+    /// The permits were handed out.
+    Done,
+}
+
+/// The future that `Semaphore::acquire` returns.
+///
+/// Dropping the future gives up the place in the queue, or gives back the
+/// permits if they were already assigned to it.
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    wanted: usize,
+    state: AcquireState,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = SemaphorePermit<'a>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<SemaphorePermit<'a>> {
+        let semaphore = self.semaphore;
+        let mut state = semaphore.state.lock();
+        match self.state {
+            AcquireState::Start => {
+                // We only take permits if nobody waits, otherwise we would
+                // overtake the waiting tasks.
+                if state.waiters.is_empty() && state.permits >= self.wanted {
+                    state.permits -= self.wanted;
+                } else {
+                    let id = state.waiters.push(self.wanted, cx.waker());
+                    self.state = AcquireState::Waiting(id);
+                    return Poll::Pending;
+                }
+            }
+            AcquireState::Waiting(id) => {
+                // `grant` takes us out of the queue when it assigns the permits
+                // to us.
+                if state.waiters.update(id, cx.waker()) {
+                    return Poll::Pending;
+                }
+            }
+            AcquireState::Done => panic!("`Acquire` polled after completion"),
+        }
+        self.state = AcquireState::Done;
+        Poll::Ready(SemaphorePermit { semaphore, permits: self.wanted })
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        if let AcquireState::Waiting(id) = self.state {
+            let mut state = self.semaphore.state.lock();
+            if state.waiters.remove(id) {
+                // Tasks behind us might be served now, e.g. if we waited for
+                // more permits than they do.
+                let granted = state.grant();
+                drop(state);
+                granted.into_iter().for_each(Waiter::wake);
+            } else {
+                // The permits were assigned to us, but we no longer need them.
+                drop(state);
+                self.semaphore.add_permits(self.wanted);
+            }
+        }
+    }
+}
@@ -0,0 +1,105 @@
+//! # Wait queue module
+//!
+//! The FIFO list of waiting tasks that all primitives of the `sync` module
+//! and the channels share.
+//!
+//! A waiting future pushes itself to the queue and remembers its ID. The
+//! primitive wakes waiters by taking them out of the queue, so a future that
+//! doesn't find its ID in the queue anymore knows that it was woken on purpose
+//! and not by a stale wake-up.
+
+use alloc::collections::VecDeque;
+use core::task::Waker;
+
+pub(crate) struct Waiter {
+    id: u64,
+    waker: Waker,
+    /// What the waiter waits for, e.g. the number of permits of a semaphore.
+    pub(crate) wanted: usize,
+}
+
+impl Waiter {
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Wakes the task of the waiter.
+    pub(crate) fn wake(self) {
+        self.waker.wake();
+    }
+}
+
+pub(crate) struct WaitQueue {
+    waiters: VecDeque<Waiter>,
+    next_id: u64,
+}
+
+impl WaitQueue {
+    pub(crate) const fn new() -> Self {
+        WaitQueue {
+            waiters: VecDeque::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Adds a waiter to the back of the queue and returns its ID.
+    pub(crate) fn push(&mut self, wanted: usize, waker: &Waker) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.waiters.push_back(Waiter { id, waker: waker.clone(), wanted });
+        id
+    }
+
+    /// Updates the waker of a waiter. Returns `false` if the waiter is no
+    /// longer in the queue, i.e. it was woken.
+    pub(crate) fn update(&mut self, id: u64, waker: &Waker) -> bool {
+        match self.waiters.iter_mut().find(|waiter| waiter.id == id) {
+            Some(waiter) => {
+                if !waiter.waker.will_wake(waker) {
+                    waiter.waker = waker.clone();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes a waiter that gives up waiting. Returns `false` if the waiter
+    /// is no longer in the queue, i.e. it was woken.
+    pub(crate) fn remove(&mut self, id: u64) -> bool {
+        match self.waiters.iter().position(|waiter| waiter.id == id) {
+            Some(index) => {
+                self.waiters.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the waiter that waits the longest.
+    pub(crate) fn front(&self) -> Option<&Waiter> {
+        self.waiters.front()
+    }
+
+    /// Takes the waiter that waits the longest out of the queue.
+    pub(crate) fn pop(&mut self) -> Option<Waiter> {
+        self.waiters.pop_front()
+    }
+
+    /// Takes all waiters out of the queue.
+    pub(crate) fn take_all(&mut self) -> VecDeque<Waiter> {
+        core::mem::take(&mut self.waiters)
+    }
+
+    /// Wakes all waiters and takes them out of the queue.
+    ///
+    /// Unlike `take_all`, this keeps the memory of the queue, so it doesn't
+    /// free anything, which matters in interrupt handlers.
+    pub(crate) fn wake_all(&mut self) {
+        self.waiters.drain(..).for_each(Waiter::wake);
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.waiters.is_empty()
+    }
+}
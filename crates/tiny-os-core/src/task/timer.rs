@@ -0,0 +1,303 @@
+//! # Timer wheel module
+//!
+//! A hashed timing wheel that stores the wakers of sleeping tasks by their
+//! deadline and wakes them when the time advances past it.
+//!
+//! The wheel doesn't read a clock itself. Times are plain `u64` values in any
+//! unit (the kernel uses nanoseconds since boot), and the owner calls `advance`
+//! with the current time, e.g. from the timer interrupt. This keeps the wheel
+//! testable on the host.
+
+use alloc::vec::Vec;
+use core::task::Waker;
+
+/// The number of slots of the wheel.
+pub const WHEEL_SIZE: usize = 256;
+
+/// Identifies a timer in the wheel, see `TimerWheel::insert`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId {
+    slot: usize,
+    id: u64,
+}
+
+struct Entry {
+    id: u64,
+    deadline: u64,
+    waker: Waker,
+}
+
+/// A timing wheel with `WHEEL_SIZE` slots of `granularity` time units each.
+///
+/// A timer is stored in the slot that covers its deadline (modulo the size of
+/// the wheel). Advancing the time only looks at the slots between the old and
+/// the new time, so the cost doesn't depend on the number of timers that are
+/// far in the future.
+pub struct TimerWheel {
+    slots: [Vec<Entry>; WHEEL_SIZE],
+    granularity: u64,
+    /// The time of the last `advance` call.
+    now: u64,
+    next_id: u64,
+    len: usize,
+}
+
+impl TimerWheel {
+    /// Creates an empty wheel whose slots cover `granularity` time units.
+    ///
+    /// The function is `const`, so that the wheel can be stored in a static.
+    pub const fn new(granularity: u64) -> Self {
+        // See `FixedSizeBlockAllocator::new` for why we need this constant.
+        const EMPTY: Vec<Entry> = Vec::new();
+        TimerWheel {
+            slots: [EMPTY; WHEEL_SIZE],
+            granularity,
+            now: 0,
+            next_id: 0,
+            len: 0,
+        }
+    }
+
+    /// Returns the time of the last `advance` call.
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    /// Returns the number of timers in the wheel.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if the wheel contains no timers.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Adds a timer that wakes `waker` once the time reaches `deadline`.
+    ///
+    /// A deadline that has already passed is woken by the next `advance`.
+    /// Inserting allocates, so it must not be called from an interrupt handler.
+    pub fn insert(&mut self, deadline: u64, waker: Waker) -> TimerId {
+        // A deadline in the past goes into the current slot, which is checked
+        // by every `advance`.
+        let slot = self.slot_of(deadline.max(self.now));
+        let id = self.next_id;
+        self.next_id += 1;
+        self.slots[slot].push(Entry { id, deadline, waker });
+        self.len += 1;
+        TimerId { slot, id }
+    }
+
+    /// Replaces the waker of a timer, e.g. because its future was polled with
+    /// a different context. Returns `false` if the timer already fired or was
+    /// removed.
+    pub fn update_waker(&mut self, timer: TimerId, waker: &Waker) -> bool {
+        match self.slots[timer.slot].iter_mut().find(|entry| entry.id == timer.id) {
+            Some(entry) => {
+                if !entry.waker.will_wake(waker) {
+                    entry.waker = waker.clone();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes a timer. Returns `false` if it already fired or was removed.
+    pub fn remove(&mut self, timer: TimerId) -> bool {
+        let slot = &mut self.slots[timer.slot];
+        match slot.iter().position(|entry| entry.id == timer.id) {
+            Some(index) => {
+                slot.swap_remove(index);
+                self.len -= 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the earliest deadline of all timers.
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.slots.iter().flatten().map(|entry| entry.deadline).min()
+    }
+
+    /// Advances the time to `now` and passes the wakers of all expired timers
+    /// to `wake`. The expired timers are removed from the wheel.
+    ///
+    /// This doesn't allocate, so it can run in an interrupt handler. A `now`
+    /// that is smaller than the last one is ignored.
+    pub fn advance(&mut self, now: u64, mut wake: impl FnMut(Waker)) {
+        let now = now.max(self.now);
+        let first = self.now / self.granularity;
+        let last = now / self.granularity;
+        // We revisit the slot of the last call, since it might contain timers
+        // that expire later in the same slot. If we skipped a complete round,
+        // every slot must be checked once.
+        let slots = (last - first).min(WHEEL_SIZE as u64 - 1);
+        for offset in 0..=slots {
+            let slot = &mut self.slots[((first + offset) % WHEEL_SIZE as u64) as usize];
+            // Timers of later rounds share the slot, so we only take the
+            // expired ones.
+            let mut index = 0;
+            while index < slot.len() {
+                if slot[index].deadline <= now {
+                    wake(slot.swap_remove(index).waker);
+                    self.len -= 1;
+                } else {
+                    index += 1;
+                }
+            }
+        }
+        self.now = now;
+    }
+
+    fn slot_of(&self, time: u64) -> usize {
+        ((time / self.granularity) % WHEEL_SIZE as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{ sync::Arc, task::Wake };
+    use core::sync::atomic::{ AtomicUsize, Ordering };
+
+    /// A waker that counts how often it was woken.
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn counting_waker() -> (Arc<CountingWaker>, Waker) {
+        let counter = Arc::new(CountingWaker(AtomicUsize::new(0)));
+        (counter.clone(), Waker::from(counter))
+    }
+
+    fn wakes(counter: &CountingWaker) -> usize {
+        counter.0.load(Ordering::SeqCst)
+    }
+
+    fn advance(wheel: &mut TimerWheel, now: u64) {
+        wheel.advance(now, Waker::wake);
+    }
+
+    #[test]
+    fn wakes_at_deadline() {
+        let mut wheel = TimerWheel::new(10);
+        let (counter, waker) = counting_waker();
+        wheel.insert(25, waker);
+
+        advance(&mut wheel, 20);
+        assert_eq!(wakes(&counter), 0);
+        // Same slot as before, but now past the deadline.
+        advance(&mut wheel, 25);
+        assert_eq!(wakes(&counter), 1);
+        assert!(wheel.is_empty());
+
+        advance(&mut wheel, 1000);
+        assert_eq!(wakes(&counter), 1);
+    }
+
+    #[test]
+    fn later_rounds_stay_in_the_wheel() {
+        let mut wheel = TimerWheel::new(1);
+        let (counter, waker) = counting_waker();
+        // The same slot as deadline 5, but three rounds later.
+        let deadline = 5 + 3 * WHEEL_SIZE as u64;
+        wheel.insert(deadline, waker);
+
+        for now in 0..deadline {
+            advance(&mut wheel, now);
+        }
+        assert_eq!(wakes(&counter), 0);
+        assert_eq!(wheel.next_deadline(), Some(deadline));
+        advance(&mut wheel, deadline);
+        assert_eq!(wakes(&counter), 1);
+    }
+
+    #[test]
+    fn large_jumps_check_every_slot() {
+        let mut wheel = TimerWheel::new(1);
+        let counters: Vec<_> = (0..WHEEL_SIZE as u64)
+            .map(|deadline| {
+                let (counter, waker) = counting_waker();
+                wheel.insert(deadline * 7, waker);
+                counter
+            })
+            .collect();
+
+        advance(&mut wheel, 100 * WHEEL_SIZE as u64);
+        assert!(counters.iter().all(|counter| wakes(counter) == 1));
+        assert!(wheel.is_empty());
+    }
+
+    #[test]
+    fn past_deadlines_fire_on_next_advance() {
+        let mut wheel = TimerWheel::new(10);
+        advance(&mut wheel, 500);
+        let (counter, waker) = counting_waker();
+        wheel.insert(100, waker);
+
+        advance(&mut wheel, 500);
+        assert_eq!(wakes(&counter), 1);
+    }
+
+    #[test]
+    fn removed_timers_dont_fire() {
+        let mut wheel = TimerWheel::new(10);
+        let (first, waker) = counting_waker();
+        let timer = wheel.insert(50, waker);
+        let (second, waker) = counting_waker();
+        wheel.insert(50, waker);
+
+        assert!(wheel.remove(timer));
+        assert!(!wheel.remove(timer));
+        assert_eq!(wheel.len(), 1);
+
+        advance(&mut wheel, 50);
+        assert_eq!(wakes(&first), 0);
+        assert_eq!(wakes(&second), 1);
+        assert!(!wheel.update_waker(timer, &Waker::from(first)));
+    }
+
+    #[test]
+    fn updated_waker_is_woken() {
+        let mut wheel = TimerWheel::new(10);
+        let (old, waker) = counting_waker();
+        let timer = wheel.insert(30, waker);
+        let (new, waker) = counting_waker();
+        assert!(wheel.update_waker(timer, &waker));
+
+        advance(&mut wheel, 30);
+        assert_eq!(wakes(&old), 0);
+        assert_eq!(wakes(&new), 1);
+    }
+
+    #[test]
+    fn time_never_goes_back() {
+        let mut wheel = TimerWheel::new(10);
+        advance(&mut wheel, 100);
+        advance(&mut wheel, 50);
+        assert_eq!(wheel.now(), 100);
+    }
+}
+
+// ********** Sidenote **********
+//
+// # Timing wheels
+//
+// The obvious data structure for timers is a priority queue ordered by
+// deadline. It finds the next expired timer quickly, but every insertion costs
+// `O(log n)` and the heap must be reorganized in the timer interrupt.
+//
+// A timing wheel, described by Varghese and Lauck in "Hashed and Hierarchical
+// Timing Wheels" (1987), works like a clock face instead: every slot covers a
+// short interval, and a timer is hashed into the slot of its deadline. Adding
+// and removing a timer only touches one slot, and a timer interrupt only checks
+// the slots that the clock hand passed since the last interrupt. Timers that
+// are more than one round away share a slot with earlier ones and are simply
+// skipped until their round comes. Linux used a similar design for its
+// `timer_list` for a long time.
@@ -0,0 +1,55 @@
+//! # Host test for the allocations of the executor
+//!
+//! Records the layouts that spawning a task allocates, to check that every
+//! task allocates one `TaskHeader`, which the slab caches of the kernel are
+//! sized for, and that the header is freed with the task.
+
+use std::{
+    alloc::{ GlobalAlloc, Layout, System },
+    sync::atomic::{ AtomicBool, AtomicUsize, Ordering },
+};
+use tiny_os_core::task::{ executor::Executor, TaskHeader };
+
+/// Counts the allocations and deallocations with the layout of a
+/// `TaskHeader` while `RECORDING` is set.
+struct RecordingAllocator;
+
+static RECORDING: AtomicBool = AtomicBool::new(false);
+static HEADER_ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static HEADER_DEALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for RecordingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if RECORDING.load(Ordering::SeqCst) && layout == Layout::new::<TaskHeader>() {
+            HEADER_ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        }
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if RECORDING.load(Ordering::SeqCst) && layout == Layout::new::<TaskHeader>() {
+            HEADER_DEALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        }
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: RecordingAllocator = RecordingAllocator;
+
+#[test]
+fn spawn_allocates_one_task_header() {
+    let mut executor = Executor::new();
+    // Run one task first, so that the maps and lists of the executor already
+    // have their nodes and don't allocate blocks of the same size by chance.
+    executor.spawn(async {});
+    executor.run_ready_tasks();
+    RECORDING.store(true, Ordering::SeqCst);
+    executor.spawn(async {});
+    assert_eq!(HEADER_ALLOCATIONS.load(Ordering::SeqCst), 1);
+
+    // The task finishes in its first poll, which frees its header.
+    executor.run_ready_tasks();
+    RECORDING.store(false, Ordering::SeqCst);
+    assert_eq!(HEADER_DEALLOCATIONS.load(Ordering::SeqCst), 1);
+}
@@ -0,0 +1,292 @@
+//! # Host tests for the channels
+//!
+//! Passes values between tasks on the executor, and from another thread,
+//! which stands in for an interrupt handler.
+
+use futures_util::stream::StreamExt;
+use std::{ cell::RefCell, rc::Rc, thread };
+use tiny_os_core::task::{
+    channel::{ broadcast, mpsc, oneshot, RecvError, SendError, TryRecvError, TrySendError },
+    executor::Executor,
+};
+
+type Log = Rc<RefCell<Vec<String>>>;
+
+fn run(executor: &mut Executor) {
+    while !executor.is_idle() {
+        executor.run_ready_tasks();
+    }
+}
+
+#[test]
+fn bounded_send_waits_for_receiver() {
+    let (sender, mut receiver) = mpsc::channel(2);
+    let log: Log = Rc::default();
+    let mut executor = Executor::new();
+    let task_log = log.clone();
+    executor.spawn(async move {
+        for i in 0..4 {
+            sender.send(i).await.unwrap();
+            task_log.borrow_mut().push(format!("sent {}", i));
+        }
+    });
+    run(&mut executor);
+    // The buffer is full after two values.
+    assert_eq!(*log.borrow(), ["sent 0", "sent 1"]);
+
+    let task_log = log.clone();
+    executor.spawn(async move {
+        while let Some(i) = receiver.recv().await {
+            task_log.borrow_mut().push(format!("received {}", i));
+        }
+        task_log.borrow_mut().push("closed".into());
+    });
+    run(&mut executor);
+    assert_eq!(
+        *log.borrow(),
+        [
+            "sent 0", "sent 1",
+            "received 0", "received 1", "sent 2", "sent 3",
+            "received 2", "received 3", "closed",
+        ]
+    );
+    assert_eq!(executor.task_count(), 0);
+}
+
+#[test]
+fn bounded_try_send() {
+    let (sender, mut receiver) = mpsc::channel(1);
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+    sender.try_send(1).unwrap();
+    assert_eq!(sender.try_send(2), Err(TrySendError::Full(2)));
+    assert_eq!(receiver.len(), 1);
+    assert_eq!(receiver.try_recv(), Ok(1));
+
+    sender.try_send(3).unwrap();
+    drop(receiver);
+    assert!(sender.is_closed());
+    assert_eq!(sender.try_send(4), Err(TrySendError::Closed(4)));
+}
+
+#[test]
+fn receiver_sees_values_sent_before_last_sender_dropped() {
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let other = sender.clone();
+    sender.send("first").unwrap();
+    other.send("second").unwrap();
+    drop(sender);
+    drop(other);
+    assert_eq!(receiver.try_recv(), Ok("first"));
+    assert_eq!(receiver.try_recv(), Ok("second"));
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Closed));
+}
+
+#[test]
+fn unbounded_receiver_is_a_stream() {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    for i in 0..1000 {
+        sender.send(i).unwrap();
+    }
+    drop(sender);
+    let mut executor = Executor::new();
+    let handle = executor.spawn(receiver.fold(0, |sum, i| async move { sum + i }));
+    run(&mut executor);
+    assert!(handle.is_finished());
+
+    let (sender, receiver) = mpsc::unbounded_channel::<u32>();
+    drop(receiver);
+    assert_eq!(sender.send(1), Err(SendError(1)));
+}
+
+#[test]
+fn waiting_senders_fail_when_receiver_dropped() {
+    let (sender, receiver) = mpsc::channel(1);
+    sender.try_send(0).unwrap();
+    let log: Log = Rc::default();
+    let mut executor = Executor::new();
+    let task_log = log.clone();
+    executor.spawn(async move {
+        let result = sender.send(1).await;
+        task_log.borrow_mut().push(format!("{:?}", result.map_err(SendError::into_inner)));
+    });
+    run(&mut executor);
+    assert!(log.borrow().is_empty());
+    drop(receiver);
+    run(&mut executor);
+    assert_eq!(*log.borrow(), ["Err(1)"]);
+}
+
+#[test]
+fn cancelled_send_passes_free_place_on() {
+    let (sender, mut receiver) = mpsc::channel(1);
+    let sender = Rc::new(sender);
+    sender.try_send(0).unwrap();
+    let mut executor = Executor::new();
+    let task_sender = sender.clone();
+    let aborted = executor.spawn(async move { task_sender.send(1).await });
+    let task_sender = sender.clone();
+    let waiting = executor.spawn(async move { task_sender.send(2).await });
+    run(&mut executor);
+
+    // The free place goes to the first sender, which is aborted before it
+    // can use it.
+    assert_eq!(receiver.try_recv(), Ok(0));
+    aborted.abort();
+    run(&mut executor);
+    assert!(waiting.is_finished());
+    assert_eq!(receiver.try_recv(), Ok(2));
+}
+
+#[test]
+fn try_send_from_another_thread() {
+    const VALUES: u64 = 1000;
+    let (sender, mut receiver) = mpsc::channel(16);
+    let sum = Rc::new(RefCell::new(0));
+    let mut executor = Executor::new();
+    let task_sum = sum.clone();
+    executor.spawn(async move {
+        while let Some(i) = receiver.recv().await {
+            *task_sum.borrow_mut() += i;
+        }
+    });
+    // Like an interrupt handler, the thread never waits for the receiver; it
+    // retries when the buffer is full.
+    let thread = thread::spawn(move || {
+        for i in 0..VALUES {
+            let mut value = i;
+            while let Err(err) = sender.try_send(value) {
+                value = err.into_inner();
+                thread::yield_now();
+            }
+        }
+    });
+    while executor.task_count() > 0 {
+        executor.run_ready_tasks();
+    }
+    thread.join().unwrap();
+    assert_eq!(*sum.borrow(), VALUES * (VALUES - 1) / 2);
+}
+
+#[test]
+fn oneshot_delivers_value() {
+    let (sender, receiver) = oneshot::channel();
+    let mut executor = Executor::new();
+    let handle = executor.spawn(receiver);
+    run(&mut executor);
+    assert!(!handle.is_finished());
+    assert!(!sender.is_closed());
+    sender.send(42).unwrap();
+    run(&mut executor);
+    assert!(handle.is_finished());
+
+    let (sender, mut receiver) = oneshot::channel();
+    sender.send("value").unwrap();
+    assert_eq!(receiver.try_recv(), Ok("value"));
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Closed));
+}
+
+#[test]
+fn oneshot_reports_dropped_halves() {
+    let (sender, receiver) = oneshot::channel::<u32>();
+    let result = Rc::new(RefCell::new(None));
+    let mut executor = Executor::new();
+    let task_result = result.clone();
+    executor.spawn(async move {
+        *task_result.borrow_mut() = Some(receiver.await);
+    });
+    run(&mut executor);
+    drop(sender);
+    run(&mut executor);
+    assert_eq!(*result.borrow(), Some(Err(RecvError)));
+
+    let (sender, receiver) = oneshot::channel();
+    drop(receiver);
+    assert!(sender.is_closed());
+    assert_eq!(sender.send(Rc::new(1)).map_err(|err| *err.0), Err(1));
+}
+
+#[test]
+fn oneshot_value_is_dropped_with_receiver() {
+    let value = Rc::new(());
+    let (sender, receiver) = oneshot::channel();
+    sender.send(value.clone()).unwrap();
+    assert_eq!(Rc::strong_count(&value), 2);
+    drop(receiver);
+    assert_eq!(Rc::strong_count(&value), 1);
+}
+
+#[test]
+fn broadcast_delivers_to_every_receiver() {
+    let (sender, first) = broadcast::channel(4);
+    let second = sender.subscribe();
+    assert_eq!(sender.receiver_count(), 2);
+    let log: Log = Rc::default();
+    let mut executor = Executor::new();
+    for (name, mut receiver) in [("first", first), ("second", second)] {
+        let log = log.clone();
+        executor.spawn(async move {
+            while let Some(value) = receiver.next().await {
+                log.borrow_mut().push(format!("{} {}", name, value));
+            }
+        });
+    }
+    executor.spawn(async move {
+        sender.send(1).await.unwrap();
+        sender.send(2).await.unwrap();
+    });
+    run(&mut executor);
+    let mut log = log.borrow().clone();
+    log.sort();
+    assert_eq!(log, ["first 1", "first 2", "second 1", "second 2"]);
+    assert_eq!(executor.task_count(), 0);
+}
+
+#[test]
+fn broadcast_waits_for_slowest_receiver() {
+    let (sender, mut fast) = broadcast::channel(2);
+    let mut slow = sender.subscribe();
+    let log: Log = Rc::default();
+    let mut executor = Executor::new();
+    let task_log = log.clone();
+    executor.spawn(async move {
+        for i in 0..3 {
+            sender.send(i).await.unwrap();
+            task_log.borrow_mut().push(format!("sent {}", i));
+        }
+    });
+    run(&mut executor);
+    assert_eq!(*log.borrow(), ["sent 0", "sent 1"]);
+
+    // The fast receiver alone doesn't free a slot.
+    assert_eq!(fast.try_recv(), Ok(0));
+    assert_eq!(fast.try_recv(), Ok(1));
+    assert_eq!(fast.try_recv(), Err(TryRecvError::Empty));
+    run(&mut executor);
+    assert_eq!(log.borrow().len(), 2);
+
+    assert_eq!(slow.try_recv(), Ok(0));
+    run(&mut executor);
+    assert_eq!(*log.borrow(), ["sent 0", "sent 1", "sent 2"]);
+    assert_eq!(slow.len(), 2);
+    assert_eq!(fast.try_recv(), Ok(2));
+    assert_eq!(slow.try_recv(), Ok(1));
+    assert_eq!(slow.try_recv(), Ok(2));
+    assert_eq!(slow.try_recv(), Err(TryRecvError::Closed));
+}
+
+#[test]
+fn broadcast_dropped_receiver_frees_slots() {
+    let (sender, mut receiver) = broadcast::channel(1);
+    let lagging = sender.subscribe();
+    sender.try_send(1).unwrap();
+    assert_eq!(sender.try_send(2), Err(TrySendError::Full(2)));
+    assert_eq!(receiver.try_recv(), Ok(1));
+    assert_eq!(sender.try_send(2), Err(TrySendError::Full(2)));
+    drop(lagging);
+    sender.try_send(2).unwrap();
+    assert_eq!(receiver.try_recv(), Ok(2));
+
+    drop(receiver);
+    assert_eq!(sender.receiver_count(), 0);
+    assert_eq!(sender.try_send(3), Err(TrySendError::Closed(3)));
+}
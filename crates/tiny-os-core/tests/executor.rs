@@ -0,0 +1,618 @@
+//! # Host tests for the executor
+//!
+//! Checks how the executor queues tasks and handles wake-ups, using futures
+//! that are controlled by the test.
+
+use std::{
+    cell::RefCell,
+    future::{ self, Future },
+    panic::{ self, AssertUnwindSafe },
+    pin::Pin,
+    rc::Rc,
+    sync::{ Arc, Mutex, atomic::{ AtomicU64, Ordering } },
+    task::{ Context, Poll, Waker },
+    time::Duration,
+};
+use tiny_os_core::task::{
+    Task,
+    JoinError,
+    JoinHandle,
+    MonitorError,
+    Priority,
+    TaskInfo,
+    TaskState,
+    WakeSource,
+    executor::{ Executor, SpawnError, POLL_BUDGET },
+};
+
+/// A future that stays pending until `ready` is set and stores its waker, so
+/// that the test can wake it like an interrupt handler would.
+#[derive(Clone, Default)]
+struct Signal {
+    state: Arc<Mutex<(bool, Option<Waker>)>>,
+}
+
+impl Signal {
+    fn set(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.0 = true;
+        if let Some(waker) = state.1.take() {
+            waker.wake();
+        }
+    }
+
+    fn waker(&self) -> Option<Waker> {
+        self.state.lock().unwrap().1.clone()
+    }
+}
+
+impl Future for Signal {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.0 {
+            Poll::Ready(())
+        } else {
+            state.1 = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+/// A future that wakes itself and returns `Pending` the given number of times.
+struct YieldTimes(usize);
+
+impl Future for YieldTimes {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.0 == 0 {
+            Poll::Ready(())
+        } else {
+            self.0 -= 1;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+/// A future that logs its name on every poll and wakes itself, forever.
+struct Busy {
+    name: &'static str,
+    log: Rc<RefCell<Vec<&'static str>>>,
+}
+
+impl Future for Busy {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        self.log.borrow_mut().push(self.name);
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+/// Returns the snapshot of the task with the given name.
+fn find(executor: &Executor, name: &str) -> TaskInfo {
+    let snapshot = executor.snapshot();
+    *snapshot.iter().find(|info| info.name == Some(name)).unwrap()
+}
+
+/// Spawns a task that awaits `handle` and returns the slot that it stores the
+/// result in.
+fn join<T: 'static>(
+    executor: &mut Executor,
+    handle: JoinHandle<T>,
+) -> Rc<RefCell<Option<Result<T, JoinError>>>> {
+    let slot = Rc::new(RefCell::new(None));
+    let result = slot.clone();
+    executor.spawn(async move {
+        *result.borrow_mut() = Some(handle.await);
+    });
+    slot
+}
+
+/// Sets the flag when it is dropped.
+struct DropFlag(Rc<RefCell<bool>>);
+
+impl Drop for DropFlag {
+    fn drop(&mut self) {
+        *self.0.borrow_mut() = true;
+    }
+}
+
+#[test]
+fn finished_tasks_are_removed() {
+    let mut executor = Executor::new();
+    executor.spawn(async {});
+    assert_eq!(executor.task_count(), 1);
+    assert!(!executor.is_idle());
+    executor.run_ready_tasks();
+    assert_eq!(executor.task_count(), 0);
+    assert!(executor.is_idle());
+}
+
+#[test]
+fn tasks_run_in_spawn_order() {
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let mut executor = Executor::new();
+    for i in 0..5 {
+        let log = log.clone();
+        executor.spawn(async move { log.borrow_mut().push(i) });
+    }
+    executor.run_ready_tasks();
+    assert_eq!(*log.borrow(), vec![0, 1, 2, 3, 4]);
+}
+
+#[test]
+fn self_waking_task_is_polled_again() {
+    let mut executor = Executor::new();
+    executor.spawn(YieldTimes(10));
+    // Each wake-up pushes the task to the back of the queue, which
+    // `run_ready_tasks` keeps draining until it is empty.
+    executor.run_ready_tasks();
+    assert_eq!(executor.task_count(), 0);
+}
+
+#[test]
+fn pending_task_waits_for_wake_up() {
+    let signal = Signal::default();
+    let mut executor = Executor::new();
+    let future = signal.clone();
+    executor.spawn(future);
+
+    executor.run_ready_tasks();
+    assert_eq!(executor.task_count(), 1);
+    assert!(executor.is_idle());
+
+    signal.set();
+    assert!(!executor.is_idle());
+    executor.run_ready_tasks();
+    assert_eq!(executor.task_count(), 0);
+}
+
+#[test]
+fn wake_up_of_finished_task_is_ignored() {
+    let signal = Signal::default();
+    let mut executor = Executor::new();
+    let future = signal.clone();
+    executor.spawn(future);
+    executor.run_ready_tasks();
+    let waker = signal.waker().unwrap();
+
+    signal.set();
+    executor.run_ready_tasks();
+    assert_eq!(executor.task_count(), 0);
+
+    // The task no longer exists, so the executor must skip its ID.
+    waker.wake();
+    assert!(!executor.is_idle());
+    executor.run_ready_tasks();
+    assert!(executor.is_idle());
+}
+
+#[test]
+fn join_handle_returns_output() {
+    let mut executor = Executor::new();
+    let handle = executor.spawn(async { 42 });
+    let result = join(&mut executor, handle);
+    executor.run_ready_tasks();
+    assert_eq!(*result.borrow(), Some(Ok(42)));
+    assert_eq!(executor.task_count(), 0);
+}
+
+#[test]
+fn join_handle_waits_for_pending_task() {
+    let signal = Signal::default();
+    let mut executor = Executor::new();
+    let future = signal.clone();
+    let handle = executor.spawn(async move {
+        future.await;
+        String::from("done")
+    });
+    let result = join(&mut executor, handle);
+
+    executor.run_ready_tasks();
+    assert_eq!(*result.borrow(), None);
+    signal.set();
+    executor.run_ready_tasks();
+    assert_eq!(*result.borrow(), Some(Ok(String::from("done"))));
+}
+
+#[test]
+fn abort_cancels_task() {
+    let dropped = Rc::new(RefCell::new(false));
+    let guard = DropFlag(dropped.clone());
+    let mut executor = Executor::new();
+    let handle = executor.spawn(async move {
+        let _guard = guard;
+        future::pending::<()>().await;
+    });
+    executor.run_ready_tasks();
+    assert!(!handle.is_finished());
+
+    // The task is only dropped when the executor runs it again.
+    handle.abort();
+    assert!(!*dropped.borrow());
+    let result = join(&mut executor, handle);
+    executor.run_ready_tasks();
+    assert_eq!(*result.borrow(), Some(Err(JoinError::Cancelled)));
+    assert!(*dropped.borrow());
+    assert_eq!(executor.task_count(), 0);
+}
+
+#[test]
+fn abort_before_first_poll() {
+    let polled = Rc::new(RefCell::new(false));
+    let flag = polled.clone();
+    let mut executor = Executor::new();
+    let handle = executor.spawn(async move {
+        *flag.borrow_mut() = true;
+    });
+    handle.abort();
+    let result = join(&mut executor, handle);
+    executor.run_ready_tasks();
+    assert!(!*polled.borrow());
+    assert_eq!(*result.borrow(), Some(Err(JoinError::Cancelled)));
+}
+
+#[test]
+fn abort_after_completion_keeps_output() {
+    let mut executor = Executor::new();
+    let handle = executor.spawn(async { "done" });
+    executor.run_ready_tasks();
+    assert!(handle.is_finished());
+    handle.abort();
+    let result = join(&mut executor, handle);
+    executor.run_ready_tasks();
+    assert_eq!(*result.borrow(), Some(Ok("done")));
+}
+
+#[test]
+fn join_handle_reports_panic() {
+    let mut executor = Executor::new();
+    let handle = executor.spawn(async {
+        panic!("task failed");
+    });
+    let outcome = panic::catch_unwind(AssertUnwindSafe(|| executor.run_ready_tasks()));
+    assert!(outcome.is_err());
+    assert!(handle.is_finished());
+
+    // The executor drops the panicked task without polling its future again.
+    let result = join(&mut executor, handle);
+    executor.run_ready_tasks();
+    assert_eq!(*result.borrow(), Some(Err(JoinError::Panicked)));
+    assert_eq!(executor.task_count(), 0);
+}
+
+#[test]
+fn dropped_executor_cancels_tasks() {
+    let mut executor = Executor::new();
+    let handles: Vec<_> = (0..3).map(|_| executor.spawn(future::pending::<u32>())).collect();
+    executor.run_ready_tasks();
+    drop(executor);
+
+    let mut executor = Executor::new();
+    let results: Vec<_> = handles.into_iter().map(|handle| join(&mut executor, handle)).collect();
+    executor.run_ready_tasks();
+    for result in results {
+        assert_eq!(*result.borrow(), Some(Err(JoinError::Cancelled)));
+    }
+}
+
+#[test]
+fn task_spawns_task() {
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    let outer_log = log.clone();
+    executor.spawn(async move {
+        let inner_log = outer_log.clone();
+        let handle = spawner.spawn(async move {
+            inner_log.borrow_mut().push("inner");
+            1
+        });
+        outer_log.borrow_mut().push("outer");
+        let output = handle.await.unwrap();
+        outer_log.borrow_mut().push(if output == 1 { "joined" } else { "wrong output" });
+    });
+    // A single call runs the spawned task as well.
+    executor.run_ready_tasks();
+    assert_eq!(*log.borrow(), vec!["outer", "inner", "joined"]);
+    assert_eq!(executor.task_count(), 0);
+}
+
+#[test]
+fn spawned_task_wakes_idle_executor() {
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    assert!(executor.is_idle());
+    let handle = spawner.spawn(async { 5 });
+    assert!(!executor.is_idle());
+    assert_eq!(executor.task_count(), 0);
+
+    let result = join(&mut executor, handle);
+    executor.run_ready_tasks();
+    assert_eq!(*result.borrow(), Some(Ok(5)));
+}
+
+#[test]
+fn waker_outlives_executor() {
+    let signal = Signal::default();
+    let mut executor = Executor::new();
+    executor.spawn(signal.clone());
+    executor.run_ready_tasks();
+    let waker = signal.waker().unwrap();
+    drop(executor);
+
+    // The waker still holds the header of the task, but the executor is
+    // gone, so waking does nothing.
+    let clone = waker.clone();
+    clone.wake();
+    waker.wake_by_ref();
+    drop(waker);
+    signal.set();
+}
+
+#[test]
+fn spawn_fails_without_executor() {
+    let executor = Executor::new();
+    let spawner = executor.spawner();
+    drop(executor);
+
+    let dropped = Rc::new(RefCell::new(false));
+    let guard = DropFlag(dropped.clone());
+    let result = spawner.try_spawn(async move {
+        let _guard = guard;
+    });
+    assert_eq!(result.unwrap_err(), SpawnError::NoExecutor);
+    assert!(*dropped.borrow());
+}
+
+#[test]
+fn repeated_wake_ups_poll_task_once() {
+    let polls = Rc::new(RefCell::new(0));
+    let waker = Rc::new(RefCell::new(None));
+    let mut executor = Executor::new();
+    let (task_polls, task_waker) = (polls.clone(), waker.clone());
+    executor.spawn(future::poll_fn(move |cx| {
+        *task_polls.borrow_mut() += 1;
+        *task_waker.borrow_mut() = Some(cx.waker().clone());
+        Poll::<()>::Pending
+    }));
+    executor.run_ready_tasks();
+    assert_eq!(*polls.borrow(), 1);
+
+    // The task is scheduled only once, however often it is woken.
+    let waker = waker.borrow_mut().take().unwrap();
+    for _ in 0..1000 {
+        waker.wake_by_ref();
+    }
+    executor.run_ready_tasks();
+    assert_eq!(*polls.borrow(), 2);
+    assert!(executor.is_idle());
+}
+
+#[test]
+fn thousands_of_self_waking_tasks() {
+    const TASKS: usize = 5000;
+    const YIELDS: usize = 10;
+
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    let mut handles = Vec::new();
+    for i in 0..TASKS {
+        let priority = Priority::ALL[i % Priority::ALL.len()];
+        // Half of the tasks go through the spawn queue.
+        if i % 2 == 0 {
+            handles.push(executor.spawn_with_priority(priority, YieldTimes(YIELDS)));
+        } else {
+            handles.push(spawner.spawn_with_priority(priority, YieldTimes(YIELDS)));
+        }
+    }
+
+    // Every task stays in the run queue the whole time, which a bounded queue
+    // couldn't hold.
+    let mut rounds = 0;
+    while !executor.is_idle() {
+        executor.run_ready_tasks();
+        rounds += 1;
+    }
+    assert_eq!(executor.task_count(), 0);
+    assert!(handles.iter().all(JoinHandle::is_finished));
+    assert_eq!(rounds, (TASKS * (YIELDS + 1)).div_ceil(POLL_BUDGET));
+}
+
+#[test]
+fn higher_priority_runs_first() {
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let mut executor = Executor::new();
+    for &priority in [Priority::Background, Priority::Normal, Priority::BottomHalf].iter() {
+        let log = log.clone();
+        executor.spawn_with_priority(priority, async move { log.borrow_mut().push(priority) });
+    }
+    let spawner = executor.spawner();
+    let spawned_log = log.clone();
+    spawner.spawn_with_priority(Priority::Interactive, async move {
+        spawned_log.borrow_mut().push(Priority::Interactive)
+    });
+    executor.run_ready_tasks();
+    assert_eq!(*log.borrow(), Priority::ALL.to_vec());
+}
+
+#[test]
+fn woken_task_keeps_priority() {
+    let signal = Signal::default();
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let mut executor = Executor::new();
+    let (future, task_log) = (signal.clone(), log.clone());
+    executor.spawn_with_priority(Priority::Interactive, async move {
+        future.await;
+        task_log.borrow_mut().push("interactive");
+    });
+    executor.run_ready_tasks();
+
+    let normal_log = log.clone();
+    executor.spawn(async move { normal_log.borrow_mut().push("normal") });
+    signal.set();
+    executor.run_ready_tasks();
+    assert_eq!(*log.borrow(), vec!["interactive", "normal"]);
+}
+
+#[test]
+fn busy_tasks_share_polls_by_weight() {
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let mut executor = Executor::new();
+    for &(name, priority) in [
+        ("bottom half", Priority::BottomHalf),
+        ("interactive", Priority::Interactive),
+        ("normal", Priority::Normal),
+        ("background", Priority::Background),
+    ]
+    .iter()
+    {
+        executor.spawn_with_priority(priority, Busy { name, log: log.clone() });
+    }
+    // A full round polls every level as often as its weight.
+    executor.run_ready_tasks();
+    let log = log.borrow();
+    assert_eq!(log.len(), POLL_BUDGET);
+    let round: usize = Priority::ALL.iter().map(|priority| priority.weight()).sum();
+    let count = |name| log[..round].iter().filter(|&&entry| entry == name).count();
+    assert_eq!(count("bottom half"), Priority::BottomHalf.weight());
+    assert_eq!(count("interactive"), Priority::Interactive.weight());
+    assert_eq!(count("normal"), Priority::Normal.weight());
+    assert_eq!(count("background"), Priority::Background.weight());
+}
+
+#[test]
+fn busy_task_doesnt_starve_others() {
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let mut executor = Executor::new();
+    executor.spawn_with_priority(Priority::BottomHalf, Busy { name: "busy", log: log.clone() });
+    let background_log = log.clone();
+    executor.spawn_with_priority(Priority::Background, async move {
+        background_log.borrow_mut().push("background");
+    });
+
+    // The busy task never stops, but the budget ends the call.
+    executor.run_ready_tasks();
+    assert!(!executor.is_idle());
+    let log = log.borrow();
+    let position = log.iter().position(|&entry| entry == "background").unwrap();
+    assert_eq!(position, Priority::BottomHalf.weight());
+    assert_eq!(executor.task_count(), 1);
+}
+
+#[test]
+fn snapshot_shows_task_states() {
+    let signal = Signal::default();
+    let mut executor = Executor::new();
+    executor.spawn_named("waiting", Priority::Interactive, signal.clone());
+    executor.spawn_named("yielding", Priority::Normal, YieldTimes(1));
+    executor.spawn(async {});
+
+    let ready = find(&executor, "waiting");
+    assert_eq!(ready.priority, Priority::Interactive);
+    assert_eq!(ready.state, TaskState::Ready);
+    assert_eq!(ready.polls, 0);
+    assert_eq!(ready.last_wake, WakeSource::Spawn);
+    assert_eq!(executor.snapshot().len(), 3);
+
+    executor.run_ready_tasks();
+    // Finished tasks are removed from the snapshot.
+    let snapshot = executor.snapshot();
+    assert_eq!(snapshot.len(), 1);
+    assert_eq!(snapshot[0].name, Some("waiting"));
+    assert_eq!(snapshot[0].state, TaskState::Pending);
+    assert_eq!(snapshot[0].polls, 1);
+
+    signal.set();
+    let woken = find(&executor, "waiting");
+    assert_eq!(woken.state, TaskState::Ready);
+    assert_eq!(woken.last_wake, WakeSource::External);
+}
+
+#[test]
+fn spawn_task_keeps_id_and_name() {
+    let signal = Signal::default();
+    let task = Task::named("named", signal.clone());
+    let id = task.id();
+    let mut executor = Executor::new();
+    let handle = executor.spawn_task(task);
+    let result = join(&mut executor, handle);
+
+    let info = find(&executor, "named");
+    assert_eq!(info.id, id);
+    assert_eq!(info.priority, Priority::Normal);
+
+    executor.run_ready_tasks();
+    signal.set();
+    executor.run_ready_tasks();
+    assert_eq!(*result.borrow(), Some(Ok(())));
+    assert_eq!(executor.task_count(), 0);
+}
+
+#[test]
+fn snapshot_records_waking_task() {
+    let signal = Signal::default();
+    let mut executor = Executor::new();
+    let future = signal.clone();
+    executor.spawn_named("waiting", Priority::Normal, async move {
+        future.await;
+        // Stay in the task list, so that the test can see the wake-up.
+        future::pending::<()>().await;
+    });
+    executor.run_ready_tasks();
+    let waking = executor.spawn_named("waking", Priority::Normal, async move { signal.set() });
+
+    // The waking task is gone after the run, so we take its ID first.
+    let waking_id = find(&executor, "waking").id;
+    executor.run_ready_tasks();
+    assert!(waking.is_finished());
+    let woken = find(&executor, "waiting");
+    assert_eq!(woken.last_wake, WakeSource::Task(waking_id));
+    assert_eq!(woken.polls, 2);
+}
+
+#[test]
+fn monitor_sees_running_task() {
+    let mut executor = Executor::new();
+    let monitor = executor.monitor();
+    let states = Rc::new(RefCell::new(Vec::new()));
+    let task_states = states.clone();
+    executor.spawn_named("self", Priority::Normal, async move {
+        let snapshot = monitor.snapshot().unwrap();
+        task_states.borrow_mut().extend(snapshot.iter().map(|info| info.state));
+    });
+    executor.run_ready_tasks();
+    assert_eq!(*states.borrow(), vec![TaskState::Running]);
+}
+
+#[test]
+fn monitor_fails_without_executor() {
+    let executor = Executor::new();
+    let monitor = executor.monitor();
+    drop(executor);
+    assert_eq!(monitor.try_for_each(|_| {}).unwrap_err(), MonitorError::NoExecutor);
+}
+
+#[test]
+fn busy_time_is_measured_with_clock() {
+    // A fake clock that only the task advances.
+    static CLOCK: AtomicU64 = AtomicU64::new(0);
+    let mut executor = Executor::new();
+    executor.set_clock(|| CLOCK.load(Ordering::Relaxed));
+    executor.spawn_named("busy", Priority::Normal, future::poll_fn(|cx| {
+        CLOCK.fetch_add(1000, Ordering::Relaxed);
+        cx.waker().wake_by_ref();
+        Poll::<()>::Pending
+    }));
+
+    executor.run_ready_tasks();
+    let busy = find(&executor, "busy");
+    assert_eq!(busy.polls, POLL_BUDGET as u64);
+    assert_eq!(busy.busy, Duration::from_nanos(1000 * POLL_BUDGET as u64));
+    assert_eq!(busy.last_wake, WakeSource::Task(busy.id));
+}
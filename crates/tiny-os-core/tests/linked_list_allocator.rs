@@ -0,0 +1,124 @@
+//! # Host tests for the linked list allocator
+//!
+//! Runs long randomized sequences of allocations and deallocations against a
+//! simulated heap region and checks the allocator invariants after each step.
+
+use std::{ alloc::Layout, collections::BTreeMap };
+use tiny_os_core::allocator::linked_list::{ LinkedListAllocator, FitStrategy };
+
+const HEAP_SIZE: usize = 64 * 1024;
+
+/// The simulated heap region.
+#[repr(align(4096))]
+struct Heap([u8; HEAP_SIZE]);
+
+/// A small xorshift generator, so that failing sequences can be reproduced
+/// from their seed.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next() % bound as u64) as usize
+    }
+}
+
+/// A live allocation, filled with a pattern derived from its start address.
+struct Allocation {
+    layout: Layout,
+    pattern: u8,
+}
+
+fn run_sequence(strategy: FitStrategy, seed: u64, steps: usize) {
+    let mut heap = Box::new(Heap([0; HEAP_SIZE]));
+    let heap_start = heap.0.as_mut_ptr() as usize;
+    let mut allocator = LinkedListAllocator::with_strategy(strategy);
+    unsafe { allocator.init(heap_start, HEAP_SIZE) };
+
+    let mut rng = Rng(seed);
+    // Live allocations by start address.
+    let mut live: BTreeMap<usize, Allocation> = BTreeMap::new();
+
+    for step in 0..steps {
+        if live.is_empty() || rng.below(3) != 0 {
+            let size = 1 + rng.below(1024);
+            let align = 1 << rng.below(8);
+            let layout = Layout::from_size_align(size, align).unwrap();
+            let ptr = unsafe { allocator.allocate(layout) };
+            if ptr.is_null() {
+                // The heap is full or too fragmented, which is fine as long as
+                // the invariants hold.
+                continue;
+            }
+            let start = ptr as usize;
+            let end = start + size;
+            assert_eq!(start % align, 0, "seed {} step {}: misaligned", seed, step);
+            assert!(start >= heap_start && end <= heap_start + HEAP_SIZE);
+            // The allocation must not overlap with its neighbours.
+            if let Some((&prev, a)) = live.range(..start).next_back() {
+                assert!(prev + a.layout.size() <= start, "seed {} step {}: overlap", seed, step);
+            }
+            if let Some((&next, _)) = live.range(start..).next() {
+                assert!(end <= next, "seed {} step {}: overlap", seed, step);
+            }
+            let pattern = rng.next() as u8;
+            unsafe { std::ptr::write_bytes(ptr, pattern, size) };
+            live.insert(start, Allocation { layout, pattern });
+        } else {
+            let index = rng.below(live.len());
+            let start = *live.keys().nth(index).unwrap();
+            let allocation = live.remove(&start).unwrap();
+            // The allocator must not have written into live memory.
+            let memory = unsafe {
+                std::slice::from_raw_parts(start as *const u8, allocation.layout.size())
+            };
+            assert!(
+                memory.iter().all(|&b| b == allocation.pattern),
+                "seed {} step {}: allocation was overwritten", seed, step,
+            );
+            unsafe { allocator.deallocate(start as *mut u8, allocation.layout) };
+        }
+        assert_eq!(allocator.allocation_count(), live.len());
+    }
+
+    for (start, allocation) in live {
+        unsafe { allocator.deallocate(start as *mut u8, allocation.layout) };
+    }
+    // All free regions must have been merged into one again.
+    assert_eq!(allocator.allocation_count(), 0);
+    assert_eq!(allocator.bytes_free(), HEAP_SIZE);
+    assert_eq!(allocator.largest_free_region(), HEAP_SIZE);
+}
+
+#[test]
+fn random_sequences_first_fit() {
+    for seed in 1..=20 {
+        run_sequence(FitStrategy::FirstFit, seed, 5_000);
+    }
+}
+
+#[test]
+fn random_sequences_best_fit() {
+    for seed in 1..=20 {
+        run_sequence(FitStrategy::BestFit, seed, 5_000);
+    }
+}
+
+#[test]
+fn exhausted_heap_returns_null() {
+    let mut heap = Box::new(Heap([0; HEAP_SIZE]));
+    let mut allocator = LinkedListAllocator::new();
+    unsafe { allocator.init(heap.0.as_mut_ptr() as usize, HEAP_SIZE) };
+    let layout = Layout::from_size_align(HEAP_SIZE, 8).unwrap();
+    let ptr = unsafe { allocator.allocate(layout) };
+    assert!(!ptr.is_null());
+    assert!(unsafe { allocator.allocate(Layout::from_size_align(8, 8).unwrap()) }.is_null());
+    unsafe { allocator.deallocate(ptr, layout) };
+    assert_eq!(allocator.largest_free_region(), HEAP_SIZE);
+}
@@ -0,0 +1,332 @@
+//! # Host tests for the sync primitives
+//!
+//! Runs tasks that contend for the primitives of `task::sync` on the executor
+//! and checks the order in which they get access.
+
+use std::{
+    cell::RefCell,
+    future::Future,
+    pin::Pin,
+    rc::Rc,
+    task::{ Context, Poll },
+};
+use tiny_os_core::task::{
+    executor::Executor,
+    sync::{ Barrier, Mutex, Notify, RwLock, Semaphore },
+};
+
+/// Returns `Pending` once, so that the executor runs the other tasks.
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.0 {
+            Poll::Ready(())
+        } else {
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+fn yield_now() -> YieldNow {
+    YieldNow(false)
+}
+
+type Log = Rc<RefCell<Vec<&'static str>>>;
+
+fn run(executor: &mut Executor) {
+    while !executor.is_idle() {
+        executor.run_ready_tasks();
+    }
+}
+
+#[test]
+fn mutex_is_held_across_await() {
+    let mutex = Rc::new(Mutex::new(0));
+    let log: Log = Rc::default();
+    let mut executor = Executor::new();
+    for &name in ["first", "second"].iter() {
+        let (mutex, log) = (mutex.clone(), log.clone());
+        executor.spawn(async move {
+            let mut guard = mutex.lock().await;
+            log.borrow_mut().push(name);
+            // The other task runs while we hold the lock, but it has to wait.
+            yield_now().await;
+            *guard += 1;
+            log.borrow_mut().push(name);
+        });
+    }
+    run(&mut executor);
+    assert_eq!(*log.borrow(), vec!["first", "first", "second", "second"]);
+    assert_eq!(*mutex.try_lock().unwrap(), 2);
+    assert_eq!(executor.task_count(), 0);
+}
+
+#[test]
+fn mutex_try_lock_fails_while_locked() {
+    let mut mutex = Mutex::new(vec![1]);
+    {
+        let guard = mutex.try_lock().unwrap();
+        assert!(mutex.try_lock().is_none());
+        drop(guard);
+    }
+    mutex.get_mut().push(2);
+    assert_eq!(mutex.into_inner(), vec![1, 2]);
+}
+
+#[test]
+fn cancelled_lock_leaves_the_queue() {
+    let mutex = Rc::new(Mutex::new(()));
+    let log: Log = Rc::default();
+    let mut executor = Executor::new();
+    let guard = mutex.try_lock().unwrap();
+
+    let (task_mutex, task_log) = (mutex.clone(), log.clone());
+    let aborted = executor.spawn(async move {
+        let _guard = task_mutex.lock().await;
+        task_log.borrow_mut().push("aborted");
+    });
+    let (task_mutex, task_log) = (mutex.clone(), log.clone());
+    executor.spawn(async move {
+        let _guard = task_mutex.lock().await;
+        task_log.borrow_mut().push("waiting");
+    });
+    run(&mut executor);
+    assert_eq!(executor.task_count(), 2);
+
+    // The aborted task is first in the queue, so the lock must skip it.
+    aborted.abort();
+    run(&mut executor);
+    drop(guard);
+    run(&mut executor);
+    assert_eq!(*log.borrow(), vec!["waiting"]);
+    assert_eq!(executor.task_count(), 0);
+}
+
+#[test]
+fn rwlock_readers_share_and_writer_waits() {
+    let lock = Rc::new(RwLock::new(0));
+    let log: Log = Rc::default();
+    let mut executor = Executor::new();
+    for &name in ["reader 1", "reader 2"].iter() {
+        let (lock, log) = (lock.clone(), log.clone());
+        executor.spawn(async move {
+            let _guard = lock.read().await;
+            log.borrow_mut().push(name);
+            yield_now().await;
+            log.borrow_mut().push(name);
+        });
+    }
+    let (task_lock, task_log) = (lock.clone(), log.clone());
+    executor.spawn(async move {
+        *task_lock.write().await += 1;
+        task_log.borrow_mut().push("writer");
+    });
+    // This reader comes after the writer, so it must not overtake it.
+    let (task_lock, task_log) = (lock.clone(), log.clone());
+    executor.spawn(async move {
+        assert_eq!(*task_lock.read().await, 1);
+        task_log.borrow_mut().push("reader 3");
+    });
+
+    run(&mut executor);
+    assert_eq!(
+        *log.borrow(),
+        vec!["reader 1", "reader 2", "reader 1", "reader 2", "writer", "reader 3"]
+    );
+    assert_eq!(executor.task_count(), 0);
+}
+
+#[test]
+fn rwlock_try_variants() {
+    let lock = RwLock::new(5);
+    let read = lock.try_read().unwrap();
+    assert!(lock.try_read().is_some());
+    assert!(lock.try_write().is_none());
+    drop(read);
+    let write = lock.try_write().unwrap();
+    assert!(lock.try_read().is_none());
+    drop(write);
+    assert_eq!(lock.into_inner(), 5);
+}
+
+#[test]
+fn semaphore_limits_concurrency() {
+    let semaphore = Rc::new(Semaphore::new(2));
+    let running = Rc::new(RefCell::new((0, 0)));
+    let mut executor = Executor::new();
+    for _ in 0..5 {
+        let (semaphore, running) = (semaphore.clone(), running.clone());
+        executor.spawn(async move {
+            let _permit = semaphore.acquire().await;
+            {
+                let mut running = running.borrow_mut();
+                running.0 += 1;
+                running.1 = running.1.max(running.0);
+            }
+            yield_now().await;
+            running.borrow_mut().0 -= 1;
+        });
+    }
+    run(&mut executor);
+    assert_eq!(*running.borrow(), (0, 2));
+    assert_eq!(semaphore.available_permits(), 2);
+}
+
+#[test]
+fn semaphore_serves_waiters_in_order() {
+    let semaphore = Rc::new(Semaphore::new(1));
+    let log: Log = Rc::default();
+    let mut executor = Executor::new();
+    let permit = semaphore.try_acquire().unwrap();
+
+    let (task_semaphore, task_log) = (semaphore.clone(), log.clone());
+    executor.spawn(async move {
+        let _permits = task_semaphore.acquire_many(3).await;
+        task_log.borrow_mut().push("three");
+    });
+    let (task_semaphore, task_log) = (semaphore.clone(), log.clone());
+    executor.spawn(async move {
+        let _permit = task_semaphore.acquire().await;
+        task_log.borrow_mut().push("one");
+    });
+    run(&mut executor);
+
+    // Two permits are available, but the first waiter needs three, and the
+    // second one must not overtake it.
+    semaphore.add_permits(2);
+    assert!(semaphore.try_acquire().is_none());
+    run(&mut executor);
+    assert!(log.borrow().is_empty());
+
+    drop(permit);
+    run(&mut executor);
+    assert_eq!(*log.borrow(), vec!["three", "one"]);
+    assert_eq!(semaphore.available_permits(), 3);
+}
+
+#[test]
+fn notify_one_stores_permit() {
+    let notify = Rc::new(Notify::new());
+    let log: Log = Rc::default();
+    let mut executor = Executor::new();
+    notify.notify_one();
+    notify.notify_one();
+
+    for &name in ["first", "second"].iter() {
+        let (notify, log) = (notify.clone(), log.clone());
+        executor.spawn(async move {
+            notify.notified().await;
+            log.borrow_mut().push(name);
+        });
+    }
+    // Only one permit was stored.
+    run(&mut executor);
+    assert_eq!(*log.borrow(), vec!["first"]);
+
+    notify.notify_one();
+    run(&mut executor);
+    assert_eq!(*log.borrow(), vec!["first", "second"]);
+}
+
+#[test]
+fn notify_waiters_wakes_all() {
+    let notify = Rc::new(Notify::new());
+    let log: Log = Rc::default();
+    let mut executor = Executor::new();
+    for &name in ["first", "second"].iter() {
+        let (notify, log) = (notify.clone(), log.clone());
+        executor.spawn(async move {
+            notify.notified().await;
+            log.borrow_mut().push(name);
+        });
+    }
+    run(&mut executor);
+    notify.notify_waiters();
+    run(&mut executor);
+    assert_eq!(*log.borrow(), vec!["first", "second"]);
+
+    // `notify_waiters` doesn't store a permit.
+    let task_notify = notify.clone();
+    executor.spawn(async move { task_notify.notified().await });
+    run(&mut executor);
+    assert_eq!(executor.task_count(), 1);
+}
+
+#[test]
+fn dropped_notified_passes_notification_on() {
+    let notify = Rc::new(Notify::new());
+    let log: Log = Rc::default();
+    let mut executor = Executor::new();
+    let (task_notify, task_log) = (notify.clone(), log.clone());
+    let aborted = executor.spawn(async move {
+        task_notify.notified().await;
+        task_log.borrow_mut().push("aborted");
+    });
+    let (task_notify, task_log) = (notify.clone(), log.clone());
+    executor.spawn(async move {
+        task_notify.notified().await;
+        task_log.borrow_mut().push("waiting");
+    });
+    run(&mut executor);
+
+    // The notification goes to the first task, which is aborted before it
+    // sees it.
+    notify.notify_one();
+    aborted.abort();
+    run(&mut executor);
+    assert_eq!(*log.borrow(), vec!["waiting"]);
+    assert_eq!(executor.task_count(), 0);
+}
+
+#[test]
+fn barrier_releases_group_with_one_leader() {
+    let barrier = Rc::new(Barrier::new(3));
+    let leaders = Rc::new(RefCell::new(0));
+    let log: Log = Rc::default();
+    let mut executor = Executor::new();
+    // Two groups of three tasks use the same barrier.
+    for _ in 0..6 {
+        let (barrier, leaders, log) = (barrier.clone(), leaders.clone(), log.clone());
+        executor.spawn(async move {
+            log.borrow_mut().push("arrived");
+            if barrier.wait().await.is_leader() {
+                *leaders.borrow_mut() += 1;
+            }
+            log.borrow_mut().push("released");
+        });
+    }
+    run(&mut executor);
+    assert_eq!(*leaders.borrow(), 2);
+    let log = log.borrow();
+    // Nobody of the first group is released before the group is complete.
+    assert_eq!(log[..3], ["arrived"; 3]);
+    assert_eq!(log.iter().filter(|&&entry| entry == "released").count(), 6);
+    assert_eq!(executor.task_count(), 0);
+}
+
+#[test]
+fn barrier_forgets_cancelled_waiter() {
+    let barrier = Rc::new(Barrier::new(2));
+    let mut executor = Executor::new();
+    let task_barrier = barrier.clone();
+    let aborted = executor.spawn(async move { task_barrier.wait().await });
+    run(&mut executor);
+    aborted.abort();
+    run(&mut executor);
+
+    // The aborted task left the group, so one task alone can't pass.
+    let task_barrier = barrier.clone();
+    executor.spawn(async move { task_barrier.wait().await });
+    run(&mut executor);
+    assert_eq!(executor.task_count(), 1);
+
+    let task_barrier = barrier.clone();
+    executor.spawn(async move { task_barrier.wait().await });
+    run(&mut executor);
+    assert_eq!(executor.task_count(), 0);
+}
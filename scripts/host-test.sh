@@ -0,0 +1,11 @@
+#!/bin/sh
+#
+# Runs the unit and integration tests of the `tiny-os-core` crate on the host.
+#
+# See `crates/tiny-os-core/src/lib.rs` for why we need a stable toolchain and an
+# explicit host target.
+
+set -e
+
+cd "$(dirname "$0")/../crates/tiny-os-core"
+cargo +stable test --target x86_64-unknown-linux-gnu "$@"
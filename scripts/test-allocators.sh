@@ -0,0 +1,11 @@
+#!/bin/sh
+#
+# Runs the `heap_allocation` integration test once for every global allocator
+# that can be selected through the `alloc-*` cargo features.
+
+set -e
+
+for allocator in bump linked-list fixed-size-block slab external; do
+    echo "Testing the global allocator with feature alloc-$allocator"
+    cargo test --test heap_allocation --no-default-features --features "alloc-$allocator"
+done
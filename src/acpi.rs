@@ -0,0 +1,421 @@
+//! # ACPI module
+//!
+//! Finds the ACPI tables that the firmware left in memory and parses the
+//! Multiple APIC Description Table (MADT), which tells us which interrupt
+//! controllers the machine has and how the legacy ISA interrupts are wired to
+//! them, and the HPET table, which tells us where the High Precision Event
+//! Timer is.
+//!
+//! We only read the static tables. Interpreting AML (the bytecode in the DSDT)
+//! would require a full interpreter, which we don't need for interrupt routing.
+
+use alloc::vec::Vec;
+use core::{ convert::TryInto, slice };
+use x86_64::{ PhysAddr, VirtAddr };
+
+/// The signature of the Root System Description Pointer.
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+/// The signature of the MADT.
+const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+/// The signature of the HPET description table.
+const HPET_SIGNATURE: &[u8; 4] = b"HPET";
+/// The size of the header that all system description tables start with.
+const SDT_HEADER_SIZE: usize = 36;
+
+/// Where the polarity of an interrupt line is active.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+/// Whether an interrupt line signals by an edge or by a level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+/// A processor and its local APIC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalApicEntry {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    /// Whether the processor can be used.
+    pub enabled: bool,
+}
+
+/// An I/O APIC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApicEntry {
+    pub id: u8,
+    /// The physical address of its registers.
+    pub address: u32,
+    /// The first global system interrupt (GSI) that it handles.
+    pub gsi_base: u32,
+}
+
+/// An ISA interrupt that is not connected to the I/O APIC input with the same
+/// number, or that doesn't use the ISA defaults (active high, edge triggered).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    /// The ISA IRQ number.
+    pub source: u8,
+    /// The global system interrupt that it is connected to.
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+/// The parsed content of the MADT.
+#[derive(Debug, Clone)]
+pub struct Madt {
+    /// The physical address of the local APIC registers.
+    pub local_apic_address: u64,
+    /// Whether the machine also has the legacy 8259 PICs, which must be
+    /// masked when the APIC is used.
+    pub has_8259: bool,
+    pub local_apics: Vec<LocalApicEntry>,
+    pub io_apics: Vec<IoApicEntry>,
+    pub overrides: Vec<InterruptOverride>,
+}
+
+impl Madt {
+    /// Parses a complete MADT, including its header.
+    ///
+    /// Returns `None` if the signature or checksum is wrong or the table is
+    /// truncated.
+    pub fn parse(table: &[u8]) -> Option<Madt> {
+        if table.len() < SDT_HEADER_SIZE + 8 || &table[..4] != MADT_SIGNATURE {
+            return None;
+        }
+        let length = read_u32(table, 4)? as usize;
+        let table = table.get(..length)?;
+        if checksum(table) != 0 {
+            return None;
+        }
+
+        let mut madt = Madt {
+            local_apic_address: u64::from(read_u32(table, SDT_HEADER_SIZE)?),
+            has_8259: read_u32(table, SDT_HEADER_SIZE + 4)? & 1 != 0,
+            local_apics: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+        };
+
+        // The header is followed by a list of variable-sized entries, each
+        // starting with its type and length.
+        let mut offset = SDT_HEADER_SIZE + 8;
+        while offset + 2 <= table.len() {
+            let entry_type = table[offset];
+            let entry_len = usize::from(table[offset + 1]);
+            let entry = table.get(offset..offset + entry_len)?;
+            if entry_len < 2 {
+                return None;
+            }
+            match entry_type {
+                0 => madt.local_apics.push(LocalApicEntry {
+                    processor_id: *entry.get(2)?,
+                    apic_id: *entry.get(3)?,
+                    enabled: read_u32(entry, 4)? & 1 != 0,
+                }),
+                1 => madt.io_apics.push(IoApicEntry {
+                    id: *entry.get(2)?,
+                    address: read_u32(entry, 4)?,
+                    gsi_base: read_u32(entry, 8)?,
+                }),
+                2 => {
+                    let flags = read_u16(entry, 8)?;
+                    madt.overrides.push(InterruptOverride {
+                        source: *entry.get(3)?,
+                        gsi: read_u32(entry, 4)?,
+                        // A value of 0 means "conforms to the bus", which is
+                        // active high and edge triggered for ISA.
+                        polarity: if flags & 0b11 == 0b11 {
+                            Polarity::ActiveLow
+                        } else {
+                            Polarity::ActiveHigh
+                        },
+                        trigger: if (flags >> 2) & 0b11 == 0b11 {
+                            TriggerMode::Level
+                        } else {
+                            TriggerMode::Edge
+                        },
+                    });
+                }
+                // A 64-bit address that replaces the 32-bit one above.
+                5 => madt.local_apic_address = read_u64(entry, 4)?,
+                // Other entries (e.g. NMI sources or x2APIC entries) are
+                // not used yet.
+                _ => {}
+            }
+            offset += entry_len;
+        }
+        Some(madt)
+    }
+
+    /// Returns the global system interrupt and the signal type of the given
+    /// ISA IRQ, taking the interrupt source overrides into account.
+    pub fn isa_irq(&self, irq: u8) -> (u32, Polarity, TriggerMode) {
+        self.overrides
+            .iter()
+            .find(|o| o.source == irq)
+            .map(|o| (o.gsi, o.polarity, o.trigger))
+            .unwrap_or((u32::from(irq), Polarity::ActiveHigh, TriggerMode::Edge))
+    }
+}
+
+/// The parsed content of the HPET description table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HpetTable {
+    /// The physical address of the HPET registers.
+    pub address: u64,
+    /// The sequence number of the timer block, if there are several.
+    pub number: u8,
+    /// The smallest period (in counter ticks) that the timer supports in
+    /// periodic mode without losing interrupts.
+    pub minimum_tick: u16,
+}
+
+impl HpetTable {
+    /// Parses a complete HPET table, including its header.
+    ///
+    /// Returns `None` if the signature or checksum is wrong, the table is
+    /// truncated, or the registers are not in memory space.
+    pub fn parse(table: &[u8]) -> Option<HpetTable> {
+        if table.len() < SDT_HEADER_SIZE + 20 || &table[..4] != HPET_SIGNATURE {
+            return None;
+        }
+        let length = read_u32(table, 4)? as usize;
+        let table = table.get(..length)?;
+        if checksum(table) != 0 {
+            return None;
+        }
+        // The address is a "Generic Address Structure": an address space ID
+        // (0 is system memory), the register width and offset, the access
+        // size, and the 64-bit address.
+        let address_space = *table.get(SDT_HEADER_SIZE + 4)?;
+        if address_space != 0 {
+            return None;
+        }
+        Some(HpetTable {
+            address: read_u64(table, SDT_HEADER_SIZE + 8)?,
+            number: *table.get(SDT_HEADER_SIZE + 16)?,
+            minimum_tick: read_u16(table, SDT_HEADER_SIZE + 17)?,
+        })
+    }
+}
+
+/// Searches the firmware memory for the MADT and parses it.
+///
+/// Returns `None` if there are no ACPI tables or no valid MADT.
+///
+/// This function is unsafe because the caller must guarantee that the
+/// complete physical memory is mapped at `physical_memory_offset`.
+pub unsafe fn find_madt(physical_memory_offset: VirtAddr) -> Option<Madt> {
+    let table = find_table(physical_memory_offset, MADT_SIGNATURE)?;
+    Madt::parse(table)
+}
+
+/// Searches the firmware memory for the HPET table and parses it.
+///
+/// Returns `None` if there are no ACPI tables or the machine has no HPET.
+///
+/// This function is unsafe because the caller must guarantee that the
+/// complete physical memory is mapped at `physical_memory_offset`.
+pub unsafe fn find_hpet(physical_memory_offset: VirtAddr) -> Option<HpetTable> {
+    let table = find_table(physical_memory_offset, HPET_SIGNATURE)?;
+    HpetTable::parse(table)
+}
+
+/// Returns the system description table with the given signature.
+unsafe fn find_table(
+    physical_memory_offset: VirtAddr,
+    signature: &[u8; 4],
+) -> Option<&'static [u8]> {
+    let rsdp = find_rsdp(physical_memory_offset)?;
+    // ACPI 2.0 added the XSDT, which holds 64-bit table addresses. Older
+    // firmware only provides the RSDT with 32-bit addresses.
+    let revision = rsdp[15];
+    let (root, entry_size) = if revision >= 2 {
+        (read_u64(rsdp, 24)?, 8)
+    } else {
+        (u64::from(read_u32(rsdp, 16)?), 4)
+    };
+    let root = table_at(physical_memory_offset, PhysAddr::new(root))?;
+
+    root[SDT_HEADER_SIZE..]
+        .chunks_exact(entry_size)
+        .map(|entry| match entry_size {
+            8 => u64::from_le_bytes(entry.try_into().unwrap()),
+            _ => u64::from(u32::from_le_bytes(entry.try_into().unwrap())),
+        })
+        .filter_map(|addr| table_at(physical_memory_offset, PhysAddr::new(addr)))
+        .find(|table| &table[..4] == signature)
+}
+
+/// Returns the system description table at the given physical address, or
+/// `None` if its checksum is wrong.
+unsafe fn table_at(physical_memory_offset: VirtAddr, addr: PhysAddr) -> Option<&'static [u8]> {
+    let ptr: *const u8 = (physical_memory_offset + addr.as_u64()).as_ptr();
+    let header = slice::from_raw_parts(ptr, SDT_HEADER_SIZE);
+    let length = read_u32(header, 4)? as usize;
+    if length < SDT_HEADER_SIZE {
+        return None;
+    }
+    let table = slice::from_raw_parts(ptr, length);
+    if checksum(table) == 0 {
+        Some(table)
+    } else {
+        None
+    }
+}
+
+/// Searches the areas where a BIOS places the Root System Description Pointer:
+/// the first KiB of the Extended BIOS Data Area (EBDA) and the read-only BIOS
+/// area between `0xe0000` and `0xfffff`.
+unsafe fn find_rsdp(physical_memory_offset: VirtAddr) -> Option<&'static [u8]> {
+    let phys = |addr: u64| (physical_memory_offset + addr).as_ptr::<u8>();
+    // The BIOS data area stores the segment of the EBDA at `0x40e`.
+    let ebda = u64::from(*(phys(0x40e) as *const u16)) << 4;
+    let areas = [(ebda, ebda + 1024), (0xe0000, 0x100000)];
+
+    for &(start, end) in areas.iter().filter(|&&(start, _)| start != 0) {
+        // The RSDP is always aligned to 16 bytes.
+        for addr in (start..end).step_by(16) {
+            let candidate = slice::from_raw_parts(phys(addr), 20);
+            if &candidate[..8] == RSDP_SIGNATURE && checksum(candidate) == 0 {
+                // Since revision 2, the structure is 36 bytes long and has an
+                // additional checksum over all of them.
+                if candidate[15] >= 2 {
+                    let extended = slice::from_raw_parts(phys(addr), 36);
+                    if checksum(extended) != 0 {
+                        continue;
+                    }
+                    return Some(extended);
+                }
+                return Some(candidate);
+            }
+        }
+    }
+    None
+}
+
+/// All bytes of a valid ACPI structure add up to zero.
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(bytes.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.get(offset..offset + 8)?.try_into().ok()?))
+}
+
+/// Builds a MADT with the given entries and a valid checksum.
+#[cfg(test)]
+fn build_madt(entries: &[&[u8]]) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&0xfee0_0000u32.to_le_bytes());
+    body.extend_from_slice(&1u32.to_le_bytes());
+    for entry in entries {
+        body.extend_from_slice(entry);
+    }
+    build_table(MADT_SIGNATURE, &body)
+}
+
+/// Builds a table with the given signature and body and a valid checksum.
+#[cfg(test)]
+fn build_table(signature: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    let mut table = Vec::new();
+    table.extend_from_slice(signature);
+    table.extend_from_slice(&[0; SDT_HEADER_SIZE - 4]);
+    table.extend_from_slice(body);
+    let length = table.len() as u32;
+    table[4..8].copy_from_slice(&length.to_le_bytes());
+    table[9] = 0u8.wrapping_sub(checksum(&table));
+    table
+}
+
+#[test_case]
+fn parse_madt() {
+    let table = build_madt(&[
+        // a local APIC with ID 0
+        &[0, 8, 0, 0, 1, 0, 0, 0],
+        // an I/O APIC at 0xfec00000 for GSIs 0 to 23
+        &[1, 12, 1, 0, 0x00, 0x00, 0xc0, 0xfe, 0, 0, 0, 0],
+        // ISA IRQ 0 is connected to GSI 2
+        &[2, 10, 0, 0, 2, 0, 0, 0, 0, 0],
+        // ISA IRQ 9 is active low and level triggered
+        &[2, 10, 0, 9, 9, 0, 0, 0, 0x0f, 0],
+    ]);
+    let madt = Madt::parse(&table).unwrap();
+    assert_eq!(madt.local_apic_address, 0xfee0_0000);
+    assert!(madt.has_8259);
+    assert_eq!(madt.local_apics, [LocalApicEntry { processor_id: 0, apic_id: 0, enabled: true }]);
+    assert_eq!(madt.io_apics, [IoApicEntry { id: 1, address: 0xfec0_0000, gsi_base: 0 }]);
+    assert_eq!(madt.isa_irq(0), (2, Polarity::ActiveHigh, TriggerMode::Edge));
+    assert_eq!(madt.isa_irq(1), (1, Polarity::ActiveHigh, TriggerMode::Edge));
+    assert_eq!(madt.isa_irq(9), (9, Polarity::ActiveLow, TriggerMode::Level));
+}
+
+#[test_case]
+fn reject_invalid_madt() {
+    let mut table = build_madt(&[&[0, 8, 0, 0, 1, 0, 0, 0]]);
+    table[SDT_HEADER_SIZE] ^= 1;
+    assert!(Madt::parse(&table).is_none());
+    // an entry that reaches past the end of the table
+    let table = build_madt(&[&[1, 12, 1, 0]]);
+    assert!(Madt::parse(&table).is_none());
+}
+
+#[test_case]
+fn parse_hpet() {
+    let mut body = Vec::new();
+    // event timer block ID
+    body.extend_from_slice(&0x8086_a201u32.to_le_bytes());
+    // system memory, 64 bits wide, at 0xfed00000
+    body.extend_from_slice(&[0, 64, 0, 0]);
+    body.extend_from_slice(&0xfed0_0000u64.to_le_bytes());
+    // HPET number 0, minimum tick 128, no page protection
+    body.extend_from_slice(&[0, 128, 0, 0]);
+    let table = build_table(HPET_SIGNATURE, &body);
+    assert_eq!(
+        HpetTable::parse(&table),
+        Some(HpetTable { address: 0xfed0_0000, number: 0, minimum_tick: 128 })
+    );
+
+    // registers in I/O space are not supported
+    body[4] = 1;
+    assert!(HpetTable::parse(&build_table(HPET_SIGNATURE, &body)).is_none());
+    // a MADT is not an HPET table
+    assert!(HpetTable::parse(&build_table(MADT_SIGNATURE, &body)).is_none());
+}
+
+// ********** Sidenote **********
+//
+// # ACPI tables
+//
+// The Advanced Configuration and Power Interface (ACPI) is the standard way for
+// the firmware to describe the hardware to the operating system. The firmware
+// places a small structure, the Root System Description Pointer (RSDP),
+// somewhere in the BIOS memory area. It points to a root table (RSDT or XSDT)
+// that lists the physical addresses of all other tables, for example the FADT
+// (power management), the HPET table, or the MADT.
+//
+// Every table starts with a common 36-byte header that contains a four letter
+// signature, the length of the table, and a checksum byte that is chosen so
+// that all bytes of the table add up to zero. We check the checksum of every
+// table before using it, since a wrong table would make us program the
+// interrupt controllers with garbage.
+//
+// Before ACPI, the same information was provided by the "MultiProcessor
+// Specification" tables. All machines that we care about (including QEMU)
+// provide ACPI tables, so we don't parse the MP tables and simply fall back to
+// the 8259 PIC if no MADT is found.
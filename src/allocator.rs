@@ -5,38 +5,137 @@
 //! It provides a simple dummy allocator.
 //! 
 //! It implements the basic allocation interface of Rust and creates a heap
-//! memory region.
+//! memory region. The heap starts with `HEAP_SIZE` bytes and grows on demand
+//! up to a configurable limit.
+//!
+//! The global allocator is selected with exactly one of the following cargo
+//! features:
+//!
+//! - `alloc-bump`: `bump::BumpAllocator`
+//! - `alloc-linked-list`: `linked_list::LinkedListAllocator`
+//! - `alloc-fixed-size-block` (default): `fixed_size_block::FixedSizeBlockAllocator`
+//! - `alloc-slab`: `slab::SlabAllocator`
+//! - `alloc-external`: the `Heap` of the `linked_list_allocator` crate
+//!
+//! Only the block-based allocators and the external one grow the heap, the
+//! bump and linked list allocators are limited to `HEAP_SIZE`.
+//!
+//! When the heap is exhausted, the reclaim callbacks of the `oom` module run
+//! before an allocation fails. Use `try_alloc`, `try_box`, or
+//! `try_vec_with_capacity` where a failed allocation can be handled.
 
 use alloc::alloc::{ GlobalAlloc, Layout };
-use core::ptr::null_mut;
+use core::{
+    mem::ManuallyDrop,
+    ops::{ Deref, DerefMut },
+    ptr::null_mut,
+    sync::atomic::{ AtomicUsize, Ordering },
+};
 use x86_64::{
+    instructions::interrupts,
     structures::paging::{
         Mapper, Size4KiB, FrameAllocator, Page, PageTableFlags,
         mapper::MapToError,
     },
     VirtAddr,
 };
-// use bump::BumpAllocator;
-//use linked_list::LinkedListAllocator;
-use fixed_size_block::FixedSizeBlockAllocator;
+use crate::memory;
+use tiny_os_core::allocator::align_up;
+use stats::{ HeapStats, HeapReport };
 
 pub mod bump;
+pub mod debug;
+pub mod external;
 pub mod linked_list;
 pub mod fixed_size_block;
+pub mod oom;
+pub mod slab;
+pub mod stats;
+
+pub use oom::{ AllocError, try_alloc, try_box, try_vec_with_capacity };
 
 // We can choose any virtual address range that we like, as long as it is not
 // already used for a different memory region.
 pub const HEAP_START: usize = 0x_4444_4444_0000;
-// If we need more space in the future, we can simply increase it.
+// The initial size of the heap. When it is exhausted, the global allocator maps
+// additional pages through `grow_heap`, so this doesn't need to be tuned for
+// large workloads.
 pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+// The default upper bound for the heap size, including the initial `HEAP_SIZE`.
+// It can be changed at runtime through `set_heap_limit`.
+pub const HEAP_MAX_SIZE: usize = 16 * 1024 * 1024; // 16 MiB
+// The heap grows by at least this many bytes at once, so that a series of small
+// allocations doesn't need to map a new page each time.
+const HEAP_GROWTH_STEP: usize = 64 * 1024; // 64 KiB
+
+/// The current upper bound for the heap size.
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_MAX_SIZE);
+
+// The `alloc-*` features are mutually exclusive. Cargo has no way to express
+// this, so we check it here. Note that `alloc-fixed-size-block` is a default
+// feature, so the other allocators need `--no-default-features`.
+#[cfg(not(any(
+    feature = "alloc-bump",
+    feature = "alloc-linked-list",
+    feature = "alloc-fixed-size-block",
+    feature = "alloc-slab",
+    feature = "alloc-external",
+)))]
+compile_error!("no global allocator selected, enable one of the `alloc-*` features");
+
+#[cfg(any(
+    all(feature = "alloc-bump", feature = "alloc-linked-list"),
+    all(feature = "alloc-bump", feature = "alloc-fixed-size-block"),
+    all(feature = "alloc-bump", feature = "alloc-slab"),
+    all(feature = "alloc-bump", feature = "alloc-external"),
+    all(feature = "alloc-linked-list", feature = "alloc-fixed-size-block"),
+    all(feature = "alloc-linked-list", feature = "alloc-slab"),
+    all(feature = "alloc-linked-list", feature = "alloc-external"),
+    all(feature = "alloc-fixed-size-block", feature = "alloc-slab"),
+    all(feature = "alloc-fixed-size-block", feature = "alloc-external"),
+    all(feature = "alloc-slab", feature = "alloc-external"),
+))]
+compile_error!(
+    "the `alloc-*` features are mutually exclusive, \
+     use `--no-default-features` to select an allocator other than the default"
+);
+
+/// The allocator type selected by the `alloc-*` features.
+#[cfg(feature = "alloc-bump")]
+pub type SelectedAllocator = bump::BumpAllocator;
+#[cfg(feature = "alloc-linked-list")]
+pub type SelectedAllocator = linked_list::LinkedListAllocator;
+#[cfg(feature = "alloc-fixed-size-block")]
+pub type SelectedAllocator = fixed_size_block::FixedSizeBlockAllocator;
+#[cfg(feature = "alloc-slab")]
+pub type SelectedAllocator = slab::SlabAllocator;
+#[cfg(feature = "alloc-external")]
+pub type SelectedAllocator = external::ExternalAllocator;
+
+/// The type of the global allocator.
+pub type GlobalAllocator = Locked<SelectedAllocator>;
+
+// The allocator instance. All allocations reach it through `GLOBAL_ALLOCATOR`
+// below, while `init_heap` and `heap_stats` access it directly.
+static ALLOCATOR: GlobalAllocator = Locked::new(SelectedAllocator::new());
+
+// With the `debug-alloc` feature, `DEBUG_ALLOCATOR` wraps `ALLOCATOR`, which is
+// still initialized and queried directly.
+#[cfg(feature = "debug-alloc")]
+static DEBUG_ALLOCATOR: debug::DebugAllocator<GlobalAllocator> =
+    debug::DebugAllocator::new(&ALLOCATOR);
 
 // The attribute tells the Rust compiler which allocator instance it should use
-// as the global heap allocator.
+// as the global heap allocator. The `Reclaiming` wrapper runs the reclaim
+// callbacks of the `oom` module when the wrapped allocator is exhausted.
+#[cfg(not(feature = "debug-alloc"))]
+#[global_allocator]
+static GLOBAL_ALLOCATOR: oom::Reclaiming<GlobalAllocator> = oom::Reclaiming::new(&ALLOCATOR);
+
+#[cfg(feature = "debug-alloc")]
 #[global_allocator]
-static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
-//static ALLOCATOR: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator::new());
-// static ALLOCATOR: Locked<BumpAllocator> = Locked::new(BumpAllocator::new());
-// static ALLOCATOR: LockedHeap = LockedHeap::empty(); // create a static allocator
+static GLOBAL_ALLOCATOR: oom::Reclaiming<debug::DebugAllocator<GlobalAllocator>> =
+    oom::Reclaiming::new(&DEBUG_ALLOCATOR);
 
 /// Creates a heap memory region from which the allocator can allocate memory.
 ///
@@ -85,13 +184,7 @@ pub fn init_heap(
     //   method returns a MapperFlush instance that we can use to update the
     //   translation lookaside buffer using the flush method.
     for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe {
-            mapper.map_to(page, frame, flags, frame_allocator)?.flush()
-        }
+        map_heap_page(page, mapper, frame_allocator)?;
     }
 
     // Initialize the allocator after creating the heap.
@@ -108,9 +201,138 @@ pub fn init_heap(
         // memory.
     }
 
+    register_builtin_reclaimers();
+
+    Ok(())
+}
+
+/// Registers the reclaim callbacks of the selected allocator.
+fn register_builtin_reclaimers() {
+    // Memory in the quarantine is already freed by its owner.
+    #[cfg(feature = "debug-alloc")]
+    oom::register_reclaimer("debug-alloc quarantine", |_| DEBUG_ALLOCATOR.flush_quarantine())
+        .expect("failed to register reclaimer");
+
+    // The callbacks run outside of the allocator lock, but an interrupted
+    // allocation might still hold it, so we must not block.
+    #[cfg(feature = "alloc-fixed-size-block")]
+    oom::register_reclaimer("fixed-size block free lists", |_| {
+        ALLOCATOR.try_lock().map_or(0, |mut allocator| allocator.release_free_blocks())
+    })
+    .expect("failed to register reclaimer");
+
+    #[cfg(feature = "alloc-slab")]
+    oom::register_reclaimer("empty slabs", |_| {
+        ALLOCATOR.try_lock().map_or(0, |mut allocator| allocator.shrink())
+    })
+    .expect("failed to register reclaimer");
+}
+
+/// Maps a single heap page to a newly allocated frame.
+fn map_heap_page(
+    page: Page,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let frame = frame_allocator
+        .allocate_frame()
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    unsafe {
+        mapper.map_to(page, frame, flags, frame_allocator)?.flush()
+    }
     Ok(())
 }
 
+/// Allocates from the given `linked_list_allocator` heap and grows it if it is
+/// exhausted.
+///
+/// This is the shared fallback path of our block-based allocators. Only the
+/// kernel heap at `HEAP_START` grows; other heaps simply fail.
+fn alloc_from_heap(heap: &mut linked_list_allocator::Heap, layout: Layout) -> *mut u8 {
+    if let Ok(ptr) = heap.allocate_first_fit(layout) {
+        return ptr.as_ptr();
+    }
+    if heap.bottom() != HEAP_START {
+        return null_mut();
+    }
+
+    // The new pages are mapped directly behind the current end of the heap, so
+    // `Heap::extend` can add them as a new hole that is merged with a free
+    // region at the end of the heap. We request `size + align` bytes to make
+    // sure that the allocation fits even if nothing is merged.
+    let min_bytes = match layout.size().checked_add(layout.align()) {
+        Some(bytes) => bytes,
+        None => return null_mut(),
+    };
+    match grow_heap(heap.top(), min_bytes) {
+        Some(by) => {
+            unsafe { heap.extend(by) };
+            match heap.allocate_first_fit(layout) {
+                Ok(ptr) => ptr.as_ptr(),
+                Err(_) => null_mut(),
+            }
+        }
+        None => null_mut(),
+    }
+}
+
+/// Returns the current statistics of the global allocator.
+///
+/// This can be used to print memory reports or to check for leaks, e.g. by
+/// comparing the `allocation_count` before and after a piece of code.
+pub fn heap_stats() -> HeapReport {
+    // Blocks in the quarantine of the debug allocator were freed by their
+    // owner, so they shouldn't show up as allocations.
+    #[cfg(feature = "debug-alloc")]
+    DEBUG_ALLOCATOR.flush_quarantine();
+
+    ALLOCATOR.lock().report()
+}
+
+/// Sets the upper bound for the heap size in bytes.
+///
+/// The limit only affects future growth, the heap never shrinks below its
+/// current size.
+pub fn set_heap_limit(limit: usize) {
+    HEAP_LIMIT.store(limit, Ordering::Relaxed);
+}
+
+/// Maps additional pages at the current end of the heap (`heap_top`) so that
+/// at least `min_bytes` more bytes are available.
+///
+/// Returns the number of newly mapped bytes, or `None` if the heap limit would
+/// be exceeded, no frames are left, or `memory::init_kernel_memory` was not
+/// called yet. It is called by the global allocator while it holds its lock,
+/// so it must not allocate heap memory itself.
+fn grow_heap(heap_top: usize, min_bytes: usize) -> Option<usize> {
+    let by = align_up(min_bytes.max(HEAP_GROWTH_STEP), 4096);
+    let new_top = heap_top.checked_add(by)?;
+    if new_top - HEAP_START > HEAP_LIMIT.load(Ordering::Relaxed) {
+        return None;
+    }
+
+    memory::with_kernel_memory(|memory| {
+        let first_page = Page::containing_address(VirtAddr::new(heap_top as u64));
+        let last_page = Page::containing_address(VirtAddr::new(new_top as u64 - 1));
+        let pages = Page::range_inclusive(first_page, last_page);
+        for (mapped, page) in pages.enumerate() {
+            if map_heap_page(page, &mut memory.mapper, &mut memory.frame_allocator).is_err() {
+                // Roll back the pages that we mapped so far, so that the next
+                // attempt doesn't fail because they are already mapped.
+                for page in pages.take(mapped) {
+                    unsafe {
+                        memory::unmap_page(page, &mut memory.mapper, &mut memory.frame_allocator)
+                            .expect("failed to unmap heap page");
+                    }
+                }
+                return None;
+            }
+        }
+        Some(by)
+    })?
+}
+
 /// Dummy allocator
 ///
 /// It does the absolute minimum to implement the `GlobalAlloc` trait and always
@@ -142,6 +364,10 @@ unsafe impl GlobalAlloc for Dummy {
 /// 
 /// It imposes no restrictions on the wrapped type `A`, so it can be used to
 /// wrap all kinds of types, not just allocators.
+///
+/// Interrupts are disabled while the lock is held. Otherwise, an interrupt
+/// handler that allocates (e.g. to spawn a task) would spin forever on the
+/// lock of the allocation that it interrupted.
 pub struct Locked<A> {
     inner: spin::Mutex<A>,
 }
@@ -154,33 +380,63 @@ impl<A> Locked<A> {
         }
     }
     
-    /// A convenience function that calls lock on the wrapped `Mutex`.
-    pub fn lock(&self) -> spin::MutexGuard<A> {
-        self.inner.lock()
+    /// A convenience function that disables interrupts and calls lock on the
+    /// wrapped `Mutex`.
+    pub fn lock(&self) -> LockedGuard<A> {
+        let enable_interrupts = interrupts::are_enabled();
+        interrupts::disable();
+        LockedGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            enable_interrupts,
+        }
+    }
+
+    /// Like `lock`, but returns `None` instead of spinning if the lock is
+    /// already taken.
+    pub fn try_lock(&self) -> Option<LockedGuard<A>> {
+        let enable_interrupts = interrupts::are_enabled();
+        interrupts::disable();
+        match self.inner.try_lock() {
+            Some(guard) => Some(LockedGuard { guard: ManuallyDrop::new(guard), enable_interrupts }),
+            None => {
+                if enable_interrupts {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
     }
 }
 
-/// Align the given address `addr` upwards to alignment `align`.
-///
-/// Requires that `align` is a power of two.
-fn align_up(addr: usize, align: usize) -> usize {
-    // let remainder = addr % align;
-    // if remainder == 0 {
-    //     addr // addr already aligned with the given alignment
-    // } else {
-    //     // Align the address by subtracting the remainder (so that the new
-    //     // remainder is 0) and then adding the alignment (so that the address
-    //     // does not become smaller than the original address).
-    //     addr - remainder + align
-    // }
-
-    // Note that this isn't the most efficient way to implement this function. A
-    // much faster implementation below:
-
-    // Create a bitmask to align the address in a very efficient way.
-    (addr + align - 1) & !(align - 1)
-    // the `!` operator is bitwise NOT.
-    // the `&` operator is bitwise AND.
+/// The guard of `Locked`, which enables interrupts again after it released
+/// the lock, if they were enabled before.
+pub struct LockedGuard<'a, A> {
+    guard: ManuallyDrop<spin::MutexGuard<'a, A>>,
+    enable_interrupts: bool,
+}
+
+impl<A> Deref for LockedGuard<'_, A> {
+    type Target = A;
+
+    fn deref(&self) -> &A {
+        &self.guard
+    }
+}
+
+impl<A> DerefMut for LockedGuard<'_, A> {
+    fn deref_mut(&mut self) -> &mut A {
+        &mut self.guard
+    }
+}
+
+impl<A> Drop for LockedGuard<'_, A> {
+    fn drop(&mut self) {
+        // The lock must be released before an interrupt can arrive.
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.enable_interrupts {
+            interrupts::enable();
+        }
+    }
 }
 
 // ********** Sidenote **********
@@ -8,7 +8,7 @@
 
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr;
-use super::{ Locked, align_up };
+use super::{ Locked, align_up, stats::HeapStats };
 
 pub struct BumpAllocator {
     heap_start: usize,
@@ -50,6 +50,30 @@ impl BumpAllocator {
     }
 }
 
+// Memory of freed allocations is only reused once all allocations are freed,
+// so everything between `heap_start` and `next` counts as allocated.
+impl HeapStats for BumpAllocator {
+    fn heap_size(&self) -> usize {
+        self.heap_end - self.heap_start
+    }
+
+    fn bytes_allocated(&self) -> usize {
+        self.next - self.heap_start
+    }
+
+    fn bytes_free(&self) -> usize {
+        self.heap_end - self.next
+    }
+
+    fn allocation_count(&self) -> usize {
+        self.allocations
+    }
+
+    fn largest_free_region(&self) -> Option<usize> {
+        Some(self.heap_end - self.next)
+    }
+}
+
 unsafe impl GlobalAlloc for Locked<BumpAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let mut bump = self.lock(); // get a mutable reference
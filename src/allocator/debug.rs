@@ -0,0 +1,252 @@
+//! # Debug allocator module
+//!
+//! A wrapper around another allocator that helps to find heap corruption.
+//!
+//! Every allocation gets a header and guard bytes ("red zones") on both sides.
+//! Memory is filled with a pattern on allocation and on deallocation, freed
+//! blocks are kept in a quarantine for a while before they are given back to
+//! the wrapped allocator, and double frees as well as deallocations with a
+//! different layout are detected. All problems are reported through
+//! `serial_println!`.
+//!
+//! The global allocator is wrapped when the `debug-alloc` cargo feature is
+//! enabled.
+
+use alloc::alloc::{ GlobalAlloc, Layout };
+use core::{
+    mem, ptr, slice,
+    sync::atomic::{ AtomicUsize, Ordering },
+};
+use x86_64::instructions::interrupts;
+use crate::serial_println;
+use super::align_up;
+
+/// The byte that new allocations are filled with.
+pub const ALLOC_FILL: u8 = 0xcd;
+/// The byte that freed allocations are filled with.
+pub const FREE_FILL: u8 = 0xdd;
+/// The byte that the red zones are filled with.
+pub const GUARD_FILL: u8 = 0xfd;
+
+/// The size of the red zone on each side of an allocation.
+pub const RED_ZONE_SIZE: usize = 16;
+/// The number of freed blocks that are kept in the quarantine.
+pub const QUARANTINE_LEN: usize = 64;
+
+/// Marks the header of an allocated block.
+const ALLOCATED_MAGIC: usize = 0xa110_ca7e_d0d0_beef;
+/// Marks the header of a freed block in the quarantine.
+const FREED_MAGIC: usize = 0xf7ee_d0d0_dead_beef;
+
+/// The number of problems that were detected so far.
+static ERRORS: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the number of heap problems that the debug allocators detected.
+pub fn error_count() -> usize {
+    ERRORS.load(Ordering::Relaxed)
+}
+
+/// The metadata that is stored in front of the leading red zone.
+#[repr(C)]
+struct Header {
+    magic: usize,
+    size: usize,
+    align: usize,
+}
+
+/// A freed block that waits in the quarantine.
+#[derive(Clone, Copy)]
+struct QuarantinedBlock {
+    ptr: usize,
+    size: usize,
+    align: usize,
+}
+
+/// A ring buffer of freed blocks.
+struct Quarantine {
+    blocks: [Option<QuarantinedBlock>; QUARANTINE_LEN],
+    next: usize,
+}
+
+/// The debug allocator type.
+///
+/// It wraps a reference to another allocator, so that the wrapped allocator
+/// can still be initialized and queried through its own static.
+pub struct DebugAllocator<A: 'static> {
+    inner: &'static A,
+    quarantine: spin::Mutex<Quarantine>,
+}
+
+impl<A: GlobalAlloc + 'static> DebugAllocator<A> {
+    /// Creates a debug allocator that forwards to `inner`.
+    pub const fn new(inner: &'static A) -> Self {
+        DebugAllocator {
+            inner,
+            quarantine: spin::Mutex::new(Quarantine {
+                blocks: [None; QUARANTINE_LEN],
+                next: 0,
+            }),
+        }
+    }
+
+    /// Gives all blocks in the quarantine back to the wrapped allocator.
+    ///
+    /// This is useful before comparing heap statistics, since quarantined
+    /// blocks still count as allocated for the wrapped allocator. Returns the
+    /// number of user bytes that were given back.
+    pub fn flush_quarantine(&self) -> usize {
+        // Like the lock of `Locked`, the quarantine is only locked with
+        // interrupts disabled, since interrupt handlers may allocate.
+        interrupts::without_interrupts(|| {
+            let mut released = 0;
+            let mut quarantine = self.quarantine.lock();
+            for slot in quarantine.blocks.iter_mut() {
+                if let Some(block) = slot.take() {
+                    released += block.size;
+                    unsafe { self.release(block) };
+                }
+            }
+            released
+        })
+    }
+
+    /// Checks that a quarantined block was not written to and gives it back
+    /// to the wrapped allocator.
+    unsafe fn release(&self, block: QuarantinedBlock) {
+        let user = slice::from_raw_parts(block.ptr as *const u8, block.size);
+        if let Some(offset) = user.iter().position(|&b| b != FREE_FILL) {
+            report(format_args!(
+                "use after free: block {:#x} (size {}) was written at offset {}",
+                block.ptr, block.size, offset,
+            ));
+        }
+        let (outer, front) = outer_layout(block.size, block.align);
+        self.inner.dealloc((block.ptr - front) as *mut u8, outer);
+    }
+}
+
+unsafe impl<A: GlobalAlloc + 'static> GlobalAlloc for DebugAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (outer, front) = outer_layout(layout.size(), layout.align());
+        let base = self.inner.alloc(outer);
+        if base.is_null() {
+            return base;
+        }
+        let user = base.add(front);
+        header(user).write(Header {
+            magic: ALLOCATED_MAGIC,
+            size: layout.size(),
+            align: layout.align(),
+        });
+        ptr::write_bytes(user.sub(RED_ZONE_SIZE), GUARD_FILL, RED_ZONE_SIZE);
+        ptr::write_bytes(user, ALLOC_FILL, layout.size());
+        ptr::write_bytes(user.add(layout.size()), GUARD_FILL, RED_ZONE_SIZE);
+        user
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let header = &mut *header(ptr);
+        match header.magic {
+            ALLOCATED_MAGIC => {}
+            FREED_MAGIC => {
+                // We leak the block instead of freeing it a second time, which
+                // would corrupt the free list of the wrapped allocator.
+                report(format_args!("double free of block {:#x}", ptr as usize));
+                return;
+            }
+            _ => {
+                report(format_args!(
+                    "dealloc of unknown block {:#x} (or its header was overwritten)",
+                    ptr as usize,
+                ));
+                return;
+            }
+        }
+        if header.size != layout.size() || header.align != layout.align() {
+            report(format_args!(
+                "layout mismatch for block {:#x}: allocated with size {} align {}, \
+                 freed with size {} align {}",
+                ptr as usize, header.size, header.align, layout.size(), layout.align(),
+            ));
+        }
+        // From here on we use the recorded layout, so that a mismatch doesn't
+        // corrupt the wrapped allocator as well.
+        let size = header.size;
+        check_red_zone(ptr, "before", ptr.sub(RED_ZONE_SIZE));
+        check_red_zone(ptr, "after", ptr.add(size));
+
+        header.magic = FREED_MAGIC;
+        ptr::write_bytes(ptr, FREE_FILL, size);
+
+        let block = QuarantinedBlock { ptr: ptr as usize, size, align: header.align };
+        let evicted = interrupts::without_interrupts(|| {
+            let mut quarantine = self.quarantine.lock();
+            let index = quarantine.next;
+            quarantine.next = (index + 1) % QUARANTINE_LEN;
+            mem::replace(&mut quarantine.blocks[index], Some(block))
+        });
+        if let Some(evicted) = evicted {
+            self.release(evicted);
+        }
+    }
+}
+
+/// Returns the layout that is requested from the wrapped allocator for a block
+/// of the given size and alignment, and the offset of the user data in it.
+///
+/// The block looks like this:
+///
+/// ```text
+/// | padding | Header | red zone | user data | red zone |
+///                               ^ front
+/// ```
+fn outer_layout(size: usize, align: usize) -> (Layout, usize) {
+    let align = align.max(mem::align_of::<Header>());
+    let front = align_up(mem::size_of::<Header>() + RED_ZONE_SIZE, align);
+    let layout = Layout::from_size_align(front + size + RED_ZONE_SIZE, align)
+        .expect("invalid debug allocation layout");
+    (layout, front)
+}
+
+/// Returns a pointer to the header of the block with the given user pointer.
+unsafe fn header(user: *mut u8) -> *mut Header {
+    user.sub(RED_ZONE_SIZE + mem::size_of::<Header>()) as *mut Header
+}
+
+/// Reports bytes of a red zone that were overwritten.
+unsafe fn check_red_zone(block: *mut u8, side: &str, zone: *const u8) {
+    let zone = slice::from_raw_parts(zone, RED_ZONE_SIZE);
+    let corrupted = zone.iter().filter(|&&b| b != GUARD_FILL).count();
+    if corrupted > 0 {
+        report(format_args!(
+            "heap overflow: {} guard bytes {} block {:#x} were overwritten",
+            corrupted, side, block as usize,
+        ));
+    }
+}
+
+fn report(message: core::fmt::Arguments) {
+    ERRORS.fetch_add(1, Ordering::Relaxed);
+    serial_println!("[debug-alloc] {}", message);
+}
+
+// ********** Sidenote **********
+//
+// # Why the fill patterns?
+//
+// The patterns make uninitialized and freed memory easy to spot: a pointer or
+// length of `0xcdcdcdcdcdcdcdcd` was read from memory that was never written,
+// while `0xdddddddddddddddd` means the memory was already freed. Both are
+// non-canonical addresses on x86_64, so dereferencing them causes a general
+// protection fault instead of silently reading some other data.
+//
+// # Why a quarantine?
+//
+// Without it, a freed block is often handed out again by the very next
+// allocation of the same size. A task that still holds a dangling pointer would
+// then silently modify the new owner's data. Keeping freed blocks out of
+// circulation for a while makes it likely that such a write hits the free
+// pattern, which we check when the block leaves the quarantine.
+//
+// A double free is only detected while the block is in the quarantine. After
+// it was released, its header may have been overwritten by a new allocation.
@@ -0,0 +1,80 @@
+//! # External allocator module
+//!
+//! A thin wrapper around the `Heap` of the `linked_list_allocator` crate.
+//!
+//! We used the crate's `LockedHeap` type before implementing our own
+//! allocators. Wrapping the `Heap` ourselves lets it grow like our other
+//! heaps and count its allocations for `HeapStats`, so it can be selected as
+//! global allocator through the `alloc-external` feature like all others.
+
+use alloc::alloc::{ GlobalAlloc, Layout };
+use core::ptr::NonNull;
+use linked_list_allocator::Heap;
+use super::{ Locked, stats::HeapStats };
+
+/// The allocator type.
+pub struct ExternalAllocator {
+    heap: Heap,
+    /// The number of allocations that were not freed yet.
+    allocations: usize,
+}
+
+impl ExternalAllocator {
+    /// Creates an empty ExternalAllocator.
+    pub const fn new() -> Self {
+        ExternalAllocator {
+            heap: Heap::empty(),
+            allocations: 0,
+        }
+    }
+
+    /// Initialize the allocator with the given heap bounds.
+    ///
+    /// This function is unsafe because the caller must guarantee that the given
+    /// heap bounds are valid and that the heap is unused. This method must be
+    /// called only once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap.init(heap_start, heap_size);
+    }
+}
+
+impl HeapStats for ExternalAllocator {
+    fn heap_size(&self) -> usize {
+        self.heap.size()
+    }
+
+    fn bytes_allocated(&self) -> usize {
+        self.heap.used()
+    }
+
+    fn bytes_free(&self) -> usize {
+        self.heap.free()
+    }
+
+    fn allocation_count(&self) -> usize {
+        self.allocations
+    }
+
+    // The crate doesn't expose its list of free regions, see
+    // `HeapStats::largest_free_region`.
+    fn largest_free_region(&self) -> Option<usize> {
+        None
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<ExternalAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut allocator = self.lock();
+        let ptr = super::alloc_from_heap(&mut allocator.heap, layout);
+        if !ptr.is_null() {
+            allocator.allocations += 1;
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut allocator = self.lock();
+        allocator.heap.deallocate(NonNull::new(ptr).unwrap(), layout);
+        allocator.allocations -= 1;
+    }
+}
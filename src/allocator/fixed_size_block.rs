@@ -11,40 +11,11 @@
 
 use alloc::alloc::{ Layout, GlobalAlloc };
 use core::{
-    ptr::{ self, NonNull },
+    ptr::NonNull,
     mem,
 };
-use super::Locked;
-
-/// The block sizes to use.
-///
-/// The sizes must each be power of 2 because they are also used as the block
-/// alignment (alignments must be always powers of 2).
-/// 
-/// We don’t define any block sizes smaller than 8 because each block must be
-/// capable of storing a 64-bit pointer to the next block when freed. For
-/// allocations greater than 2048 bytes we will fall back to a linked list
-/// allocator.
-/// 
-/// To simplify the implementation, we define that the size of a block is also
-/// its required alignment in memory. So a 16 byte block is always aligned on a
-/// 16-byte boundary and a 512 byte block is aligned on a 512-byte boundary.
-const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
-
-/// A helper function that choose an appropriate (lowest possible) block size
-/// for the given layout.
-///
-/// Returns an index into the `BLOCK_SIZES` array.
-fn list_index(layout: &Layout) -> Option<usize> {
-    // The block must have at least the size and alignment required by the given
-    // layout.
-    let required_block_size = layout.size().max(layout.align());
-    // To find the next-larger block in the `BLOCK_SIZES` slice, we first use
-    // the `iter()` method to get an iterator and then the `position()` method
-    // to find the index of the first block that is as least as large as the
-    // `required_block_size`.
-    BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
-}
+use tiny_os_core::allocator::fixed_size_block::{ BLOCK_SIZES, list_index };
+use super::{ Locked, stats::{ HeapStats, SizeClass, SizeClasses } };
 
 struct ListNode {
     // An optional pointer to the next node. The `&'static mut` type
@@ -61,6 +32,8 @@ pub struct FixedSizeBlockAllocator {
     // As a fallback allocator for allocations larger than the largest block
     // size we use the allocator provided by the `linked_list_allocator`.
     fallback_allocator: linked_list_allocator::Heap,
+    // The number of allocations that were not freed yet.
+    allocations: usize,
 }
 
 impl FixedSizeBlockAllocator {
@@ -79,6 +52,7 @@ impl FixedSizeBlockAllocator {
         FixedSizeBlockAllocator {
             list_heads: [EMPTY; BLOCK_SIZES.len()],
             fallback_allocator: linked_list_allocator::Heap::empty(),
+            allocations: 0,
         }
     }
 
@@ -102,10 +76,78 @@ impl FixedSizeBlockAllocator {
         // raw pointer that is guaranteed to be not the null pointer. By mapping
         // the `Ok` case to the `NonNull::as_ptr` method and the `Err` case to a
         // null pointer, we can easily translate this back to a `*mut u8` type.
-        match self.fallback_allocator.allocate_first_fit(layout) {
-            Ok(ptr) => ptr.as_ptr(),
-            Err(_) => ptr::null_mut(),
+        //
+        // If the fallback heap is exhausted, `alloc_from_heap` tries to grow it
+        // before giving up.
+        super::alloc_from_heap(&mut self.fallback_allocator, layout)
+    }
+
+    /// Gives all blocks in the free lists back to the fallback allocator.
+    ///
+    /// Blocks are never split or merged, so a long free list of small blocks
+    /// can't be used for a larger allocation. Releasing them lets the fallback
+    /// allocator merge them into larger free regions. Returns the number of
+    /// bytes that were given back.
+    pub fn release_free_blocks(&mut self) -> usize {
+        let mut released = 0;
+        for (index, &block_size) in BLOCK_SIZES.iter().enumerate() {
+            // Blocks are allocated from the fallback allocator with the block
+            // size as size and alignment, see `alloc`.
+            let layout = Layout::from_size_align(block_size, block_size).unwrap();
+            while let Some(node) = self.list_heads[index].take() {
+                self.list_heads[index] = node.next.take();
+                let ptr = NonNull::from(node).cast::<u8>();
+                unsafe { self.fallback_allocator.deallocate(ptr, layout) };
+                released += block_size;
+            }
         }
+        released
+    }
+
+    /// Returns the number of blocks in the list of the given index.
+    fn free_blocks(&self, index: usize) -> usize {
+        let mut count = 0;
+        let mut current = self.list_heads[index].as_deref();
+        while let Some(node) = current {
+            count += 1;
+            current = node.next.as_deref();
+        }
+        count
+    }
+}
+
+// Free blocks are still counted as used by the fallback allocator, so we move
+// them from its `used` to its `free` bytes.
+impl HeapStats for FixedSizeBlockAllocator {
+    fn heap_size(&self) -> usize {
+        self.fallback_allocator.size()
+    }
+
+    fn bytes_allocated(&self) -> usize {
+        self.fallback_allocator.used() - self.size_classes().free_bytes()
+    }
+
+    fn bytes_free(&self) -> usize {
+        self.fallback_allocator.free() + self.size_classes().free_bytes()
+    }
+
+    fn allocation_count(&self) -> usize {
+        self.allocations
+    }
+
+    // The `Heap` type of the `linked_list_allocator` crate doesn't expose its
+    // list of free regions, so we can't determine the largest one, see
+    // `HeapStats::largest_free_region`.
+    fn largest_free_region(&self) -> Option<usize> {
+        None
+    }
+
+    fn size_classes(&self) -> SizeClasses {
+        let mut classes = SizeClasses::new();
+        for (index, &block_size) in BLOCK_SIZES.iter().enumerate() {
+            classes.push(SizeClass { block_size, free_blocks: self.free_blocks(index) });
+        }
+        classes
     }
 }
 
@@ -116,7 +158,7 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
         
         // Calculate the appropriate block size for the given layout and get the
         // corresponding index into the `list_heads` array.
-        match list_index(&layout) {
+        let ptr = match list_index(&layout) {
             Some(index) => {
                 // We try to remove the first node in the corresponding list
                 // started by `list_heads[index]` using the `Option::take`
@@ -154,7 +196,11 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
             // No block size fits for the allocation, therefore we use the
             // `fallback_allocator` using the `fallback_alloc` function.
             None => allocator.fallback_alloc(layout),
+        };
+        if !ptr.is_null() {
+            allocator.allocations += 1;
         }
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
@@ -196,6 +242,7 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
                 allocator.fallback_allocator.deallocate(ptr, layout);
             }
         }
+        allocator.allocations -= 1;
     }
 }
 
@@ -0,0 +1,263 @@
+//! # Out-of-memory handling module
+//!
+//! A registry of reclaim callbacks that run when the heap is exhausted, and the
+//! fallible allocation functions of the kernel.
+//!
+//! Subsystems that keep memory around that they don't strictly need (e.g. the
+//! free lists of our block-based allocators, object caches, or buffers that can
+//! be rebuilt later) register a callback through `register_reclaimer`. When an
+//! allocation fails, the global allocator runs all callbacks and retries the
+//! allocation once before it reports the error.
+//!
+//! Code that can handle a failed allocation uses `try_alloc`, `try_box`, or
+//! `try_vec_with_capacity` instead of `Box::new` or `Vec::with_capacity`,
+//! which call the `alloc_error_handler` and therefore panic.
+
+use alloc::{
+    alloc::{ GlobalAlloc, Layout },
+    boxed::Box,
+    vec::Vec,
+};
+use core::{
+    fmt,
+    ptr::NonNull,
+    sync::atomic::{ AtomicBool, Ordering },
+};
+use x86_64::instructions::interrupts;
+
+/// The maximum number of reclaim callbacks that can be registered.
+pub const MAX_RECLAIMERS: usize = 8;
+
+/// A reclaim callback.
+///
+/// It receives the layout of the failed allocation and returns the number of
+/// bytes that it gave back to the heap (an estimate is fine, it is only used
+/// to decide whether a retry is worth it).
+///
+/// The callback runs in the context of the failed allocation, which might be
+/// an interrupt handler. It must therefore never block on a lock, but use
+/// `try_lock` and give up if the lock is taken.
+pub type ReclaimFn = fn(Layout) -> usize;
+
+#[derive(Clone, Copy)]
+struct Reclaimer {
+    name: &'static str,
+    reclaim: ReclaimFn,
+}
+
+/// The registered reclaim callbacks.
+///
+/// Registering a callback must not allocate, so we use a fixed-size array
+/// instead of a `Vec`. Allocations in interrupt handlers run `reclaim`, so the
+/// registry is only locked with interrupts disabled.
+static RECLAIMERS: spin::Mutex<[Option<Reclaimer>; MAX_RECLAIMERS]> =
+    spin::Mutex::new([None; MAX_RECLAIMERS]);
+
+/// Set while the reclaim callbacks run, so that a failed allocation inside a
+/// callback doesn't run them again.
+static RECLAIMING: AtomicBool = AtomicBool::new(false);
+
+/// The error returned by `register_reclaimer` if all slots are taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegistryFull;
+
+/// Registers a callback that is run before an allocation fails.
+///
+/// The `name` is only used for diagnostics.
+pub fn register_reclaimer(name: &'static str, reclaim: ReclaimFn) -> Result<(), RegistryFull> {
+    interrupts::without_interrupts(|| {
+        let mut reclaimers = RECLAIMERS.lock();
+        let slot = reclaimers
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(RegistryFull)?;
+        *slot = Some(Reclaimer { name, reclaim });
+        Ok(())
+    })
+}
+
+/// Removes all registrations of the given callback.
+pub fn unregister_reclaimer(reclaim: ReclaimFn) {
+    interrupts::without_interrupts(|| {
+        let mut reclaimers = RECLAIMERS.lock();
+        // Move the remaining callbacks to the front, so that the registry
+        // stays in registration order.
+        let mut kept = 0;
+        for index in 0..MAX_RECLAIMERS {
+            if let Some(reclaimer) = reclaimers[index].take() {
+                if reclaimer.reclaim as usize != reclaim as usize {
+                    reclaimers[kept] = Some(reclaimer);
+                    kept += 1;
+                }
+            }
+        }
+    });
+}
+
+/// Calls the `f` closure with the name of each registered reclaim callback.
+pub fn for_each_reclaimer(mut f: impl FnMut(&'static str)) {
+    // Like `reclaim`, we copy the registry, so that `f` runs with interrupts
+    // enabled and may register or unregister callbacks itself.
+    let reclaimers = interrupts::without_interrupts(|| *RECLAIMERS.lock());
+    for reclaimer in reclaimers.iter().flatten() {
+        f(reclaimer.name);
+    }
+}
+
+/// Runs all reclaim callbacks and returns the number of bytes that they gave
+/// back.
+///
+/// The callbacks run in reverse registration order. The allocator registers
+/// its own callbacks in `init_heap`, before any subsystem can, so they run
+/// last and also pick up the memory that the subsystems just freed (e.g. into
+/// the free lists of the fixed-size block allocator).
+///
+/// Returns 0 without running anything if it is called from a reclaim callback
+/// or if the registry is locked.
+pub fn reclaim(layout: Layout) -> usize {
+    if RECLAIMING.swap(true, Ordering::Acquire) {
+        return 0;
+    }
+    // We copy the callbacks so that the registry isn't locked while they run.
+    // Otherwise, a callback that registers another callback would deadlock.
+    //
+    // Like the callbacks themselves, we must never wait for a lock here. All
+    // other accesses disable interrupts, so the lock is only taken if another
+    // CPU holds it, and then we rather let the allocation fail than spin.
+    let reclaimers = match RECLAIMERS.try_lock() {
+        Some(reclaimers) => *reclaimers,
+        None => {
+            RECLAIMING.store(false, Ordering::Release);
+            return 0;
+        }
+    };
+    let released = reclaimers
+        .iter()
+        .rev()
+        .flatten()
+        .map(|reclaimer| (reclaimer.reclaim)(layout))
+        .sum();
+    RECLAIMING.store(false, Ordering::Release);
+    released
+}
+
+/// A wrapper around the global allocator that runs the reclaim callbacks when
+/// an allocation fails.
+///
+/// Like `DebugAllocator`, it wraps a reference, so that the wrapped allocator
+/// can still be used through its own static.
+pub struct Reclaiming<A: 'static> {
+    inner: &'static A,
+}
+
+impl<A: GlobalAlloc + 'static> Reclaiming<A> {
+    /// Creates a wrapper that forwards to `inner`.
+    pub const fn new(inner: &'static A) -> Self {
+        Reclaiming { inner }
+    }
+}
+
+// The wrapped allocator has released its lock by the time it returns a null
+// pointer, so the reclaim callbacks are free to deallocate memory.
+unsafe impl<A: GlobalAlloc + 'static> GlobalAlloc for Reclaiming<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        if ptr.is_null() && reclaim(layout) > 0 {
+            return self.inner.alloc(layout);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout)
+    }
+}
+
+/// The error returned by the fallible allocation functions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocError {
+    /// The heap has no memory left for an allocation of this layout, even
+    /// after running the reclaim callbacks.
+    OutOfMemory(Layout),
+    /// The requested size overflows `isize::MAX`.
+    CapacityOverflow,
+}
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AllocError::OutOfMemory(layout) => write!(
+                f,
+                "out of memory (size {}, align {})",
+                layout.size(), layout.align(),
+            ),
+            AllocError::CapacityOverflow => write!(f, "capacity overflow"),
+        }
+    }
+}
+
+/// Allocates memory for the given layout from the global allocator.
+///
+/// Unlike the allocation functions of `alloc`, this never calls the
+/// `alloc_error_handler`. For a layout of size zero, a dangling pointer with
+/// the right alignment is returned. The memory must be freed with `dealloc`.
+pub fn try_alloc(layout: Layout) -> Result<NonNull<u8>, AllocError> {
+    if layout.size() == 0 {
+        // Calling `GlobalAlloc::alloc` with a zero size is undefined behavior.
+        return Ok(unsafe { NonNull::new_unchecked(layout.align() as *mut u8) });
+    }
+    let ptr = unsafe { alloc::alloc::alloc(layout) };
+    NonNull::new(ptr).ok_or(AllocError::OutOfMemory(layout))
+}
+
+/// Frees memory that was returned by `try_alloc`.
+///
+/// This function is unsafe because the caller must guarantee that `ptr` was
+/// returned by `try_alloc` with the same `layout` and isn't used anymore.
+pub unsafe fn dealloc(ptr: NonNull<u8>, layout: Layout) {
+    if layout.size() != 0 {
+        alloc::alloc::dealloc(ptr.as_ptr(), layout);
+    }
+}
+
+/// Moves `value` to the heap, or returns an error if there is not enough
+/// memory.
+pub fn try_box<T>(value: T) -> Result<Box<T>, AllocError> {
+    let ptr = try_alloc(Layout::new::<T>())?.cast::<T>();
+    unsafe {
+        ptr.as_ptr().write(value);
+        // The memory was allocated by the global allocator with the layout of
+        // `T`, which is what `Box` expects. For zero-sized types, `Box` uses a
+        // dangling pointer as well.
+        Ok(Box::from_raw(ptr.as_ptr()))
+    }
+}
+
+/// Creates an empty vector with space for exactly `capacity` elements, or
+/// returns an error if there is not enough memory.
+pub fn try_vec_with_capacity<T>(capacity: usize) -> Result<Vec<T>, AllocError> {
+    let layout = Layout::array::<T>(capacity).map_err(|_| AllocError::CapacityOverflow)?;
+    let mut vec = Vec::new();
+    vec.try_reserve_exact(capacity)
+        .map_err(|_| AllocError::OutOfMemory(layout))?;
+    Ok(vec)
+}
+
+// ********** Sidenote **********
+//
+// # Why not just panic?
+//
+// A kernel can't ask the operating system for more memory like a normal
+// program, so running out of heap is not necessarily a bug: a network driver
+// that can't allocate a receive buffer should drop the packet, not halt the
+// whole system. The allocation types of `alloc` (e.g. `Box::new` or
+// `Vec::push`) have no way to report a failure, which is why the kernel
+// provides its own fallible functions for the places where a failure can be
+// handled.
+//
+// # Why the retry in the global allocator?
+//
+// The reclaim callbacks run for every failed allocation, including those of
+// `Box::new`. This way, the memory in caches is used before any allocation
+// fails, not only before the fallible ones do. The Linux kernel calls the same
+// idea "shrinkers".
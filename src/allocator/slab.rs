@@ -0,0 +1,494 @@
+//! # Slab allocator module
+//!
+//! An allocator that keeps a separate cache for each object size that the
+//! kernel allocates frequently.
+//!
+//! Each cache carves page-sized _slabs_ into equally sized object slots. Like
+//! the fixed-size block allocator, allocations and deallocations are very fast
+//! because a free slot can be taken from the front of a list. Unlike it, slabs
+//! whose objects are all free are given back to the fallback heap, and caches
+//! can be sized exactly for an object (e.g. the header of a task) instead of
+//! rounding up to the next power of two.
+
+use alloc::alloc::{ Layout, GlobalAlloc };
+use core::{ mem, ptr::{ self, NonNull } };
+use linked_list_allocator::Heap;
+use super::{
+    align_up,
+    linked_list::ListNode,
+    Locked,
+    stats::{ HeapStats, SizeClass, SizeClasses },
+};
+use tiny_os_core::task::TaskHeader;
+
+/// The size of a slab. A slab is also aligned to its size, which allows us to
+/// find the slab of an object by masking the lower bits of its address.
+const SLAB_SIZE: usize = 4096;
+
+/// The header at the start of every slab.
+struct Slab {
+    /// The next slab in the list of the owning cache.
+    next: Option<&'static mut Slab>,
+    /// The address of the first free object, or `0` if the slab is full. Each
+    /// free object stores the address of the next free object in its link
+    /// word.
+    free_head: usize,
+    /// The number of objects that are currently handed out.
+    in_use: usize,
+}
+
+/// A hook that is called with a pointer to an object slot.
+pub type ObjectHook = fn(*mut u8);
+
+/// A cache of equally sized objects.
+///
+/// The optional constructor is called for every object when a new slab is
+/// created, and the destructor for every object before a slab is released.
+/// Objects are expected to be returned in their constructed state, so
+/// expensive initialization only happens once per slot instead of once per
+/// allocation. If hooks are set, the free-list link is stored behind the object
+/// so that it doesn't overwrite the constructed state.
+pub struct SlabCache {
+    name: &'static str,
+    object_size: usize,
+    align: usize,
+    ctor: Option<ObjectHook>,
+    dtor: Option<ObjectHook>,
+    /// Slabs that have at least one free and one used object. Full slabs are
+    /// not linked anywhere until one of their objects is freed.
+    partial: Option<&'static mut Slab>,
+    /// A single completely free slab that we keep around to avoid releasing
+    /// and recreating a slab when an object is freed and allocated in turns.
+    empty: Option<&'static mut Slab>,
+    /// The number of slabs that currently belong to this cache.
+    slab_count: usize,
+    /// The number of objects that are currently handed out.
+    objects_in_use: usize,
+}
+
+impl SlabCache {
+    /// Creates an empty cache for objects of the given size and alignment.
+    pub const fn new(name: &'static str, object_size: usize, align: usize) -> Self {
+        // Every slot must be able to store the free-list link.
+        let align = if align < mem::align_of::<usize>() {
+            mem::align_of::<usize>()
+        } else {
+            align
+        };
+        SlabCache {
+            name,
+            object_size,
+            align,
+            ctor: None,
+            dtor: None,
+            partial: None,
+            empty: None,
+            slab_count: 0,
+            objects_in_use: 0,
+        }
+    }
+
+    /// Creates an empty cache for objects of type `T`.
+    pub const fn for_type<T>(name: &'static str) -> Self {
+        SlabCache::new(name, mem::size_of::<T>(), mem::align_of::<T>())
+    }
+
+    /// Sets the constructor and destructor hooks of the cache.
+    pub const fn with_hooks(
+        self,
+        ctor: Option<ObjectHook>,
+        dtor: Option<ObjectHook>,
+    ) -> Self {
+        SlabCache { ctor, dtor, ..self }
+    }
+
+    /// Returns the name of the cache.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Returns the size of the objects in this cache.
+    pub fn object_size(&self) -> usize {
+        self.object_size
+    }
+
+    /// Returns the number of slabs that currently belong to this cache.
+    pub fn slab_count(&self) -> usize {
+        self.slab_count
+    }
+
+    /// Returns the number of objects that are currently handed out.
+    pub fn objects_in_use(&self) -> usize {
+        self.objects_in_use
+    }
+
+    /// Returns the number of free object slots in all slabs of this cache.
+    pub fn free_objects(&self) -> usize {
+        self.slab_count * self.slots_per_slab() - self.objects_in_use
+    }
+
+    /// Returns whether an allocation with the given layout fits into a slot
+    /// of this cache.
+    pub fn fits(&self, layout: &Layout) -> bool {
+        layout.size() <= self.object_size && layout.align() <= self.align
+    }
+
+    /// The offset of the free-list link inside a slot.
+    fn link_offset(&self) -> usize {
+        if self.ctor.is_some() || self.dtor.is_some() {
+            align_up(self.object_size, mem::align_of::<usize>())
+        } else {
+            0
+        }
+    }
+
+    /// The distance between two slots.
+    fn slot_size(&self) -> usize {
+        let size = self.object_size.max(self.link_offset() + mem::size_of::<usize>());
+        align_up(size, self.align)
+    }
+
+    /// The offset of the first slot, which is placed behind the slab header.
+    fn first_slot_offset(&self) -> usize {
+        align_up(mem::size_of::<Slab>(), self.align)
+    }
+
+    /// The number of slots in a single slab.
+    fn slots_per_slab(&self) -> usize {
+        SLAB_SIZE.saturating_sub(self.first_slot_offset()) / self.slot_size()
+    }
+
+    /// Takes an object from the cache, creating a new slab from `heap` if no
+    /// free object is left.
+    ///
+    /// Returns a null pointer if no new slab can be allocated.
+    ///
+    /// This method is unsafe because the caller must pass the same heap on
+    /// every call, which must stay valid for the lifetime of the cache.
+    pub unsafe fn alloc(&mut self, heap: &mut Heap) -> *mut u8 {
+        let link_offset = self.link_offset();
+        if self.partial.is_none() {
+            let slab = match self.empty.take() {
+                Some(slab) => slab,
+                None => match self.create_slab(heap) {
+                    Some(slab) => slab,
+                    None => return ptr::null_mut(),
+                },
+            };
+            self.partial = Some(slab);
+        }
+
+        let slab = self.partial.as_mut().unwrap();
+        let object = slab.free_head;
+        slab.free_head = *((object + link_offset) as *const usize);
+        slab.in_use += 1;
+        if slab.free_head == 0 {
+            // The slab is full now, so we unlink it from the partial list. We
+            // find it again through the object address when it is freed.
+            let full = self.partial.take().unwrap();
+            self.partial = full.next.take();
+        }
+        self.objects_in_use += 1;
+        object as *mut u8
+    }
+
+    /// Gives an object back to the cache.
+    ///
+    /// Slabs that become completely free are released to `heap`, except for
+    /// one that is kept for future allocations.
+    ///
+    /// This method is unsafe because the caller must guarantee that `ptr` was
+    /// returned by `alloc` of this cache and that the same heap is passed.
+    pub unsafe fn free(&mut self, ptr: *mut u8, heap: &mut Heap) {
+        let object = ptr as usize;
+        let slab_addr = object & !(SLAB_SIZE - 1);
+        let slab = &mut *(slab_addr as *mut Slab);
+        let was_full = slab.free_head == 0;
+
+        *((object + self.link_offset()) as *mut usize) = slab.free_head;
+        slab.free_head = object;
+        slab.in_use -= 1;
+        self.objects_in_use -= 1;
+
+        if slab.in_use > 0 {
+            if was_full {
+                slab.next = self.partial.take();
+                self.partial = Some(slab);
+            }
+            return;
+        }
+
+        // The slab is completely free. Take it out of the partial list (full
+        // slabs aren't linked anywhere) and either keep or release it.
+        let slab = if was_full {
+            slab
+        } else {
+            self.unlink_partial(slab_addr)
+                .expect("slab missing from partial list")
+        };
+        if self.empty.is_none() {
+            self.empty = Some(slab);
+        } else {
+            self.release_slab(slab, heap);
+        }
+    }
+
+    /// Releases the completely free slab that the cache keeps around, if any.
+    ///
+    /// This method is unsafe for the same reasons as `free`.
+    pub unsafe fn shrink(&mut self, heap: &mut Heap) {
+        if let Some(slab) = self.empty.take() {
+            self.release_slab(slab, heap);
+        }
+    }
+
+    /// Removes the slab at the given address from the partial list.
+    fn unlink_partial(&mut self, slab_addr: usize) -> Option<&'static mut Slab> {
+        let mut current = &mut self.partial;
+        while current.is_some() {
+            let addr = current.as_ref().map(|s| &**s as *const Slab as usize);
+            if addr == Some(slab_addr) {
+                let slab = current.take().unwrap();
+                *current = slab.next.take();
+                return Some(slab);
+            }
+            current = &mut current.as_mut().unwrap().next;
+        }
+        None
+    }
+
+    /// Allocates a new slab from the heap, constructs all of its objects and
+    /// links them into the free list of the slab.
+    unsafe fn create_slab(&mut self, heap: &mut Heap) -> Option<&'static mut Slab> {
+        let slots = self.slots_per_slab();
+        if slots == 0 {
+            return None;
+        }
+        let layout = Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap();
+        let slab_addr = super::alloc_from_heap(heap, layout) as usize;
+        if slab_addr == 0 {
+            return None;
+        }
+
+        // Link the slots in reverse order, so that the free list starts with
+        // the slot at the lowest address.
+        let mut free_head = 0;
+        for index in (0..slots).rev() {
+            let object = slab_addr + self.first_slot_offset() + index * self.slot_size();
+            if let Some(ctor) = self.ctor {
+                ctor(object as *mut u8);
+            }
+            *((object + self.link_offset()) as *mut usize) = free_head;
+            free_head = object;
+        }
+
+        let slab_ptr = slab_addr as *mut Slab;
+        slab_ptr.write(Slab { next: None, free_head, in_use: 0 });
+        self.slab_count += 1;
+        Some(&mut *slab_ptr)
+    }
+
+    /// Destructs all objects of a completely free slab and gives its memory
+    /// back to the heap.
+    unsafe fn release_slab(&mut self, slab: &'static mut Slab, heap: &mut Heap) {
+        let slab_addr = slab as *mut Slab as usize;
+        if let Some(dtor) = self.dtor {
+            for index in 0..self.slots_per_slab() {
+                let object = slab_addr + self.first_slot_offset() + index * self.slot_size();
+                dtor(object as *mut u8);
+            }
+        }
+        let layout = Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap();
+        heap.deallocate(NonNull::new(slab_addr as *mut u8).unwrap(), layout);
+        self.slab_count -= 1;
+    }
+}
+
+/// The size classes for allocations that don't match one of the object caches.
+const SIZE_CLASSES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024];
+
+/// The allocator type.
+pub struct SlabAllocator {
+    /// Caches for kernel objects that are allocated often. An allocation is
+    /// served from one of them if its size matches exactly.
+    object_caches: [SlabCache; 3],
+    /// Generic caches for all other small allocations.
+    size_caches: [SlabCache; SIZE_CLASSES.len()],
+    /// Backs the slabs and serves allocations that are too large for a slab.
+    fallback_allocator: Heap,
+    /// The number of allocations served by the fallback heap that were not
+    /// freed yet.
+    large_allocations: usize,
+}
+
+impl SlabAllocator {
+    /// Creates an empty SlabAllocator.
+    pub const fn new() -> Self {
+        SlabAllocator {
+            object_caches: [
+                // The executor allocates a `Box<TaskHeader>` for every task.
+                // The wakers of the task point to the header, so they don't
+                // allocate.
+                SlabCache::for_type::<TaskHeader>("task_header"),
+                SlabCache::for_type::<ListNode>("list_node"),
+            ],
+            size_caches: [
+                SlabCache::new("size-8", 8, 8),
+                SlabCache::new("size-16", 16, 16),
+                SlabCache::new("size-32", 32, 32),
+                SlabCache::new("size-64", 64, 64),
+                SlabCache::new("size-128", 128, 128),
+                SlabCache::new("size-256", 256, 256),
+                SlabCache::new("size-512", 512, 512),
+                SlabCache::new("size-1024", 1024, 1024),
+            ],
+            fallback_allocator: Heap::empty(),
+            large_allocations: 0,
+        }
+    }
+
+    /// Initialize the allocator with the given heap bounds.
+    ///
+    /// This function is unsafe because the caller must guarantee that the given
+    /// heap bounds are valid and that the heap is unused. This method must be
+    /// called only once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.fallback_allocator.init(heap_start, heap_size);
+    }
+
+    /// Returns an iterator over all caches, e.g. for printing statistics.
+    pub fn caches(&self) -> impl Iterator<Item = &SlabCache> + '_ {
+        self.object_caches.iter().chain(self.size_caches.iter())
+    }
+
+    /// Gives the completely free slabs that the caches keep around back to the
+    /// fallback heap.
+    ///
+    /// Returns the number of bytes that were given back.
+    pub fn shrink(&mut self) -> usize {
+        let SlabAllocator { object_caches, size_caches, fallback_allocator, .. } = self;
+        let mut released = 0;
+        for cache in object_caches.iter_mut().chain(size_caches.iter_mut()) {
+            let slabs = cache.slab_count;
+            unsafe { cache.shrink(fallback_allocator) };
+            released += (slabs - cache.slab_count) * SLAB_SIZE;
+        }
+        released
+    }
+}
+
+// Slabs are allocated from the fallback heap, so the free slots of the caches
+// are counted as used by the heap.
+impl HeapStats for SlabAllocator {
+    fn heap_size(&self) -> usize {
+        self.fallback_allocator.size()
+    }
+
+    fn bytes_allocated(&self) -> usize {
+        self.fallback_allocator.used() - self.size_classes().free_bytes()
+    }
+
+    fn bytes_free(&self) -> usize {
+        self.fallback_allocator.free() + self.size_classes().free_bytes()
+    }
+
+    fn allocation_count(&self) -> usize {
+        let objects: usize = self.caches().map(|c| c.objects_in_use()).sum();
+        objects + self.large_allocations
+    }
+
+    // See `HeapStats::largest_free_region` for why this is unknown.
+    fn largest_free_region(&self) -> Option<usize> {
+        None
+    }
+
+    fn size_classes(&self) -> SizeClasses {
+        let mut classes = SizeClasses::new();
+        for cache in self.caches() {
+            classes.push(SizeClass {
+                block_size: cache.slot_size(),
+                free_blocks: cache.free_objects(),
+            });
+        }
+        classes
+    }
+}
+
+/// Chooses the cache for the given layout, preferring an exact match among the
+/// object caches over the smallest fitting size class.
+fn find_cache<'a>(
+    object_caches: &'a mut [SlabCache],
+    size_caches: &'a mut [SlabCache],
+    layout: &Layout,
+) -> Option<&'a mut SlabCache> {
+    object_caches
+        .iter_mut()
+        .find(|c| c.object_size == layout.size() && layout.align() <= c.align)
+        .or_else(|| size_caches.iter_mut().find(|c| c.fits(layout)))
+}
+
+unsafe impl GlobalAlloc for Locked<SlabAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut allocator = self.lock();
+        // Destructure the allocator, so that we can borrow a cache and the
+        // fallback heap at the same time.
+        let SlabAllocator {
+            object_caches, size_caches, fallback_allocator, large_allocations,
+        } = &mut *allocator;
+
+        match find_cache(object_caches, size_caches, &layout) {
+            Some(cache) => cache.alloc(fallback_allocator),
+            None => {
+                let ptr = super::alloc_from_heap(fallback_allocator, layout);
+                if !ptr.is_null() {
+                    *large_allocations += 1;
+                }
+                ptr
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut allocator = self.lock();
+        let SlabAllocator {
+            object_caches, size_caches, fallback_allocator, large_allocations,
+        } = &mut *allocator;
+
+        // The cache selection only depends on the layout, so we find the same
+        // cache that served the allocation.
+        match find_cache(object_caches, size_caches, &layout) {
+            Some(cache) => cache.free(ptr, fallback_allocator),
+            None => {
+                let ptr = NonNull::new(ptr).unwrap();
+                fallback_allocator.deallocate(ptr, layout);
+                *large_allocations -= 1;
+            }
+        }
+    }
+}
+
+// ********** Sidenote **********
+//
+// # Slab allocation
+//
+// The slab allocator was introduced by Jeff Bonwick for the SunOS kernel and
+// is used in a similar form by Linux. It builds on two observations:
+//
+// - Kernels allocate a small number of object types over and over again (task
+//   structures, wakers, list nodes, ...). A cache per type wastes no memory on
+//   rounding and keeps objects of the same type close together.
+// - Initializing an object is often more expensive than allocating it. By
+//   returning objects to the cache in their constructed state, the constructor
+//   only runs once per slot instead of once per allocation.
+//
+// Each cache consists of slabs, which are page-sized memory blocks that are
+// split into object slots. A slab is either full, partially used, or empty.
+// Allocations are served from partial slabs first to keep the number of slabs
+// low. When the last object of a slab is freed, the slab is returned to the
+// fallback heap, so that memory used for a burst of small objects can later be
+// reused for other sizes. This is the main advantage over our fixed-size block
+// allocator, which never gives blocks back.
+//
+// Because slabs are aligned to their size, the slab of an object can be found
+// by clearing the lower bits of the object address, so no lookup structure is
+// needed on deallocation.
@@ -0,0 +1,155 @@
+//! # Heap statistics module
+//!
+//! A common interface for querying how much memory an allocator has handed
+//! out, how much is still free, and how fragmented the free memory is.
+//!
+//! The statistics are collected while the allocator is locked, so collecting
+//! them must not allocate. For this reason, all types in this module have a
+//! fixed size and can be returned by value.
+
+use core::fmt;
+
+/// The maximum number of size classes that a `HeapReport` can hold.
+pub const MAX_SIZE_CLASSES: usize = 16;
+
+/// The statistics of a single size class of a block-based allocator.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SizeClass {
+    /// The size of the blocks in this class.
+    pub block_size: usize,
+    /// The number of blocks in the free list of this class.
+    pub free_blocks: usize,
+}
+
+/// A fixed-capacity list of size classes.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SizeClasses {
+    classes: [SizeClass; MAX_SIZE_CLASSES],
+    len: usize,
+}
+
+impl SizeClasses {
+    /// Creates an empty list.
+    pub const fn new() -> Self {
+        SizeClasses {
+            classes: [SizeClass { block_size: 0, free_blocks: 0 }; MAX_SIZE_CLASSES],
+            len: 0,
+        }
+    }
+
+    /// Appends a size class to the list.
+    ///
+    /// Panics if the list already holds `MAX_SIZE_CLASSES` entries.
+    pub fn push(&mut self, class: SizeClass) {
+        assert!(self.len < MAX_SIZE_CLASSES, "too many size classes");
+        self.classes[self.len] = class;
+        self.len += 1;
+    }
+
+    /// Returns the size classes as a slice.
+    pub fn as_slice(&self) -> &[SizeClass] {
+        &self.classes[..self.len]
+    }
+
+    /// Returns the number of free bytes in all free lists together.
+    pub fn free_bytes(&self) -> usize {
+        self.as_slice()
+            .iter()
+            .map(|class| class.block_size * class.free_blocks)
+            .sum()
+    }
+}
+
+/// Statistics that every heap allocator of our kernel provides.
+///
+/// All byte counts include the bookkeeping overhead of the allocator (e.g.
+/// padding for alignment or rounding up to a block size), so `bytes_allocated`
+/// and `bytes_free` add up to roughly `heap_size`.
+pub trait HeapStats {
+    /// The size of the heap memory that the allocator currently manages.
+    fn heap_size(&self) -> usize;
+
+    /// The number of bytes that are currently not available for allocations.
+    fn bytes_allocated(&self) -> usize;
+
+    /// The number of bytes that are currently available for allocations.
+    fn bytes_free(&self) -> usize;
+
+    /// The number of allocations that were not freed yet.
+    fn allocation_count(&self) -> usize;
+
+    /// The size of the largest contiguous free region, or `None` if the
+    /// allocator can't determine it.
+    ///
+    /// Comparing this value with `bytes_free` shows how fragmented the heap is:
+    /// if it is much smaller, large allocations can fail although enough memory
+    /// is free in total.
+    ///
+    /// The bump and linked list allocators always know the value. The
+    /// fixed-size block, slab, and external allocators return `None`: they
+    /// keep their free memory in the `Heap` of the `linked_list_allocator`
+    /// crate, which doesn't expose its free regions. Their free blocks can't
+    /// stand in for it, since the heap might have larger free regions than
+    /// any block. Use `size_classes` to see how their free blocks are
+    /// distributed.
+    fn largest_free_region(&self) -> Option<usize>;
+
+    /// The lengths of the free lists of a block-based allocator.
+    ///
+    /// The default implementation returns an empty list, which is correct for
+    /// allocators that don't use size classes.
+    fn size_classes(&self) -> SizeClasses {
+        SizeClasses::new()
+    }
+
+    /// Collects all statistics into a single report.
+    fn report(&self) -> HeapReport {
+        HeapReport {
+            heap_size: self.heap_size(),
+            bytes_allocated: self.bytes_allocated(),
+            bytes_free: self.bytes_free(),
+            allocation_count: self.allocation_count(),
+            largest_free_region: self.largest_free_region(),
+            size_classes: self.size_classes(),
+        }
+    }
+}
+
+/// A snapshot of the statistics of an allocator.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapReport {
+    pub heap_size: usize,
+    pub bytes_allocated: usize,
+    pub bytes_free: usize,
+    pub allocation_count: usize,
+    pub largest_free_region: Option<usize>,
+    pub size_classes: SizeClasses,
+}
+
+impl fmt::Display for HeapReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "heap size:           {} bytes", self.heap_size)?;
+        writeln!(f, "allocated:           {} bytes", self.bytes_allocated)?;
+        writeln!(f, "free:                {} bytes", self.bytes_free)?;
+        writeln!(f, "allocations:         {}", self.allocation_count)?;
+        match self.largest_free_region {
+            Some(size) => writeln!(f, "largest free region: {} bytes", size)?,
+            None => writeln!(f, "largest free region: unknown")?,
+        }
+        for class in self.size_classes.as_slice() {
+            writeln!(f, "  {:>5} byte blocks: {} free", class.block_size, class.free_blocks)?;
+        }
+        Ok(())
+    }
+}
+
+// ********** Sidenote **********
+//
+// # Why no `Vec` in the report?
+//
+// A report is usually created by locking the global allocator and querying the
+// statistics. If we created a `Vec` for the size classes at this point, the
+// allocation would try to lock the global allocator a second time, which
+// deadlocks with our spinlock-based `Locked` wrapper. A fixed-size array avoids
+// the problem and is more than large enough for the handful of size classes
+// that our allocators use.
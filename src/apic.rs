@@ -0,0 +1,370 @@
+//! # APIC module
+//!
+//! Support for the Advanced Programmable Interrupt Controller, which replaces
+//! the legacy 8259 PIC on all modern x86 machines.
+//!
+//! Each CPU core has its own _local APIC_, which receives interrupts and
+//! signals them to the core. Interrupts of external devices arrive at an
+//! _I/O APIC_, which forwards them to the local APIC of a core according to
+//! its redirection table.
+//!
+//! `init` detects both through the ACPI MADT, routes all lines that have a
+//! handler (see `interrupts::register_irq`) through the I/O APIC and masks the
+//! 8259 PIC. If the machine has no APIC, the kernel keeps using the 8259 PIC
+//! that `crate::init` set up.
+
+use core::{
+    fmt,
+    ptr,
+    sync::atomic::{ AtomicU64, Ordering },
+};
+use x86_64::{
+    instructions::{ interrupts, port::Port },
+    registers::model_specific::Msr,
+    PhysAddr, VirtAddr,
+};
+use crate::{
+    acpi::{ self, Madt, Polarity, TriggerMode },
+    interrupts::{ irq, SPURIOUS_INTERRUPT_VECTOR },
+    memory,
+};
+
+/// The model specific register that holds the physical base address of the
+/// local APIC and its global enable bit.
+const IA32_APIC_BASE_MSR: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// Offsets of the local APIC registers that we use.
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TASK_PRIORITY: usize = 0x80;
+const LAPIC_EOI: usize = 0xb0;
+const LAPIC_SPURIOUS: usize = 0xf0;
+/// Bit 8 of the spurious interrupt vector register enables the local APIC.
+const LAPIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+
+/// The virtual address of the local APIC registers, or 0 if the APIC is not
+/// used.
+static LOCAL_APIC: AtomicU64 = AtomicU64::new(0);
+
+/// The I/O APICs of the machine, after `init` succeeded.
+static IO_APIC: spin::Mutex<Option<IoApic>> = spin::Mutex::new(None);
+
+/// The MADT, which we need to find the I/O APIC input of an ISA IRQ.
+static MADT: spin::Once<Madt> = spin::Once::new();
+
+/// The reasons why the APIC can't be used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicError {
+    /// The CPU has no local APIC.
+    Unsupported,
+    /// `memory::init_kernel_memory` was not called yet.
+    NoKernelMemory,
+    /// The firmware provides no (valid) MADT.
+    NoMadt,
+    /// The MADT lists no I/O APIC.
+    NoIoApic,
+    /// The registers could not be mapped.
+    MappingFailed,
+}
+
+impl fmt::Display for ApicError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            ApicError::Unsupported => "the CPU has no local APIC",
+            ApicError::NoKernelMemory => "kernel memory is not initialized",
+            ApicError::NoMadt => "no ACPI MADT found",
+            ApicError::NoIoApic => "no I/O APIC found",
+            ApicError::MappingFailed => "failed to map the APIC registers",
+        };
+        f.write_str(message)
+    }
+}
+
+/// An entry of the I/O APIC redirection table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Redirection {
+    /// The interrupt vector that the CPU receives.
+    pub vector: u8,
+    /// The local APIC ID of the CPU that receives the interrupt.
+    pub destination: u8,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+    pub masked: bool,
+}
+
+impl Redirection {
+    fn to_raw(self) -> u64 {
+        let mut raw = u64::from(self.vector);
+        // Delivery mode "fixed" (0) and physical destination mode (0) are the
+        // defaults, so we only need to set the remaining bits.
+        if self.polarity == Polarity::ActiveLow {
+            raw |= 1 << 13;
+        }
+        if self.trigger == TriggerMode::Level {
+            raw |= 1 << 15;
+        }
+        if self.masked {
+            raw |= 1 << 16;
+        }
+        raw | u64::from(self.destination) << 56
+    }
+
+    fn from_raw(raw: u64) -> Redirection {
+        Redirection {
+            vector: raw as u8,
+            destination: (raw >> 56) as u8,
+            polarity: if raw & 1 << 13 != 0 { Polarity::ActiveLow } else { Polarity::ActiveHigh },
+            trigger: if raw & 1 << 15 != 0 { TriggerMode::Level } else { TriggerMode::Edge },
+            masked: raw & 1 << 16 != 0,
+        }
+    }
+}
+
+/// An I/O APIC, accessed through its two memory-mapped registers.
+struct IoApic {
+    /// The virtual address of the registers.
+    base: VirtAddr,
+    /// The first global system interrupt that this I/O APIC handles.
+    gsi_base: u32,
+    /// The number of inputs (i.e. redirection entries).
+    inputs: u32,
+}
+
+impl IoApic {
+    // Offsets of the I/O APIC registers.
+    const REGISTER_SELECT: u64 = 0x00;
+    const REGISTER_WINDOW: u64 = 0x10;
+    const VERSION: u32 = 0x01;
+    const REDIRECTION_TABLE: u32 = 0x10;
+
+    /// This function is unsafe because `base` must point to the mapped
+    /// registers of an I/O APIC.
+    unsafe fn new(base: VirtAddr, gsi_base: u32) -> IoApic {
+        let mut io_apic = IoApic { base, gsi_base, inputs: 0 };
+        // Bits 16–23 of the version register hold the index of the last
+        // redirection entry.
+        io_apic.inputs = ((io_apic.read(Self::VERSION) >> 16) & 0xff) + 1;
+        io_apic
+    }
+
+    /// The I/O APIC has only two registers: we select the internal register
+    /// by writing its index to `REGISTER_SELECT` and then access it through
+    /// `REGISTER_WINDOW`.
+    fn read(&mut self, register: u32) -> u32 {
+        unsafe {
+            ptr::write_volatile((self.base + Self::REGISTER_SELECT).as_mut_ptr(), register);
+            ptr::read_volatile((self.base + Self::REGISTER_WINDOW).as_ptr())
+        }
+    }
+
+    fn write(&mut self, register: u32, value: u32) {
+        unsafe {
+            ptr::write_volatile((self.base + Self::REGISTER_SELECT).as_mut_ptr(), register);
+            ptr::write_volatile((self.base + Self::REGISTER_WINDOW).as_mut_ptr(), value);
+        }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.inputs
+    }
+
+    fn redirection(&mut self, gsi: u32) -> Redirection {
+        let register = Self::REDIRECTION_TABLE + 2 * (gsi - self.gsi_base);
+        let low = u64::from(self.read(register));
+        let high = u64::from(self.read(register + 1));
+        Redirection::from_raw(high << 32 | low)
+    }
+
+    fn set_redirection(&mut self, gsi: u32, entry: Redirection) {
+        let register = Self::REDIRECTION_TABLE + 2 * (gsi - self.gsi_base);
+        let raw = entry.to_raw();
+        // Mask the input while we change the entry, so that no interrupt is
+        // delivered with a half-written entry.
+        self.write(register, 1 << 16);
+        self.write(register + 1, (raw >> 32) as u32);
+        self.write(register, raw as u32);
+    }
+}
+
+/// Switches interrupt handling from the 8259 PIC to the APIC.
+///
+/// Must be called after `memory::init_kernel_memory`, since the APIC registers
+/// need to be mapped. On error, nothing is changed and the 8259 PIC stays
+/// active.
+pub fn init() -> Result<(), ApicError> {
+    if !has_local_apic() {
+        return Err(ApicError::Unsupported);
+    }
+    let physical_memory_offset =
+        memory::physical_memory_offset().ok_or(ApicError::NoKernelMemory)?;
+    let madt = unsafe { acpi::find_madt(physical_memory_offset) }.ok_or(ApicError::NoMadt)?;
+    let madt = MADT.call_once(|| madt);
+    let io_apic_entry = madt.io_apics.first().ok_or(ApicError::NoIoApic)?;
+
+    // The MADT contains the default address, but the firmware or a previous
+    // kernel might have moved the local APIC, so we use the address from the
+    // MSR.
+    let mut apic_base = Msr::new(IA32_APIC_BASE_MSR);
+    let base = unsafe { apic_base.read() };
+    let local_apic_phys = PhysAddr::new(base & 0x000f_ffff_ffff_f000);
+    let local_apic = unsafe { memory::map_mmio(local_apic_phys, 4096) }
+        .ok_or(ApicError::MappingFailed)?;
+    let io_apic_phys = PhysAddr::new(u64::from(io_apic_entry.address));
+    let io_apic_base = unsafe { memory::map_mmio(io_apic_phys, 4096) }
+        .ok_or(ApicError::MappingFailed)?;
+
+    interrupts::without_interrupts(|| {
+        unsafe {
+            disable_pic();
+            apic_base.write(base | APIC_BASE_ENABLE);
+        }
+        LOCAL_APIC.store(local_apic.as_u64(), Ordering::SeqCst);
+        // Accept interrupts of all priorities and enable the local APIC.
+        write_local(LAPIC_TASK_PRIORITY, 0);
+        write_local(
+            LAPIC_SPURIOUS,
+            LAPIC_SOFTWARE_ENABLE | u32::from(SPURIOUS_INTERRUPT_VECTOR),
+        );
+
+        let mut io_apic = unsafe { IoApic::new(io_apic_base, io_apic_entry.gsi_base) };
+        // Mask all inputs, the firmware might have left some of them enabled.
+        for gsi in io_apic.gsi_base..io_apic.gsi_base + io_apic.inputs {
+            let mut entry = io_apic.redirection(gsi);
+            entry.masked = true;
+            io_apic.set_redirection(gsi, entry);
+        }
+        *IO_APIC.lock() = Some(io_apic);
+    });
+    // All I/O APIC inputs are masked until this call routes the lines that
+    // have a handler.
+    irq::route_registered_lines();
+    Ok(())
+}
+
+/// Returns `true` if `init` succeeded and interrupts are handled by the APIC.
+pub fn is_enabled() -> bool {
+    LOCAL_APIC.load(Ordering::Relaxed) != 0
+}
+
+/// Returns the ID of the local APIC of the current CPU.
+pub fn local_apic_id() -> Option<u8> {
+    if is_enabled() {
+        Some((read_local(LAPIC_ID) >> 24) as u8)
+    } else {
+        None
+    }
+}
+
+/// Signals the end of an interrupt to the local APIC.
+///
+/// Unlike the 8259 PIC, the APIC doesn't need to know which interrupt was
+/// handled, it always completes the one with the highest priority.
+pub fn end_of_interrupt() {
+    write_local(LAPIC_EOI, 0);
+}
+
+/// Returns the global system interrupt and signal type of an interrupt line.
+///
+/// Lines 0–15 are the ISA IRQs, which might be connected to a different I/O
+/// APIC input. All higher lines are PCI interrupts, which are active low and
+/// level triggered.
+fn line_to_gsi(line: u8) -> Option<(u32, Polarity, TriggerMode)> {
+    let madt = MADT.r#try()?;
+    if line < 16 {
+        Some(madt.isa_irq(line))
+    } else {
+        Some((u32::from(line), Polarity::ActiveLow, TriggerMode::Level))
+    }
+}
+
+/// Routes the given interrupt line to `vector` on the current CPU.
+///
+/// Returns the global system interrupt that the line is connected to, or
+/// `None` if the APIC is not used or no I/O APIC handles the line.
+pub fn route_irq(line: u8, vector: u8) -> Option<u32> {
+    let (gsi, polarity, trigger) = line_to_gsi(line)?;
+    let destination = local_apic_id()?;
+    set_redirection(gsi, Redirection { vector, destination, polarity, trigger, masked: false })?;
+    Some(gsi)
+}
+
+/// Masks the I/O APIC input of the given interrupt line.
+pub fn mask_irq(line: u8) -> Option<()> {
+    let (gsi, _, _) = line_to_gsi(line)?;
+    let mut entry = redirection(gsi)?;
+    entry.masked = true;
+    set_redirection(gsi, entry)
+}
+
+/// Returns the global system interrupt that the given line is connected to.
+pub fn gsi_of_line(line: u8) -> Option<u32> {
+    line_to_gsi(line).map(|(gsi, _, _)| gsi)
+}
+
+/// Returns the redirection entry of the given global system interrupt.
+pub fn redirection(gsi: u32) -> Option<Redirection> {
+    let mut io_apic = IO_APIC.lock();
+    let io_apic = io_apic.as_mut().filter(|io_apic| io_apic.handles(gsi))?;
+    Some(io_apic.redirection(gsi))
+}
+
+/// Changes the redirection entry of the given global system interrupt.
+///
+/// Returns `None` if no I/O APIC handles the interrupt.
+pub fn set_redirection(gsi: u32, entry: Redirection) -> Option<()> {
+    interrupts::without_interrupts(|| {
+        let mut io_apic = IO_APIC.lock();
+        let io_apic = io_apic.as_mut().filter(|io_apic| io_apic.handles(gsi))?;
+        io_apic.set_redirection(gsi, entry);
+        Some(())
+    })
+}
+
+/// Checks bit 9 of the `edx` register returned by CPUID leaf 1.
+fn has_local_apic() -> bool {
+    let result = unsafe { core::arch::x86_64::__cpuid(1) };
+    result.edx & (1 << 9) != 0
+}
+
+/// Masks all inputs of both 8259 PICs.
+///
+/// The PICs stay remapped to vectors 32–47 by `crate::init`, so a spurious
+/// interrupt that they might still send doesn't look like a CPU exception.
+unsafe fn disable_pic() {
+    Port::<u8>::new(0x21).write(0xff);
+    Port::<u8>::new(0xa1).write(0xff);
+}
+
+fn read_local(register: usize) -> u32 {
+    let base = LOCAL_APIC.load(Ordering::Relaxed) as usize;
+    unsafe { ptr::read_volatile((base + register) as *const u32) }
+}
+
+fn write_local(register: usize, value: u32) {
+    let base = LOCAL_APIC.load(Ordering::Relaxed) as usize;
+    unsafe { ptr::write_volatile((base + register) as *mut u32, value) }
+}
+
+// ********** Sidenote **********
+//
+// # Why replace the 8259 PIC?
+//
+// The two chained 8259 PICs provide only 15 interrupt lines, and they can only
+// deliver interrupts to a single CPU. The APIC architecture splits the job: the
+// I/O APIC receives the device interrupts (24 inputs on most machines) and
+// sends them as messages to the local APIC of any core, which is the
+// prerequisite for using multiple cores later.
+//
+// # Global system interrupts
+//
+// The inputs of all I/O APICs are numbered consecutively, these numbers are
+// called global system interrupts (GSIs). The legacy ISA IRQs are usually
+// connected to the GSI with the same number, with one common exception that is
+// also present in QEMU: the timer (IRQ 0) is connected to GSI 2. The MADT lists
+// such differences as "interrupt source overrides".
+//
+// # Spurious interrupts
+//
+// If an interrupt disappears before the local APIC could deliver it, the local
+// APIC sends the spurious interrupt vector instead. This interrupt must not be
+// acknowledged with an EOI, so it gets its own handler.
@@ -1,16 +1,35 @@
 //! # Interrupts module
 //! 
 //! Handle CPU exceptions in our kernel.
+//!
+//! Every architectural exception has a handler (most of them in the
+//! `exceptions` submodule), which prints a `FaultReport` instead of escalating
+//! to a double fault.
+//!
+//! Hardware interrupts arrive through the legacy 8259 PIC after `crate::init`
+//! and through the APIC once `apic::init` succeeded. Drivers attach their
+//! handlers through `register_irq` (see the `irq` submodule), which also takes
+//! care of the end-of-interrupt signal.
 
 use x86_64::structures::idt::{ InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode };
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin;
-use crate::{ print, println, gdt, hlt_loop };
+use crate::{ println, apic, pit, memory::fault::{ self, FaultOutcome } };
+use exceptions::{ ErrorCode, Exception, ExceptionFrame, FaultAction, FaultReport };
+
+pub mod exceptions;
+pub mod irq;
+
+pub use irq::{ register_irq, unregister_irq, IrqHandle, IrqError, IrqResult };
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
+/// The vector that the local APIC uses for spurious interrupts. The lowest
+/// four bits must be set on older CPUs, so the last vector is a common choice.
+pub const SPURIOUS_INTERRUPT_VECTOR: u8 = 0xff;
+
 /// The timer uses line 0 of the primary PIC. This means that it arrives at the
 /// CPU as interrupt 32 (0 + offset 32). Instead of hardcoding index 32, we
 /// store it in an InterruptIndex enum.
@@ -22,11 +41,11 @@ pub enum InterruptIndex {
 }
 
 impl InterruptIndex {
-    fn as_u8(self) -> u8 {
+    pub fn as_u8(self) -> u8 {
         self as u8
     }
 
-    fn as_usize(self) -> usize {
+    pub fn as_usize(self) -> usize {
         usize::from(self.as_u8())
     }
 }
@@ -57,20 +76,17 @@ lazy_static! {
     /// creates a new `InterruptDescriptorTable`.
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        // Add breakpoint handler to our IDT.
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        // Register a page fault handler in our IDT.
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        unsafe {
-            idt.double_fault.set_handler_fn(double_fault_handler)
-                // Assigns a IST stack to this handler in the IDT
-                // by setting the stack index.
-                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
-        }
-        idt[InterruptIndex::Timer.as_usize()]
-            .set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()]
-            .set_handler_fn(keyboard_interrupt_handler);
+        // All exceptions go through the entry stubs of the `exceptions`
+        // module, which save the registers for the fault report. The stubs
+        // call the breakpoint, page fault, and double fault handlers below.
+        // The double fault handler runs on the IST stack of
+        // `gdt::DOUBLE_FAULT_IST_INDEX`.
+        exceptions::set_handlers(&mut idt);
+        // Hardware interrupts are dispatched to the handlers registered
+        // through `register_irq`.
+        irq::set_trampolines(&mut idt);
+        idt[usize::from(SPURIOUS_INTERRUPT_VECTOR)]
+            .set_handler_fn(spurious_interrupt_handler);
         idt
     };
 }
@@ -79,6 +95,39 @@ pub fn init_idt() {
     // In order that the CPU uses our new interrupt descriptor table, we need to
     // load it using the `lidt` instruction.
     IDT.load();
+
+    // The timer and keyboard handlers don't capture anything, so registering
+    // them doesn't allocate, although the heap doesn't exist yet.
+    let timer_line = InterruptIndex::Timer.as_u8() - irq::IRQ_BASE;
+    register_irq(timer_line, timer_interrupt).expect("failed to register timer handler");
+    let keyboard_line = InterruptIndex::Keyboard.as_u8() - irq::IRQ_BASE;
+    register_irq(keyboard_line, keyboard_interrupt).expect("failed to register keyboard handler");
+}
+
+/// Signals the end of the interrupt with the given vector to the active
+/// interrupt controller.
+///
+/// The PIC expects an explicit “end of interrupt” (EOI) signal from our
+/// interrupt handler. This signal tells the controller that the interrupt was
+/// processed and that the system is ready to receive the next interrupt.
+///
+/// ********** Sidenote **********
+///
+/// With the 8259 PIC, `notify_end_of_interrupt` figures out whether the
+/// primary or secondary PIC sent the interrupt and then uses the command and
+/// data ports to send an EOI signal to respective controllers. If the secondary
+/// PIC sent the interrupt both PICs need to be notified because the secondary
+/// PIC is connected to an input line of the primary PIC.
+///
+/// We need to be careful to use the correct interrupt vector number, otherwise
+/// we could accidentally delete an important unsent interrupt or cause our
+/// system to hang. This is the reason that the function is unsafe.
+unsafe fn end_of_interrupt(vector: u8) {
+    if apic::is_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        PICS.lock().notify_end_of_interrupt(vector);
+    }
 }
 
 /// A handler for the breakpoint exception.
@@ -95,33 +144,41 @@ pub fn init_idt() {
 /// continues the program.
 /// 
 /// For our use case, we don’t need to overwrite any instructions. Instead, we
-/// just want to print a message when the breakpoint instruction is executed and
-/// then continue the program.
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+/// just want to print the fault report when the breakpoint instruction is
+/// executed and then continue the program. Since `#BP` is a trap, returning
+/// continues after the `int3`.
+fn breakpoint_handler(frame: &mut ExceptionFrame) {
+    exceptions::handle(frame, Exception::Breakpoint, None, FaultAction::Continue);
 }
 
 /// A page fault handler.
 /// 
-/// The `PageFaultErrorCode` type provides more information about the type of
-/// memory access that caused the page fault, for example whether it was caused
-/// by a read or write operation.
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
+/// The error code decodes to a `PageFaultErrorCode`, which provides more
+/// information about the type of memory access that caused the page fault, for
+/// example whether it was caused by a read or write operation. The `CR2`
+/// register is automatically set by the CPU on a page fault and contains the
+/// accessed virtual address that caused the page fault.
+///
+/// The handler first asks the region table of `memory::fault` to resolve the
+/// fault. Only if no region does, it falls back to the fault report.
+fn page_fault_handler(frame: &mut ExceptionFrame) {
     use x86_64::registers::control::Cr2;
 
-    println!("EXCEPTION: PAGE FAULT");
-    // The `CR2` register is automatically set by the CPU on a page fault and
-    // contains the accessed virtual address that caused the page fault. We use
-    // the `Cr2::read` function to read it.
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    // We can’t continue execution without resolving the page fault, so we enter
-    // a `hlt_loop` at the end.
-    hlt_loop();
+    let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+    let address = Cr2::read();
+    match fault::handle_page_fault(address, error_code) {
+        // Returning restarts the faulting instruction, which now succeeds.
+        FaultOutcome::Resolved => return,
+        FaultOutcome::Fatal(reason) => match fault::region_at(address) {
+            Some(region) => println!("Page fault in region `{}`: {}", region.name, reason),
+            None => println!("Page fault: {}", reason),
+        },
+        FaultOutcome::Unresolved => {}
+    }
+    // We can’t continue execution without resolving the page fault, so we
+    // panic unless a fault hook resolves it.
+    let error_code = Some(ErrorCode::PageFault(error_code));
+    exceptions::handle(frame, Exception::PageFault, error_code, FaultAction::Panic);
 }
 
 /// A double fault handler.
@@ -131,50 +188,22 @@ extern "x86-interrupt" fn page_fault_handler(
 /// One difference to the breakpoint handler is that the double fault handler is
 /// diverging. The reason is that the x86_64 architecture does not permit
 /// returning from a double fault exception.
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame, _error_code: u64) -> !
-{
-    // Prints a short error message and dumps the exception stack frame. The
-    // error code of the double fault handler is always zero, so there’s no
-    // reason to print it.
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+fn double_fault_handler(frame: &mut ExceptionFrame) -> ! {
+    // Prints the fault report. The error code of the double fault handler is
+    // always zero, so there’s no reason to include it.
+    panic!("{}", FaultReport::capture(Exception::DoubleFault, None, frame));
 }
 
 // A handler function for the timer interrupt.
-extern "x86-interrupt" fn timer_interrupt_handler(
-    _stack_frame: InterruptStackFrame)
-{
-    // As the timer interrupt happens periodically, we would expect to see a dot
-    // appearing on each timer tick.
-    print!(".");
-
-    // End of interrupt.
-    //
-    // The PIC expects an explicit “end of interrupt” (EOI) signal from our
-    // interrupt handler. This signal tells the controller that the interrupt
-    // was processed and that the system is ready to receive the next interrupt.
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-        // ********** Sidenote **********
-        //
-        // The `notify_end_of_interrupt` figures out whether the primary or
-        // secondary PIC sent the interrupt and then uses the command and data
-        // ports to send an EOI signal to respective controllers. If the
-        // secondary PIC sent the interrupt both PICs need to be notified
-        // because the secondary PIC is connected to an input line of the
-        // primary PIC.
-        // 
-        // We need to be careful to use the correct interrupt vector number,
-        // otherwise we could accidentally delete an important unsent interrupt
-        // or cause our system to hang. This is the reason that the function is
-        // unsafe.
-    }
+fn timer_interrupt() -> IrqResult {
+    // Advances the kernel clock by one tick and wakes the tasks whose sleep
+    // ended.
+    pit::tick();
+    crate::task::timer::on_tick();
+    IrqResult::Handled
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(
-    _stack_frame: InterruptStackFrame)
-{
+fn keyboard_interrupt() -> IrqResult {
     use x86_64::instructions::port::Port;
 
     // Read the scancode from the keyboard controller.
@@ -189,11 +218,14 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(
 
     // Replaced the keyboard handling code in this handler.
     crate::task::keyboard::add_scancode(scancode);
+    IrqResult::Handled
+}
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
+// A handler for spurious interrupts of the local APIC. They must not be
+// acknowledged, so there is nothing to do.
+extern "x86-interrupt" fn spurious_interrupt_handler(
+    _stack_frame: InterruptStackFrame)
+{
 }
 
 // ********** Sidenote **********
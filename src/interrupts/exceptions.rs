@@ -0,0 +1,458 @@
+//! # CPU exceptions
+//!
+//! Handlers for all architectural exception vectors and a uniform report of
+//! the faulting state.
+//!
+//! A fault that the kernel can't resolve panics with a `FaultReport`, which
+//! contains the decoded error code, the interrupt stack frame, the general
+//! purpose registers, and the control registers. Without these handlers, an
+//! exception such as a division by zero would escalate to a double fault,
+//! which tells us nothing about the cause.
+//!
+//! A `FaultHook` can take over before the kernel panics, e.g. to skip the
+//! faulting instruction in a test.
+
+use core::fmt;
+use x86_64::{
+    registers::control::{ Cr0, Cr2, Cr3, Cr4 },
+    structures::idt::{ InterruptDescriptorTable, InterruptStackFrameValue, PageFaultErrorCode },
+    VirtAddr,
+};
+use crate::println;
+
+mod entry;
+pub mod skip;
+
+pub use entry::{ ExceptionFrame, GeneralRegisters };
+
+/// The architectural exceptions that have a handler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Exception {
+    DivideError = 0,
+    Debug = 1,
+    NonMaskableInterrupt = 2,
+    Breakpoint = 3,
+    Overflow = 4,
+    BoundRangeExceeded = 5,
+    InvalidOpcode = 6,
+    DeviceNotAvailable = 7,
+    DoubleFault = 8,
+    InvalidTss = 10,
+    SegmentNotPresent = 11,
+    StackSegmentFault = 12,
+    GeneralProtectionFault = 13,
+    PageFault = 14,
+    X87FloatingPoint = 16,
+    AlignmentCheck = 17,
+    MachineCheck = 18,
+    SimdFloatingPoint = 19,
+    Virtualization = 20,
+    VmmCommunication = 29,
+    Security = 30,
+}
+
+impl Exception {
+    /// The interrupt vector of the exception.
+    pub fn vector(self) -> u8 {
+        self as u8
+    }
+
+    /// The exception with the given vector, if it is one that has a handler.
+    pub fn from_vector(vector: u8) -> Option<Exception> {
+        let exception = match vector {
+            0 => Exception::DivideError,
+            1 => Exception::Debug,
+            2 => Exception::NonMaskableInterrupt,
+            3 => Exception::Breakpoint,
+            4 => Exception::Overflow,
+            5 => Exception::BoundRangeExceeded,
+            6 => Exception::InvalidOpcode,
+            7 => Exception::DeviceNotAvailable,
+            8 => Exception::DoubleFault,
+            10 => Exception::InvalidTss,
+            11 => Exception::SegmentNotPresent,
+            12 => Exception::StackSegmentFault,
+            13 => Exception::GeneralProtectionFault,
+            14 => Exception::PageFault,
+            16 => Exception::X87FloatingPoint,
+            17 => Exception::AlignmentCheck,
+            18 => Exception::MachineCheck,
+            19 => Exception::SimdFloatingPoint,
+            20 => Exception::Virtualization,
+            29 => Exception::VmmCommunication,
+            30 => Exception::Security,
+            _ => return None,
+        };
+        Some(exception)
+    }
+
+    /// Decodes the raw error code that the CPU pushed for this exception.
+    ///
+    /// Returns `None` for exceptions without an error code and for double
+    /// faults, whose error code is always zero.
+    pub fn error_code(self, code: u64) -> Option<ErrorCode> {
+        match self {
+            Exception::InvalidTss
+            | Exception::SegmentNotPresent
+            | Exception::StackSegmentFault
+            | Exception::GeneralProtectionFault => {
+                Some(ErrorCode::Selector(SelectorErrorCode(code)))
+            }
+            Exception::PageFault => {
+                Some(ErrorCode::PageFault(PageFaultErrorCode::from_bits_truncate(code)))
+            }
+            Exception::AlignmentCheck | Exception::VmmCommunication | Exception::Security => {
+                Some(ErrorCode::Raw(code))
+            }
+            _ => None,
+        }
+    }
+
+    /// The short name that the Intel manuals use, e.g. `#GP`.
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            Exception::DivideError => "#DE",
+            Exception::Debug => "#DB",
+            Exception::NonMaskableInterrupt => "NMI",
+            Exception::Breakpoint => "#BP",
+            Exception::Overflow => "#OF",
+            Exception::BoundRangeExceeded => "#BR",
+            Exception::InvalidOpcode => "#UD",
+            Exception::DeviceNotAvailable => "#NM",
+            Exception::DoubleFault => "#DF",
+            Exception::InvalidTss => "#TS",
+            Exception::SegmentNotPresent => "#NP",
+            Exception::StackSegmentFault => "#SS",
+            Exception::GeneralProtectionFault => "#GP",
+            Exception::PageFault => "#PF",
+            Exception::X87FloatingPoint => "#MF",
+            Exception::AlignmentCheck => "#AC",
+            Exception::MachineCheck => "#MC",
+            Exception::SimdFloatingPoint => "#XM",
+            Exception::Virtualization => "#VE",
+            Exception::VmmCommunication => "#VC",
+            Exception::Security => "#SX",
+        }
+    }
+
+    /// The full name of the exception in upper case.
+    pub fn name(self) -> &'static str {
+        match self {
+            Exception::DivideError => "DIVIDE ERROR",
+            Exception::Debug => "DEBUG",
+            Exception::NonMaskableInterrupt => "NON-MASKABLE INTERRUPT",
+            Exception::Breakpoint => "BREAKPOINT",
+            Exception::Overflow => "OVERFLOW",
+            Exception::BoundRangeExceeded => "BOUND RANGE EXCEEDED",
+            Exception::InvalidOpcode => "INVALID OPCODE",
+            Exception::DeviceNotAvailable => "DEVICE NOT AVAILABLE",
+            Exception::DoubleFault => "DOUBLE FAULT",
+            Exception::InvalidTss => "INVALID TSS",
+            Exception::SegmentNotPresent => "SEGMENT NOT PRESENT",
+            Exception::StackSegmentFault => "STACK SEGMENT FAULT",
+            Exception::GeneralProtectionFault => "GENERAL PROTECTION FAULT",
+            Exception::PageFault => "PAGE FAULT",
+            Exception::X87FloatingPoint => "X87 FLOATING POINT",
+            Exception::AlignmentCheck => "ALIGNMENT CHECK",
+            Exception::MachineCheck => "MACHINE CHECK",
+            Exception::SimdFloatingPoint => "SIMD FLOATING POINT",
+            Exception::Virtualization => "VIRTUALIZATION",
+            Exception::VmmCommunication => "VMM COMMUNICATION",
+            Exception::Security => "SECURITY",
+        }
+    }
+}
+
+/// The descriptor table that a selector error code refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorTable {
+    Gdt,
+    Idt,
+    Ldt,
+}
+
+/// The error code of `#TS`, `#NP`, `#SS`, and `#GP`, which describes the
+/// segment selector or IDT entry that caused the fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelectorErrorCode(pub u64);
+
+impl SelectorErrorCode {
+    /// Whether the exception was caused by an event external to the program,
+    /// e.g. a hardware interrupt.
+    pub fn external(self) -> bool {
+        self.0 & 1 != 0
+    }
+
+    /// The table that contains the descriptor.
+    pub fn table(self) -> DescriptorTable {
+        match (self.0 >> 1) & 0b11 {
+            0b00 => DescriptorTable::Gdt,
+            0b10 => DescriptorTable::Ldt,
+            // 0b01 and 0b11 both mean IDT
+            _ => DescriptorTable::Idt,
+        }
+    }
+
+    /// The index of the descriptor in its table.
+    pub fn index(self) -> u16 {
+        ((self.0 >> 3) & 0x1fff) as u16
+    }
+
+    /// Many faults (e.g. a `#GP` caused by a non-canonical address) are not
+    /// related to a selector and push an error code of zero.
+    pub fn is_null(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_null() {
+            return write!(f, "0x0 (no selector)");
+        }
+        write!(f, "{:#x} (index {} in {:?}", self.0, self.index(), self.table())?;
+        if self.external() {
+            write!(f, ", external")?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// The decoded error code of an exception.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    Selector(SelectorErrorCode),
+    PageFault(PageFaultErrorCode),
+    /// An error code without a known structure (or one that is always zero).
+    Raw(u64),
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorCode::Selector(code) => write!(f, "{}", code),
+            ErrorCode::PageFault(code) => write!(f, "{:#x} ({:?})", code.bits(), code),
+            ErrorCode::Raw(code) => write!(f, "{:#x}", code),
+        }
+    }
+}
+
+/// A snapshot of the CPU state when an exception occurred.
+#[derive(Clone, Copy)]
+pub struct FaultReport {
+    pub exception: Exception,
+    pub error_code: Option<ErrorCode>,
+    pub stack_frame: InterruptStackFrameValue,
+    /// The general purpose registers of the interrupted code.
+    pub registers: GeneralRegisters,
+    pub cr0: u64,
+    /// The accessed address of the last page fault.
+    pub cr2: VirtAddr,
+    pub cr3: u64,
+    pub cr4: u64,
+}
+
+impl FaultReport {
+    /// Collects the state for the given exception.
+    pub fn capture(
+        exception: Exception,
+        error_code: Option<ErrorCode>,
+        frame: &ExceptionFrame,
+    ) -> FaultReport {
+        FaultReport {
+            exception,
+            error_code,
+            stack_frame: frame.stack_frame,
+            registers: frame.registers,
+            cr0: Cr0::read_raw(),
+            cr2: Cr2::read(),
+            cr3: Cr3::read_raw().0.start_address().as_u64(),
+            cr4: Cr4::read_raw(),
+        }
+    }
+}
+
+impl fmt::Display for FaultReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let frame = &self.stack_frame;
+        writeln!(
+            f,
+            "EXCEPTION: {} ({}, vector {})",
+            self.exception.name(), self.exception.mnemonic(), self.exception.vector(),
+        )?;
+        if let Some(error_code) = self.error_code {
+            writeln!(f, "Error code: {}", error_code)?;
+        }
+        writeln!(
+            f,
+            "RIP: {:#018x}  CS: {:#06x}  RFLAGS: {:#018x}",
+            frame.instruction_pointer.as_u64(), frame.code_segment, frame.cpu_flags,
+        )?;
+        writeln!(
+            f,
+            "RSP: {:#018x}  SS: {:#06x}",
+            frame.stack_pointer.as_u64(), frame.stack_segment,
+        )?;
+        writeln!(f, "{}", self.registers)?;
+        writeln!(f, "CR0: {:#018x}  CR2: {:#018x}", self.cr0, self.cr2.as_u64())?;
+        write!(f, "CR3: {:#018x}  CR4: {:#018x}", self.cr3, self.cr4)
+    }
+}
+
+/// What happens after an exception was reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultAction {
+    /// Panic with the fault report.
+    Panic,
+    /// Return from the handler. For faults, this restarts the faulting
+    /// instruction, which only makes sense if its cause was resolved.
+    Continue,
+    /// Continue execution at the given address.
+    ResumeAt(VirtAddr),
+}
+
+/// A function that decides how to handle an exception instead of the kernel.
+pub type FaultHook = fn(&FaultReport) -> FaultAction;
+
+static FAULT_HOOK: spin::Mutex<Option<FaultHook>> = spin::Mutex::new(None);
+
+/// Installs a hook that is called for every exception except double faults
+/// and machine checks, which can't be recovered from. Pass `None` to remove
+/// it again.
+pub fn set_fault_hook(hook: Option<FaultHook>) {
+    x86_64::instructions::interrupts::without_interrupts(|| *FAULT_HOOK.lock() = hook);
+}
+
+/// Reports an exception and applies the resulting `FaultAction`.
+///
+/// `default` is used if no hook is installed. The frame must be the one that
+/// the entry stub passed to the handler, so that `ResumeAt` changes the real
+/// return address.
+pub(super) fn handle(
+    frame: &mut ExceptionFrame,
+    exception: Exception,
+    error_code: Option<ErrorCode>,
+    default: FaultAction,
+) {
+    let report = FaultReport::capture(exception, error_code, frame);
+    // The exception might have interrupted `set_fault_hook`, so we must not
+    // wait for the lock.
+    let hook = FAULT_HOOK.try_lock().and_then(|hook| *hook);
+    let action = hook.map_or(default, |hook| hook(&report));
+    match action {
+        FaultAction::Panic => panic!("{}", report),
+        // Without a hook, we at least tell the user what happened.
+        FaultAction::Continue if hook.is_none() => println!("{}", report),
+        FaultAction::Continue => {}
+        FaultAction::ResumeAt(address) => frame.stack_frame.instruction_pointer = address,
+    }
+}
+
+/// Points all exception vectors of the given IDT to the entry stubs, which
+/// call `dispatch`.
+///
+/// The double fault uses its own stack, see `gdt::DOUBLE_FAULT_IST_INDEX`.
+pub(super) fn set_handlers(idt: &mut InterruptDescriptorTable) {
+    entry::set_entries(idt);
+}
+
+/// Handles the exception that the entry stub saved in `frame`.
+///
+/// The breakpoint, page fault, and double fault handlers live in
+/// `interrupts.rs` itself. All other exceptions are reported and panic
+/// unless a hook resolves them.
+fn dispatch(frame: &mut ExceptionFrame) {
+    let exception = match Exception::from_vector(frame.vector as u8) {
+        Some(exception) => exception,
+        // The stubs only exist for the vectors of `Exception`.
+        None => panic!("exception entry with unknown vector {}", frame.vector),
+    };
+    match exception {
+        Exception::Breakpoint => super::breakpoint_handler(frame),
+        Exception::PageFault => super::page_fault_handler(frame),
+        Exception::DoubleFault => super::double_fault_handler(frame),
+        Exception::Debug => debug_handler(frame),
+        Exception::NonMaskableInterrupt => non_maskable_interrupt_handler(frame),
+        Exception::MachineCheck => machine_check_handler(frame),
+        _ => {
+            let error_code = exception.error_code(frame.error_code);
+            handle(frame, exception, error_code, FaultAction::Panic);
+        }
+    }
+}
+
+/// The debug exception is a trap (e.g. after a single step), so execution can
+/// simply continue after it was reported.
+fn debug_handler(frame: &mut ExceptionFrame) {
+    // We don't support single-stepping yet, so we clear the trap flag.
+    // Otherwise, the exception would occur again after every instruction.
+    frame.stack_frame.cpu_flags &= !(1 << 8);
+    handle(frame, Exception::Debug, None, FaultAction::Continue);
+}
+
+/// An NMI signals a hardware problem (e.g. a memory parity error) or a
+/// watchdog. We can't do anything about it, but it is no reason to stop.
+fn non_maskable_interrupt_handler(frame: &mut ExceptionFrame) {
+    handle(frame, Exception::NonMaskableInterrupt, None, FaultAction::Continue);
+}
+
+/// After a machine check, the state of the CPU is undefined, so the handler
+/// must not return.
+fn machine_check_handler(frame: &mut ExceptionFrame) -> ! {
+    panic!("{}", FaultReport::capture(Exception::MachineCheck, None, frame));
+}
+
+// ********** Sidenote **********
+//
+// # Faults, traps, and aborts
+//
+// The Intel manuals divide exceptions into three classes:
+//
+// - A _fault_ (e.g. `#PF` or `#GP`) is reported before the faulting
+//   instruction completes. The saved instruction pointer points to the
+//   faulting instruction, so returning from the handler restarts it. This is
+//   why returning only makes sense if the handler resolved the cause, e.g. by
+//   mapping the missing page.
+// - A _trap_ (e.g. `#DB` after a single step or `#BP`) is reported after the
+//   instruction completed, so execution continues with the next instruction.
+// - An _abort_ (`#DF` and `#MC`) doesn't allow a reliable restart at all.
+//
+// # Register state
+//
+// The `x86-interrupt` calling convention saves all registers that the handler
+// uses, but it doesn't give us access to their values. This is why the
+// exception vectors point to the small assembly stubs of the `entry` module
+// instead of `x86-interrupt` functions. A stub pushes a dummy error code if
+// the CPU didn't push one, the vector, and then all general purpose registers.
+// Together with what the CPU pushed (instruction pointer, code segment, flags,
+// stack pointer, stack segment), this forms an `ExceptionFrame` on the stack,
+// whose address the stub passes to `dispatch`.
+//
+// Because the handler works on the saved registers, a change to the frame
+// (e.g. the instruction pointer for `FaultAction::ResumeAt`) takes effect when
+// the stub restores the registers and returns with `iretq`.
+//
+// The segment registers other than `cs` and `ss` and the x87 state are not
+// part of the report. Our kernel doesn't use them, and the x87 state would
+// have to be saved with `fxsave` before any handler code runs.
+//
+// # Not all exceptions can occur
+//
+// `tests/exceptions.rs` triggers every exception that our kernel can cause
+// on purpose. This is the complete list of the ones it can't:
+//
+// - `#OF` and `#BR` are raised by the `into` and `bound` instructions, which
+//   are invalid in 64-bit mode.
+// - `#DF` only occurs if the handler of another exception fails. Instead of
+//   breaking the IDT of the exception test, `tests/stack_overflow.rs` causes
+//   one with its own IDT.
+// - `#TS` requires a hardware task switch, which doesn't exist in 64-bit mode.
+// - `#AC` requires user mode, because alignment checks only apply at CPL 3.
+// - `#XM` requires SSE, which our target disables.
+// - `#MC` is reported by the hardware itself, e.g. for a bus error.
+// - `#VE` and `#VC` only occur in virtual machines that use EPT violations
+//   or AMD SEV-ES, and `#SX` only under AMD SVM security features.
+//
+// We still install handlers for them, so that any unexpected exception
+// produces a report instead of a double fault.
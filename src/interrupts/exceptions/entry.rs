@@ -0,0 +1,228 @@
+//! # Exception entry
+//!
+//! The assembly entry points of the exception handlers.
+//!
+//! The `x86-interrupt` calling convention doesn't tell a handler the values
+//! of the general purpose registers of the interrupted code. So every
+//! exception vector has a small stub that pushes them to the stack, next to
+//! the frame that the CPU pushed, and then calls `dispatch` with a pointer to
+//! the resulting `ExceptionFrame`. When `dispatch` returns, the stub restores
+//! the registers and returns with `iretq`.
+
+use core::{ arch::global_asm, fmt };
+use x86_64::{
+    structures::idt::{ InterruptDescriptorTable, InterruptStackFrameValue },
+    VirtAddr,
+};
+
+/// The general purpose registers of the interrupted code, in the order in
+/// which the entry stub leaves them on the stack.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct GeneralRegisters {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+}
+
+impl fmt::Display for GeneralRegisters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rows = [
+            [("RAX", self.rax), ("RBX", self.rbx), ("RCX", self.rcx)],
+            [("RDX", self.rdx), ("RSI", self.rsi), ("RDI", self.rdi)],
+            [("RBP", self.rbp), ("R8 ", self.r8), ("R9 ", self.r9)],
+            [("R10", self.r10), ("R11", self.r11), ("R12", self.r12)],
+            [("R13", self.r13), ("R14", self.r14), ("R15", self.r15)],
+        ];
+        for (i, row) in rows.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            let [(a, a_value), (b, b_value), (c, c_value)] = *row;
+            write!(
+                f,
+                "{}: {:#018x}  {}: {:#018x}  {}: {:#018x}",
+                a, a_value, b, b_value, c, c_value,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Everything that is on the stack when the entry stub calls `dispatch`.
+///
+/// Changes to the registers or the stack frame take effect when the handler
+/// returns.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct ExceptionFrame {
+    pub registers: GeneralRegisters,
+    /// The vector of the exception, pushed by the stub.
+    pub vector: u64,
+    /// The error code that the CPU pushed, or 0 for exceptions without one,
+    /// for which the stub pushes a 0 instead.
+    pub error_code: u64,
+    pub stack_frame: InterruptStackFrameValue,
+}
+
+// The part of the entry that all vectors share. It saves the registers in the
+// reverse order of `GeneralRegisters`, so that `rsp` points to an
+// `ExceptionFrame` afterwards.
+//
+// The System V ABI requires a 16-byte aligned stack at the call. The CPU
+// aligns the stack before it pushes its frame, but we don't rely on that and
+// align it ourselves. `rbp` is preserved by the call, so we can keep the old
+// stack pointer in it.
+global_asm!(
+    ".pushsection .text.exception_entry, \"ax\"",
+    ".global tiny_os_exception_entry_common",
+    "tiny_os_exception_entry_common:",
+    "cld",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rdi, rsp",
+    "mov rbp, rsp",
+    "and rsp, -16",
+    "call tiny_os_exception_dispatch",
+    "mov rsp, rbp",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    // Remove the vector and the error code.
+    "add rsp, 16",
+    "iretq",
+    ".popsection",
+);
+
+/// Defines the entry stub of one vector. Vectors without an error code push a
+/// 0 in its place, so that all frames look the same.
+macro_rules! entry_stub {
+    ($name:ident, $vector:literal) => {
+        entry_stub!($name, $vector, "push 0");
+    };
+    ($name:ident, $vector:literal, error_code) => {
+        // The CPU already pushed the error code, and `nop` keeps the template
+        // the same as above.
+        entry_stub!($name, $vector, "nop");
+    };
+    ($name:ident, $vector:literal, $error_code:literal) => {
+        global_asm!(
+            ".pushsection .text.exception_entry, \"ax\"",
+            concat!(".global ", stringify!($name)),
+            concat!(stringify!($name), ":"),
+            $error_code,
+            concat!("push ", stringify!($vector)),
+            "jmp tiny_os_exception_entry_common",
+            ".popsection",
+        );
+        extern "C" {
+            fn $name();
+        }
+    };
+}
+
+entry_stub!(tiny_os_exception_entry_0, 0);
+entry_stub!(tiny_os_exception_entry_1, 1);
+entry_stub!(tiny_os_exception_entry_2, 2);
+entry_stub!(tiny_os_exception_entry_3, 3);
+entry_stub!(tiny_os_exception_entry_4, 4);
+entry_stub!(tiny_os_exception_entry_5, 5);
+entry_stub!(tiny_os_exception_entry_6, 6);
+entry_stub!(tiny_os_exception_entry_7, 7);
+entry_stub!(tiny_os_exception_entry_8, 8, error_code);
+entry_stub!(tiny_os_exception_entry_10, 10, error_code);
+entry_stub!(tiny_os_exception_entry_11, 11, error_code);
+entry_stub!(tiny_os_exception_entry_12, 12, error_code);
+entry_stub!(tiny_os_exception_entry_13, 13, error_code);
+entry_stub!(tiny_os_exception_entry_14, 14, error_code);
+entry_stub!(tiny_os_exception_entry_16, 16);
+entry_stub!(tiny_os_exception_entry_17, 17, error_code);
+entry_stub!(tiny_os_exception_entry_18, 18);
+entry_stub!(tiny_os_exception_entry_19, 19);
+entry_stub!(tiny_os_exception_entry_20, 20);
+entry_stub!(tiny_os_exception_entry_29, 29, error_code);
+entry_stub!(tiny_os_exception_entry_30, 30, error_code);
+
+/// Returns the address of an entry stub.
+fn addr(stub: unsafe extern "C" fn()) -> VirtAddr {
+    VirtAddr::new(stub as usize as u64)
+}
+
+/// Points the exception entries of the IDT to the stubs.
+///
+/// The double fault runs on its own stack, see `gdt`, so that a kernel stack
+/// overflow doesn't escalate to a triple fault.
+pub(super) fn set_entries(idt: &mut InterruptDescriptorTable) {
+    // The stubs expect the frame that the CPU pushes for their vector, with or
+    // without an error code, which is why `set_handler_addr` is unsafe.
+    unsafe {
+        idt.divide_error.set_handler_addr(addr(tiny_os_exception_entry_0));
+        idt.debug.set_handler_addr(addr(tiny_os_exception_entry_1));
+        idt.non_maskable_interrupt.set_handler_addr(addr(tiny_os_exception_entry_2));
+        idt.breakpoint.set_handler_addr(addr(tiny_os_exception_entry_3));
+        idt.overflow.set_handler_addr(addr(tiny_os_exception_entry_4));
+        idt.bound_range_exceeded.set_handler_addr(addr(tiny_os_exception_entry_5));
+        idt.invalid_opcode.set_handler_addr(addr(tiny_os_exception_entry_6));
+        idt.device_not_available.set_handler_addr(addr(tiny_os_exception_entry_7));
+        idt.double_fault
+            .set_handler_addr(addr(tiny_os_exception_entry_8))
+            .set_stack_index(crate::gdt::DOUBLE_FAULT_IST_INDEX);
+        idt.invalid_tss.set_handler_addr(addr(tiny_os_exception_entry_10));
+        idt.segment_not_present.set_handler_addr(addr(tiny_os_exception_entry_11));
+        idt.stack_segment_fault.set_handler_addr(addr(tiny_os_exception_entry_12));
+        idt.general_protection_fault.set_handler_addr(addr(tiny_os_exception_entry_13));
+        idt.page_fault.set_handler_addr(addr(tiny_os_exception_entry_14));
+        idt.x87_floating_point.set_handler_addr(addr(tiny_os_exception_entry_16));
+        idt.alignment_check.set_handler_addr(addr(tiny_os_exception_entry_17));
+        idt.machine_check.set_handler_addr(addr(tiny_os_exception_entry_18));
+        idt.simd_floating_point.set_handler_addr(addr(tiny_os_exception_entry_19));
+        idt.virtualization.set_handler_addr(addr(tiny_os_exception_entry_20));
+        idt.vmm_communication_exception.set_handler_addr(addr(tiny_os_exception_entry_29));
+        idt.security_exception.set_handler_addr(addr(tiny_os_exception_entry_30));
+    }
+}
+
+/// Called by the stubs with the state of the interrupted code.
+///
+/// The stubs refer to it by name, which is why the name must not be mangled.
+#[no_mangle]
+extern "C" fn tiny_os_exception_dispatch(frame: &mut ExceptionFrame) {
+    super::dispatch(frame);
+}
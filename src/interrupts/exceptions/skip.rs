@@ -0,0 +1,60 @@
+//! # Skipping faulting instructions
+//!
+//! A fault hook for tests that cause exceptions on purpose. The
+//! `trigger_exception!` macro executes an instruction after storing the
+//! address behind it, and `record_and_skip` records the report of the
+//! exception and resumes execution at that address. This way, a test doesn't
+//! need to know the length of the faulting instruction.
+//!
+//! Install the hook with `set_fault_hook(Some(skip::record_and_skip))` and
+//! read the report with `take_last_fault` after the exception.
+
+use core::sync::atomic::{ AtomicU64, Ordering };
+use x86_64::VirtAddr;
+use super::{ FaultAction, FaultReport };
+
+/// The address behind the instruction of the last `trigger_exception!`.
+///
+/// Only public for the macro; use `resume_address` to read it.
+#[doc(hidden)]
+pub static RESUME_AT: AtomicU64 = AtomicU64::new(0);
+
+/// The report of the last exception that `record_and_skip` saw.
+static LAST_FAULT: spin::Mutex<Option<FaultReport>> = spin::Mutex::new(None);
+
+/// A `FaultHook` that records the report and continues behind the instruction
+/// of the last `trigger_exception!`.
+pub fn record_and_skip(report: &FaultReport) -> FaultAction {
+    *LAST_FAULT.lock() = Some(*report);
+    FaultAction::ResumeAt(resume_address())
+}
+
+/// Takes the report that `record_and_skip` recorded last, if there is one.
+pub fn take_last_fault() -> Option<FaultReport> {
+    LAST_FAULT.lock().take()
+}
+
+/// The address behind the instruction of the last `trigger_exception!`.
+pub fn resume_address() -> VirtAddr {
+    VirtAddr::new(RESUME_AT.load(Ordering::SeqCst))
+}
+
+/// Executes the given instruction after storing the address behind it for
+/// `record_and_skip`. Additional operands are passed on to `asm!`.
+///
+/// This is unsafe like `asm!` itself.
+#[macro_export]
+macro_rules! trigger_exception {
+    ($instruction:literal $(, $($operands:tt)*)?) => {
+        core::arch::asm!(
+            "lea {resume}, [rip + 2f]",
+            "mov [{slot}], {resume}",
+            $instruction,
+            "2:",
+            resume = out(reg) _,
+            slot = in(reg) &$crate::interrupts::exceptions::skip::RESUME_AT
+                as *const core::sync::atomic::AtomicU64 as *mut u64,
+            $($($operands)*)?
+        )
+    };
+}
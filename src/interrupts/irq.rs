@@ -0,0 +1,274 @@
+//! # IRQ handler registry
+//!
+//! Lets drivers attach handlers to hardware interrupt lines at runtime instead
+//! of adding a function to the IDT in `interrupts.rs`.
+//!
+//! Every line has a small trampoline in the IDT that calls all handlers that
+//! are registered for the line and then sends the end-of-interrupt signal to
+//! the active interrupt controller. Several handlers can share a line, which is
+//! common for PCI devices.
+//!
+//! Line `n` is delivered as interrupt vector `IRQ_BASE + n`. With the 8259 PIC,
+//! the lines are the ISA IRQs 0–15. With the APIC, lines 0–15 are still the ISA
+//! IRQs (the I/O APIC input is looked up in the MADT), while higher lines are
+//! the global system interrupts with the same number.
+
+use alloc::boxed::Box;
+use core::sync::atomic::{ AtomicU64, Ordering };
+use x86_64::{
+    instructions::{ interrupts, port::Port },
+    structures::idt::{ HandlerFunc, InterruptStackFrame },
+};
+use crate::apic;
+use super::{ PIC_1_OFFSET, PICS };
+
+/// The vector of line 0. The following lines use the following vectors.
+pub const IRQ_BASE: u8 = PIC_1_OFFSET;
+/// The number of lines that handlers can be registered for. This matches the
+/// number of inputs of the I/O APIC in QEMU and most PCs.
+pub const NUM_IRQ_LINES: usize = 24;
+/// The maximum number of handlers that can share a single line.
+pub const MAX_HANDLERS_PER_LINE: usize = 4;
+
+/// Tells the dispatcher whether a handler dealt with the interrupt.
+///
+/// On a shared line, every handler must check whether its own device raised
+/// the interrupt and return `NotMine` otherwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqResult {
+    Handled,
+    NotMine,
+}
+
+/// The errors of `register_irq`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// The line is not below `NUM_IRQ_LINES`.
+    InvalidLine,
+    /// `MAX_HANDLERS_PER_LINE` handlers are already registered for the line.
+    LineFull,
+}
+
+/// Identifies a registered handler, see `unregister_irq`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqHandle {
+    line: u8,
+    id: u64,
+}
+
+impl IrqHandle {
+    /// The line that the handler is registered for.
+    pub fn line(&self) -> u8 {
+        self.line
+    }
+}
+
+type Handler = Box<dyn Fn() -> IrqResult + Send + Sync>;
+
+struct Slot {
+    id: u64,
+    handler: Handler,
+}
+
+type Line = [Option<Slot>; MAX_HANDLERS_PER_LINE];
+
+// See `FixedSizeBlockAllocator::new` for why we need these constants.
+const EMPTY_SLOT: Option<Slot> = None;
+const EMPTY_LINE: Line = [EMPTY_SLOT; MAX_HANDLERS_PER_LINE];
+
+/// The handlers of all lines.
+///
+/// The trampolines only need read access, so they can't block each other.
+/// Writers disable interrupts, so a trampoline never waits for a writer on the
+/// same CPU.
+static HANDLERS: spin::RwLock<[Line; NUM_IRQ_LINES]> =
+    spin::RwLock::new([EMPTY_LINE; NUM_IRQ_LINES]);
+
+/// The ID of the next registered handler.
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// The number of interrupts per line that no handler claimed.
+static UNHANDLED: [AtomicU64; NUM_IRQ_LINES] = {
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    [ZERO; NUM_IRQ_LINES]
+};
+
+/// Registers `handler` for the given line and unmasks the line at the active
+/// interrupt controller.
+///
+/// The handler runs in interrupt context, so it must not block on locks that
+/// normal code holds with interrupts enabled, and it should not allocate.
+/// Handlers that don't capture anything are zero-sized, so boxing them doesn't
+/// allocate and they can be registered before the heap is initialized.
+pub fn register_irq<F>(line: u8, handler: F) -> Result<IrqHandle, IrqError>
+where
+    F: Fn() -> IrqResult + Send + Sync + 'static,
+{
+    if usize::from(line) >= NUM_IRQ_LINES {
+        return Err(IrqError::InvalidLine);
+    }
+    let handler: Handler = Box::new(handler);
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+
+    interrupts::without_interrupts(|| {
+        let mut handlers = HANDLERS.write();
+        let slot = handlers[usize::from(line)]
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(IrqError::LineFull)?;
+        *slot = Some(Slot { id, handler });
+        Ok(())
+    })?;
+    enable_line(line);
+    Ok(IrqHandle { line, id })
+}
+
+/// Removes a handler that was registered by `register_irq`.
+///
+/// The line is masked again when its last handler is removed.
+pub fn unregister_irq(handle: IrqHandle) {
+    let (removed, line_empty) = interrupts::without_interrupts(|| {
+        let mut handlers = HANDLERS.write();
+        let line = &mut handlers[usize::from(handle.line)];
+        let removed = line
+            .iter_mut()
+            .find(|slot| slot.as_ref().map_or(false, |s| s.id == handle.id))
+            .and_then(Option::take);
+        (removed, line.iter().all(Option::is_none))
+    });
+    if line_empty {
+        disable_line(handle.line);
+    }
+    // The handler is dropped here, outside of the lock and with interrupts
+    // enabled, since dropping it might free heap memory.
+    drop(removed);
+}
+
+/// Returns `true` if at least one handler is registered for the line.
+pub fn has_handlers(line: u8) -> bool {
+    HANDLERS
+        .read()
+        .get(usize::from(line))
+        .map_or(false, |line| line.iter().any(Option::is_some))
+}
+
+/// Returns the number of interrupts on the line that no handler claimed.
+pub fn unhandled_count(line: u8) -> u64 {
+    UNHANDLED[usize::from(line)].load(Ordering::Relaxed)
+}
+
+/// Sets the trampolines of all lines in the given IDT.
+pub(super) fn set_trampolines(idt: &mut x86_64::structures::idt::InterruptDescriptorTable) {
+    for (line, &trampoline) in IRQ_TRAMPOLINES.iter().enumerate() {
+        idt[usize::from(IRQ_BASE) + line].set_handler_fn(trampoline);
+    }
+}
+
+/// Routes all lines that have handlers through the I/O APIC.
+///
+/// Called by `apic::init` when it takes over from the 8259 PIC.
+pub fn route_registered_lines() {
+    for line in 0..NUM_IRQ_LINES as u8 {
+        if has_handlers(line) {
+            apic::route_irq(line, IRQ_BASE + line);
+        }
+    }
+}
+
+/// Calls all handlers of the line and signals the end of the interrupt.
+fn dispatch(line: u8) {
+    let handled = {
+        let handlers = HANDLERS.read();
+        handlers[usize::from(line)]
+            .iter()
+            .flatten()
+            // We call every handler, even after one handled the interrupt,
+            // since an edge-triggered line only fires once for several devices.
+            .fold(false, |handled, slot| (slot.handler)() == IrqResult::Handled || handled)
+    };
+    if !handled {
+        UNHANDLED[usize::from(line)].fetch_add(1, Ordering::Relaxed);
+    }
+    unsafe { super::end_of_interrupt(IRQ_BASE + line) };
+}
+
+/// Unmasks the line at the active interrupt controller.
+fn enable_line(line: u8) {
+    if apic::is_enabled() {
+        apic::route_irq(line, IRQ_BASE + line);
+    } else if line < 16 {
+        interrupts::without_interrupts(|| unsafe { set_pic_mask(line, false) });
+    }
+}
+
+/// Masks the line at the active interrupt controller.
+fn disable_line(line: u8) {
+    if apic::is_enabled() {
+        apic::mask_irq(line);
+    } else if line < 16 {
+        interrupts::without_interrupts(|| unsafe { set_pic_mask(line, true) });
+    }
+}
+
+/// Changes the mask bit of a line of the 8259 PICs.
+///
+/// We hold the `PICS` lock, so that this doesn't interfere with an EOI.
+unsafe fn set_pic_mask(line: u8, masked: bool) {
+    let _pics = PICS.lock();
+    let (mut port, bit) = if line < 8 {
+        (Port::<u8>::new(0x21), line)
+    } else {
+        // Lines of the secondary PIC only arrive if the cascade line 2 of the
+        // primary PIC is unmasked as well.
+        let mut primary = Port::<u8>::new(0x21);
+        let mask = primary.read();
+        primary.write(mask & !(1 << 2));
+        (Port::<u8>::new(0xa1), line - 8)
+    };
+    let mask = port.read();
+    port.write(if masked { mask | 1 << bit } else { mask & !(1 << bit) });
+}
+
+/// Defines a trampoline function for each line and the `IRQ_TRAMPOLINES`
+/// array that holds them.
+///
+/// The `x86-interrupt` calling convention doesn't tell a handler which vector
+/// it was called for, so every vector needs its own function.
+macro_rules! irq_trampolines {
+    ($($line:literal => $name:ident),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+                dispatch($line);
+            }
+        )*
+
+        const IRQ_TRAMPOLINES: [HandlerFunc; NUM_IRQ_LINES] = [$($name),*];
+    };
+}
+
+irq_trampolines! {
+    0 => irq_0, 1 => irq_1, 2 => irq_2, 3 => irq_3,
+    4 => irq_4, 5 => irq_5, 6 => irq_6, 7 => irq_7,
+    8 => irq_8, 9 => irq_9, 10 => irq_10, 11 => irq_11,
+    12 => irq_12, 13 => irq_13, 14 => irq_14, 15 => irq_15,
+    16 => irq_16, 17 => irq_17, 18 => irq_18, 19 => irq_19,
+    20 => irq_20, 21 => irq_21, 22 => irq_22, 23 => irq_23,
+}
+
+// ********** Sidenote **********
+//
+// # Shared interrupt lines
+//
+// The PIC and the I/O APIC have only a few inputs, so several devices are often
+// connected to the same one. When the line fires, the kernel can't tell which
+// device raised it. Like Linux, we simply ask every registered handler: each
+// driver checks the status register of its device and returns `NotMine` if it
+// has nothing to report. If no handler claims an interrupt, we count it in
+// `unhandled_count`, which helps to debug misconfigured devices.
+//
+// # Why no `Vec` per line?
+//
+// The trampolines run in interrupt context, where allocating is dangerous (the
+// interrupted code might hold the allocator lock). A fixed number of slots per
+// line avoids any allocation in the dispatch path and also keeps the
+// registration possible before the heap exists.
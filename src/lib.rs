@@ -16,21 +16,35 @@ use core::panic::PanicInfo;
 pub mod vga_buffer;
 pub mod serial;
 pub mod interrupts;
+pub mod acpi;
+pub mod apic;
 pub mod gdt;
 pub mod memory;
 pub mod allocator;
 pub mod task;
+pub mod pit;
+pub mod rtc;
+pub mod time;
 
 /// A central place for initialization routines.
 pub fn init() {
     // Loads our GDT.
     gdt::init();
-    // Creates a new IDT.
+    
+    // Initializes the 8259 PIC. It handles hardware interrupts until
+    // `apic::init` switches to the APIC, which needs the heap and the kernel
+    // memory, or for good if the machine has no APIC.
+    unsafe { interrupts::PICS.lock().initialize() }; // the initialize function is unsafe because it can cause undefined behavior if the PIC is misconfigured.
+
+    // Creates a new IDT. This also registers the timer and keyboard handlers,
+    // which unmasks their lines at the PIC, so it must come after the PIC
+    // initialization, which restores the masks of the firmware.
     interrupts::init_idt();
 
-    // Initializes the 8259 PIC.
-    unsafe { interrupts::PICS.lock().initialize() }; // the initialize function is unsafe because it can cause undefined behavior if the PIC is misconfigured.
-    
+    // Programs the timer to interrupt every millisecond instead of the 18.2 Hz
+    // of the firmware, so that it can serve as the kernel clock.
+    pit::set_frequency(pit::DEFAULT_FREQUENCY).expect("invalid default timer frequency");
+
     // Enable interrupts.
     // 
     // Until now nothing happened because interrupts are still disabled in the
@@ -159,7 +173,10 @@ fn test_breakpoint_exception() {
 // occurs.
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
-    // There’s nothing we can do to resolve the failure, so we just panic with a
-    // message that contains the `Layout` instance.
+    // The reclaim callbacks of the `allocator::oom` module already ran without
+    // freeing enough memory, so there’s nothing left that we can do to resolve
+    // the failure. We just panic with a message that contains the `Layout`
+    // instance. Code that can handle the failure should use the fallible
+    // functions like `allocator::try_box` instead.
     panic!("allocation error: {:?}", layout)
 }
@@ -9,8 +9,8 @@ extern crate alloc;
 use core::panic::PanicInfo;
 use bootloader::{ BootInfo, entry_point };
 use alloc::{ boxed::Box, vec, vec::Vec, rc::Rc };
-use tiny_os::{ println, print };
-use tiny_os::task::{ Task, executor::Executor, keyboard };
+use tiny_os::{ println, print, apic, rtc, time };
+use tiny_os::task::{ Priority, executor::Executor, keyboard };
 
 // To make sure that the entry point function has always the correct signature
 // that the bootloader expects, the `bootloader` crate provides an `entry_point`
@@ -39,13 +39,33 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
 
     allocator::init_heap(&mut mapper, &mut frame_allocator)
         .expect("heap initialization failed");
         // in case the fn returns an error, we panic using the `expect` method
         // since there is currently no sensible way for us to handle this error.
 
+    // Hand the page table and frame allocator over to the kernel, so that the
+    // heap can map additional pages when it runs out of memory.
+    memory::init_kernel_memory(mapper, frame_allocator);
+
+    // Switch from the 8259 PIC to the APIC, which needs the kernel memory to
+    // map its registers. Without an APIC, the PIC simply stays active.
+    if let Err(err) = apic::init() {
+        println!("APIC not available ({}), using the 8259 PIC", err);
+    }
+
+    // Switch the kernel clock from PIT ticks to the TSC or HPET, which needs
+    // the kernel memory for the HPET registers.
+    println!("clock source: {:?}", time::init());
+
+    // Set the wall clock from the real-time clock.
+    rtc::init();
+    println!("booted at {}", time::SystemTime::now());
+
     // allocate a number on the heap
     let heap_value = Box::new(42);
     println!("heap_value at {:p}", heap_value); // print the underlying heap pointer
@@ -119,7 +139,9 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
     // With the `BootInfoFrameAllocator`, behind the scenes, the `map_to` method
     // creates the missing page tables.
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
     // Map an unused page.
     // This maps the page to the VGA text buffer frame, so we should see any
     // write to it on the screen.
@@ -211,18 +233,22 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     // A new instance of our `Executor` type is created with an empty
     // `task_queue`.
     let mut executor = Executor::new();
-    // Uncomment lines below to use the simple executor.
+    // Uncomment lines below to use the simple executor. Its `spawn` method
+    // takes a `Task`, so wrap the futures below in `Task::new` there.
     // let mut executor = SimpleExecutor::new();
 
-    // Call the asynchronous `example_task` function, which returns a future. We
-    // wrap this future in the `Task` type, which moves it to the heap and pins
-    // it, and then add the task to the `task_queue` of the executor through the
-    // `spawn` method.
-    executor.spawn(Task::new(example_task()));
+    // Call the asynchronous `example_task` function, which returns a future.
+    // The `spawn` method wraps this future in the `Task` type, which moves it
+    // to the heap and pins it, and then adds the task to the `task_queue` of
+    // the executor.
+    executor.spawn(example_task());
 
     // Add the `print_keypresses` task to our executor to get working keyboard
-    // input.
-    executor.spawn(Task::new(keyboard::print_keypresses()));
+    // input. It reacts to the user, so it gets a higher priority than other
+    // tasks. The name shows up in the task dump, which the F12 key and the
+    // panic handler print to the serial port. (The simple executor has no
+    // priorities or names, use `spawn` there.)
+    executor.spawn_named("keyboard", Priority::Interactive, keyboard::print_keypresses());
 
     // Start the execution of the single task in the queue.
     // 
@@ -245,6 +271,8 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     println!("{}", info);
+    // If a task panicked, the dump shows it as running.
+    tiny_os::task::dump_tasks();
     tiny_os::hlt_loop();
 }
 
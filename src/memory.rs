@@ -10,15 +10,24 @@
 //!   virtual to physical addresses.
 //! - a function to create new mappings in the page tables and to find unused
 //!   memory frames for creating new page tables.
+//! - a buddy allocator for physically contiguous multi-frame blocks (see the
+//!   `buddy` submodule).
+//! - a table of regions that resolve page faults in their address range, e.g.
+//!   by allocating frames lazily (see the `fault` submodule).
 
 use x86_64::{
     structures::paging::{
         PageTable, OffsetPageTable, PhysFrame, Size4KiB, FrameAllocator,
+        FrameDeallocator, Mapper, Page, PageTableFlags, mapper::UnmapError,
     },
     VirtAddr, PhysAddr,
 };
+use core::sync::atomic::{ AtomicU64, Ordering };
 use bootloader::bootinfo::{ MemoryMap, MemoryRegionType };
 
+pub mod buddy;
+pub mod fault;
+
 /// Initialize a new `OffsetPageTable`.
 ///
 /// This function is unsafe because the caller must guarantee that the
@@ -67,6 +76,151 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr)
     &mut *page_table_ptr // unsafe
 }
 
+/// The page table and frame allocator of the kernel, bundled together so
+/// that they can be stored in a global after boot.
+pub struct KernelMemory {
+    pub mapper: OffsetPageTable<'static>,
+    pub frame_allocator: BootInfoFrameAllocator,
+}
+
+/// Global access to the kernel's page table and frame allocator for subsystems
+/// that need to create mappings after boot, e.g. the heap when it grows.
+static KERNEL_MEMORY: spin::Mutex<Option<KernelMemory>> = spin::Mutex::new(None);
+
+/// Hands the page table and frame allocator over to the kernel.
+///
+/// Until this function is called, `with_kernel_memory` returns `None`, so for
+/// example the heap can't grow beyond its initial size.
+pub fn init_kernel_memory(
+    mapper: OffsetPageTable<'static>,
+    frame_allocator: BootInfoFrameAllocator,
+) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        *KERNEL_MEMORY.lock() = Some(KernelMemory { mapper, frame_allocator });
+    });
+}
+
+/// Runs the given closure with exclusive access to the kernel's page table and
+/// frame allocator.
+///
+/// Returns `None` if `init_kernel_memory` was not called yet. Interrupts are
+/// disabled while the closure runs to avoid deadlocks with interrupt handlers.
+/// For the same reason, the closure must not allocate heap memory because the
+/// heap allocator might call this function to grow the heap.
+pub fn with_kernel_memory<F, R>(f: F) -> Option<R>
+where
+    F: FnOnce(&mut KernelMemory) -> R,
+{
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| KERNEL_MEMORY.lock().as_mut().map(f))
+}
+
+/// Like `with_kernel_memory`, but returns `None` instead of waiting if another
+/// user holds the lock.
+///
+/// Exception handlers use this function: they might have interrupted the code
+/// that holds the lock, so waiting for it would deadlock.
+pub fn try_with_kernel_memory<F, R>(f: F) -> Option<R>
+where
+    F: FnOnce(&mut KernelMemory) -> R,
+{
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| KERNEL_MEMORY.try_lock()?.as_mut().map(f))
+}
+
+/// The start of the virtual address range for memory-mapped device registers.
+pub const MMIO_START: u64 = 0x_5555_5555_0000;
+/// The size of the virtual address range for memory-mapped device registers.
+pub const MMIO_SIZE: u64 = 16 * 1024 * 1024; // 16 MiB
+
+/// The next unused virtual address in the MMIO range. Device mappings are never
+/// removed, so a simple bump pointer is enough. Only a failed `map_mmio` gives
+/// its range back, if it was the last one.
+static NEXT_MMIO_ADDR: AtomicU64 = AtomicU64::new(MMIO_START);
+
+/// Maps `size` bytes of device registers at the physical address `phys` and
+/// returns the virtual address that corresponds to `phys`.
+///
+/// The pages are mapped with caching disabled, which memory-mapped registers
+/// require: every read and write must reach the device. Returns `None` if
+/// `init_kernel_memory` was not called yet, the MMIO range is exhausted, or
+/// the mapping fails.
+///
+/// This function is unsafe because the caller must guarantee that `phys` is
+/// the address of device memory and not of a frame that is in use otherwise.
+pub unsafe fn map_mmio(phys: PhysAddr, size: u64) -> Option<VirtAddr> {
+    let first_frame = PhysFrame::<Size4KiB>::containing_address(phys);
+    let last_frame = PhysFrame::<Size4KiB>::containing_address(phys + size.max(1) - 1u64);
+    let frames = PhysFrame::range_inclusive(first_frame, last_frame);
+    let bytes = frames.count() as u64 * 4096;
+
+    // Reserve the virtual range, but only if it fits. A plain `fetch_add`
+    // would move the pointer past the end of the range even if the check
+    // fails, so we check before we update it.
+    let mut start = NEXT_MMIO_ADDR.load(Ordering::Relaxed);
+    loop {
+        let end = start.checked_add(bytes)?;
+        if end > MMIO_START + MMIO_SIZE {
+            return None;
+        }
+        match NEXT_MMIO_ADDR.compare_exchange(start, end, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => break,
+            Err(current) => start = current,
+        }
+    }
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH;
+    let page_at = |index: usize| {
+        Page::containing_address(VirtAddr::new(start + index as u64 * 4096))
+    };
+
+    let mapped = with_kernel_memory(|memory| {
+        for (index, frame) in frames.enumerate() {
+            let page = page_at(index);
+            match memory.mapper.map_to(page, frame, flags, &mut memory.frame_allocator) {
+                Ok(flush) => flush.flush(),
+                Err(_) => {
+                    // Remove the pages that we mapped so far. The frames belong
+                    // to the device, so unlike `unmap_page`, we don't give them
+                    // to the frame allocator.
+                    for index in 0..index {
+                        let (_, flush) = memory.mapper
+                            .unmap(page_at(index))
+                            .expect("failed to unmap MMIO page");
+                        flush.flush();
+                    }
+                    return false;
+                }
+            }
+        }
+        true
+    });
+    if mapped != Some(true) {
+        // Give the range back, unless another mapping was reserved behind it
+        // in the meantime.
+        let _ = NEXT_MMIO_ADDR.compare_exchange(
+            start + bytes,
+            start,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+        return None;
+    }
+    Some(VirtAddr::new(start + (phys - first_frame.start_address())))
+}
+
+/// Returns the virtual address at which the complete physical memory is
+/// mapped, or `None` if `init_kernel_memory` was not called yet.
+pub fn physical_memory_offset() -> Option<VirtAddr> {
+    with_kernel_memory(|memory| memory.mapper.phys_offset())
+}
+
 /*
 
 /// Creates an example mapping for the given virtual page to frame `0xb8000`,
@@ -125,12 +279,25 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
 
 /// A `FrameAllocator` that returns usable frames from the bootloader's memory
 /// map.
+///
+/// Every physical frame up to the highest usable address is tracked by a
+/// single bit in a bitmap: a set bit means that the frame is in use (or not
+/// usable at all), a cleared bit means that the frame is free. This allows us
+/// to give frames back through the `FrameDeallocator` trait, which the old
+/// "bump" implementation could not do.
+///
+/// The bitmap owns all usable frames, except for the ones that were handed to
+/// another allocator with `reserve_contiguous`, e.g. to the buddy allocator.
+/// Those frames stay marked as used for as long as the kernel runs.
 pub struct BootInfoFrameAllocator {
-    /// A `'static` reference to the memory map passed by the bootloader.
-    memory_map: &'static MemoryMap,
-    /// Keeps track of number of the next frame that the allocator should
-    /// return.
-    next: usize,
+    /// The frame bitmap. It lives in the first usable region that is large
+    /// enough to hold it and is accessed through the physical memory mapping.
+    bitmap: &'static mut [u64],
+    /// Index of the bitmap word where the next search for a free frame
+    /// starts. All words before it are known to be full.
+    next_word: usize,
+    /// Number of frames that are currently free.
+    free_frames: usize,
 }
 
 impl BootInfoFrameAllocator {
@@ -138,63 +305,204 @@ impl BootInfoFrameAllocator {
     ///
     /// This function is unsafe because the caller must guarantee that the
     /// passed memory map is valid. The main requirement is that all frames that
-    /// are marked as `USABLE` in it are really unused.
-    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
-            memory_map,
-            // Initialized with 0 and will be increased for every frame
-            // allocation to avoid returning the same frame twice.
-            next: 0,
+    /// are marked as `USABLE` in it are really unused. Also, the complete
+    /// physical memory must be mapped to virtual memory at the passed
+    /// `physical_memory_offset`, since the bitmap is stored in physical memory.
+    pub unsafe fn init(
+        memory_map: &'static MemoryMap,
+        physical_memory_offset: VirtAddr,
+    ) -> Self {
+        // The bitmap needs one bit for every frame up to the end of the highest
+        // usable region. Frames above it are never handed out, so we don't
+        // need to track them.
+        let frame_count = usable_frames(memory_map)
+            .map(|frame| frame_number(frame) + 1)
+            .max()
+            .unwrap_or(0);
+        let words = (frame_count + 63) / 64;
+        let bitmap_frames = ((words * 8) as u64 + 4095) / 4096;
+
+        // Place the bitmap at the start of the first usable region that is
+        // large enough to hold it.
+        let bitmap_start = memory_map
+            .iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable)
+            .find(|r| {
+                r.range.end_addr() - r.range.start_addr() >= bitmap_frames * 4096
+            })
+            .map(|r| PhysAddr::new(r.range.start_addr()))
+            .expect("no usable region large enough for the frame bitmap");
+        let bitmap_virt = physical_memory_offset + bitmap_start.as_u64();
+        let bitmap_ptr: *mut u64 = bitmap_virt.as_mut_ptr();
+        let bitmap = core::slice::from_raw_parts_mut(bitmap_ptr, words);
+
+        let mut allocator = BootInfoFrameAllocator {
+            bitmap,
+            next_word: 0,
+            free_frames: 0,
+        };
+        // Start with every frame marked as used and then free the usable ones,
+        // so that reserved holes in the memory map are never handed out.
+        for word in allocator.bitmap.iter_mut() {
+            *word = !0;
         }
+        for frame in usable_frames(memory_map) {
+            allocator.clear(frame_number(frame));
+        }
+        // Finally, reserve the frames that hold the bitmap itself.
+        let first_bitmap_frame = frame_number(PhysFrame::containing_address(bitmap_start));
+        for number in first_bitmap_frame..first_bitmap_frame + bitmap_frames as usize {
+            allocator.set(number);
+        }
+        allocator
+    }
+
+    /// Returns the number of frames that are currently free.
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Marks `count` contiguous free frames as used and returns the first of
+    /// them. The first frame number is a multiple of `align`, which must be a
+    /// power of two.
+    ///
+    /// Unlike `allocate_frame`, this is meant for handing a range over to
+    /// another allocator for good, see `buddy::BuddyFrameAllocator::init`. The
+    /// frames must therefore never be passed to `deallocate_frame`. The search
+    /// walks the whole bitmap, so it should only be done during boot.
+    pub fn reserve_contiguous(&mut self, count: usize, align: usize) -> Option<PhysFrame> {
+        assert!(align.is_power_of_two(), "frame alignment must be a power of two");
+        let frame_count = self.bitmap.len() * 64;
+        let mut start = 0;
+        while start + count <= frame_count {
+            match (start..start + count).find(|&number| self.is_used(number)) {
+                // Continue at the next aligned frame behind the used one.
+                Some(used) => start = (used / align + 1) * align,
+                None => {
+                    for number in start..start + count {
+                        self.set(number);
+                    }
+                    let addr = PhysAddr::new(start as u64 * 4096);
+                    return Some(PhysFrame::containing_address(addr));
+                }
+            }
+        }
+        None
+    }
+
+    /// Returns whether the frame with the given number is currently in use.
+    fn is_used(&self, number: usize) -> bool {
+        self.bitmap[number / 64] & (1 << (number % 64)) != 0
+    }
+
+    /// Marks the frame with the given number as used.
+    fn set(&mut self, number: usize) {
+        debug_assert!(!self.is_used(number));
+        self.bitmap[number / 64] |= 1 << (number % 64);
+        self.free_frames -= 1;
     }
 
-    /// An auxiliary method that returns an iterator over the usable frames
-    /// specified in the memory map.
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        // Get usable regions from memory map.
-        //
-        // Note: The `iter` method convert the memory map to an iterator of
-        // `MemoryRegions`. The `filter` method to skip any reserved or
-        // otherwise unavailable regions. The bootloader updates the memory map
-        // for all the mappings it creates, so frames that are used by our
-        // kernel (code, data or stack) or to store the boot information are
-        // already marked as InUse or similar. Thus we can be sure that Usable
-        // frames are not used somewhere else.
-        let regions = self.memory_map.iter();
-        let usable_regions = regions
-            .filter(|r| r.region_type == MemoryRegionType::Usable);
-        // Map each region to its address range.
-        //
-        // Note: `map` combinator transform our iterator of memory regions to an
-        // iterator of address ranges.
-        //
-        // `start_addr` method returns the physical start address of the memory
-        // region.
-        let addr_ranges = usable_regions
-            .map(|r| r.range.start_addr()..r.range.end_addr());
-        // Transform to an iterator of frame start addresses.
-        //
-        // Note: `flat_map` to transform the address ranges into an iterator of
-        // frame start addresses, choosing every 4096th address using `step_by`.
-        // Since 4096 bytes (= 4 KiB) is the page size, we get the start address
-        // of each frame. The bootloader page aligns all usable memory areas so
-        // that we don’t need any alignment or rounding code here.
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        // Create `PhysFrame` types from the start addresses.
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    /// Marks the frame with the given number as free.
+    fn clear(&mut self, number: usize) {
+        debug_assert!(self.is_used(number));
+        self.bitmap[number / 64] &= !(1 << (number % 64));
+        self.free_frames += 1;
+        // The freed frame might be located before the current search start.
+        self.next_word = self.next_word.min(number / 64);
     }
 }
 
+/// An auxiliary function that returns an iterator over the usable frames
+/// specified in the memory map.
+fn usable_frames(memory_map: &'static MemoryMap) -> impl Iterator<Item = PhysFrame> {
+    // Get usable regions from memory map.
+    //
+    // Note: The `iter` method convert the memory map to an iterator of
+    // `MemoryRegions`. The `filter` method to skip any reserved or
+    // otherwise unavailable regions. The bootloader updates the memory map
+    // for all the mappings it creates, so frames that are used by our
+    // kernel (code, data or stack) or to store the boot information are
+    // already marked as InUse or similar. Thus we can be sure that Usable
+    // frames are not used somewhere else.
+    let regions = memory_map.iter();
+    let usable_regions = regions
+        .filter(|r| r.region_type == MemoryRegionType::Usable);
+    // Map each region to its address range.
+    //
+    // Note: `map` combinator transform our iterator of memory regions to an
+    // iterator of address ranges.
+    //
+    // `start_addr` method returns the physical start address of the memory
+    // region.
+    let addr_ranges = usable_regions
+        .map(|r| r.range.start_addr()..r.range.end_addr());
+    // Transform to an iterator of frame start addresses.
+    //
+    // Note: `flat_map` to transform the address ranges into an iterator of
+    // frame start addresses, choosing every 4096th address using `step_by`.
+    // Since 4096 bytes (= 4 KiB) is the page size, we get the start address
+    // of each frame. The bootloader page aligns all usable memory areas so
+    // that we don’t need any alignment or rounding code here.
+    let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
+    // Create `PhysFrame` types from the start addresses.
+    frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+}
+
+/// Returns the index of the given frame in the frame bitmap.
+fn frame_number(frame: PhysFrame) -> usize {
+    (frame.start_address().as_u64() / 4096) as usize
+}
+
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        // Before returning that frame, we increase `self.next` by one so that
-        // we return the following frame on the next call.
-        self.next += 1;
-        frame
+        // Skip over full words, so that each word is only scanned once until
+        // a frame in front of it gets freed again. This makes allocation O(1)
+        // amortized instead of re-walking the memory map on every call.
+        while self.next_word < self.bitmap.len() {
+            let word = self.bitmap[self.next_word];
+            if word != !0 {
+                let number = self.next_word * 64 + (!word).trailing_zeros() as usize;
+                self.set(number);
+                let addr = PhysAddr::new(number as u64 * 4096);
+                return Some(PhysFrame::containing_address(addr));
+            }
+            self.next_word += 1;
+        }
+        None
     }
 }
 
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    /// Gives the frame back to the bitmap, so that it can be returned by a
+    /// later `allocate_frame` call.
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let number = frame_number(frame);
+        assert!(
+            number < self.bitmap.len() * 64,
+            "frame {:?} is not managed by this allocator",
+            frame
+        );
+        assert!(self.is_used(number), "frame {:?} freed twice", frame);
+        self.clear(number);
+    }
+}
+
+/// Unmaps the given page and gives its frame back to the frame allocator.
+///
+/// This function is unsafe because the caller must guarantee that the page is
+/// no longer in use. Otherwise the frame might be handed out again while it is
+/// still referenced.
+pub unsafe fn unmap_page(
+    page: Page,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+) -> Result<(), UnmapError> {
+    let (frame, flush) = mapper.unmap(page)?;
+    flush.flush();
+    frame_deallocator.deallocate_frame(frame);
+    Ok(())
+}
+
 /*
 
 /// Translates the given virtual address to the mapped physical address, or
@@ -327,3 +635,16 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr)
 // While our `create_example_mapping` function is just some example code, we are
 // now able to create new mappings for arbitrary pages. This will be essential
 // for allocating memory or implementing multithreading in future.
+//
+// ## Bitmap frame allocator
+//
+// The allocator above could only hand frames out, never take them back, and
+// got slower with every allocation because of the `nth` call. The current
+// implementation stores one bit per physical frame in a bitmap instead. The
+// bitmap itself needs storage before we have a heap, so we carve it out of the
+// first usable region that is large enough and access it through the physical
+// memory mapping of the bootloader. For 4 GiB of memory this costs 128 KiB.
+//
+// To find a free frame quickly, we remember the first bitmap word that might
+// still contain a cleared bit. Full words are skipped 64 frames at a time and
+// freeing a frame moves the search start back if necessary.
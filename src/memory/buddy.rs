@@ -0,0 +1,273 @@
+//! # Buddy frame allocator module
+//!
+//! A physical frame allocator that hands out physically contiguous blocks of
+//! `2^order` frames.
+//!
+//! Free blocks are kept in one list per order. An allocation takes a block of
+//! the smallest order that is large enough and splits it in halves until it has
+//! the requested size. On deallocation, a block is merged with its "buddy" (the
+//! other half of the block it was split from) as long as that buddy is free as
+//! well. This way, large contiguous blocks are rebuilt when their parts are
+//! freed again.
+//!
+//! The buddy allocator doesn't read the memory map itself. It gets a range of
+//! frames that the bitmap allocator gave up, so that no frame is owned by both
+//! allocators (see the sidenote below).
+
+use x86_64::{
+    structures::paging::{ PhysFrame, Size4KiB, FrameAllocator, FrameDeallocator },
+    VirtAddr, PhysAddr,
+};
+use super::BootInfoFrameAllocator;
+
+/// The largest supported order. A block of this order consists of `2^9 = 512`
+/// frames, i.e. 2 MiB, which is also the size of a huge page.
+pub const MAX_ORDER: usize = 9;
+
+/// The size of a single frame in bytes.
+const FRAME_SIZE: u64 = 4096;
+
+/// A free block. Like the `ListNode` of our heap allocators, the node is stored
+/// in the free memory itself, which we access through the physical memory
+/// mapping.
+struct FreeBlock {
+    next: Option<&'static mut FreeBlock>,
+}
+
+pub struct BuddyFrameAllocator {
+    /// The virtual address at which the complete physical memory is mapped.
+    physical_memory_offset: VirtAddr,
+    /// One list of free blocks for each order.
+    free_lists: [Option<&'static mut FreeBlock>; MAX_ORDER + 1],
+    /// Number of frames that are currently free.
+    free_frames: usize,
+}
+
+impl BuddyFrameAllocator {
+    /// Creates an empty buddy allocator.
+    ///
+    /// Memory needs to be added through `add_region` before the allocator can
+    /// return any frames.
+    pub fn new(physical_memory_offset: VirtAddr) -> Self {
+        // See `FixedSizeBlockAllocator::new` for why we need this constant.
+        const EMPTY: Option<&'static mut FreeBlock> = None;
+
+        BuddyFrameAllocator {
+            physical_memory_offset,
+            free_lists: [EMPTY; MAX_ORDER + 1],
+            free_frames: 0,
+        }
+    }
+
+    /// Creates a buddy allocator that manages `blocks` blocks of `MAX_ORDER`,
+    /// which it takes out of the passed bitmap allocator.
+    ///
+    /// The frames are reserved in the bitmap, so the bitmap allocator never
+    /// hands them out again. Returns `None` if the bitmap has no free range of
+    /// that size.
+    ///
+    /// This function is unsafe because the complete physical memory must be
+    /// mapped to virtual memory at the passed `physical_memory_offset`.
+    pub unsafe fn init(
+        frame_allocator: &mut BootInfoFrameAllocator,
+        blocks: usize,
+        physical_memory_offset: VirtAddr,
+    ) -> Option<Self> {
+        let block_frames = 1 << MAX_ORDER;
+        let first_frame =
+            frame_allocator.reserve_contiguous(blocks * block_frames, block_frames)?;
+        let start = first_frame.start_address();
+        let mut allocator = BuddyFrameAllocator::new(physical_memory_offset);
+        allocator.add_region(start, start + blocks as u64 * block_size(MAX_ORDER));
+        Some(allocator)
+    }
+
+    /// Adds the physical memory range `start..end` to the allocator.
+    ///
+    /// The range is split into the largest blocks that are naturally aligned,
+    /// i.e. a block of order `n` always starts at a multiple of `2^n` frames.
+    /// This is required for finding the buddy of a block by its address.
+    ///
+    /// This method is unsafe because the caller must guarantee that the range
+    /// is unused and mapped at the physical memory offset.
+    pub unsafe fn add_region(&mut self, start: PhysAddr, end: PhysAddr) {
+        let mut start = start.align_up(FRAME_SIZE).as_u64();
+        let end = end.align_down(FRAME_SIZE).as_u64();
+        while start < end {
+            let mut order = MAX_ORDER;
+            while start % block_size(order) != 0 || start + block_size(order) > end {
+                order -= 1;
+            }
+            self.push(order, start);
+            self.free_frames += 1 << order;
+            start += block_size(order);
+        }
+    }
+
+    /// Returns the number of frames that are currently free.
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Returns the number of free blocks of the given order.
+    ///
+    /// This walks the free list of the order, so it is meant for tests and
+    /// statistics.
+    pub fn free_blocks(&self, order: usize) -> usize {
+        let mut count = 0;
+        let mut current = self.free_lists[order].as_deref();
+        while let Some(block) = current {
+            count += 1;
+            current = block.next.as_deref();
+        }
+        count
+    }
+
+    /// Allocates `2^order` physically contiguous frames.
+    ///
+    /// Returns the first frame of the block. The block is aligned to its own
+    /// size, e.g. an order 9 block always starts at a 2 MiB boundary.
+    pub fn allocate_contiguous(&mut self, order: usize) -> Option<PhysFrame> {
+        if order > MAX_ORDER {
+            return None;
+        }
+        // Find the smallest order that has a free block.
+        let mut current_order = (order..=MAX_ORDER)
+            .find(|&o| self.free_lists[o].is_some())?;
+        let addr = self.pop(current_order)?;
+        // Split the block until it has the requested size. The upper half of
+        // each split becomes a free block of the next smaller order.
+        while current_order > order {
+            current_order -= 1;
+            unsafe { self.push(current_order, addr + block_size(current_order)) };
+        }
+        self.free_frames -= 1 << order;
+        Some(PhysFrame::containing_address(PhysAddr::new(addr)))
+    }
+
+    /// Frees a block that was returned by `allocate_contiguous` with the same
+    /// `order`.
+    ///
+    /// This method is unsafe because the caller must ensure that the block is
+    /// no longer used and that `order` matches the allocation.
+    pub unsafe fn deallocate_contiguous(&mut self, frame: PhysFrame, order: usize) {
+        assert!(order <= MAX_ORDER, "invalid block order {}", order);
+        let mut addr = frame.start_address().as_u64();
+        assert_eq!(addr % block_size(order), 0, "block is not aligned to its order");
+        self.free_frames += 1 << order;
+
+        // Merge the block with its buddy for as long as the buddy is free. The
+        // buddy of a block only differs in the bit that corresponds to the
+        // block size, so we can find it with a simple XOR.
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy = addr ^ block_size(order);
+            if !self.remove(order, buddy) {
+                break;
+            }
+            addr = addr.min(buddy);
+            order += 1;
+        }
+        self.push(order, addr);
+    }
+
+    /// Pushes the block at the given physical address to the list of `order`.
+    unsafe fn push(&mut self, order: usize, addr: u64) {
+        let node = FreeBlock {
+            next: self.free_lists[order].take(),
+        };
+        let node_ptr: *mut FreeBlock = (self.physical_memory_offset + addr).as_mut_ptr();
+        node_ptr.write(node);
+        self.free_lists[order] = Some(&mut *node_ptr);
+    }
+
+    /// Pops the first block of the list of `order` and returns its physical
+    /// address.
+    fn pop(&mut self, order: usize) -> Option<u64> {
+        let block = self.free_lists[order].take()?;
+        self.free_lists[order] = block.next.take();
+        Some(self.phys_addr(block))
+    }
+
+    /// Removes the block at the given physical address from the list of
+    /// `order`. Returns `false` if the block is not in the list.
+    fn remove(&mut self, order: usize, addr: u64) -> bool {
+        let offset = self.physical_memory_offset.as_u64();
+        let mut current = &mut self.free_lists[order];
+        while current.is_some() {
+            let block_addr = current
+                .as_ref()
+                .map(|b| &**b as *const FreeBlock as u64 - offset);
+            if block_addr == Some(addr) {
+                let block = current.take().unwrap();
+                *current = block.next.take();
+                return true;
+            }
+            current = &mut current.as_mut().unwrap().next;
+        }
+        false
+    }
+
+    /// Returns the physical address of a free block.
+    fn phys_addr(&self, block: &FreeBlock) -> u64 {
+        block as *const FreeBlock as u64 - self.physical_memory_offset.as_u64()
+    }
+}
+
+/// Returns the size of a block of the given order in bytes.
+const fn block_size(order: usize) -> u64 {
+    FRAME_SIZE << order
+}
+
+/// Returns the smallest order whose blocks hold at least `frames` frames, or
+/// `None` if the request is larger than a block of `MAX_ORDER`.
+pub fn order_for_frames(frames: usize) -> Option<usize> {
+    (0..=MAX_ORDER).find(|&order| 1 << order >= frames)
+}
+
+// A single frame is simply a block of order 0, so the buddy allocator can also
+// be used wherever a normal frame allocator is expected, e.g. for `map_to`.
+unsafe impl FrameAllocator<Size4KiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.allocate_contiguous(0)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.deallocate_contiguous(frame, 0)
+    }
+}
+
+// ********** Sidenote **********
+//
+// # Buddy allocation
+//
+// The bitmap allocator in the parent module answers "give me any free frame"
+// very quickly, but it can't efficiently answer "give me 64 free frames that
+// are next to each other". Devices that access memory directly (DMA) don't go
+// through our page tables, so their buffers must be contiguous in _physical_
+// memory. Large kernel stacks benefit from this too.
+//
+// The buddy system restricts block sizes to powers of two, which keeps the
+// bookkeeping simple: every block of order `n` (except for the largest order)
+// has exactly one buddy of the same order, and the two buddies together form a
+// naturally aligned block of order `n + 1`. When both are free, they are merged,
+// so external fragmentation is undone as soon as memory is freed again.
+//
+// # Who owns which frame
+//
+// A frame must belong to exactly one allocator, otherwise both could hand it
+// out. The bitmap allocator (`BootInfoFrameAllocator`) owns all usable frames
+// of the memory map after boot. `BuddyFrameAllocator::init` asks it for a
+// naturally aligned range with `reserve_contiguous`, which marks the range as
+// used in the bitmap. From then on, the buddy allocator owns these frames and
+// the bitmap owns the rest. Frames must be freed to the allocator that
+// returned them; the bitmap can't take buddy frames back, because they are
+// already marked as used from its point of view.
+//
+// # Finding free buddies
+//
+// Finding out whether the buddy is free currently requires walking the free
+// list of its order. Kernels like Linux keep an additional bitmap per order for
+// this, which we could add if the lists ever become long.
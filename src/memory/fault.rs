@@ -0,0 +1,321 @@
+//! # Page fault regions module
+//!
+//! A table of virtual memory regions that know how to resolve page faults in
+//! their address range.
+//!
+//! The page fault handler looks up the faulting address in this table. If a
+//! region contains it, the region resolves the fault (e.g. by mapping a fresh
+//! frame) and the faulting instruction is restarted. Only faults that no region
+//! resolves end in the fatal fault report.
+//!
+//! The supported region kinds are:
+//! - `Lazy`: pages are mapped to zeroed frames on their first access.
+//! - `Guard`: every access is a bug, e.g. a stack overflow, and is reported
+//!   with the name of the region.
+//! - `CopyOnWrite`: pages are mapped read-only to shared frames and get a
+//!   private copy on their first write.
+//! - `Custom`: a handler function decides.
+
+use x86_64::{
+    instructions::interrupts,
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{ FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB },
+    },
+    VirtAddr,
+};
+use super::{ try_with_kernel_memory, with_kernel_memory, KernelMemory };
+
+/// The maximum number of regions in the table.
+pub const MAX_REGIONS: usize = 16;
+
+/// The size of a page in bytes.
+const PAGE_SIZE: u64 = 4096;
+
+/// The result of resolving a page fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultOutcome {
+    /// The cause of the fault was removed, so the faulting instruction can be
+    /// restarted.
+    Resolved,
+    /// The region doesn't handle this kind of access (e.g. a write to a page
+    /// that is mapped read-only on purpose).
+    Unresolved,
+    /// The access is a bug. The reason is printed before the fault report.
+    Fatal(&'static str),
+}
+
+/// A function that resolves page faults of a `Custom` region.
+///
+/// It runs in the page fault handler with interrupts disabled, so it must not
+/// allocate heap memory or block on locks. Use `try_with_kernel_memory` to
+/// access the page tables.
+pub type FaultHandler = fn(&PageFault) -> FaultOutcome;
+
+/// What happens on a page fault in a region.
+#[derive(Debug, Clone, Copy)]
+pub enum RegionKind {
+    /// Maps a zeroed frame with the given flags on the first access.
+    Lazy(PageTableFlags),
+    /// Reports every access as fatal.
+    Guard,
+    /// Replaces a read-only shared frame with a writable copy on the first
+    /// write, see `map_copy_on_write`.
+    CopyOnWrite,
+    /// Calls the given handler.
+    Custom(FaultHandler),
+}
+
+/// A page-aligned range of virtual memory with a fault handling strategy.
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    pub start: VirtAddr,
+    /// The size in bytes, a multiple of the page size.
+    pub size: u64,
+    /// Only used for diagnostics.
+    pub name: &'static str,
+    pub kind: RegionKind,
+}
+
+impl Region {
+    /// Returns `true` if the region contains the given address.
+    pub fn contains(&self, address: VirtAddr) -> bool {
+        address >= self.start && address.as_u64() - self.start.as_u64() < self.size
+    }
+
+    fn overlaps(&self, other: &Region) -> bool {
+        self.start.as_u64() < other.start.as_u64() + other.size
+            && other.start.as_u64() < self.start.as_u64() + self.size
+    }
+}
+
+/// A page fault in a region.
+#[derive(Debug, Clone, Copy)]
+pub struct PageFault {
+    /// The accessed address, read from `CR2`.
+    pub address: VirtAddr,
+    pub error_code: PageFaultErrorCode,
+    pub region: Region,
+}
+
+impl PageFault {
+    /// The page that contains the accessed address.
+    pub fn page(&self) -> Page {
+        Page::containing_address(self.address)
+    }
+}
+
+/// The errors of the region functions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionError {
+    /// The start or size of the region is not page-aligned, or it is empty.
+    Unaligned,
+    /// The region overlaps a registered region.
+    Overlap,
+    /// `MAX_REGIONS` regions are already registered.
+    TableFull,
+    /// No region of the right kind contains the page.
+    NoRegion,
+    /// `init_kernel_memory` was not called yet, or creating the mapping failed.
+    MapFailed,
+}
+
+/// The registered regions.
+///
+/// Like the reclaim callbacks of the allocator, the table has a fixed size, so
+/// that registering a region doesn't allocate.
+static REGIONS: spin::Mutex<[Option<Region>; MAX_REGIONS]> =
+    spin::Mutex::new([None; MAX_REGIONS]);
+
+/// Adds a region to the table.
+///
+/// Pages of the region that are already mapped are left alone, the region
+/// only handles the faults of unmapped (or, for `CopyOnWrite`, read-only)
+/// pages.
+pub fn register_region(region: Region) -> Result<(), RegionError> {
+    if region.size == 0
+        || !region.start.is_aligned(PAGE_SIZE)
+        || region.size % PAGE_SIZE != 0
+    {
+        return Err(RegionError::Unaligned);
+    }
+    // The page fault handler only uses `try_lock`, but disabling interrupts
+    // keeps it from failing because of us.
+    interrupts::without_interrupts(|| {
+        let mut regions = REGIONS.lock();
+        if regions.iter().flatten().any(|other| other.overlaps(&region)) {
+            return Err(RegionError::Overlap);
+        }
+        let slot = regions
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(RegionError::TableFull)?;
+        *slot = Some(region);
+        Ok(())
+    })
+}
+
+/// Removes the region that starts at `start` from the table and returns it.
+///
+/// The pages that the region mapped stay mapped. Their owner is responsible
+/// for unmapping them, e.g. through `unmap_page`.
+pub fn unregister_region(start: VirtAddr) -> Option<Region> {
+    interrupts::without_interrupts(|| {
+        REGIONS
+            .lock()
+            .iter_mut()
+            .find(|slot| slot.as_ref().map_or(false, |region| region.start == start))
+            .and_then(Option::take)
+    })
+}
+
+/// Returns the region that contains the given address.
+///
+/// Returns `None` as well if the table is locked, since the page fault handler
+/// uses this function for its diagnostics.
+pub fn region_at(address: VirtAddr) -> Option<Region> {
+    let regions = REGIONS.try_lock()?;
+    regions.iter().flatten().find(|region| region.contains(address)).copied()
+}
+
+/// Tries to resolve a page fault at the given address.
+///
+/// Called by the page fault handler. Returns `Unresolved` if no region
+/// contains the address.
+pub fn handle_page_fault(address: VirtAddr, error_code: PageFaultErrorCode) -> FaultOutcome {
+    let region = match region_at(address) {
+        Some(region) => region,
+        None => return FaultOutcome::Unresolved,
+    };
+    let fault = PageFault { address, error_code, region };
+    match region.kind {
+        RegionKind::Lazy(flags) => resolve_lazy(&fault, flags),
+        RegionKind::Guard => FaultOutcome::Fatal("access to a guard page"),
+        RegionKind::CopyOnWrite => resolve_copy_on_write(&fault),
+        RegionKind::Custom(handler) => handler(&fault),
+    }
+}
+
+/// Maps the given frame read-only to a page of a `CopyOnWrite` region.
+///
+/// Reads access the shared frame, while the first write gives the page a
+/// private copy. The frame itself is never freed by the region.
+///
+/// This function is unsafe because the caller must guarantee that the frame
+/// stays valid as long as the page refers to it.
+pub unsafe fn map_copy_on_write(page: Page, frame: PhysFrame) -> Result<(), RegionError> {
+    match region_at(page.start_address()) {
+        Some(Region { kind: RegionKind::CopyOnWrite, .. }) => {}
+        _ => return Err(RegionError::NoRegion),
+    }
+    with_kernel_memory(|memory| {
+        memory.mapper
+            .map_to(page, frame, PageTableFlags::PRESENT, &mut memory.frame_allocator)
+            .map(|flush| flush.flush())
+            .map_err(|_| RegionError::MapFailed)
+    })
+    .unwrap_or(Err(RegionError::MapFailed))
+}
+
+/// Maps a zeroed frame to the faulting page.
+fn resolve_lazy(fault: &PageFault, flags: PageTableFlags) -> FaultOutcome {
+    // The page is present, so the access itself is not allowed.
+    if fault.error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return FaultOutcome::Unresolved;
+    }
+    let outcome = try_with_kernel_memory(|memory| {
+        let frame = match allocate_frame(memory) {
+            Some(frame) => frame,
+            None => return FaultOutcome::Fatal("out of physical memory"),
+        };
+        unsafe {
+            frame_ptr(memory, frame).write_bytes(0, PAGE_SIZE as usize);
+            map_page(memory, fault.page(), frame, flags | PageTableFlags::PRESENT)
+        }
+    });
+    // The kernel memory is locked by the code that we interrupted, so the
+    // fault can't be resolved.
+    outcome.unwrap_or(FaultOutcome::Unresolved)
+}
+
+/// Gives the faulting page a writable copy of its shared frame.
+fn resolve_copy_on_write(fault: &PageFault) -> FaultOutcome {
+    let write_to_present_page = PageFaultErrorCode::PROTECTION_VIOLATION
+        | PageFaultErrorCode::CAUSED_BY_WRITE;
+    if !fault.error_code.contains(write_to_present_page) {
+        return FaultOutcome::Unresolved;
+    }
+    let outcome = try_with_kernel_memory(|memory| {
+        let shared = match memory.mapper.translate_page(fault.page()) {
+            Ok(frame) => frame,
+            Err(_) => return FaultOutcome::Unresolved,
+        };
+        let copy = match allocate_frame(memory) {
+            Some(frame) => frame,
+            None => return FaultOutcome::Fatal("out of physical memory"),
+        };
+        unsafe {
+            let source = frame_ptr(memory, shared);
+            frame_ptr(memory, copy).copy_from_nonoverlapping(source, PAGE_SIZE as usize);
+            match memory.mapper.unmap(fault.page()) {
+                Ok((_, flush)) => flush.flush(),
+                Err(_) => return FaultOutcome::Unresolved,
+            }
+            map_page(memory, fault.page(), copy, PageTableFlags::PRESENT | PageTableFlags::WRITABLE)
+        }
+    });
+    outcome.unwrap_or(FaultOutcome::Unresolved)
+}
+
+fn allocate_frame(memory: &mut KernelMemory) -> Option<PhysFrame> {
+    memory.frame_allocator.allocate_frame()
+}
+
+/// Returns a pointer to the given frame in the physical memory mapping.
+fn frame_ptr(memory: &KernelMemory, frame: PhysFrame) -> *mut u8 {
+    (memory.mapper.phys_offset() + frame.start_address().as_u64()).as_mut_ptr()
+}
+
+/// Maps the page and returns `Resolved`, or `Fatal` if that fails.
+///
+/// This function is unsafe for the same reasons as `Mapper::map_to`.
+unsafe fn map_page(
+    memory: &mut KernelMemory,
+    page: Page<Size4KiB>,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+) -> FaultOutcome {
+    match memory.mapper.map_to(page, frame, flags, &mut memory.frame_allocator) {
+        Ok(flush) => {
+            flush.flush();
+            FaultOutcome::Resolved
+        }
+        Err(_) => FaultOutcome::Fatal("failed to map the faulting page"),
+    }
+}
+
+// ********** Sidenote **********
+//
+// # Why resolve page faults at all?
+//
+// A page fault is not necessarily an error. The CPU reports every access to a
+// page that is not mapped (or not mapped with the needed permissions), and the
+// kernel decides what that means. Operating systems use this for:
+//
+// - Lazy allocation: a large region (e.g. a thread stack) is reserved, but
+//   frames are only mapped for the pages that are actually used.
+// - Guard pages: a page that is never mapped, placed below a stack or after a
+//   buffer. An access to it is a bug, and a report that names the region is
+//   much more helpful than a generic page fault.
+// - Copy-on-write: two address spaces share the same frames until one of them
+//   writes. This is how `fork` on Unix avoids copying all memory.
+//
+// # Limitations
+//
+// The frames of a copy-on-write region have no reference count, so the
+// shared frame is never freed when its last user writes to it. Its owner
+// remains responsible for it.
+//
+// A page fault on the kernel stack itself can't be resolved here: the CPU
+// can't push the exception frame onto a stack that is not mapped, so it raises
+// a double fault instead (see the `stack_overflow` test).
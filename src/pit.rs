@@ -0,0 +1,159 @@
+//! # Programmable Interval Timer module
+//!
+//! Programs channel 0 of the 8253/8254 PIT to a configurable frequency and
+//! turns its interrupts into the kernel clock: a monotonic tick counter and
+//! the `uptime` since the timer was started.
+//!
+//! The timer interrupt (line 0) calls `tick` on every interrupt, no matter
+//! whether it is delivered by the 8259 PIC or the I/O APIC.
+
+use core::{
+    fmt,
+    sync::atomic::{ AtomicU32, AtomicU64, Ordering },
+    time::Duration,
+};
+use x86_64::instructions::{ interrupts, port::Port };
+
+/// The frequency of the oscillator that drives the PIT, in Hz.
+pub const BASE_FREQUENCY: u32 = 1_193_182;
+
+/// The frequency that `crate::init` programs, in Hz. A tick of one millisecond
+/// is a common choice for a kernel clock.
+pub const DEFAULT_FREQUENCY: u32 = 1000;
+
+/// The lowest possible frequency (a divisor of 65536), about 18.2 Hz. This is
+/// what the firmware programs.
+pub const MIN_FREQUENCY: u32 = (BASE_FREQUENCY + 65535) / 65536;
+
+/// The highest possible frequency (a divisor of 2), about 596.6 kHz. Mode 2
+/// doesn't allow a divisor of 1.
+pub const MAX_FREQUENCY: u32 = BASE_FREQUENCY / 2;
+
+const CHANNEL_0_PORT: u16 = 0x40;
+const COMMAND_PORT: u16 = 0x43;
+
+/// Channel 0, access mode "low byte then high byte", operating mode 2 (rate
+/// generator), binary counting.
+const COMMAND_RATE_GENERATOR: u8 = 0b00_11_010_0;
+
+/// The number of timer interrupts since boot.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// The divisor that channel 0 is programmed with, or 0 if the PIT was not
+/// programmed yet.
+static DIVISOR: AtomicU32 = AtomicU32::new(0);
+
+/// The uptime in nanoseconds and the tick count at the last frequency change.
+/// Both are needed to compute the uptime across frequency changes.
+static BASE_NANOS: AtomicU64 = AtomicU64::new(0);
+static BASE_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// The error of `set_frequency`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidFrequency(pub u32);
+
+impl fmt::Display for InvalidFrequency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "the PIT can't run at {} Hz (supported: {}–{} Hz)",
+            self.0, MIN_FREQUENCY, MAX_FREQUENCY,
+        )
+    }
+}
+
+/// Programs channel 0 to interrupt `frequency` times per second.
+///
+/// The PIT can only divide its base frequency by an integer, so the actual
+/// frequency (see `frequency`) differs slightly from the requested one. The
+/// uptime is computed from the actual frequency, so it stays accurate.
+pub fn set_frequency(frequency: u32) -> Result<(), InvalidFrequency> {
+    if frequency < MIN_FREQUENCY || frequency > MAX_FREQUENCY {
+        return Err(InvalidFrequency(frequency));
+    }
+    // Round to the nearest divisor.
+    let divisor = ((BASE_FREQUENCY + frequency / 2) / frequency).clamp(2, 65536);
+
+    // The tick handler must not see the new divisor with the old base.
+    interrupts::without_interrupts(|| {
+        BASE_NANOS.store(uptime_nanos(), Ordering::Relaxed);
+        BASE_TICKS.store(TICKS.load(Ordering::Relaxed), Ordering::Relaxed);
+        DIVISOR.store(divisor, Ordering::Relaxed);
+
+        let mut command = Port::<u8>::new(COMMAND_PORT);
+        let mut channel_0 = Port::<u8>::new(CHANNEL_0_PORT);
+        unsafe {
+            command.write(COMMAND_RATE_GENERATOR);
+            // A divisor of 65536 is written as 0.
+            channel_0.write(divisor as u8);
+            channel_0.write((divisor >> 8) as u8);
+        }
+    });
+    Ok(())
+}
+
+/// Returns the actual frequency of the timer in Hz (rounded to the nearest
+/// integer), or 0 if `set_frequency` was not called yet.
+pub fn frequency() -> u32 {
+    match DIVISOR.load(Ordering::Relaxed) {
+        0 => 0,
+        divisor => (BASE_FREQUENCY + divisor / 2) / divisor,
+    }
+}
+
+/// Returns the number of timer interrupts since boot.
+///
+/// The counter only increases, so it can be used to order events. Its rate
+/// depends on the frequency, use `uptime` for durations.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Returns the time since the PIT was programmed, with the resolution of one
+/// tick.
+pub fn uptime() -> Duration {
+    Duration::from_nanos(interrupts::without_interrupts(uptime_nanos))
+}
+
+/// Counts a timer interrupt. Called by the timer interrupt handler.
+pub(crate) fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Computes the uptime in nanoseconds. Must be called with interrupts
+/// disabled, so that the base values belong together.
+fn uptime_nanos() -> u64 {
+    let divisor = DIVISOR.load(Ordering::Relaxed);
+    let ticks = TICKS.load(Ordering::Relaxed) - BASE_TICKS.load(Ordering::Relaxed);
+    // A tick takes `divisor / BASE_FREQUENCY` seconds. We multiply first to
+    // keep the precision, which needs 128 bits.
+    let nanos = u128::from(ticks) * u128::from(divisor) * 1_000_000_000
+        / u128::from(BASE_FREQUENCY);
+    BASE_NANOS.load(Ordering::Relaxed) + nanos as u64
+}
+
+// ********** Sidenote **********
+//
+// # The Programmable Interval Timer
+//
+// The PIT (Intel 8253/8254) has an oscillator running at about 1.193182 MHz and
+// three channels that divide this frequency by a 16-bit counter. Channel 0 is
+// connected to IRQ 0, channel 1 once refreshed the DRAM, and channel 2 drives
+// the PC speaker.
+//
+// The firmware programs channel 0 with the largest divisor (65536), which gives
+// the well-known 18.2 interrupts per second. That is far too coarse for a
+// kernel clock, so we reprogram it in `crate::init`.
+//
+// To program a channel, we first write a command byte to port 0x43 that selects
+// the channel, the access mode (we write the low and then the high byte of the
+// divisor), and the operating mode. Mode 2, the "rate generator", produces one
+// pulse every `divisor` input cycles, which is exactly a periodic interrupt.
+// Mode 3 (square wave) would work as well, but mode 2 is what most kernels use.
+//
+// # Why not count nanoseconds in the handler?
+//
+// With a frequency of 1000 Hz, a tick is 1193 / 1193182 s ≈ 999.85 µs long,
+// which is not a whole number of nanoseconds. Adding a rounded period on every
+// tick would accumulate the rounding error, so we count ticks and convert them
+// when `uptime` is called.
@@ -0,0 +1,489 @@
+//! # Real-time clock module
+//!
+//! A driver for the CMOS real-time clock, the battery-powered clock that keeps
+//! the date and time while the computer is switched off.
+//!
+//! `read` returns the current `DateTime` of the RTC. `init` reads it once at
+//! boot and sets the wall clock of `time::SystemTime`, which then follows the
+//! monotonic kernel clock. The RTC can also raise a periodic interrupt on IRQ 8
+//! (see `enable_periodic_interrupt`).
+
+use core::{
+    fmt,
+    sync::atomic::{ AtomicU32, AtomicU64, Ordering },
+};
+use x86_64::instructions::{ interrupts, port::Port };
+use crate::{
+    interrupts::irq::{ self, IrqError, IrqHandle, IrqResult },
+    time::{ self, SystemTime },
+};
+
+/// The ISA interrupt line of the RTC.
+pub const IRQ_LINE: u8 = 8;
+
+/// The lowest and highest frequency of the periodic interrupt, in Hz.
+pub const MIN_PERIODIC_FREQUENCY: u32 = 2;
+pub const MAX_PERIODIC_FREQUENCY: u32 = 8192;
+
+const INDEX_PORT: u16 = 0x70;
+const DATA_PORT: u16 = 0x71;
+
+// The CMOS registers of the RTC.
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+const STATUS_C: u8 = 0x0c;
+/// The century isn't part of the original RTC. The FADT tells where it is, but
+/// QEMU and virtually all PCs use this register.
+const CENTURY: u8 = 0x32;
+
+/// Status A: the RTC is updating its registers.
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+/// Status A: the bits that select the rate of the periodic interrupt.
+const RATE_MASK: u8 = 0x0f;
+/// Status B: the periodic interrupt is enabled.
+const PERIODIC_INTERRUPT_ENABLE: u8 = 1 << 6;
+/// Status B: the registers hold binary values instead of BCD.
+const BINARY_MODE: u8 = 1 << 2;
+/// Status B: the hours are in 24-hour format instead of 12-hour format.
+const HOUR_24: u8 = 1 << 1;
+/// Status C: the RTC raised an interrupt.
+const INTERRUPT_REQUEST: u8 = 1 << 7;
+/// Status C: the interrupt was a periodic one.
+const PERIODIC_FLAG: u8 = 1 << 6;
+/// In 12-hour format, the highest bit of the hours is set after noon.
+const PM: u8 = 1 << 7;
+
+/// The index and data ports of the CMOS.
+///
+/// Selecting a register and accessing it are two port accesses, so they must
+/// not be interrupted by another access. Normal code only holds the lock with
+/// interrupts disabled, so the interrupt handler can't deadlock.
+static CMOS: spin::Mutex<Cmos> = spin::Mutex::new(Cmos {
+    index: Port::new(INDEX_PORT),
+    data: Port::new(DATA_PORT),
+});
+
+/// The number of periodic interrupts since `enable_periodic_interrupt`.
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+/// The frequency of the periodic interrupt, or 0 if it is disabled.
+static PERIODIC_FREQUENCY: AtomicU32 = AtomicU32::new(0);
+/// The registered interrupt handler.
+static PERIODIC_HANDLER: spin::Mutex<Option<IrqHandle>> = spin::Mutex::new(None);
+
+struct Cmos {
+    index: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Cmos {
+    /// Reads a register. Bit 7 of the index port disables the NMI, so we
+    /// always leave it cleared.
+    fn read(&mut self, register: u8) -> u8 {
+        unsafe {
+            self.index.write(register);
+            self.data.read()
+        }
+    }
+
+    fn write(&mut self, register: u8, value: u8) {
+        unsafe {
+            self.index.write(register);
+            self.data.write(value);
+        }
+    }
+}
+
+fn with_cmos<R>(f: impl FnOnce(&mut Cmos) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut CMOS.lock()))
+}
+
+/// A date and time in UTC, with a resolution of one second.
+///
+/// The fields are ordered from the most to the least significant, so the
+/// derived ordering is the chronological one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DateTime {
+    pub year: u16,
+    /// 1–12
+    pub month: u8,
+    /// 1–31
+    pub day: u8,
+    /// 0–23
+    pub hour: u8,
+    /// 0–59
+    pub minute: u8,
+    /// 0–59
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Returns the date and time `seconds` after the Unix epoch
+    /// (1970-01-01 00:00:00 UTC).
+    pub fn from_unix_seconds(seconds: u64) -> DateTime {
+        let days = seconds / 86400;
+        let time = seconds % 86400;
+        let (year, month, day) = civil_from_days(days);
+        DateTime {
+            year,
+            month,
+            day,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+
+    /// Returns the seconds since the Unix epoch, or `None` if the date is
+    /// invalid or before 1970.
+    pub fn to_unix_seconds(&self) -> Option<u64> {
+        if !self.is_valid() || self.year < 1970 {
+            return None;
+        }
+        let days = days_from_civil(self.year, self.month, self.day);
+        let time = u64::from(self.hour) * 3600 + u64::from(self.minute) * 60
+            + u64::from(self.second);
+        Some(days * 86400 + time)
+    }
+
+    /// Returns `true` if all fields are in range and the day exists in the
+    /// month.
+    pub fn is_valid(&self) -> bool {
+        (1..=12).contains(&self.month)
+            && self.day >= 1
+            && self.day <= days_in_month(self.year, self.month)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+}
+
+/// Formats the date and time like `2021-06-30 23:59:59`.
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second,
+        )
+    }
+}
+
+fn is_leap_year(year: u16) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Returns the number of days from 1970-01-01 to the given date, which must
+/// not be before 1970.
+///
+/// This is the algorithm from Howard Hinnant's "chrono-Compatible Low-Level
+/// Date Algorithms". It treats March as the first month of the year, so that
+/// the leap day is the last day of the year.
+fn days_from_civil(year: u16, month: u8, day: u8) -> u64 {
+    let year = u64::from(year) - if month <= 2 { 1 } else { 0 };
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let month_from_march = (u64::from(month) + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + u64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    // 719468 is the number of days from 0000-03-01 to 1970-01-01.
+    era * 146097 + day_of_era - 719468
+}
+
+/// The inverse of `days_from_civil`.
+fn civil_from_days(days: u64) -> (u16, u8, u8) {
+    let days = days + 719468;
+    let era = days / 146097;
+    let day_of_era = days % 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 { month_from_march + 3 } else { month_from_march - 9 };
+    let year = era * 400 + year_of_era + if month <= 2 { 1 } else { 0 };
+    (year as u16, month as u8, day as u8)
+}
+
+/// The time registers as the RTC stores them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+impl RawTime {
+    fn read(cmos: &mut Cmos) -> RawTime {
+        RawTime {
+            second: cmos.read(SECONDS),
+            minute: cmos.read(MINUTES),
+            hour: cmos.read(HOURS),
+            day: cmos.read(DAY),
+            month: cmos.read(MONTH),
+            year: cmos.read(YEAR),
+            century: cmos.read(CENTURY),
+        }
+    }
+
+    /// Converts the registers according to the format in status register B.
+    fn decode(&self, status_b: u8) -> DateTime {
+        let binary = status_b & BINARY_MODE != 0;
+        let convert = |value: u8| if binary { value } else { bcd_to_binary(value) };
+
+        let mut hour = convert(self.hour & !PM);
+        if status_b & HOUR_24 == 0 {
+            // 12 AM is midnight and 12 PM is noon.
+            hour %= 12;
+            if self.hour & PM != 0 {
+                hour += 12;
+            }
+        }
+        let century = match convert(self.century) {
+            century @ 19..=21 => century,
+            // no century register
+            _ => 20,
+        };
+        DateTime {
+            year: u16::from(century) * 100 + u16::from(convert(self.year)),
+            month: convert(self.month),
+            day: convert(self.day),
+            hour,
+            minute: convert(self.minute),
+            second: convert(self.second),
+        }
+    }
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+/// Reads the date and time of the RTC.
+///
+/// Takes up to a few milliseconds if the RTC is updating its registers.
+pub fn read() -> DateTime {
+    let (raw, status_b) = loop {
+        // The RTC updates its registers once per second, which takes up to 2 ms
+        // (see the sidenote). We wait until no update is in progress, and read
+        // the registers twice to notice an update that started in between.
+        while with_cmos(|cmos| cmos.read(STATUS_A) & UPDATE_IN_PROGRESS != 0) {
+            core::hint::spin_loop();
+        }
+        let (first, second, status_b) = with_cmos(|cmos| {
+            (RawTime::read(cmos), RawTime::read(cmos), cmos.read(STATUS_B))
+        });
+        if first == second {
+            break (first, status_b);
+        }
+    };
+    raw.decode(status_b)
+}
+
+/// Reads the RTC and sets the wall clock (see `time::SystemTime`).
+///
+/// The RTC only counts whole seconds, so the wall clock can be up to a second
+/// behind.
+pub fn init() -> DateTime {
+    let now = read();
+    if let Some(seconds) = now.to_unix_seconds() {
+        time::set_system_time(SystemTime::from_unix_seconds(seconds));
+    }
+    now
+}
+
+/// The error of `enable_periodic_interrupt`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeriodicError {
+    /// The frequency is not a power of two between `MIN_PERIODIC_FREQUENCY`
+    /// and `MAX_PERIODIC_FREQUENCY`.
+    InvalidFrequency(u32),
+    /// The handler couldn't be registered.
+    Irq(IrqError),
+}
+
+impl fmt::Display for PeriodicError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PeriodicError::InvalidFrequency(frequency) => write!(
+                f,
+                "the RTC can't interrupt at {} Hz (supported: powers of two from {} to {} Hz)",
+                frequency, MIN_PERIODIC_FREQUENCY, MAX_PERIODIC_FREQUENCY,
+            ),
+            PeriodicError::Irq(err) => write!(f, "failed to register the RTC handler: {:?}", err),
+        }
+    }
+}
+
+/// Lets the RTC interrupt `frequency` times per second on IRQ 8.
+///
+/// The RTC divides its 32768 Hz oscillator by a power of two, so `frequency`
+/// must be a power of two. Calling this again changes the frequency.
+pub fn enable_periodic_interrupt(frequency: u32) -> Result<(), PeriodicError> {
+    if !frequency.is_power_of_two()
+        || !(MIN_PERIODIC_FREQUENCY..=MAX_PERIODIC_FREQUENCY).contains(&frequency)
+    {
+        return Err(PeriodicError::InvalidFrequency(frequency));
+    }
+    // The frequency is `32768 >> (rate - 1)`.
+    let rate = (16 - frequency.trailing_zeros()) as u8;
+
+    let mut handler = PERIODIC_HANDLER.lock();
+    if handler.is_none() {
+        *handler = Some(irq::register_irq(IRQ_LINE, rtc_interrupt).map_err(PeriodicError::Irq)?);
+    }
+    PERIODIC_FREQUENCY.store(frequency, Ordering::Relaxed);
+    with_cmos(|cmos| {
+        let status_a = cmos.read(STATUS_A);
+        cmos.write(STATUS_A, (status_a & !RATE_MASK) | rate);
+        let status_b = cmos.read(STATUS_B);
+        cmos.write(STATUS_B, status_b | PERIODIC_INTERRUPT_ENABLE);
+        // The RTC doesn't raise another interrupt until status C is read, so
+        // we clear an interrupt that is still pending from the firmware.
+        cmos.read(STATUS_C);
+    });
+    Ok(())
+}
+
+/// Stops the periodic interrupt and removes its handler.
+pub fn disable_periodic_interrupt() {
+    with_cmos(|cmos| {
+        let status_b = cmos.read(STATUS_B);
+        cmos.write(STATUS_B, status_b & !PERIODIC_INTERRUPT_ENABLE);
+    });
+    PERIODIC_FREQUENCY.store(0, Ordering::Relaxed);
+    if let Some(handle) = PERIODIC_HANDLER.lock().take() {
+        irq::unregister_irq(handle);
+    }
+}
+
+/// Returns the frequency of the periodic interrupt in Hz, or `None` if it is
+/// disabled.
+pub fn periodic_frequency() -> Option<u32> {
+    match PERIODIC_FREQUENCY.load(Ordering::Relaxed) {
+        0 => None,
+        frequency => Some(frequency),
+    }
+}
+
+/// Returns the number of periodic interrupts so far.
+pub fn periodic_ticks() -> u64 {
+    PERIODIC_TICKS.load(Ordering::Relaxed)
+}
+
+fn rtc_interrupt() -> IrqResult {
+    // Reading status C acknowledges the interrupt at the RTC.
+    let flags = CMOS.lock().read(STATUS_C);
+    if flags & INTERRUPT_REQUEST == 0 {
+        return IrqResult::NotMine;
+    }
+    if flags & PERIODIC_FLAG != 0 {
+        PERIODIC_TICKS.fetch_add(1, Ordering::Relaxed);
+    }
+    IrqResult::Handled
+}
+
+#[test_case]
+fn decode_bcd_12_hour() {
+    // 2021-06-30 11:59:58 PM, in BCD with a century register
+    let raw = RawTime {
+        second: 0x58,
+        minute: 0x59,
+        hour: PM | 0x11,
+        day: 0x30,
+        month: 0x06,
+        year: 0x21,
+        century: 0x20,
+    };
+    let date_time = raw.decode(0);
+    assert_eq!(alloc::format!("{}", date_time), "2021-06-30 23:59:58");
+
+    // 12 AM is midnight
+    let raw = RawTime { hour: 0x12, ..raw };
+    assert_eq!(raw.decode(0).hour, 0);
+    // 12 PM is noon
+    let raw = RawTime { hour: PM | 0x12, ..raw };
+    assert_eq!(raw.decode(0).hour, 12);
+}
+
+#[test_case]
+fn decode_binary_24_hour() {
+    // without a century register
+    let raw = RawTime { second: 5, minute: 4, hour: 23, day: 2, month: 1, year: 99, century: 0 };
+    let date_time = raw.decode(BINARY_MODE | HOUR_24);
+    assert_eq!(alloc::format!("{}", date_time), "2099-01-02 23:04:05");
+}
+
+#[test_case]
+fn unix_seconds() {
+    let epoch = DateTime { year: 1970, month: 1, day: 1, hour: 0, minute: 0, second: 0 };
+    assert_eq!(epoch.to_unix_seconds(), Some(0));
+    assert_eq!(DateTime::from_unix_seconds(0), epoch);
+
+    // a leap day
+    let leap_day = DateTime { year: 2024, month: 2, day: 29, hour: 12, minute: 30, second: 15 };
+    assert_eq!(leap_day.to_unix_seconds(), Some(1_709_209_815));
+    assert_eq!(DateTime::from_unix_seconds(1_709_209_815), leap_day);
+
+    // 2100 is not a leap year
+    let invalid = DateTime { year: 2100, month: 2, day: 29, hour: 0, minute: 0, second: 0 };
+    assert!(!invalid.is_valid());
+    assert_eq!(invalid.to_unix_seconds(), None);
+
+    // every day of four centuries survives the round trip
+    for days in (0..146097 * 4).step_by(13) {
+        let seconds = days * 86400 + 3661;
+        assert_eq!(DateTime::from_unix_seconds(seconds).to_unix_seconds(), Some(seconds));
+    }
+}
+
+// ********** Sidenote **********
+//
+// # The CMOS real-time clock
+//
+// The RTC (originally a Motorola MC146818) is part of the CMOS, a small battery
+// backed memory of 128 bytes. It is accessed through two ports: we write the
+// number of a register to port 0x70 and then read or write the register through
+// port 0x71. Bit 7 of port 0x70 also controls whether the NMI is masked, which
+// is an unfortunate design decision of the original PC.
+//
+// The RTC increments its time registers once per second. While it does so,
+// which takes up to 2 ms, the registers may hold inconsistent values, so we
+// might read 12:59:59 as 12:00:59 or 13:59:59. Status register A has an
+// "update in progress" bit that is set shortly (244 µs) before the update
+// starts, so there is always enough time to read the registers after we saw
+// the bit cleared. We still compare two reads, because an interrupt (or a
+// slow virtual machine) might delay us.
+//
+// The firmware decides the format of the registers: status register B tells
+// whether the values are binary or binary coded decimal (BCD, where 0x59 means
+// 59), and whether the hours use the 24-hour or the 12-hour format. Most
+// firmware uses BCD and 24 hours, so the other formats are rarely tested.
+//
+// The RTC has no concept of time zones. Linux and QEMU keep it in UTC, Windows
+// in local time. We assume UTC.
+//
+// # The periodic interrupt
+//
+// Besides the clock, the RTC has a divider that raises IRQ 8 at 2 to 8192 Hz.
+// After an interrupt, the RTC doesn't raise another one until status register
+// C is read, so the handler must always read it, even if it has nothing else
+// to do.
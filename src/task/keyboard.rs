@@ -38,6 +38,9 @@ static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
 // safely stored in a static and modified concurrently.
 static WAKER: AtomicWaker = AtomicWaker::new();
 
+/// The scancode of pressing F12 in scancode set 1.
+const DUMP_TASKS_SCANCODE: u8 = 0x58;
+
 /// Fill the scancode queue.
 /// 
 /// Called by the keyboard interrupt handler
@@ -47,6 +50,13 @@ pub(crate) fn add_scancode(scancode: u8) {
     // Since this function should not be callable from `main.rs`, we use the
     // `pub(crate)` visibility to make it only available to `lib.rs`.
 
+    // Pressing F12 dumps the tasks to the serial port. We do this here instead
+    // of in the keyboard task, since it must work while a task hangs the
+    // executor.
+    if scancode == DUMP_TASKS_SCANCODE {
+        super::dump_tasks();
+    }
+
     // Use the `OnceCell::try_get` to get a reference to the initialized queue.
     if let Ok(queue) = SCANCODE_QUEUE.try_get() {
         if let Err(_) = queue.push(scancode) {
@@ -1,111 +1,185 @@
 //! # Task module
+//!
+//! The tasks and executors are implemented in the `tiny_os_core` crate, so
+//! that their logic can be tested on the host. This module re-exports them and
+//! adds the parts that need the hardware: sleeping the CPU while the executor
+//! is idle, the timer futures, and the keyboard task. The async `sync`
+//! primitives (`Mutex`, `RwLock`, `Semaphore`, `Notify`, and `Barrier`) and
+//! the `channel`s, which tasks and interrupt handlers use to pass values to
+//! tasks, come from the core crate as well.
+//!
+//! It also provides the global `spawn` function, which starts a task on the
+//! running executor from anywhere in the kernel, and `dump_tasks`, which
+//! prints what the tasks of the running executor are doing.
 
-use core::{ 
-    future::Future, 
-    pin::Pin,
-    task::{ Context, Poll },
-    sync::atomic::{ AtomicU64, Ordering },
+use core::future::Future;
+use x86_64::instructions::interrupts;
+use crate::serial_println;
+
+pub use tiny_os_core::task::{
+    Task,
+    TaskId,
+    JoinError,
+    JoinHandle,
+    Priority,
+    Monitor,
+    MonitorError,
+    TaskInfo,
+    TaskState,
+    WakeSource,
+    channel,
+    simple_executor,
+    sync,
 };
-use alloc::boxed::Box;
+pub use tiny_os_core::task::executor::{ SpawnError, Spawner };
 
 pub mod executor;
-pub mod simple_executor;
 pub mod keyboard;
+pub mod timer;
+
+/// The spawner of the executor that `spawn` uses.
+struct GlobalSpawner(Option<Spawner>);
+
+// A `Spawner` is not `Send`, since the tasks that it creates aren't. Our kernel
+// only runs on one CPU, so the spawner and its tasks never move to another CPU.
+// Interrupt handlers run on the same CPU and only access the spawner through
+// the lock, which normal code holds with interrupts disabled.
+unsafe impl Send for GlobalSpawner {}
 
-// A newtype wrapper around a pinned, heap allocated, and dynamically dispatched
-// future with the empty type `()` as output.
-pub struct Task {
-    // This field makes it possible to uniquely name a task, which is required
-    // for waking a specific task.
-    id: TaskId,
-    future: Pin<Box<dyn Future<Output = ()>>>,
+static GLOBAL_SPAWNER: spin::Mutex<GlobalSpawner> = spin::Mutex::new(GlobalSpawner(None));
+
+/// Makes `spawner` the target of `spawn`. `Executor::run` calls this for its
+/// own spawner.
+pub fn set_global_spawner(spawner: Spawner) {
+    let old = interrupts::without_interrupts(|| GLOBAL_SPAWNER.lock().0.replace(spawner));
+    // Dropping the old spawner doesn't free memory of the heap, unless it was
+    // the last reference to its spawn queue. Either way, we drop it with
+    // interrupts enabled.
+    drop(old);
 }
 
-impl Task {
-    // Create a new Task structs from futures.
-    //
-    // The `'static` lifetime is required here because the returned `Task` can
-    // live for an arbitrary time, so the future needs to be valid for that time
-    // too.
-    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
-        Task {
-            id: TaskId::new(),
-            // Pins `future` in memory.
-            future: Box::pin(future),
-        }
-    }
+/// Spawns a task on the running executor and returns its `JoinHandle`.
+///
+/// This works from tasks and interrupt handlers. The task gets the `Normal`
+/// priority. Panics if no executor is running, see `try_spawn` for a variant
+/// that doesn't panic.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + 'static,
+    F::Output: 'static,
+{
+    spawn_with_priority(Priority::Normal, future)
+}
 
-    // Allow the executor to poll the stored future.
-    fn poll(&mut self, context: &mut Context) -> Poll<()> {
-        // Since the `poll` method of the `Future` trait expects to be called on
-        // a `Pin<&mut T>` type, we use the `Pin::as_mut` method to convert the
-        // `self.future` field of type `Pin<Box<T>>` first. Then we `call` poll
-        // on the converted `self.future` field and return the result. Since the
-        // `Task::poll` method should be only called by the executor, we keep
-        // the function private to the `task` module.
-        self.future.as_mut().poll(context)
+/// Spawns a task with the given priority, see `spawn`.
+pub fn spawn_with_priority<F>(priority: Priority, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + 'static,
+    F::Output: 'static,
+{
+    match try_spawn_with_priority(priority, future) {
+        Ok(handle) => handle,
+        Err(err) => panic!("failed to spawn task: {}", err),
     }
 }
 
-// Gives each task an unique ID. This is required because we need a way to
-// specify which task should be woken.
-//
-// The `TaskId` struct is a simple wrapper type around `u64`. The sortable trait
-// is important because we want to use `TaskId` as the key type of a `BTreeMap`.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-struct TaskId(u64);
-
-impl TaskId {
-    fn new() -> Self {
-        // Ensure that each ID is assigned only once.
-        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
-        // The fetch_add method atomically increases the value and returns the
-        // previous value in one atomic operation. This means that even when the
-        // TaskId::new method is called in parallel, every ID is returned
-        // exactly once.
-        //
-        // The Ordering parameter defines whether the compiler is allowed to
-        // reorder the fetch_add operation in the instructions stream. Since we
-        // only require that the ID is unique, the Relaxed ordering with the
-        // weakest requirements is enough in this case.
-        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+/// Spawns a task with a name, which shows up in `dump_tasks`, see `spawn`.
+pub fn spawn_named<F>(name: &'static str, priority: Priority, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + 'static,
+    F::Output: 'static,
+{
+    let spawner = interrupts::without_interrupts(|| GLOBAL_SPAWNER.lock().0.clone());
+    match spawner {
+        Some(spawner) => spawner.spawn_named(name, priority, future),
+        None => panic!("failed to spawn task {}: {}", name, SpawnError::NoExecutor),
     }
 }
 
-// ********** SIdenote **********
-//
-// # Implementation
-//
-// ## Task
-//
-// The `Task` struct is a newtype wrapper around a pinned, heap allocated, and
-// dynamically dispatched future with the empty type `()` as output. Let’s go
-// through it in detail:
-// - We require that the future associated with a task returns `()`. This means
-//   that tasks don’t return any result, they are just executed for its side
-//   effects. For example, the `example_task` function we defined in `main.rs`
-//   has no return value, but it prints something to the screen as a side
-//   effect.
-// - The `dyn` keyword indicates that we store a trait object in the Box. This
-//   means that the methods on the future are dynamically dispatched, which
-//   makes it possible to store different types of futures in the Task type.
-//   This is important because each `async fn` has its own type and we want to
-//   be able to create multiple different tasks.
-// - The `Pin<Box>` type ensures that a value cannot be moved in memory by
-//   placing it on the heap and preventing the creation of `&mut` references to
-//   it. This is important because futures generated by async/await might be
-//   self-referential, i.e. contain pointers to itself that would be invalidated
-//   when the future is moved.
-//
-// ## Executor with Waker Support
+/// Spawns a task like `spawn`, but returns an error instead of panicking.
+///
+/// Interrupt handlers should use this function, usually with the `BottomHalf`
+/// priority. Spawning allocates the task on the heap, which is safe in
+/// interrupt handlers, see the sidenote.
+pub fn try_spawn<F>(future: F) -> Result<JoinHandle<F::Output>, SpawnError>
+where
+    F: Future + 'static,
+    F::Output: 'static,
+{
+    try_spawn_with_priority(Priority::Normal, future)
+}
+
+/// Spawns a task with the given priority, see `try_spawn`.
+pub fn try_spawn_with_priority<F>(
+    priority: Priority,
+    future: F,
+) -> Result<JoinHandle<F::Output>, SpawnError>
+where
+    F: Future + 'static,
+    F::Output: 'static,
+{
+    let spawner = interrupts::without_interrupts(|| GLOBAL_SPAWNER.lock().0.clone());
+    spawner.ok_or(SpawnError::NoExecutor)?.try_spawn_with_priority(priority, future)
+}
+
+/// The monitor of the executor that `dump_tasks` prints.
+struct GlobalMonitor(Option<Monitor>);
+
+// Like the `Spawner`, a `Monitor` is not `Send`. It holds an `Rc` based weak
+// reference, whose counts are not atomic, so `dump_tasks` uses the monitor
+// while holding the lock instead of cloning it.
+unsafe impl Send for GlobalMonitor {}
+
+static GLOBAL_MONITOR: spin::Mutex<GlobalMonitor> = spin::Mutex::new(GlobalMonitor(None));
+
+/// Makes `monitor` the source of `dump_tasks`. `Executor::run` calls this for
+/// its own monitor.
+pub fn set_global_monitor(monitor: Monitor) {
+    let old = interrupts::without_interrupts(|| GLOBAL_MONITOR.lock().0.replace(monitor));
+    drop(old);
+}
+
+/// Prints the state of all tasks of the running executor to the serial port.
+///
+/// This neither allocates nor blocks, so it can be called from the panic
+/// handler and from interrupt handlers, even while a task hangs the executor.
+/// If a lock that it needs is taken, it prints why it failed instead.
+pub fn dump_tasks() {
+    interrupts::without_interrupts(|| {
+        let global = match GLOBAL_MONITOR.try_lock() {
+            Some(global) => global,
+            None => {
+                serial_println!("task dump failed: the monitor is locked");
+                return;
+            }
+        };
+        let monitor = match &global.0 {
+            Some(monitor) => monitor,
+            None => {
+                serial_println!("task dump failed: no executor is running");
+                return;
+            }
+        };
+        serial_println!("tasks:");
+        if let Err(err) = monitor.try_for_each(|info| serial_println!("{}", info)) {
+            serial_println!("task dump failed: {}", err);
+        }
+    });
+}
+
+// ********** Sidenote **********
 //
-// To fix the performance problem, we need to create an executor that properly
-// utilizes the `Waker` notifications. This way, the executor is notified when
-// the next keyboard interrupt occurs, so it does not need to keep polling the
-// `print_keypresses` task over and over again.
+// # Spawning from interrupt handlers
 //
-// ### Task Id
+// The spawn queue is a lock-free `SegQueue`, so pushing a task never blocks.
+// Creating the task allocates heap memory, though, and our heap allocators use
+// a spin lock. If an interrupt handler spawned a task while the interrupted
+// code was in the middle of an allocation, the handler would wait for a lock
+// that is never released. This is why `allocator::Locked` disables interrupts
+// while the lock is held: the interrupt is delayed until the allocation is
+// done, and the handler always finds the lock free.
 //
-// The first step in creating an executor with proper support for waker
-// notifications is to give each task an unique ID. This is required because we
-// need a way to specify which task should be woken.
+// Spawning can still fail if the heap is exhausted, which is why interrupt
+// handlers should use `try_spawn` instead of `spawn`. If an interrupt only
+// needs to hand data to a task, it is cheaper to wake an existing task, like
+// the keyboard interrupt does with the `ScancodeStream`.
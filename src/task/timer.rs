@@ -0,0 +1,222 @@
+//! # Timer module
+//!
+//! Futures that let tasks wait for time to pass:
+//!
+//! - `sleep` and `sleep_until` complete at a deadline.
+//! - `timeout` cancels a future that takes too long.
+//! - `interval` completes periodically.
+//!
+//! The futures register their waker in the timer wheel of the `tiny_os_core`
+//! crate, which the timer interrupt advances on every tick. This way, a
+//! sleeping task is not polled until its deadline expired.
+
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{ Context, Poll, Waker },
+    time::Duration,
+};
+use futures_util::{ future::poll_fn, stream::Stream };
+use tiny_os_core::task::timer::{ TimerId, TimerWheel };
+use x86_64::instructions::interrupts;
+use crate::time::Instant;
+
+/// The time that a slot of the wheel covers: one millisecond, which is one
+/// tick of the PIT at its default frequency.
+const GRANULARITY_NANOS: u64 = 1_000_000;
+
+/// The timers of all sleeping tasks.
+///
+/// Tasks only access the wheel with interrupts disabled, so the timer
+/// interrupt never waits for the lock.
+static WHEEL: spin::Mutex<TimerWheel> = spin::Mutex::new(TimerWheel::new(GRANULARITY_NANOS));
+
+fn with_wheel<R>(f: impl FnOnce(&mut TimerWheel) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut WHEEL.lock()))
+}
+
+/// Wakes the tasks whose deadline expired. Called by the timer interrupt
+/// handler.
+///
+/// Must not block or allocate heap.
+pub(crate) fn on_tick() {
+    WHEEL.lock().advance(Instant::now().as_nanos(), Waker::wake);
+}
+
+/// Returns the number of timers that are waiting for their deadline.
+pub fn pending_timers() -> usize {
+    with_wheel(|wheel| wheel.len())
+}
+
+/// Returns a future that completes after `duration`.
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(Instant::now() + duration)
+}
+
+/// Returns a future that completes at `deadline`.
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep { deadline, timer: None }
+}
+
+/// The future returned by `sleep` and `sleep_until`.
+///
+/// The timer is only registered in the wheel when the future is polled, and
+/// it is removed again when the future is dropped.
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Sleep {
+    deadline: Instant,
+    timer: Option<TimerId>,
+}
+
+impl Sleep {
+    /// Returns the instant at which the future completes.
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Changes the deadline, which also makes a completed future pending again.
+    pub fn reset(&mut self, deadline: Instant) {
+        self.cancel();
+        self.deadline = deadline;
+    }
+
+    fn cancel(&mut self) {
+        if let Some(timer) = self.timer.take() {
+            with_wheel(|wheel| wheel.remove(timer));
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        // The wheel only wakes us, the clock decides whether we are done. This
+        // also handles a wake-up that is meant for another future of the task.
+        if Instant::now() >= self.deadline {
+            self.cancel();
+            return Poll::Ready(());
+        }
+        let deadline = self.deadline.as_nanos();
+        let timer = self.timer;
+        self.timer = Some(with_wheel(|wheel| match timer {
+            Some(timer) if wheel.update_waker(timer, cx.waker()) => timer,
+            // The timer fired, but for a clock value that was just before the
+            // deadline, or we were never registered.
+            _ => wheel.insert(deadline, cx.waker().clone()),
+        }));
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// The error of a `Timeout` whose deadline expired first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+/// Returns a future that completes with the output of `future`, or with
+/// `Elapsed` if `future` doesn't complete within `duration`.
+///
+/// On a timeout, the inner future is dropped together with the `Timeout`.
+pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+    Timeout { future, sleep: sleep(duration) }
+}
+
+/// The future returned by `timeout`.
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Timeout<F> {
+    future: F,
+    sleep: Sleep,
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // This is a manual "pin projection": the inner future is never moved
+        // out of `self`, so it stays pinned. `Sleep` is `Unpin` anyway.
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        // The inner future wins if both are ready.
+        if let Poll::Ready(output) = future.poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        Pin::new(&mut this.sleep).poll(cx).map(|()| Err(Elapsed))
+    }
+}
+
+/// Returns an `Interval` that ticks every `period`, starting immediately.
+///
+/// Panics if `period` is zero.
+pub fn interval(period: Duration) -> Interval {
+    assert!(period > Duration::from_secs(0), "interval period must not be zero");
+    Interval { period, sleep: sleep_until(Instant::now()) }
+}
+
+/// A stream of instants that are `period` apart, see `interval`.
+///
+/// If a task falls behind by more than a period, the missed ticks are skipped
+/// and the next tick is one period after the late one. This way, a long delay
+/// doesn't cause a burst of ticks.
+pub struct Interval {
+    period: Duration,
+    sleep: Sleep,
+}
+
+impl Interval {
+    /// Returns the time between two ticks.
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    /// Completes at the next tick and returns its scheduled instant.
+    pub async fn tick(&mut self) -> Instant {
+        poll_fn(|cx| self.poll_tick(cx)).await
+    }
+
+    /// Polls for the next tick, see `tick`.
+    pub fn poll_tick(&mut self, cx: &mut Context) -> Poll<Instant> {
+        if Pin::new(&mut self.sleep).poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        let scheduled = self.sleep.deadline();
+        let mut next = scheduled + self.period;
+        let now = Instant::now();
+        if next <= now {
+            next = now + self.period;
+        }
+        self.sleep.reset(next);
+        Poll::Ready(scheduled)
+    }
+}
+
+impl Stream for Interval {
+    type Item = Instant;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Instant>> {
+        self.poll_tick(cx).map(Some)
+    }
+}
+
+// ********** Sidenote **********
+//
+// # How a task sleeps
+//
+// An `async fn` can't block, so `sleep` returns a future instead. On its first
+// poll, it stores the waker of the task together with the deadline in the
+// timer wheel and returns `Poll::Pending`. The executor then doesn't poll the
+// task again until somebody calls the waker.
+//
+// That somebody is the timer interrupt: on every tick, it advances the wheel to
+// the current time, which takes the wakers of all expired timers out of the
+// wheel and wakes them. The waker pushes the header of the task to the task
+// queue of the executor, which polls the `Sleep` future again. Now the deadline
+// has passed and the future completes.
+//
+// Since the timer interrupt arrives every millisecond, a sleep can take up to
+// one millisecond longer than requested, but never less.
@@ -0,0 +1,386 @@
+//! # Time module
+//!
+//! A monotonic `Instant` with nanosecond resolution, measured from the start of
+//! the kernel clock.
+//!
+//! Like `std::time::Instant`, it can only be compared with other instants and
+//! combined with `Duration`s. Reading the clock takes no locks, so it works in
+//! interrupt handlers as well as in tasks.
+//!
+//! Until `init` runs, the clock is driven by the PIT with the resolution of one
+//! timer tick. `init` then switches to the best available source:
+//!
+//! 1. The TSC, if the CPU advertises an invariant TSC. Its frequency is
+//!    calibrated against the HPET, or against the PIT if there is no HPET.
+//! 2. The main counter of the HPET (see the `hpet` submodule).
+//! 3. Otherwise, the PIT stays the clock source.
+//!
+//! `SystemTime` is the wall clock. It follows the kernel clock from the time
+//! that `rtc::init` read from the real-time clock.
+
+use core::{
+    convert::TryFrom,
+    fmt,
+    ops::{ Add, AddAssign, Sub, SubAssign },
+    sync::atomic::{ AtomicU64, AtomicU8, Ordering },
+    time::Duration,
+};
+use x86_64::instructions::interrupts;
+use crate::{ pit, rtc::DateTime };
+
+pub mod hpet;
+pub mod tsc;
+
+/// How long the TSC is measured during calibration. A longer window gives a
+/// more exact frequency, but delays the boot.
+const CALIBRATION_WINDOW: Duration = Duration::from_millis(20);
+
+/// How often the calibration reads the reference clock without seeing it
+/// change before it gives up.
+const CALIBRATION_MAX_SPINS: u64 = 10_000_000;
+
+/// The hardware clock that `Instant::now` reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ClockSource {
+    Pit = 0,
+    Hpet = 1,
+    Tsc = 2,
+}
+
+static SOURCE: AtomicU8 = AtomicU8::new(ClockSource::Pit as u8);
+/// The time in nanoseconds and the counter value of the current source when
+/// we switched to it. This keeps the clock continuous across the switch.
+static BASE_NANOS: AtomicU64 = AtomicU64::new(0);
+static BASE_COUNTER: AtomicU64 = AtomicU64::new(0);
+/// The calibrated frequency of the TSC in Hz, or 0.
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+/// The wall clock time of the start of the kernel clock, in nanoseconds since
+/// the Unix epoch.
+static BOOT_TIME_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// Detects the HPET, calibrates the TSC, and switches the clock to the best
+/// source. Returns the chosen source.
+///
+/// Needs the kernel memory to map the HPET registers and enabled interrupts if
+/// the TSC must be calibrated against the PIT.
+pub fn init() -> ClockSource {
+    let has_hpet = hpet::init();
+    if tsc::is_present() && tsc::is_invariant() {
+        let frequency = if has_hpet {
+            let reference = || hpet::ticks_to_nanos(hpet::counter().unwrap_or(0));
+            tsc::calibrate(reference, CALIBRATION_WINDOW, CALIBRATION_MAX_SPINS)
+        } else {
+            let reference = || pit::uptime().as_nanos() as u64;
+            tsc::calibrate(reference, CALIBRATION_WINDOW, CALIBRATION_MAX_SPINS)
+        };
+        if let Some(frequency) = frequency.filter(|&frequency| frequency > 0) {
+            TSC_FREQUENCY.store(frequency, Ordering::Relaxed);
+            switch_to(ClockSource::Tsc);
+            return ClockSource::Tsc;
+        }
+    }
+    if has_hpet {
+        switch_to(ClockSource::Hpet);
+        return ClockSource::Hpet;
+    }
+    ClockSource::Pit
+}
+
+/// Returns the source that the clock currently uses.
+pub fn clock_source() -> ClockSource {
+    match SOURCE.load(Ordering::Acquire) {
+        1 => ClockSource::Hpet,
+        2 => ClockSource::Tsc,
+        _ => ClockSource::Pit,
+    }
+}
+
+/// Returns the calibrated frequency of the TSC in Hz, or `None` if the TSC is
+/// not used.
+pub fn tsc_frequency() -> Option<u64> {
+    match TSC_FREQUENCY.load(Ordering::Relaxed) {
+        0 => None,
+        frequency => Some(frequency),
+    }
+}
+
+/// Makes `source` the clock source, continuing at the current time.
+fn switch_to(source: ClockSource) {
+    interrupts::without_interrupts(|| {
+        BASE_NANOS.store(now_nanos(), Ordering::Relaxed);
+        let counter = match source {
+            ClockSource::Pit => 0,
+            ClockSource::Hpet => hpet::counter().unwrap_or(0),
+            ClockSource::Tsc => tsc::read(),
+        };
+        BASE_COUNTER.store(counter, Ordering::Relaxed);
+        SOURCE.store(source as u8, Ordering::Release);
+    });
+}
+
+/// Reads the current source and returns the nanoseconds since the start of the
+/// kernel clock.
+fn now_nanos() -> u64 {
+    let base_nanos = BASE_NANOS.load(Ordering::Relaxed);
+    let base_counter = BASE_COUNTER.load(Ordering::Relaxed);
+    match clock_source() {
+        ClockSource::Pit => pit::uptime().as_nanos() as u64,
+        ClockSource::Hpet => {
+            let ticks = hpet::counter().unwrap_or(base_counter).wrapping_sub(base_counter);
+            base_nanos + hpet::ticks_to_nanos(ticks)
+        }
+        ClockSource::Tsc => {
+            let cycles = tsc::read().wrapping_sub(base_counter);
+            let frequency = TSC_FREQUENCY.load(Ordering::Relaxed);
+            base_nanos + (u128::from(cycles) * 1_000_000_000 / u128::from(frequency)) as u64
+        }
+    }
+}
+
+/// A point in time, measured by the kernel clock.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+    /// Nanoseconds since the kernel clock was started. A `u64` lasts for more
+    /// than 500 years.
+    nanos: u64,
+}
+
+impl Instant {
+    /// Returns the current time.
+    pub fn now() -> Instant {
+        Instant::from_nanos(now_nanos())
+    }
+
+    /// Creates an instant from the number of nanoseconds since the kernel
+    /// clock was started.
+    pub const fn from_nanos(nanos: u64) -> Instant {
+        Instant { nanos }
+    }
+
+    /// Returns the number of nanoseconds since the kernel clock was started.
+    pub const fn as_nanos(&self) -> u64 {
+        self.nanos
+    }
+
+    /// Returns the time that passed from `earlier` to `self`, or zero if
+    /// `earlier` is later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.nanos.saturating_sub(earlier.nanos))
+    }
+
+    /// Returns the time that passed since `self`.
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    /// Returns `self + duration`, or `None` on overflow.
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.nanos.checked_add(nanos).map(Instant::from_nanos)
+    }
+
+    /// Returns `self - duration`, or `None` if the result would be before the
+    /// start of the clock.
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.nanos.checked_sub(nanos).map(Instant::from_nanos)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration).expect("overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration).expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, duration: Duration) {
+        *self = *self - duration;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+impl fmt::Debug for Instant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Instant({:?})", Duration::from_nanos(self.nanos))
+    }
+}
+
+/// Sets the wall clock to `now`.
+///
+/// Later calls of `SystemTime::now` add the time of the kernel clock that
+/// passed since this call.
+pub fn set_system_time(now: SystemTime) {
+    let boot_time = now.nanos.saturating_sub(Instant::now().as_nanos());
+    BOOT_TIME_NANOS.store(boot_time, Ordering::Relaxed);
+}
+
+/// A point in time measured by the wall clock, like `std::time::SystemTime`.
+///
+/// Unlike `Instant`, the wall clock is not monotonic: `set_system_time` can
+/// move it backwards. Until `rtc::init` (or `set_system_time`) was called, the
+/// wall clock starts at the Unix epoch.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SystemTime {
+    /// Nanoseconds since the Unix epoch, which lasts until the year 2554.
+    nanos: u64,
+}
+
+impl SystemTime {
+    /// 1970-01-01 00:00:00 UTC
+    pub const UNIX_EPOCH: SystemTime = SystemTime { nanos: 0 };
+
+    /// Returns the current time of the wall clock.
+    pub fn now() -> SystemTime {
+        let boot_time = BOOT_TIME_NANOS.load(Ordering::Relaxed);
+        SystemTime { nanos: boot_time + Instant::now().as_nanos() }
+    }
+
+    /// Returns the time `seconds` after the Unix epoch.
+    pub const fn from_unix_seconds(seconds: u64) -> SystemTime {
+        SystemTime { nanos: seconds * 1_000_000_000 }
+    }
+
+    /// Returns the time that passed from `earlier` to `self`, or an error
+    /// with the opposite duration if `earlier` is later.
+    pub fn duration_since(&self, earlier: SystemTime) -> Result<Duration, SystemTimeError> {
+        match self.nanos.checked_sub(earlier.nanos) {
+            Some(nanos) => Ok(Duration::from_nanos(nanos)),
+            None => Err(SystemTimeError(Duration::from_nanos(earlier.nanos - self.nanos))),
+        }
+    }
+
+    /// Returns the time that passed since `self`, see `duration_since`.
+    pub fn elapsed(&self) -> Result<Duration, SystemTimeError> {
+        SystemTime::now().duration_since(*self)
+    }
+
+    /// Returns `self + duration`, or `None` on overflow.
+    pub fn checked_add(&self, duration: Duration) -> Option<SystemTime> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.nanos.checked_add(nanos).map(|nanos| SystemTime { nanos })
+    }
+
+    /// Returns `self - duration`, or `None` if the result would be before the
+    /// Unix epoch.
+    pub fn checked_sub(&self, duration: Duration) -> Option<SystemTime> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.nanos.checked_sub(nanos).map(|nanos| SystemTime { nanos })
+    }
+
+    /// Returns the date and time in UTC, rounded down to the second.
+    pub fn to_date_time(&self) -> DateTime {
+        DateTime::from_unix_seconds(self.nanos / 1_000_000_000)
+    }
+}
+
+impl Add<Duration> for SystemTime {
+    type Output = SystemTime;
+
+    fn add(self, duration: Duration) -> SystemTime {
+        self.checked_add(duration).expect("overflow when adding duration to system time")
+    }
+}
+
+impl Sub<Duration> for SystemTime {
+    type Output = SystemTime;
+
+    fn sub(self, duration: Duration) -> SystemTime {
+        self.checked_sub(duration).expect("overflow when subtracting duration from system time")
+    }
+}
+
+impl fmt::Debug for SystemTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SystemTime({})", self)
+    }
+}
+
+/// Formats the time with milliseconds, like `2021-06-30 23:59:59.123`, which
+/// is meant for timestamps in log messages.
+impl fmt::Display for SystemTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let millis = self.nanos / 1_000_000 % 1000;
+        write!(f, "{}.{:03}", self.to_date_time(), millis)
+    }
+}
+
+/// The error of `SystemTime::duration_since` if the other time is later.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SystemTimeError(Duration);
+
+impl SystemTimeError {
+    /// Returns how much later the other time was.
+    pub fn duration(&self) -> Duration {
+        self.0
+    }
+}
+
+impl fmt::Display for SystemTimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "second time provided was later than self by {:?}", self.0)
+    }
+}
+
+#[test_case]
+fn format_system_time() {
+    let time = SystemTime::from_unix_seconds(1_709_209_815) + Duration::from_millis(42);
+    assert_eq!(alloc::format!("{}", time), "2024-02-29 12:30:15.042");
+    let earlier = time - Duration::from_secs(1);
+    assert_eq!(time.duration_since(earlier), Ok(Duration::from_secs(1)));
+    assert_eq!(
+        earlier.duration_since(time).unwrap_err().duration(),
+        Duration::from_secs(1)
+    );
+}
+
+// ********** Sidenote **********
+//
+// # Clock sources
+//
+// A PC has several clocks with very different properties:
+//
+// - The PIT interrupts at a fixed rate. Counting its interrupts is simple, but
+//   the resolution is the tick (one millisecond in our kernel).
+// - The HPET has a counter that runs at 10 MHz or more and can be read at any
+//   time. Reading it is a memory-mapped I/O access, which is slow (around a
+//   microsecond in a VM).
+// - The TSC counts CPU cycles and is read with a single instruction. On older
+//   CPUs, its rate followed the CPU frequency and it stopped in sleep states,
+//   so it was useless as a clock. CPUs that set the "invariant TSC" bit in
+//   CPUID leaf `0x8000_0007` guarantee a constant rate.
+//
+// The TSC doesn't tell us its frequency, so we count its cycles while another
+// clock measures a known time. This is called calibration. The error of the
+// result depends on the resolution of the reference: against the HPET, a
+// window of 20 ms gives an error in the order of parts per million, against
+// the PIT, it is closer to a tenth of a percent.
+//
+// # Multiple CPUs
+//
+// Our kernel only runs on one CPU. With several CPUs, the TSCs of the CPUs
+// would have to be synchronized, or every CPU would need its own base values.
@@ -0,0 +1,105 @@
+//! # HPET module
+//!
+//! A driver for the main counter of the High Precision Event Timer.
+//!
+//! The HPET has a free-running counter with a frequency of at least 10 MHz
+//! (QEMU uses 100 MHz), which makes it a good reference for calibrating the TSC
+//! and a clock source of its own. We only use the counter, not the comparators
+//! that can raise interrupts.
+
+use core::{
+    ptr,
+    sync::atomic::{ AtomicU64, Ordering },
+};
+use x86_64::{ PhysAddr, VirtAddr };
+use crate::{ acpi, memory };
+
+/// The General Capabilities and ID register. Bits 32–63 hold the period of
+/// the counter in femtoseconds.
+const CAPABILITIES: u64 = 0x000;
+/// The General Configuration register.
+const CONFIGURATION: u64 = 0x010;
+/// The Main Counter Value register.
+const MAIN_COUNTER: u64 = 0x0f0;
+
+/// Set in the capabilities if the main counter is 64 bits wide.
+const COUNT_SIZE_64: u64 = 1 << 13;
+/// Starts the main counter.
+const ENABLE: u64 = 1 << 0;
+
+/// The maximum counter period that the specification allows (100 ns).
+const MAX_PERIOD_FS: u64 = 100_000_000;
+
+/// The virtual address of the registers, or 0 if there is no usable HPET.
+static BASE: AtomicU64 = AtomicU64::new(0);
+/// The period of the main counter in femtoseconds.
+static PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+
+/// Finds the HPET through ACPI, maps its registers, and starts the main
+/// counter. Returns `false` if there is no usable HPET.
+///
+/// Needs the kernel memory (see `memory::init_kernel_memory`). We only support
+/// a 64-bit main counter, since a 32-bit one at 100 MHz wraps around after 43
+/// seconds and would need overflow tracking.
+pub(super) fn init() -> bool {
+    let physical_memory_offset = match memory::physical_memory_offset() {
+        Some(offset) => offset,
+        None => return false,
+    };
+    let table = match unsafe { acpi::find_hpet(physical_memory_offset) } {
+        Some(table) => table,
+        None => return false,
+    };
+    let base = match unsafe { memory::map_mmio(PhysAddr::new(table.address), 1024) } {
+        Some(base) => base,
+        None => return false,
+    };
+
+    let capabilities = unsafe { read(base, CAPABILITIES) };
+    let period = capabilities >> 32;
+    if capabilities & COUNT_SIZE_64 == 0 || period == 0 || period > MAX_PERIOD_FS {
+        return false;
+    }
+    unsafe {
+        let configuration = read(base, CONFIGURATION);
+        write(base, CONFIGURATION, configuration | ENABLE);
+    }
+    PERIOD_FS.store(period, Ordering::Relaxed);
+    BASE.store(base.as_u64(), Ordering::Release);
+    true
+}
+
+/// Returns `true` if `init` found a usable HPET.
+pub fn is_available() -> bool {
+    BASE.load(Ordering::Acquire) != 0
+}
+
+/// Returns the frequency of the main counter in Hz, or `None` if there is no
+/// usable HPET.
+pub fn frequency() -> Option<u64> {
+    match PERIOD_FS.load(Ordering::Relaxed) {
+        0 => None,
+        period => Some(1_000_000_000_000_000 / period),
+    }
+}
+
+/// Returns the current value of the main counter.
+pub fn counter() -> Option<u64> {
+    match BASE.load(Ordering::Acquire) {
+        0 => None,
+        base => Some(unsafe { read(VirtAddr::new(base), MAIN_COUNTER) }),
+    }
+}
+
+/// Converts a number of counter ticks to nanoseconds.
+pub fn ticks_to_nanos(ticks: u64) -> u64 {
+    (u128::from(ticks) * u128::from(PERIOD_FS.load(Ordering::Relaxed)) / 1_000_000) as u64
+}
+
+unsafe fn read(base: VirtAddr, register: u64) -> u64 {
+    ptr::read_volatile((base + register).as_ptr::<u64>())
+}
+
+unsafe fn write(base: VirtAddr, register: u64, value: u64) {
+    ptr::write_volatile((base + register).as_mut_ptr::<u64>(), value)
+}
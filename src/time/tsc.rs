@@ -0,0 +1,66 @@
+//! # TSC module
+//!
+//! Reads and calibrates the Time Stamp Counter, a 64-bit counter in every CPU
+//! that increases with a constant rate.
+//!
+//! Reading the TSC takes only a few cycles, so it is the cheapest clock of the
+//! machine. CPUID doesn't tell its frequency reliably, so we measure it against
+//! a clock whose frequency we know (the HPET or the PIT).
+
+use core::{ arch::x86_64::{ __cpuid, _rdtsc }, time::Duration };
+
+/// Returns `true` if the CPU has a TSC.
+pub fn is_present() -> bool {
+    let result = unsafe { __cpuid(1) };
+    result.edx & (1 << 4) != 0
+}
+
+/// Returns `true` if the CPU advertises an invariant TSC, which runs at a
+/// constant rate in all power states.
+///
+/// Without this guarantee, the TSC might stop in deep sleep states or change
+/// its rate with the CPU frequency, so it can't be used as a clock.
+pub fn is_invariant() -> bool {
+    let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    if max_extended_leaf < 0x8000_0007 {
+        return false;
+    }
+    let result = unsafe { __cpuid(0x8000_0007) };
+    result.edx & (1 << 8) != 0
+}
+
+/// Returns the current value of the TSC.
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Measures the frequency of the TSC in Hz against a reference clock.
+///
+/// `reference` returns the time of the reference clock in nanoseconds. The
+/// measurement starts at a change of the reference, so that its resolution
+/// doesn't distort the result, and lasts for at least `window`. Returns `None`
+/// if the reference doesn't change within `max_spins` reads, e.g. because it
+/// is driven by interrupts that are disabled.
+pub fn calibrate(reference: impl Fn() -> u64, window: Duration, max_spins: u64) -> Option<u64> {
+    let window = window.as_nanos() as u64;
+    let wait_for_change = |since: u64| {
+        for _ in 0..max_spins {
+            let now = reference();
+            if now != since {
+                return Some(now);
+            }
+            core::hint::spin_loop();
+        }
+        None
+    };
+
+    let start = wait_for_change(reference())?;
+    let tsc_start = read();
+    let mut end = start;
+    while end - start < window {
+        end = wait_for_change(end)?;
+    }
+    let tsc_end = read();
+    let hz = u128::from(tsc_end - tsc_start) * 1_000_000_000 / u128::from(end - start);
+    Some(hz as u64)
+}
@@ -0,0 +1,101 @@
+//! # APIC test
+//!
+//! Switches from the 8259 PIC to the APIC and checks that hardware interrupts
+//! still arrive.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(tiny_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{ entry_point, BootInfo };
+use core::panic::PanicInfo;
+use x86_64::PhysAddr;
+use tiny_os::{
+    acpi::{ self, Polarity, TriggerMode },
+    allocator, apic,
+    interrupts::InterruptIndex,
+    memory,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+    use tiny_os::memory::BootInfoFrameAllocator;
+
+    tiny_os::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_kernel_memory(mapper, frame_allocator);
+
+    apic::init().expect("APIC initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    tiny_os::test_panic_handler(info)
+}
+
+// QEMU provides an I/O APIC at the standard address and a MADT that describes
+// it.
+#[test_case]
+fn madt_describes_io_apic() {
+    let offset = memory::physical_memory_offset().unwrap();
+    let madt = unsafe { acpi::find_madt(offset) }.unwrap();
+    assert!(!madt.local_apics.is_empty());
+    assert_eq!(madt.io_apics[0].address, 0xfec0_0000);
+}
+
+#[test_case]
+fn apic_is_enabled() {
+    assert!(apic::is_enabled());
+    assert!(apic::local_apic_id().is_some());
+}
+
+#[test_case]
+fn timer_and_keyboard_are_routed() {
+    let offset = memory::physical_memory_offset().unwrap();
+    let madt = unsafe { acpi::find_madt(offset) }.unwrap();
+    for &(irq, index) in &[(0, InterruptIndex::Timer), (1, InterruptIndex::Keyboard)] {
+        let (gsi, _, _) = madt.isa_irq(irq);
+        let entry = apic::redirection(gsi).unwrap();
+        assert_eq!(entry.vector, index.as_u8());
+        assert_eq!(entry.destination, apic::local_apic_id().unwrap());
+        assert_eq!(entry.polarity, Polarity::ActiveHigh);
+        assert_eq!(entry.trigger, TriggerMode::Edge);
+        assert!(!entry.masked);
+    }
+}
+
+// `hlt` only returns when an interrupt arrives. If the timer wasn't routed
+// through the I/O APIC or its EOI didn't reach the local APIC, the test would
+// hang until the test timeout.
+#[test_case]
+fn timer_interrupts_arrive() {
+    for _ in 0..10 {
+        x86_64::instructions::hlt();
+    }
+}
+
+// A mapping that doesn't fit into the MMIO range must fail without using up
+// the range, so that later mappings still succeed. Mapping the I/O APIC a
+// second time is harmless.
+#[test_case]
+fn oversized_mmio_mapping_fails() {
+    let phys = PhysAddr::new(0xfec0_0000);
+    assert!(unsafe { memory::map_mmio(phys, memory::MMIO_SIZE + 1) }.is_none());
+    assert!(unsafe { memory::map_mmio(phys, 4096) }.is_some());
+}
@@ -0,0 +1,108 @@
+//! # Async sync test
+//!
+//! Holds the `task::sync` primitives across timer sleeps, where a spin lock
+//! would hang the executor.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(tiny_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{ rc::Rc, vec, vec::Vec };
+use bootloader::{ entry_point, BootInfo };
+use core::{ cell::RefCell, panic::PanicInfo, time::Duration };
+use tiny_os::{
+    allocator,
+    task::{
+        executor::Executor,
+        sync::{ Mutex, Notify, Semaphore },
+        timer::sleep,
+    },
+    time::Instant,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+    use tiny_os::memory::{ self, BootInfoFrameAllocator };
+
+    tiny_os::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_kernel_memory(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    tiny_os::test_panic_handler(info)
+}
+
+const MS: Duration = Duration::from_millis(1);
+
+/// The primitives have `const` constructors, so they can be statics.
+static COUNTER: Mutex<u32> = Mutex::new(0);
+
+#[test_case]
+fn static_mutex_held_across_sleep() {
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let mut executor = Executor::new();
+    for i in 0..3 {
+        let log = log.clone();
+        executor.spawn(async move {
+            let mut counter = COUNTER.lock().await;
+            let before = *counter;
+            sleep(2 * MS).await;
+            *counter = before + 1;
+            log.borrow_mut().push(i);
+        });
+    }
+    executor.run_to_completion();
+    // Without the lock, all tasks would read 0 before any of them writes.
+    assert_eq!(*COUNTER.try_lock().unwrap(), 3);
+    assert_eq!(*log.borrow(), vec![0, 1, 2]);
+}
+
+#[test_case]
+fn semaphore_limits_sleeping_tasks() {
+    let semaphore = Rc::new(Semaphore::new(2));
+    let mut executor = Executor::new();
+    let start = Instant::now();
+    for _ in 0..4 {
+        let semaphore = semaphore.clone();
+        executor.spawn(async move {
+            let _permit = semaphore.acquire().await;
+            sleep(10 * MS).await;
+        });
+    }
+    executor.run_to_completion();
+    // Two rounds of two tasks.
+    assert!(start.elapsed() >= 20 * MS, "took {:?}", start.elapsed());
+    assert_eq!(semaphore.available_permits(), 2);
+}
+
+#[test_case]
+fn notify_wakes_sleeping_executor() {
+    let notify = Rc::new(Notify::new());
+    let mut executor = Executor::new();
+    let waiting = notify.clone();
+    let handle = executor.spawn(async move { waiting.notified().await });
+    executor.spawn(async move {
+        sleep(5 * MS).await;
+        notify.notify_one();
+    });
+    executor.run_to_completion();
+    assert!(handle.is_finished());
+}
@@ -0,0 +1,180 @@
+//! # Async timer test
+//!
+//! Runs tasks that sleep, time out, and tick on the executor and checks them
+//! against the kernel clock. Also joins and aborts sleeping tasks.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(tiny_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{ rc::Rc, vec, vec::Vec };
+use bootloader::{ entry_point, BootInfo };
+use core::{ cell::RefCell, panic::PanicInfo, time::Duration };
+use tiny_os::{
+    allocator,
+    task::{
+        executor::Executor,
+        timer::{ self, interval, sleep, timeout, Elapsed },
+        JoinError,
+    },
+    time::Instant,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+    use tiny_os::memory::{ self, BootInfoFrameAllocator };
+
+    tiny_os::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_kernel_memory(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    tiny_os::test_panic_handler(info)
+}
+
+const MS: Duration = Duration::from_millis(1);
+
+#[test_case]
+fn sleep_waits_for_duration() {
+    let elapsed = Rc::new(RefCell::new(None));
+    let mut executor = Executor::new();
+    let result = elapsed.clone();
+    executor.spawn(async move {
+        let start = Instant::now();
+        sleep(20 * MS).await;
+        *result.borrow_mut() = Some(start.elapsed());
+    });
+    executor.run_to_completion();
+
+    let elapsed = elapsed.borrow().unwrap();
+    assert!(elapsed >= 20 * MS, "slept only {:?}", elapsed);
+    assert!(elapsed < 40 * MS, "slept for {:?}", elapsed);
+}
+
+#[test_case]
+fn sleeps_end_in_deadline_order() {
+    let order = Rc::new(RefCell::new(Vec::new()));
+    let mut executor = Executor::new();
+    for &millis in &[30, 10, 20] {
+        let order = order.clone();
+        executor.spawn(async move {
+            sleep(millis * MS).await;
+            order.borrow_mut().push(millis);
+        });
+    }
+    executor.run_to_completion();
+    assert_eq!(*order.borrow(), vec![10, 20, 30]);
+    assert_eq!(timer::pending_timers(), 0);
+}
+
+#[test_case]
+fn timeout_elapses() {
+    let result = Rc::new(RefCell::new(None));
+    let mut executor = Executor::new();
+    let output = result.clone();
+    executor.spawn(async move {
+        *output.borrow_mut() = Some(timeout(10 * MS, sleep(1000 * MS)).await);
+    });
+    let start = Instant::now();
+    executor.run_to_completion();
+
+    assert_eq!(*result.borrow(), Some(Err(Elapsed)));
+    assert!(start.elapsed() < 500 * MS);
+    // The timer of the inner sleep was removed when it was dropped.
+    assert_eq!(timer::pending_timers(), 0);
+}
+
+#[test_case]
+fn timeout_passes_output_through() {
+    let result = Rc::new(RefCell::new(None));
+    let mut executor = Executor::new();
+    let output = result.clone();
+    executor.spawn(async move {
+        let value = timeout(100 * MS, async {
+            sleep(5 * MS).await;
+            42
+        });
+        *output.borrow_mut() = Some(value.await);
+    });
+    executor.run_to_completion();
+
+    assert_eq!(*result.borrow(), Some(Ok(42)));
+    assert_eq!(timer::pending_timers(), 0);
+}
+
+#[test_case]
+fn interval_ticks_periodically() {
+    let ticks = Rc::new(RefCell::new(Vec::new()));
+    let mut executor = Executor::new();
+    let result = ticks.clone();
+    executor.spawn(async move {
+        let mut interval = interval(5 * MS);
+        for _ in 0..4 {
+            let tick = interval.tick().await;
+            result.borrow_mut().push(tick);
+        }
+    });
+    executor.run_to_completion();
+
+    let ticks = ticks.borrow();
+    assert_eq!(ticks.len(), 4);
+    for pair in ticks.windows(2) {
+        assert_eq!(pair[1] - pair[0], 5 * MS);
+    }
+}
+
+#[test_case]
+fn join_sleeping_task() {
+    let result = Rc::new(RefCell::new(None));
+    let mut executor = Executor::new();
+    let handle = executor.spawn(async {
+        sleep(5 * MS).await;
+        7
+    });
+    let output = result.clone();
+    executor.spawn(async move {
+        *output.borrow_mut() = Some(handle.await);
+    });
+    executor.run_to_completion();
+    assert_eq!(*result.borrow(), Some(Ok(7)));
+}
+
+#[test_case]
+fn abort_sleeping_task() {
+    let mut executor = Executor::new();
+    let handle = executor.spawn(sleep(1000 * MS));
+    executor.run_ready_tasks();
+    assert_eq!(timer::pending_timers(), 1);
+
+    handle.abort();
+    let result = Rc::new(RefCell::new(None));
+    let output = result.clone();
+    executor.spawn(async move {
+        *output.borrow_mut() = Some(handle.await);
+    });
+    let start = Instant::now();
+    executor.run_to_completion();
+
+    assert_eq!(*result.borrow(), Some(Err(JoinError::Cancelled)));
+    assert!(start.elapsed() < 500 * MS);
+    // Dropping the task removed its timer.
+    assert_eq!(timer::pending_timers(), 0);
+}
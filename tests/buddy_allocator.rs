@@ -0,0 +1,132 @@
+//! # A Buddy Allocator Test
+//!
+//! The integration test ensures that the buddy allocator returns aligned
+//! contiguous blocks, merges them again when they are freed, and never shares
+//! a frame with the bitmap allocator.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(tiny_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{ entry_point, BootInfo };
+use core::panic::PanicInfo;
+use spin::Mutex;
+use tiny_os::memory::{
+    buddy::{ BuddyFrameAllocator, MAX_ORDER, order_for_frames },
+    BootInfoFrameAllocator,
+};
+use x86_64::structures::paging::{ FrameAllocator, FrameDeallocator };
+
+entry_point!(main);
+
+/// The number of `MAX_ORDER` blocks that the buddy allocator gets.
+const BLOCKS: usize = 4;
+
+static FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
+static BUDDY_ALLOCATOR: Mutex<Option<BuddyFrameAllocator>> = Mutex::new(None);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+
+    tiny_os::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    let buddy_allocator = unsafe {
+        BuddyFrameAllocator::init(&mut frame_allocator, BLOCKS, phys_mem_offset)
+    };
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+    *BUDDY_ALLOCATOR.lock() = buddy_allocator;
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    tiny_os::test_panic_handler(info)
+}
+
+// Every block must be aligned to its own size.
+#[test_case]
+fn aligned_blocks() {
+    let mut guard = BUDDY_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    for order in 0..=MAX_ORDER {
+        let frame = allocator.allocate_contiguous(order).unwrap();
+        let block_size = 4096u64 << order;
+        assert_eq!(frame.start_address().as_u64() % block_size, 0);
+        unsafe { allocator.deallocate_contiguous(frame, order) };
+    }
+}
+
+// Splitting a large block into single frames and freeing all of them again
+// must restore the original number of free frames and merge the pieces back
+// into a block of the largest order.
+#[test_case]
+fn split_and_merge() {
+    let mut guard = BUDDY_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let free_before = allocator.free_frames();
+    // The previous test freed everything, so all blocks are whole again.
+    assert_eq!(allocator.free_blocks(MAX_ORDER), BLOCKS);
+
+    let mut frames = [None; 8];
+    for slot in frames.iter_mut() {
+        *slot = allocator.allocate_frame();
+    }
+    assert_eq!(allocator.free_frames(), free_before - frames.len());
+    // All frames come from a single split block.
+    assert_eq!(allocator.free_blocks(MAX_ORDER), BLOCKS - 1);
+    for frame in frames.iter().flatten() {
+        unsafe { allocator.deallocate_frame(*frame) };
+    }
+
+    assert_eq!(allocator.free_frames(), free_before);
+    // Without merging, the freed frames would stay in the lists of the
+    // smaller orders.
+    assert_eq!(allocator.free_blocks(MAX_ORDER), BLOCKS);
+    for order in 0..MAX_ORDER {
+        assert_eq!(allocator.free_blocks(order), 0);
+    }
+}
+
+#[test_case]
+fn order_calculation() {
+    assert_eq!(order_for_frames(1), Some(0));
+    assert_eq!(order_for_frames(3), Some(2));
+    assert_eq!(order_for_frames(512), Some(MAX_ORDER));
+    assert_eq!(order_for_frames(513), None);
+}
+
+// The bitmap allocator must never return a frame that the buddy allocator
+// owns. This drains the bitmap allocator, so it must be the last test.
+#[test_case]
+fn bitmap_skips_buddy_frames() {
+    // The buddy allocator got one contiguous range, which starts at its
+    // lowest block.
+    let block_size = 4096u64 << MAX_ORDER;
+    let buddy_start = {
+        let mut guard = BUDDY_ALLOCATOR.lock();
+        let allocator = guard.as_mut().unwrap();
+        let mut blocks = [None; BLOCKS];
+        for slot in blocks.iter_mut() {
+            *slot = allocator.allocate_contiguous(MAX_ORDER);
+        }
+        for block in blocks.iter().flatten() {
+            unsafe { allocator.deallocate_contiguous(*block, MAX_ORDER) };
+        }
+        blocks.iter().flatten().map(|block| block.start_address().as_u64()).min().unwrap()
+    };
+    let buddy_range = buddy_start..buddy_start + BLOCKS as u64 * block_size;
+
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let frame_allocator = guard.as_mut().unwrap();
+    while let Some(frame) = frame_allocator.allocate_frame() {
+        assert!(!buddy_range.contains(&frame.start_address().as_u64()));
+    }
+}
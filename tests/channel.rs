@@ -0,0 +1,112 @@
+//! # Channel test
+//!
+//! Sends values from an interrupt handler to a task through the channels of
+//! `task::channel`.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(tiny_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{ rc::Rc, vec::Vec };
+use bootloader::{ entry_point, BootInfo };
+use conquer_once::spin::OnceCell;
+use core::{
+    cell::RefCell,
+    panic::PanicInfo,
+    sync::atomic::{ AtomicU32, Ordering },
+};
+use tiny_os::{
+    allocator,
+    interrupts::irq::{ self, IrqResult },
+    rtc,
+    task::{
+        channel::{ mpsc, oneshot },
+        executor::Executor,
+    },
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+    use tiny_os::memory::{ self, BootInfoFrameAllocator };
+
+    tiny_os::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_kernel_memory(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    tiny_os::test_panic_handler(info)
+}
+
+/// The number of RTC interrupts that the handler saw.
+static TICKS: AtomicU32 = AtomicU32::new(0);
+/// Like the scancode queue, the sender is created before the interrupt is
+/// enabled and lives in a static.
+static TICK_SENDER: OnceCell<mpsc::Sender<u32>> = OnceCell::uninit();
+/// Taken by the handler, which sends the tick count once.
+static TICK_ONESHOT: spin::Mutex<Option<oneshot::Sender<u32>>> = spin::Mutex::new(None);
+
+fn sending_rtc_handler() -> IrqResult {
+    let tick = TICKS.fetch_add(1, Ordering::SeqCst) + 1;
+    if let Ok(sender) = TICK_SENDER.try_get() {
+        // Ticks that don't fit into the buffer are lost, like scancodes.
+        let _ = sender.try_send(tick);
+    }
+    // Tasks only lock the mutex with interrupts disabled, so `try_lock` fails
+    // at most if the test set it up right now.
+    if let Some(sender) = TICK_ONESHOT.try_lock().and_then(|mut sender| sender.take()) {
+        let _ = sender.send(tick);
+    }
+    // The RTC driver acknowledges the interrupt.
+    IrqResult::NotMine
+}
+
+#[test_case]
+fn interrupt_sends_to_task() {
+    let (sender, mut receiver) = mpsc::channel(8);
+    TICK_SENDER.try_init_once(|| sender).expect("channel already initialized");
+    let (oneshot_sender, oneshot_receiver) = oneshot::channel();
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        *TICK_ONESHOT.lock() = Some(oneshot_sender);
+    });
+
+    let received = Rc::new(RefCell::new(Vec::new()));
+    let mut executor = Executor::new();
+    let task_received = received.clone();
+    executor.spawn(async move {
+        while task_received.borrow().len() < 5 {
+            let tick = receiver.recv().await.expect("the sender lives in a static");
+            task_received.borrow_mut().push(tick);
+        }
+    });
+    let first_tick = executor.spawn(oneshot_receiver);
+
+    let handle = irq::register_irq(rtc::IRQ_LINE, sending_rtc_handler).unwrap();
+    rtc::enable_periodic_interrupt(64).unwrap();
+    executor.run_to_completion();
+    rtc::disable_periodic_interrupt();
+    irq::unregister_irq(handle);
+
+    assert!(first_tick.is_finished());
+    let received = received.borrow();
+    assert_eq!(received.len(), 5);
+    // The ticks arrive in order, even if some were lost.
+    assert!(received.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", received);
+}
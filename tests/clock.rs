@@ -0,0 +1,108 @@
+//! # Clock test
+//!
+//! Switches the kernel clock to the best source and checks that it stays
+//! monotonic and agrees with the PIT.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(tiny_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{ entry_point, BootInfo };
+use core::{ panic::PanicInfo, time::Duration };
+use tiny_os::{
+    pit,
+    time::{ self, hpet, tsc, ClockSource, Instant },
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+    use tiny_os::{ allocator, memory::{ self, BootInfoFrameAllocator } };
+
+    tiny_os::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_kernel_memory(mapper, frame_allocator);
+
+    let before = Instant::now();
+    time::init();
+    assert!(Instant::now() >= before, "clock went backwards when switching the source");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    tiny_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn source_matches_hardware() {
+    let expected = if tsc::is_present() && tsc::is_invariant() {
+        ClockSource::Tsc
+    } else if hpet::is_available() {
+        ClockSource::Hpet
+    } else {
+        ClockSource::Pit
+    };
+    assert_eq!(time::clock_source(), expected);
+    assert_eq!(time::tsc_frequency().is_some(), expected == ClockSource::Tsc);
+}
+
+#[test_case]
+fn monotonic() {
+    let mut last = Instant::now();
+    for _ in 0..100_000 {
+        let now = Instant::now();
+        assert!(now >= last, "{:?} is before {:?}", now, last);
+        last = now;
+    }
+}
+
+#[test_case]
+fn sub_tick_resolution() {
+    if time::clock_source() == ClockSource::Pit {
+        return;
+    }
+    // Wait for the clock to change; with a counter-based source, this takes
+    // far less than a PIT tick.
+    let start = Instant::now();
+    let mut now = start;
+    while now == start {
+        now = Instant::now();
+    }
+    assert!(now - start < Duration::from_micros(100), "clock step of {:?}", now - start);
+}
+
+#[test_case]
+fn agrees_with_pit() {
+    let start = Instant::now();
+    let pit_start = pit::uptime();
+    while pit::uptime() - pit_start < Duration::from_millis(50) {
+        x86_64::instructions::hlt();
+    }
+    let elapsed = start.elapsed();
+    let pit_elapsed = pit::uptime() - pit_start;
+    // Both measurements can be off by a PIT tick at either end, plus the
+    // calibration error of the TSC.
+    let tolerance = Duration::from_millis(3);
+    let difference = if elapsed > pit_elapsed {
+        elapsed - pit_elapsed
+    } else {
+        pit_elapsed - elapsed
+    };
+    assert!(
+        difference <= tolerance,
+        "clock measured {:?}, PIT measured {:?}", elapsed, pit_elapsed
+    );
+}
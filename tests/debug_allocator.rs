@@ -0,0 +1,108 @@
+//! # A Debug Allocator Test
+//!
+//! The integration test wraps a `LinkedListAllocator` on a small static heap
+//! in a `DebugAllocator` and provokes the heap bugs it should detect. The
+//! detected problems are printed to the serial port, so the output of this test
+//! contains a few `[debug-alloc]` lines on success.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(tiny_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::alloc::{ GlobalAlloc, Layout };
+use core::panic::PanicInfo;
+use tiny_os::allocator::{
+    Locked,
+    debug::{ self, DebugAllocator },
+    linked_list::LinkedListAllocator,
+};
+
+const ARENA_SIZE: usize = 16 * 4096;
+
+/// The memory that backs the test heap.
+#[repr(align(4096))]
+struct Arena([u8; ARENA_SIZE]);
+
+static mut ARENA: Arena = Arena([0; ARENA_SIZE]);
+
+static INNER: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator::new());
+static ALLOCATOR: DebugAllocator<Locked<LinkedListAllocator>> = DebugAllocator::new(&INNER);
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    unsafe { INNER.lock().init(ARENA.0.as_ptr() as usize, ARENA_SIZE) };
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    tiny_os::test_panic_handler(info)
+}
+
+fn layout(size: usize) -> Layout {
+    Layout::from_size_align(size, 8).unwrap()
+}
+
+// New allocations are filled with the allocation pattern and freed memory with
+// the free pattern. Correct usage doesn't report anything.
+#[test_case]
+fn fill_patterns() {
+    let errors = debug::error_count();
+    let ptr = unsafe { ALLOCATOR.alloc(layout(32)) };
+    let bytes = unsafe { core::slice::from_raw_parts(ptr, 32) };
+    assert!(bytes.iter().all(|&b| b == debug::ALLOC_FILL));
+    unsafe { ALLOCATOR.dealloc(ptr, layout(32)) };
+    // The block is in the quarantine, so we can still look at it.
+    let bytes = unsafe { core::slice::from_raw_parts(ptr, 32) };
+    assert!(bytes.iter().all(|&b| b == debug::FREE_FILL));
+    assert_eq!(debug::error_count(), errors);
+}
+
+#[test_case]
+fn double_free_is_detected() {
+    let errors = debug::error_count();
+    let ptr = unsafe { ALLOCATOR.alloc(layout(64)) };
+    unsafe {
+        ALLOCATOR.dealloc(ptr, layout(64));
+        ALLOCATOR.dealloc(ptr, layout(64));
+    }
+    assert_eq!(debug::error_count(), errors + 1);
+}
+
+#[test_case]
+fn overflow_is_detected() {
+    let errors = debug::error_count();
+    let ptr = unsafe { ALLOCATOR.alloc(layout(16)) };
+    unsafe {
+        ptr.add(16).write(0); // one byte past the end
+        ALLOCATOR.dealloc(ptr, layout(16));
+    }
+    assert_eq!(debug::error_count(), errors + 1);
+}
+
+#[test_case]
+fn layout_mismatch_is_detected() {
+    let errors = debug::error_count();
+    let ptr = unsafe { ALLOCATOR.alloc(layout(24)) };
+    unsafe { ALLOCATOR.dealloc(ptr, layout(48)) };
+    assert_eq!(debug::error_count(), errors + 1);
+}
+
+// A write to a block in the quarantine is reported when it is released.
+#[test_case]
+fn use_after_free_is_detected() {
+    let errors = debug::error_count();
+    let ptr = unsafe { ALLOCATOR.alloc(layout(8)) };
+    unsafe {
+        ALLOCATOR.dealloc(ptr, layout(8));
+        ptr.write(42);
+    }
+    ALLOCATOR.flush_quarantine();
+    assert_eq!(debug::error_count(), errors + 1);
+}
@@ -0,0 +1,308 @@
+//! # Exception test
+//!
+//! Triggers the CPU exceptions that can occur in our kernel and checks the
+//! decoded fault reports.
+//!
+//! The fault hook of `exceptions::skip` records the report of every exception
+//! and resumes execution after the instruction of `trigger_exception!`.
+//!
+//! See the sidenote in `interrupts/exceptions.rs` for the exceptions that
+//! can't be triggered.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(tiny_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::{ arch::asm, panic::PanicInfo };
+use lazy_static::lazy_static;
+use tiny_os::{
+    allocator::HEAP_START,
+    interrupts::exceptions::{
+        self, skip, DescriptorTable, ErrorCode, Exception, FaultReport, SelectorErrorCode,
+    },
+    trigger_exception,
+};
+use x86_64::{
+    instructions::{ interrupts, tables },
+    registers::control::{ Cr0, Cr0Flags },
+    structures::{
+        gdt::{ Descriptor, DescriptorFlags, GlobalDescriptorTable, SegmentSelector },
+        idt::PageFaultErrorCode,
+    },
+};
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    tiny_os::init();
+    exceptions::set_fault_hook(Some(skip::record_and_skip));
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    tiny_os::test_panic_handler(info)
+}
+
+/// Returns the report of the exception that the last `trigger_exception!`
+/// caused.
+fn last_fault() -> FaultReport {
+    skip::take_last_fault().expect("no exception occurred")
+}
+
+#[test_case]
+fn divide_error() {
+    unsafe {
+        trigger_exception!(
+            "div rcx",
+            in("rcx") 0u64, inout("rax") 1u64 => _, inout("rdx") 0u64 => _,
+        );
+    }
+    let report = last_fault();
+    assert_eq!(report.exception, Exception::DivideError);
+    assert_eq!(report.exception.vector(), 0);
+    assert_eq!(report.error_code, None);
+}
+
+#[test_case]
+fn breakpoint() {
+    unsafe { trigger_exception!("int3") };
+    let report = last_fault();
+    assert_eq!(report.exception, Exception::Breakpoint);
+    // `#BP` is a trap, so it reports the address behind the `int3`.
+    assert_eq!(report.stack_frame.instruction_pointer.as_u64(), skip::resume_address().as_u64());
+}
+
+#[test_case]
+fn debug_after_single_step() {
+    // Sets the trap flag, which raises a debug exception after the next
+    // instruction. The handler clears it again.
+    unsafe {
+        trigger_exception!("pushfq\nor qword ptr [rsp], 0x100\npopfq\nnop");
+    }
+    let report = last_fault();
+    assert_eq!(report.exception, Exception::Debug);
+    // A trap reports the address of the next instruction.
+    assert_eq!(report.stack_frame.instruction_pointer.as_u64(), skip::resume_address().as_u64());
+    // The trap flag is gone, otherwise we would have seen another exception.
+    assert!(skip::take_last_fault().is_none());
+}
+
+#[test_case]
+fn non_maskable_interrupt() {
+    // A real NMI comes from the hardware, but `int 2` runs the same handler.
+    unsafe { trigger_exception!("int 2") };
+    let report = last_fault();
+    assert_eq!(report.exception, Exception::NonMaskableInterrupt);
+    assert_eq!(report.error_code, None);
+}
+
+#[test_case]
+fn invalid_opcode() {
+    unsafe { trigger_exception!("ud2") };
+    let report = last_fault();
+    assert_eq!(report.exception, Exception::InvalidOpcode);
+    assert_eq!(report.exception.mnemonic(), "#UD");
+}
+
+#[test_case]
+fn device_not_available() {
+    // `fwait` checks for pending x87 exceptions, which raises `#NM` if both
+    // the task-switched and the monitor-coprocessor flags are set.
+    unsafe {
+        Cr0::update(|flags| {
+            flags.insert(Cr0Flags::TASK_SWITCHED | Cr0Flags::MONITOR_COPROCESSOR)
+        });
+        trigger_exception!("fwait");
+        Cr0::update(|flags| flags.remove(Cr0Flags::TASK_SWITCHED));
+    }
+    let report = last_fault();
+    assert_eq!(report.exception, Exception::DeviceNotAvailable);
+    assert_ne!(report.cr0 & Cr0Flags::TASK_SWITCHED.bits(), 0);
+}
+
+#[test_case]
+fn general_protection_fault_without_selector() {
+    // A non-canonical address causes a `#GP` instead of a page fault.
+    unsafe {
+        trigger_exception!("mov rax, [rcx]", in("rcx") 1u64 << 63, out("rax") _);
+    }
+    let report = last_fault();
+    assert_eq!(report.exception, Exception::GeneralProtectionFault);
+    match report.error_code {
+        Some(ErrorCode::Selector(code)) => assert!(code.is_null()),
+        other => panic!("unexpected error code {:?}", other),
+    }
+}
+
+#[test_case]
+fn general_protection_fault_with_selector() {
+    // Our GDT has only a few entries, so index 100 is beyond its limit.
+    let selector: u16 = 100 << 3;
+    unsafe {
+        trigger_exception!("mov ds, ax", in("ax") selector);
+    }
+    let report = last_fault();
+    assert_eq!(report.exception, Exception::GeneralProtectionFault);
+    match report.error_code {
+        Some(ErrorCode::Selector(code)) => {
+            assert_eq!(code.index(), 100);
+            assert_eq!(code.table(), DescriptorTable::Gdt);
+            assert!(!code.external());
+        }
+        other => panic!("unexpected error code {:?}", other),
+    }
+}
+
+lazy_static! {
+    /// A GDT with the kernel code segment at the same index as the GDT of
+    /// the kernel, so that interrupts still work while it is loaded, and a
+    /// data segment that is not present.
+    static ref NOT_PRESENT_GDT: (GlobalDescriptorTable, SegmentSelector) = {
+        let mut gdt = GlobalDescriptorTable::new();
+        gdt.add_entry(Descriptor::kernel_code_segment());
+        let flags = DescriptorFlags::USER_SEGMENT | DescriptorFlags::WRITABLE;
+        let selector = gdt.add_entry(Descriptor::UserSegment(flags.bits()));
+        (gdt, selector)
+    };
+}
+
+#[test_case]
+fn segment_not_present() {
+    // The descriptor exists and is a valid data segment, but its present bit
+    // is clear, which raises `#NP` instead of `#GP`.
+    let selector = NOT_PRESENT_GDT.1;
+    interrupts::without_interrupts(|| {
+        let kernel_gdt = tables::sgdt();
+        NOT_PRESENT_GDT.0.load();
+        unsafe {
+            trigger_exception!("mov ds, ax", in("ax") selector.0);
+            tables::lgdt(&kernel_gdt);
+        }
+    });
+    let report = last_fault();
+    assert_eq!(report.exception, Exception::SegmentNotPresent);
+    match report.error_code {
+        Some(ErrorCode::Selector(code)) => {
+            assert_eq!(code.index(), selector.index());
+            assert_eq!(code.table(), DescriptorTable::Gdt);
+        }
+        other => panic!("unexpected error code {:?}", other),
+    }
+}
+
+#[test_case]
+fn stack_segment_fault() {
+    // Like the `#GP` above, but the address is relative to the stack pointer,
+    // which makes it a stack access.
+    unsafe {
+        trigger_exception!("mov rax, [rsp + rcx]", in("rcx") 1u64 << 63, out("rax") _);
+    }
+    let report = last_fault();
+    assert_eq!(report.exception, Exception::StackSegmentFault);
+    assert_eq!(report.error_code, Some(ErrorCode::Selector(SelectorErrorCode(0))));
+}
+
+#[test_case]
+fn page_fault() {
+    // The heap isn't mapped in this test.
+    let address = HEAP_START as u64 + 0x10;
+    unsafe {
+        trigger_exception!("mov rax, [rcx]", in("rcx") address, out("rax") _);
+    }
+    let report = last_fault();
+    assert_eq!(report.exception, Exception::PageFault);
+    assert_eq!(report.cr2.as_u64(), address);
+    match report.error_code {
+        Some(ErrorCode::PageFault(code)) => {
+            assert!(!code.contains(PageFaultErrorCode::PROTECTION_VIOLATION));
+            assert!(!code.contains(PageFaultErrorCode::CAUSED_BY_WRITE));
+        }
+        other => panic!("unexpected error code {:?}", other),
+    }
+}
+
+#[test_case]
+fn x87_floating_point() {
+    // The x87 control word with the invalid operation and zero divide
+    // exceptions unmasked. `fninit` masks all of them.
+    static CONTROL_WORD: u16 = 0x037a;
+
+    unsafe {
+        // With the numeric error flag, x87 exceptions raise `#MF` instead of
+        // an external interrupt.
+        Cr0::update(|flags| {
+            flags.insert(Cr0Flags::NUMERIC_ERROR);
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+        });
+        // 0 / 0 is an invalid operation. The x87 unit only records it, and
+        // the next waiting instruction raises the exception.
+        asm!(
+            "fninit",
+            "fldcw [{control_word}]",
+            "fldz",
+            "fldz",
+            "fdiv st, st(1)",
+            control_word = in(reg) &CONTROL_WORD as *const u16,
+        );
+        trigger_exception!("fwait");
+        // Our target doesn't use the x87 unit, so we only have to clear the
+        // exception and the register stack.
+        asm!("fnclex", "fninit");
+    }
+    let report = last_fault();
+    assert_eq!(report.exception, Exception::X87FloatingPoint);
+    assert_eq!(report.exception.mnemonic(), "#MF");
+    assert_eq!(report.error_code, None);
+}
+
+#[test_case]
+fn registers_are_captured() {
+    unsafe {
+        trigger_exception!(
+            "ud2",
+            in("rax") 0x1111_2222_3333_4444u64,
+            in("rsi") 0x5555_6666_7777_8888u64,
+            in("r12") 0x9999_aaaa_bbbb_ccccu64,
+            in("r15") 0xdddd_eeee_ffff_0000u64,
+        );
+    }
+    let report = last_fault();
+    assert_eq!(report.exception, Exception::InvalidOpcode);
+    assert_eq!(report.registers.rax, 0x1111_2222_3333_4444);
+    assert_eq!(report.registers.rsi, 0x5555_6666_7777_8888);
+    assert_eq!(report.registers.r12, 0x9999_aaaa_bbbb_cccc);
+    assert_eq!(report.registers.r15, 0xdddd_eeee_ffff_0000);
+}
+
+#[test_case]
+fn report_formatting() {
+    unsafe { trigger_exception!("ud2") };
+    let report = last_fault();
+    let mut buffer = Buffer { data: [0; 1024], len: 0 };
+    core::fmt::write(&mut buffer, format_args!("{}", report)).unwrap();
+    let text = core::str::from_utf8(&buffer.data[..buffer.len]).unwrap();
+    assert!(text.starts_with("EXCEPTION: INVALID OPCODE (#UD, vector 6)"));
+    assert!(text.contains("R15: "));
+    assert!(text.contains("CR3: "));
+}
+
+/// A fixed-size buffer for formatting without a heap.
+struct Buffer {
+    data: [u8; 1024],
+    len: usize,
+}
+
+impl core::fmt::Write for Buffer {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let end = self.len + s.len();
+        if end > self.data.len() {
+            return Err(core::fmt::Error);
+        }
+        self.data[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
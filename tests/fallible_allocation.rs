@@ -0,0 +1,126 @@
+//! # Fallible allocation test
+//!
+//! Checks that the fallible allocation functions report an exhausted heap as
+//! an error instead of panicking, and that the reclaim callbacks run before an
+//! allocation fails.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(tiny_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{ entry_point, BootInfo };
+use core::{
+    alloc::Layout,
+    panic::PanicInfo,
+    sync::atomic::{ AtomicUsize, Ordering },
+};
+use alloc::vec::Vec;
+use tiny_os::allocator::{ self, AllocError, HEAP_MAX_SIZE, oom };
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+    use tiny_os::memory::{ self, BootInfoFrameAllocator };
+
+    tiny_os::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_kernel_memory(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    tiny_os::test_panic_handler(info)
+}
+
+/// Prevents the heap from growing beyond its current size while `f` runs.
+fn with_fixed_heap(f: impl FnOnce()) {
+    allocator::set_heap_limit(allocator::heap_stats().heap_size);
+    f();
+    allocator::set_heap_limit(HEAP_MAX_SIZE);
+}
+
+#[test_case]
+fn try_box_and_vec() {
+    let value = allocator::try_box(42u64).unwrap();
+    assert_eq!(*value, 42);
+    let mut vec = allocator::try_vec_with_capacity::<u64>(100).unwrap();
+    assert_eq!(vec.capacity(), 100);
+    vec.push(1);
+    assert_eq!(vec[0], 1);
+}
+
+#[test_case]
+fn zero_sized_allocations() {
+    let layout = Layout::from_size_align(0, 16).unwrap();
+    let ptr = allocator::try_alloc(layout).unwrap();
+    assert_eq!(ptr.as_ptr() as usize % 16, 0);
+    unsafe { oom::dealloc(ptr, layout) };
+    assert!(allocator::try_box(()).is_ok());
+}
+
+#[test_case]
+fn exhausted_heap_returns_error() {
+    with_fixed_heap(|| {
+        let size = allocator::heap_stats().heap_size * 2;
+        let result = allocator::try_vec_with_capacity::<u8>(size);
+        let layout = Layout::array::<u8>(size).unwrap();
+        assert_eq!(result.err(), Some(AllocError::OutOfMemory(layout)));
+    });
+    // The heap must still be usable afterwards.
+    assert!(allocator::try_box(1u8).is_ok());
+}
+
+#[test_case]
+fn capacity_overflow() {
+    let result = allocator::try_vec_with_capacity::<u64>(usize::MAX);
+    assert_eq!(result.err(), Some(AllocError::CapacityOverflow));
+}
+
+/// Memory that `release_ballast` gives back when the heap is exhausted.
+static BALLAST: spin::Mutex<Option<Vec<u8>>> = spin::Mutex::new(None);
+/// The number of times `release_ballast` was called.
+static RECLAIM_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+fn release_ballast(_layout: Layout) -> usize {
+    RECLAIM_CALLS.fetch_add(1, Ordering::SeqCst);
+    match BALLAST.try_lock().and_then(|mut ballast| ballast.take()) {
+        Some(ballast) => ballast.capacity(),
+        None => 0,
+    }
+}
+
+// The bump allocator only reuses memory once all allocations are freed.
+#[cfg(not(feature = "alloc-bump"))]
+#[test_case]
+fn reclaimer_runs_before_failure() {
+    oom::register_reclaimer("test ballast", release_ballast).unwrap();
+    with_fixed_heap(|| {
+        // The ballast takes more than half of the free memory, so a second
+        // allocation of the same size only fits after the ballast was freed.
+        let size = allocator::heap_stats().bytes_free * 2 / 3;
+        *BALLAST.lock() = Some(allocator::try_vec_with_capacity(size).unwrap());
+        let calls = RECLAIM_CALLS.load(Ordering::SeqCst);
+
+        let vec = allocator::try_vec_with_capacity::<u8>(size);
+        assert!(vec.is_ok());
+        assert!(RECLAIM_CALLS.load(Ordering::SeqCst) > calls);
+        assert!(BALLAST.lock().is_none());
+    });
+    oom::unregister_reclaimer(release_ballast);
+}
@@ -0,0 +1,85 @@
+//! # A Frame Allocator Test
+//!
+//! The integration test ensures that the bitmap frame allocator hands out
+//! unique frames and that deallocated frames can be allocated again.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(tiny_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{ entry_point, BootInfo };
+use core::panic::PanicInfo;
+use spin::Mutex;
+use tiny_os::memory::BootInfoFrameAllocator;
+use x86_64::structures::paging::{ FrameAllocator, FrameDeallocator };
+
+entry_point!(main);
+
+// Test functions can't take arguments, so we store the allocator in a static
+// after creating it from the boot information.
+static FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+
+    tiny_os::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    tiny_os::test_panic_handler(info)
+}
+
+// Allocating two frames in a row must never return the same frame twice.
+#[test_case]
+fn unique_frames() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let first = allocator.allocate_frame().unwrap();
+    let second = allocator.allocate_frame().unwrap();
+    assert_ne!(first, second);
+    unsafe {
+        allocator.deallocate_frame(first);
+        allocator.deallocate_frame(second);
+    }
+}
+
+// A freed frame is the first candidate for the next allocation and the free
+// frame counter stays consistent.
+#[test_case]
+fn reuse_deallocated_frame() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let free_before = allocator.free_frames();
+    let frame = allocator.allocate_frame().unwrap();
+    assert_eq!(allocator.free_frames(), free_before - 1);
+    unsafe { allocator.deallocate_frame(frame) };
+    assert_eq!(allocator.free_frames(), free_before);
+    assert_eq!(allocator.allocate_frame(), Some(frame));
+    unsafe { allocator.deallocate_frame(frame) };
+}
+
+// Allocate and free many more frames than a single bitmap word holds, to make
+// sure the search start is moved back correctly.
+#[test_case]
+fn many_frames() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let free_before = allocator.free_frames();
+    for _ in 0..1000 {
+        let frame = allocator.allocate_frame().unwrap();
+        unsafe { allocator.deallocate_frame(frame) };
+    }
+    assert_eq!(allocator.free_frames(), free_before);
+}
@@ -14,7 +14,7 @@ extern crate alloc; // since we want to test allocations, we enable the `alloc`
 use bootloader::{ entry_point, BootInfo };
 use core::panic::PanicInfo;
 use alloc::{ boxed::Box, vec::Vec };
-use tiny_os::allocator::HEAP_SIZE;
+use tiny_os::allocator::{ self, HEAP_SIZE };
 
 entry_point!(main);
 
@@ -23,16 +23,18 @@ entry_point!(main);
 fn main(boot_info: &'static BootInfo) -> ! {
     use x86_64::VirtAddr;
     use tiny_os::memory::{ self, BootInfoFrameAllocator };
-    use tiny_os::allocator;
 
     tiny_os::init();
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
 
     allocator::init_heap(&mut mapper, &mut frame_allocator)
         .expect("heap initialization failed");
+    memory::init_kernel_memory(mapper, frame_allocator);
 
     test_main();
     loop {}
@@ -67,6 +69,20 @@ fn large_vec() {
     assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
 }
 
+// Allocate a vector that is larger than the initial heap, to test that the
+// heap grows on demand. The bump and linked list allocators don't grow the
+// heap, so the test is skipped for them.
+#[cfg(not(any(feature = "alloc-bump", feature = "alloc-linked-list")))]
+#[test_case]
+fn large_vec_beyond_initial_heap() {
+    let n = 4 * HEAP_SIZE / core::mem::size_of::<u64>();
+    let mut vec = Vec::with_capacity(n);
+    for i in 0..n as u64 {
+        vec.push(i);
+    }
+    assert_eq!(vec.iter().sum::<u64>(), (n as u64 - 1) * n as u64 / 2);
+}
+
 // Create ten thousand allocations after each other.
 //
 // This test ensures that the allocator reuses freed memory for subsequent
@@ -85,6 +101,10 @@ fn many_boxes() {
 // to provoke an out-of-memory failure if the allocator does not reuse freed
 // memory. Additionally, the test creates a `long_lived` allocation, which lives
 // for the whole loop execution.
+//
+// The bump allocator can't pass this test by design (see the sidenote below),
+// so it is skipped for it.
+#[cfg(not(feature = "alloc-bump"))]
 #[test_case]
 fn many_boxes_long_lived() {
     let long_lived = Box::new(1); // test the limitation of a bump allocator
@@ -95,6 +115,40 @@ fn many_boxes_long_lived() {
     assert_eq!(*long_lived, 1);
 }
 
+// Freeing everything that a test allocated must bring the allocation count of
+// the global allocator back to where it was, i.e. nothing is leaked.
+#[test_case]
+fn no_leaks() {
+    let before = allocator::heap_stats();
+    {
+        let boxed = Box::new([0u8; 100]);
+        let mut vec = Vec::new();
+        for i in 0..100 {
+            vec.push(i);
+        }
+        let during = allocator::heap_stats();
+        assert_eq!(during.allocation_count, before.allocation_count + 2);
+        assert!(during.bytes_allocated > before.bytes_allocated);
+        assert_eq!(boxed[99], 0);
+    }
+    assert_eq!(allocator::heap_stats().allocation_count, before.allocation_count);
+}
+
+// The allocated and free bytes can't exceed the size of the heap.
+#[test_case]
+fn stats_are_consistent() {
+    let stats = allocator::heap_stats();
+    assert!(stats.heap_size >= HEAP_SIZE);
+    assert!(stats.bytes_allocated + stats.bytes_free <= stats.heap_size);
+    if let Some(largest) = stats.largest_free_region {
+        assert!(largest <= stats.bytes_free);
+    }
+    // Only the allocators that manage their free regions themselves know the
+    // largest one, see `HeapStats::largest_free_region`.
+    let known = cfg!(any(feature = "alloc-bump", feature = "alloc-linked-list"));
+    assert_eq!(stats.largest_free_region.is_some(), known);
+}
+
 // ********** Sidenote **********
 //
 // # Bump Allocator
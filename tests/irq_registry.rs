@@ -0,0 +1,106 @@
+//! # IRQ registry test
+//!
+//! Registers handlers for an unused interrupt line and triggers its vector with
+//! a software interrupt, which runs the same trampoline as a hardware
+//! interrupt.
+//!
+//! All handlers in this test only use statics, so they are zero-sized and can
+//! be registered without a heap.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(tiny_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::{
+    arch::asm,
+    panic::PanicInfo,
+    sync::atomic::{ AtomicUsize, Ordering },
+};
+use tiny_os::interrupts::{
+    irq::{ self, MAX_HANDLERS_PER_LINE, NUM_IRQ_LINES },
+    register_irq, unregister_irq, IrqError, IrqResult,
+};
+
+/// ISA IRQ 5 is unused in QEMU (it is traditionally the second parallel port
+/// or a sound card).
+const LINE: u8 = 5;
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    tiny_os::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    tiny_os::test_panic_handler(info)
+}
+
+/// Triggers the vector of `LINE` (`IRQ_BASE + 5 = 37`).
+fn trigger() {
+    assert_eq!(irq::IRQ_BASE + LINE, 37);
+    unsafe { asm!("int 37") };
+}
+
+static FIRST: AtomicUsize = AtomicUsize::new(0);
+static SECOND: AtomicUsize = AtomicUsize::new(0);
+
+#[test_case]
+fn shared_line_calls_all_handlers() {
+    let first = register_irq(LINE, || {
+        FIRST.fetch_add(1, Ordering::SeqCst);
+        IrqResult::Handled
+    })
+    .unwrap();
+    let second = register_irq(LINE, || {
+        SECOND.fetch_add(1, Ordering::SeqCst);
+        IrqResult::NotMine
+    })
+    .unwrap();
+    assert!(irq::has_handlers(LINE));
+
+    trigger();
+    assert_eq!(FIRST.load(Ordering::SeqCst), 1);
+    assert_eq!(SECOND.load(Ordering::SeqCst), 1);
+
+    unregister_irq(first);
+    trigger();
+    assert_eq!(FIRST.load(Ordering::SeqCst), 1);
+    assert_eq!(SECOND.load(Ordering::SeqCst), 2);
+
+    unregister_irq(second);
+    assert!(!irq::has_handlers(LINE));
+}
+
+#[test_case]
+fn unclaimed_interrupts_are_counted() {
+    let handle = register_irq(LINE, || IrqResult::NotMine).unwrap();
+    let before = irq::unhandled_count(LINE);
+    trigger();
+    assert_eq!(irq::unhandled_count(LINE), before + 1);
+    unregister_irq(handle);
+}
+
+#[test_case]
+fn line_full() {
+    let mut handles = [None; MAX_HANDLERS_PER_LINE];
+    for handle in handles.iter_mut() {
+        *handle = Some(register_irq(LINE, || IrqResult::NotMine).unwrap());
+    }
+    assert_eq!(register_irq(LINE, || IrqResult::NotMine), Err(IrqError::LineFull));
+    for handle in handles.iter().flatten() {
+        unregister_irq(*handle);
+    }
+    // The slots can be used again.
+    let handle = register_irq(LINE, || IrqResult::NotMine).unwrap();
+    unregister_irq(handle);
+}
+
+#[test_case]
+fn invalid_line() {
+    let result = register_irq(NUM_IRQ_LINES as u8, || IrqResult::Handled);
+    assert_eq!(result, Err(IrqError::InvalidLine));
+}
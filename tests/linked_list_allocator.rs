@@ -0,0 +1,117 @@
+//! # A Linked List Allocator Test
+//!
+//! The integration test runs our `LinkedListAllocator` on a small static heap
+//! to ensure that freed regions are merged again and that both fit strategies
+//! choose the expected regions.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(tiny_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::alloc::{ GlobalAlloc, Layout };
+use core::panic::PanicInfo;
+use tiny_os::allocator::{
+    Locked,
+    linked_list::{ LinkedListAllocator, FitStrategy },
+    stats::HeapStats,
+};
+
+const ARENA_SIZE: usize = 4096;
+
+/// The memory that backs the test heaps, one arena per test.
+#[repr(align(4096))]
+struct Arena([u8; ARENA_SIZE]);
+
+static mut FIRST_FIT_ARENA: Arena = Arena([0; ARENA_SIZE]);
+static mut BEST_FIT_ARENA: Arena = Arena([0; ARENA_SIZE]);
+
+static FIRST_FIT: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator::new());
+static BEST_FIT: Locked<LinkedListAllocator> =
+    Locked::new(LinkedListAllocator::with_strategy(FitStrategy::BestFit));
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    unsafe {
+        FIRST_FIT.lock().init(FIRST_FIT_ARENA.0.as_ptr() as usize, ARENA_SIZE);
+        BEST_FIT.lock().init(BEST_FIT_ARENA.0.as_ptr() as usize, ARENA_SIZE);
+    }
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    tiny_os::test_panic_handler(info)
+}
+
+fn layout(size: usize) -> Layout {
+    Layout::from_size_align(size, 8).unwrap()
+}
+
+// After freeing all allocations in a different order than they were made, the
+// free regions are merged into a single region that spans the whole heap.
+#[test_case]
+fn freed_regions_are_merged() {
+    let mut blocks = [core::ptr::null_mut(); 16];
+    for block in blocks.iter_mut() {
+        *block = unsafe { FIRST_FIT.alloc(layout(128)) };
+        assert!(!block.is_null());
+    }
+    // Free every second block first, so that the neighbours of the later
+    // deallocations are free on both sides.
+    for block in blocks.iter().step_by(2).chain(blocks.iter().skip(1).step_by(2)) {
+        unsafe { FIRST_FIT.dealloc(*block, layout(128)) };
+    }
+
+    let allocator = FIRST_FIT.lock();
+    assert_eq!(allocator.allocation_count(), 0);
+    assert_eq!(allocator.bytes_free(), ARENA_SIZE);
+    assert_eq!(allocator.largest_free_region(), Some(ARENA_SIZE));
+}
+
+// An allocation that is larger than any single free region fails, but succeeds
+// once the regions between the holes are freed and merged.
+#[test_case]
+fn allocation_after_merge() {
+    let half = ARENA_SIZE / 2;
+    let first = unsafe { FIRST_FIT.alloc(layout(half)) };
+    let second = unsafe { FIRST_FIT.alloc(layout(half)) };
+    assert!(!first.is_null() && !second.is_null());
+    unsafe {
+        FIRST_FIT.dealloc(first, layout(half));
+        FIRST_FIT.dealloc(second, layout(half));
+    }
+    let whole = unsafe { FIRST_FIT.alloc(layout(ARENA_SIZE)) };
+    assert!(!whole.is_null());
+    unsafe { FIRST_FIT.dealloc(whole, layout(ARENA_SIZE)) };
+}
+
+// Best fit chooses the smallest suitable hole instead of the first one.
+#[test_case]
+fn best_fit_uses_smallest_region() {
+    // Create a large hole at the start and a small hole behind it:
+    // [large hole][used][small hole][used][rest]
+    let large = unsafe { BEST_FIT.alloc(layout(512)) };
+    let used_1 = unsafe { BEST_FIT.alloc(layout(64)) };
+    let small = unsafe { BEST_FIT.alloc(layout(64)) };
+    let used_2 = unsafe { BEST_FIT.alloc(layout(64)) };
+    unsafe {
+        BEST_FIT.dealloc(large, layout(512));
+        BEST_FIT.dealloc(small, layout(64));
+    }
+
+    let ptr = unsafe { BEST_FIT.alloc(layout(64)) };
+    assert_eq!(ptr, small);
+
+    unsafe {
+        BEST_FIT.dealloc(ptr, layout(64));
+        BEST_FIT.dealloc(used_1, layout(64));
+        BEST_FIT.dealloc(used_2, layout(64));
+    }
+    assert_eq!(BEST_FIT.lock().largest_free_region(), Some(ARENA_SIZE));
+}
@@ -0,0 +1,193 @@
+//! # Page fault region test
+//!
+//! Registers regions in an otherwise unused part of the address space and
+//! checks that their page faults are resolved, or reported if they can't be.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(tiny_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{ entry_point, BootInfo };
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{ AtomicU64, Ordering },
+};
+use tiny_os::{
+    interrupts::exceptions::{ self, skip, Exception },
+    memory::{
+        self,
+        fault::{ self, FaultOutcome, PageFault, Region, RegionError, RegionKind },
+    },
+    trigger_exception,
+};
+use x86_64::{
+    structures::paging::{ Mapper, Page, PageTableFlags },
+    VirtAddr,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use tiny_os::memory::BootInfoFrameAllocator;
+
+    tiny_os::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    exceptions::set_fault_hook(Some(skip::record_and_skip));
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    tiny_os::test_panic_handler(info)
+}
+
+/// The regions of the tests are placed in this otherwise unused range, one
+/// megabyte apart.
+const BASE: u64 = 0x_6666_0000_0000;
+
+fn region(index: u64, pages: u64, name: &'static str, kind: RegionKind) -> Region {
+    Region {
+        start: VirtAddr::new(BASE + index * 0x10_0000),
+        size: pages * 4096,
+        name,
+        kind,
+    }
+}
+
+/// Reads from `address`. If the page fault is not resolved, the fault hook
+/// skips the read and records the report.
+fn read_and_skip(address: u64) {
+    unsafe {
+        trigger_exception!("mov rax, [rcx]", in("rcx") address, out("rax") _);
+    }
+}
+
+#[test_case]
+fn lazy_region_maps_pages_on_access() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let lazy = region(0, 4, "lazy", RegionKind::Lazy(flags));
+    fault::register_region(lazy).unwrap();
+
+    let ptr: *mut u64 = lazy.start.as_mut_ptr();
+    for page in 0..4 {
+        let entry = unsafe { ptr.add(page * 512) };
+        // Fresh pages are zeroed.
+        assert_eq!(unsafe { entry.read_volatile() }, 0);
+        unsafe { entry.write_volatile(page as u64 + 1) };
+    }
+    for page in 0..4 {
+        assert_eq!(unsafe { ptr.add(page * 512).read_volatile() }, page as u64 + 1);
+    }
+    assert!(skip::take_last_fault().is_none());
+}
+
+#[test_case]
+fn guard_region_is_reported() {
+    let guard = region(1, 1, "test guard", RegionKind::Guard);
+    fault::register_region(guard).unwrap();
+
+    read_and_skip(guard.start.as_u64() + 8);
+    let report = skip::take_last_fault().expect("guard page access not reported");
+    assert_eq!(report.exception, Exception::PageFault);
+    assert_eq!(report.cr2, guard.start + 8u64);
+    assert_eq!(fault::region_at(report.cr2).map(|region| region.name), Some("test guard"));
+}
+
+#[test_case]
+fn unclaimed_address_is_reported() {
+    let address = BASE + 0x100_0000;
+    assert!(fault::region_at(VirtAddr::new(address)).is_none());
+
+    read_and_skip(address);
+    let report = skip::take_last_fault().expect("page fault not reported");
+    assert_eq!(report.cr2.as_u64(), address);
+}
+
+#[test_case]
+fn copy_on_write_region_copies_on_write() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let source = region(2, 1, "cow source", RegionKind::Lazy(flags));
+    let shared = region(3, 1, "cow", RegionKind::CopyOnWrite);
+    fault::register_region(source).unwrap();
+    fault::register_region(shared).unwrap();
+
+    let source_ptr: *mut u64 = source.start.as_mut_ptr();
+    unsafe { source_ptr.write_volatile(0xc0ffee) };
+    let frame = memory::with_kernel_memory(|memory| {
+        memory.mapper.translate_page(Page::containing_address(source.start)).unwrap()
+    })
+    .unwrap();
+    unsafe {
+        fault::map_copy_on_write(Page::containing_address(shared.start), frame).unwrap();
+    }
+
+    // Reads see the shared frame.
+    let shared_ptr: *mut u64 = shared.start.as_mut_ptr();
+    assert_eq!(unsafe { shared_ptr.read_volatile() }, 0xc0ffee);
+
+    // A write gives the page its own copy, so the source is unchanged.
+    unsafe { shared_ptr.write_volatile(0xdecaf) };
+    assert_eq!(unsafe { shared_ptr.read_volatile() }, 0xdecaf);
+    assert_eq!(unsafe { source_ptr.read_volatile() }, 0xc0ffee);
+    assert!(skip::take_last_fault().is_none());
+}
+
+static CUSTOM_FAULTS: AtomicU64 = AtomicU64::new(0);
+
+fn count_and_map(fault: &PageFault) -> FaultOutcome {
+    CUSTOM_FAULTS.fetch_add(1, Ordering::SeqCst);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    memory::try_with_kernel_memory(|memory| {
+        use x86_64::structures::paging::FrameAllocator;
+
+        let frame = memory.frame_allocator.allocate_frame()?;
+        unsafe {
+            memory.mapper
+                .map_to(fault.page(), frame, flags, &mut memory.frame_allocator)
+                .ok()?
+                .flush();
+        }
+        Some(FaultOutcome::Resolved)
+    })
+    .flatten()
+    .unwrap_or(FaultOutcome::Unresolved)
+}
+
+#[test_case]
+fn custom_region_calls_handler() {
+    let custom = region(4, 1, "custom", RegionKind::Custom(count_and_map));
+    fault::register_region(custom).unwrap();
+
+    let ptr: *mut u64 = custom.start.as_mut_ptr();
+    unsafe { ptr.write_volatile(7) };
+    assert_eq!(unsafe { ptr.read_volatile() }, 7);
+    // Only the first access faulted.
+    assert_eq!(CUSTOM_FAULTS.load(Ordering::SeqCst), 1);
+}
+
+#[test_case]
+fn invalid_regions_are_rejected() {
+    let empty = region(5, 0, "empty", RegionKind::Guard);
+    assert_eq!(fault::register_region(empty), Err(RegionError::Unaligned));
+    let unaligned = Region { start: empty.start + 0x10u64, size: 4096, ..empty };
+    assert_eq!(fault::register_region(unaligned), Err(RegionError::Unaligned));
+
+    let first = region(6, 2, "first", RegionKind::Guard);
+    fault::register_region(first).unwrap();
+    let overlapping = Region { start: first.start + 4096u64, ..first };
+    assert_eq!(fault::register_region(overlapping), Err(RegionError::Overlap));
+
+    assert!(fault::unregister_region(first.start).is_some());
+    assert!(fault::region_at(first.start).is_none());
+    assert!(fault::unregister_region(first.start).is_none());
+}
@@ -0,0 +1,83 @@
+//! # PIT test
+//!
+//! Checks that the timer interrupt drives the tick counter and that the uptime
+//! matches the programmed frequency.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(tiny_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::{ panic::PanicInfo, time::Duration };
+use tiny_os::pit::{ self, InvalidFrequency };
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    tiny_os::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    tiny_os::test_panic_handler(info)
+}
+
+/// Halts until `count` more timer interrupts have arrived.
+fn wait_ticks(count: u64) {
+    let target = pit::ticks() + count;
+    while pit::ticks() < target {
+        x86_64::instructions::hlt();
+    }
+}
+
+#[test_case]
+fn default_frequency() {
+    // 1193182 / 1193 = 1000.15 Hz
+    assert_eq!(pit::frequency(), pit::DEFAULT_FREQUENCY);
+}
+
+#[test_case]
+fn ticks_advance() {
+    let ticks = pit::ticks();
+    let uptime = pit::uptime();
+    wait_ticks(10);
+    assert!(pit::ticks() >= ticks + 10);
+
+    // 10 ticks at 1000 Hz are about 10 ms, but we might have started in the
+    // middle of a tick.
+    let elapsed = pit::uptime() - uptime;
+    assert!(elapsed >= Duration::from_millis(9), "elapsed: {:?}", elapsed);
+    assert!(elapsed <= Duration::from_millis(12), "elapsed: {:?}", elapsed);
+}
+
+#[test_case]
+fn uptime_survives_frequency_change() {
+    let before = pit::uptime();
+    pit::set_frequency(100).unwrap();
+    assert_eq!(pit::frequency(), 100);
+    wait_ticks(2);
+    let slow = pit::uptime();
+    // Two ticks at 100 Hz are at least 10 ms.
+    assert!(slow - before >= Duration::from_millis(10));
+
+    pit::set_frequency(pit::DEFAULT_FREQUENCY).unwrap();
+    wait_ticks(1);
+    assert!(pit::uptime() >= slow);
+}
+
+#[test_case]
+fn invalid_frequencies() {
+    assert_eq!(pit::set_frequency(0), Err(InvalidFrequency(0)));
+    assert_eq!(pit::set_frequency(pit::MIN_FREQUENCY - 1), Err(InvalidFrequency(pit::MIN_FREQUENCY - 1)));
+    let too_high = pit::BASE_FREQUENCY + 1;
+    assert_eq!(pit::set_frequency(too_high), Err(InvalidFrequency(too_high)));
+    // The base frequency itself would need a divisor of 1, which mode 2
+    // doesn't support.
+    let divisor_1 = pit::BASE_FREQUENCY;
+    assert_eq!(pit::set_frequency(divisor_1), Err(InvalidFrequency(divisor_1)));
+    let above_max = pit::MAX_FREQUENCY + 1;
+    assert_eq!(pit::set_frequency(above_max), Err(InvalidFrequency(above_max)));
+    assert_eq!(pit::frequency(), pit::DEFAULT_FREQUENCY);
+}
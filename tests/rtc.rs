@@ -0,0 +1,96 @@
+//! # RTC test
+//!
+//! Reads the CMOS real-time clock, sets the wall clock from it, and counts its
+//! periodic interrupts.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(tiny_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::{ panic::PanicInfo, time::Duration };
+use tiny_os::{
+    interrupts::irq,
+    pit,
+    rtc::{ self, PeriodicError },
+    time::SystemTime,
+};
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    tiny_os::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    tiny_os::test_panic_handler(info)
+}
+
+/// Halts for at least `duration`.
+fn wait(duration: Duration) {
+    let start = pit::uptime();
+    while pit::uptime() - start < duration {
+        x86_64::instructions::hlt();
+    }
+}
+
+#[test_case]
+fn read_valid_date() {
+    let now = rtc::read();
+    assert!(now.is_valid(), "invalid date {:?}", now);
+    // QEMU starts the RTC at the time of the host.
+    assert!(now.year >= 2020, "date {} is in the past", now);
+}
+
+#[test_case]
+fn rtc_advances() {
+    let before = rtc::read().to_unix_seconds().unwrap();
+    wait(Duration::from_millis(1100));
+    let after = rtc::read().to_unix_seconds().unwrap();
+    assert!((1..=2).contains(&(after - before)), "RTC advanced by {} s", after - before);
+}
+
+#[test_case]
+fn wall_clock_follows_rtc() {
+    let date_time = rtc::init();
+    let now = SystemTime::now();
+    let seconds = date_time.to_unix_seconds().unwrap();
+    let difference = now.duration_since(SystemTime::from_unix_seconds(seconds)).unwrap();
+    assert!(difference < Duration::from_secs(1), "wall clock is {:?} ahead", difference);
+
+    wait(Duration::from_millis(20));
+    let elapsed = now.elapsed().unwrap();
+    assert!(elapsed >= Duration::from_millis(19), "elapsed: {:?}", elapsed);
+}
+
+#[test_case]
+fn periodic_interrupt() {
+    assert_eq!(
+        rtc::enable_periodic_interrupt(1000),
+        Err(PeriodicError::InvalidFrequency(1000))
+    );
+    assert_eq!(
+        rtc::enable_periodic_interrupt(16384),
+        Err(PeriodicError::InvalidFrequency(16384))
+    );
+
+    rtc::enable_periodic_interrupt(1024).unwrap();
+    assert_eq!(rtc::periodic_frequency(), Some(1024));
+    assert!(irq::has_handlers(rtc::IRQ_LINE));
+
+    let ticks = rtc::periodic_ticks();
+    wait(Duration::from_millis(100));
+    let count = rtc::periodic_ticks() - ticks;
+    // about 102 interrupts, but QEMU's timing is not exact
+    assert!((80..=125).contains(&count), "{} interrupts in 100 ms", count);
+
+    rtc::disable_periodic_interrupt();
+    assert_eq!(rtc::periodic_frequency(), None);
+    assert!(!irq::has_handlers(rtc::IRQ_LINE));
+    let ticks = rtc::periodic_ticks();
+    wait(Duration::from_millis(20));
+    assert_eq!(rtc::periodic_ticks(), ticks);
+}
@@ -0,0 +1,106 @@
+//! # A Slab Allocator Test
+//!
+//! The integration test exercises a standalone `SlabCache` on top of a small
+//! heap, to ensure that slabs are created, reused and released again and that
+//! the constructor and destructor hooks run for every slot.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(tiny_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{ AtomicUsize, Ordering },
+};
+use linked_list_allocator::Heap;
+use spin::Mutex;
+use tiny_os::allocator::slab::SlabCache;
+
+/// The memory that backs the test heap. Slabs need to be page aligned, so we
+/// align the whole arena to a page.
+#[repr(align(4096))]
+struct Arena([u8; ARENA_SIZE]);
+
+const ARENA_SIZE: usize = 16 * 4096;
+
+static mut ARENA: Arena = Arena([0; ARENA_SIZE]);
+
+static HEAP: Mutex<Heap> = Mutex::new(Heap::empty());
+
+/// The number of slots that are currently constructed.
+static CONSTRUCTED: AtomicUsize = AtomicUsize::new(0);
+
+fn construct(_object: *mut u8) {
+    CONSTRUCTED.fetch_add(1, Ordering::Relaxed);
+}
+
+fn destruct(_object: *mut u8) {
+    CONSTRUCTED.fetch_sub(1, Ordering::Relaxed);
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    unsafe {
+        HEAP.lock().init(ARENA.0.as_ptr() as usize, ARENA_SIZE);
+    }
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    tiny_os::test_panic_handler(info)
+}
+
+// Objects handed out by a cache must be distinct and suitably aligned.
+#[test_case]
+fn distinct_objects() {
+    let mut heap = HEAP.lock();
+    let mut cache = SlabCache::new("test-48", 48, 16);
+    let mut objects = [core::ptr::null_mut(); 200];
+    for object in objects.iter_mut() {
+        *object = unsafe { cache.alloc(&mut heap) };
+        assert!(!object.is_null());
+        assert_eq!(*object as usize % 16, 0);
+    }
+    for (i, a) in objects.iter().enumerate() {
+        assert!(objects[i + 1..].iter().all(|b| a != b));
+    }
+    assert_eq!(cache.objects_in_use(), objects.len());
+    assert!(cache.slab_count() > 1);
+
+    for object in objects.iter() {
+        unsafe { cache.free(*object, &mut heap) };
+    }
+    assert_eq!(cache.objects_in_use(), 0);
+    // One empty slab is kept around until the cache is shrunk.
+    assert_eq!(cache.slab_count(), 1);
+    unsafe { cache.shrink(&mut heap) };
+    assert_eq!(cache.slab_count(), 0);
+}
+
+// The constructor runs once per slot when a slab is created and the
+// destructor once per slot when it is released.
+#[test_case]
+fn constructor_and_destructor_hooks() {
+    let mut heap = HEAP.lock();
+    let mut cache = SlabCache::new("test-hooks", 24, 8)
+        .with_hooks(Some(construct), Some(destruct));
+
+    let object = unsafe { cache.alloc(&mut heap) };
+    let constructed = CONSTRUCTED.load(Ordering::Relaxed);
+    assert!(constructed > 1);
+    // Freeing and allocating again reuses the constructed slot.
+    unsafe { cache.free(object, &mut heap) };
+    assert_eq!(unsafe { cache.alloc(&mut heap) }, object);
+    assert_eq!(CONSTRUCTED.load(Ordering::Relaxed), constructed);
+
+    unsafe {
+        cache.free(object, &mut heap);
+        cache.shrink(&mut heap);
+    }
+    assert_eq!(CONSTRUCTED.load(Ordering::Relaxed), 0);
+}
@@ -0,0 +1,126 @@
+//! # Spawn test
+//!
+//! Spawns tasks through the global `spawn` function, from tasks and from an
+//! interrupt handler.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(tiny_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{ boxed::Box, rc::Rc, vec, vec::Vec };
+use bootloader::{ entry_point, BootInfo };
+use core::{
+    cell::RefCell,
+    panic::PanicInfo,
+    sync::atomic::{ AtomicBool, AtomicU8, Ordering },
+};
+use tiny_os::{
+    allocator,
+    interrupts::irq::{ self, IrqResult },
+    rtc,
+    task::{ self, executor::Executor, Priority, SpawnError },
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+    use tiny_os::memory::{ self, BootInfoFrameAllocator };
+
+    tiny_os::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_kernel_memory(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    tiny_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn spawn_without_executor() {
+    let result = task::try_spawn(async {});
+    assert_eq!(result.unwrap_err(), SpawnError::NoExecutor);
+}
+
+#[test_case]
+fn spawn_from_task() {
+    let mut executor = Executor::new();
+    task::set_global_spawner(executor.spawner());
+
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let outer_log = log.clone();
+    executor.spawn(async move {
+        let handles: Vec<_> = (0..3)
+            .map(|i| {
+                let log = outer_log.clone();
+                task::spawn(async move {
+                    log.borrow_mut().push(i);
+                    i * 10
+                })
+            })
+            .collect();
+        let mut sum = 0;
+        for handle in handles {
+            sum += handle.await.unwrap();
+        }
+        outer_log.borrow_mut().push(sum);
+    });
+    executor.run_to_completion();
+    assert_eq!(*log.borrow(), vec![0, 1, 2, 30]);
+}
+
+/// Set by the task that the interrupt handler spawned.
+static SPAWNED_TASK_RAN: AtomicBool = AtomicBool::new(false);
+/// 0: the handler should spawn, 1: it spawned, 2: spawning failed
+static INTERRUPT_STATE: AtomicU8 = AtomicU8::new(0);
+
+fn spawning_rtc_handler() -> IrqResult {
+    if INTERRUPT_STATE.load(Ordering::SeqCst) == 0 {
+        // The allocator disables interrupts while it holds its lock, so the
+        // allocation that we interrupted has released it.
+        let result = task::try_spawn_with_priority(Priority::BottomHalf, async {
+            SPAWNED_TASK_RAN.store(true, Ordering::SeqCst);
+        });
+        INTERRUPT_STATE.store(if result.is_ok() { 1 } else { 2 }, Ordering::SeqCst);
+    }
+    // The RTC driver acknowledges the interrupt.
+    IrqResult::NotMine
+}
+
+#[test_case]
+fn spawn_from_interrupt() {
+    let mut executor = Executor::new();
+    task::set_global_spawner(executor.spawner());
+
+    let handle = irq::register_irq(rtc::IRQ_LINE, spawning_rtc_handler).unwrap();
+    rtc::enable_periodic_interrupt(64).unwrap();
+    // Instead of halting, we allocate all the time, so that the interrupt
+    // arrives in the middle of an allocation or right after it.
+    while INTERRUPT_STATE.load(Ordering::SeqCst) == 0 {
+        let value = Box::new(0u64);
+        // The volatile read keeps the compiler from removing the allocation.
+        unsafe { core::ptr::read_volatile(&*value) };
+    }
+    rtc::disable_periodic_interrupt();
+    irq::unregister_irq(handle);
+    assert_eq!(INTERRUPT_STATE.load(Ordering::SeqCst), 1);
+
+    assert!(!executor.is_idle());
+    executor.run_to_completion();
+    assert!(SPAWNED_TASK_RAN.load(Ordering::SeqCst));
+}
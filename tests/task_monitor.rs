@@ -0,0 +1,102 @@
+//! # Task monitor test
+//!
+//! Checks the task snapshots of the executor with the kernel clock and the
+//! timer interrupt, and dumps them to the serial port.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(tiny_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{ entry_point, BootInfo };
+use core::{ future, panic::PanicInfo, time::Duration };
+use tiny_os::{
+    allocator,
+    task::{ self, executor::Executor, timer::sleep, Priority, TaskInfo, TaskState, WakeSource },
+    time::Instant,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+    use tiny_os::memory::{ self, BootInfoFrameAllocator };
+
+    tiny_os::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_kernel_memory(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    tiny_os::test_panic_handler(info)
+}
+
+const MS: Duration = Duration::from_millis(1);
+
+/// Returns the snapshot of the task with the given name.
+fn find(executor: &Executor, name: &str) -> TaskInfo {
+    let snapshot = executor.snapshot();
+    *snapshot.iter().find(|info| info.name == Some(name)).unwrap()
+}
+
+#[test_case]
+fn busy_time_uses_kernel_clock() {
+    let mut executor = Executor::new();
+    executor.spawn_named("spinning", Priority::Normal, async {
+        let start = Instant::now();
+        while start.elapsed() < 5 * MS {
+            core::hint::spin_loop();
+        }
+        future::pending::<()>().await;
+    });
+    executor.run_ready_tasks();
+
+    let info = find(&executor, "spinning");
+    assert_eq!(info.state, TaskState::Pending);
+    assert_eq!(info.polls, 1);
+    assert!(info.busy >= 5 * MS, "busy for {:?}", info.busy);
+}
+
+#[test_case]
+fn timer_interrupt_is_external_wake_source() {
+    let mut executor = Executor::new();
+    executor.spawn_named("sleeping", Priority::Normal, async {
+        sleep(2 * MS).await;
+        future::pending::<()>().await;
+    });
+    executor.run_ready_tasks();
+    while find(&executor, "sleeping").state == TaskState::Pending {
+        x86_64::instructions::hlt();
+    }
+
+    let info = find(&executor, "sleeping");
+    assert_eq!(info.state, TaskState::Ready);
+    assert_eq!(info.last_wake, WakeSource::External);
+}
+
+#[test_case]
+fn dump_running_executor() {
+    let mut executor = Executor::new();
+    task::set_global_monitor(executor.monitor());
+    executor.spawn_named("dumping", Priority::Normal, async {
+        // The dump works while the executor polls a task, like in the panic
+        // handler.
+        task::dump_tasks();
+    });
+    executor.run_ready_tasks();
+    assert_eq!(executor.task_count(), 0);
+}